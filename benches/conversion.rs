@@ -0,0 +1,73 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use h264_webcam_stream::YUVBuffer;
+
+/// Compares the SIMD and scalar RGB24 and YUYV to I420 conversions of random 1080p frames. Run with
+/// `cargo bench --bench conversion`.
+fn conversion(c: &mut Criterion) {
+    let (width, height) = (1920, 1080);
+    let mut seed = 0x2545_f491_4f6c_dd1d;
+
+    let rgb = random_bytes(&mut seed, width * height * 3);
+    let yuyv = random_bytes(&mut seed, width * height * 2);
+    let mut buffer = YUVBuffer::new(width, height);
+
+    let mut group = c.benchmark_group("1080p to I420");
+    group.bench_function("RGB24 SIMD", |b| b.iter(|| buffer.read_rgb(&rgb)));
+    group.bench_function("RGB24 scalar", |b| b.iter(|| buffer.read_rgb_scalar(&rgb)));
+    group.bench_function("YUYV SIMD", |b| b.iter(|| buffer.read_yuyv(&yuyv)));
+    group.bench_function("YUYV scalar", |b| b.iter(|| buffer.read_yuyv_scalar(&yuyv)));
+    group.finish();
+}
+
+/// Compares decoding 1080p MJPEG frames straight from their YCbCr samples with converting them to RGB and back.
+fn mjpeg_decode(c: &mut Criterion) {
+    let (width, height) = (1920, 1080);
+    let rgb: Vec<u8> = (0..width * height)
+        .flat_map(|i| {
+            let (x, y) = (i % width, i / width);
+            [(x / 8) as u8, (y / 4) as u8, ((x + y) / 12) as u8]
+        })
+        .collect();
+    let mut jpeg = Vec::new();
+    jpeg_encoder::Encoder::new(&mut jpeg, 85)
+        .encode(
+            &rgb,
+            width as u16,
+            height as u16,
+            jpeg_encoder::ColorType::Rgb,
+        )
+        .unwrap();
+    let mut buffer = YUVBuffer::new(width, height);
+
+    let mut group = c.benchmark_group("1080p MJPEG to I420");
+    group.bench_function("YCbCr", |b| {
+        b.iter(|| {
+            let mut decoder = jpeg_decoder::Decoder::new(&jpeg[..]);
+            // The samples are returned unchanged instead of being converted to RGB
+            decoder.set_color_transform(jpeg_decoder::ColorTransform::RGB);
+            buffer.read_ycbcr(&decoder.decode().unwrap());
+        })
+    });
+    group.bench_function("RGB round trip", |b| {
+        b.iter(|| {
+            let mut decoder = jpeg_decoder::Decoder::new(&jpeg[..]);
+            buffer.read_rgb(&decoder.decode().unwrap());
+        })
+    });
+    group.finish();
+}
+
+/// Fills a buffer using xorshift.
+fn random_bytes(seed: &mut u64, len: usize) -> Vec<u8> {
+    (0..len)
+        .map(|_| {
+            *seed ^= *seed << 13;
+            *seed ^= *seed >> 7;
+            *seed ^= *seed << 17;
+            (*seed >> 56) as u8
+        })
+        .collect()
+}
+
+criterion_group!(benches, conversion, mjpeg_decode);
+criterion_main!(benches);
@@ -0,0 +1,24 @@
+use eyre::Result;
+use futures_util::StreamExt;
+use h264_webcam_stream::tokio::AsyncWebcamH264Stream;
+use std::path::Path;
+use tokio::io::AsyncWriteExt;
+
+/// Record a video from an async task, eg. alongside an axum server. Run with `--features tokio`.
+#[tokio::main]
+async fn main() -> Result<()> {
+    let device = h264_webcam_stream::get_device(Path::new("/dev/video0"))?;
+    let stream = h264_webcam_stream::WebcamH264Stream::from_device(device)
+        .max_fps(60)
+        .open()?;
+
+    let mut frames = AsyncWebcamH264Stream::new(stream).take(120);
+    let mut f = tokio::fs::File::create("./test.h264").await?;
+
+    while let Some(frame) = frames.next().await {
+        let frame = frame?;
+        f.write_all(&frame.h264_bytes).await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,44 @@
+use eyre::Result;
+use h264_webcam_stream::controls;
+use std::path::Path;
+
+/// Keep the image's brightness within a target band by adjusting the camera's manual exposure.
+fn main() -> Result<()> {
+    let device = h264_webcam_stream::get_device(Path::new("/dev/video0"))?;
+    let mut stream = h264_webcam_stream::WebcamH264Stream::from_device(device)
+        .max_fps(30)
+        .open()?;
+
+    controls::set_exposure_auto(stream.device(), false)?;
+
+    let exposure = controls::get_exposure_absolute(stream.device())?;
+    let mut value = exposure.value.unwrap_or(exposure.default);
+
+    let target = 100.0..140.0;
+
+    for _ in 0..300 {
+        let (_, Some(yuv_frame)) = stream.next(true)? else {
+            continue;
+        };
+
+        let mean_luma = yuv_frame.mean_luma();
+
+        // Nudge the exposure by 10% until the brightness is back within the target
+        let adjusted = if mean_luma < target.start {
+            value + (value / 10).max(1)
+        } else if mean_luma > target.end {
+            value - (value / 10).max(1)
+        } else {
+            continue;
+        }
+        .clamp(exposure.minimum, exposure.maximum);
+
+        if adjusted != value {
+            value = adjusted;
+            println!("Mean luma {:.0}, setting exposure to {}", mean_luma, value);
+            controls::set_exposure_absolute(stream.device(), value as u32)?;
+        }
+    }
+
+    Ok(())
+}
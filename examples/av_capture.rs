@@ -0,0 +1,40 @@
+use eyre::Result;
+use h264_webcam_stream::audio::{AudioCapture, AvPacket, AvStream};
+use std::io::Write;
+use std::path::Path;
+
+/// Record the webcam's video and microphone to separate files, printing each packet's capture time to show how they
+/// interleave. Run with `--features audio`.
+fn main() -> Result<()> {
+    let device_path = Path::new("/dev/video0");
+    let device = h264_webcam_stream::get_device(device_path)?;
+    let stream = h264_webcam_stream::WebcamH264Stream::from_device(device)
+        .max_fps(30)
+        .open()?;
+
+    // Falls back to ALSA's default device if the camera has no microphone
+    let audio = AudioCapture::builder().camera(device_path).open()?;
+    let mut av = AvStream::new(stream, audio);
+
+    let mut video_out = std::fs::File::create("./test.h264")?;
+    let mut audio_out = std::fs::File::create("./test.s16le")?;
+    let mut frames = 0;
+
+    while frames < 150 {
+        match av.next_packet()? {
+            AvPacket::Video(frame) => {
+                println!("video {}", frame.meta.wall_clock());
+                video_out.write_all(&frame.h264_bytes)?;
+                frames += 1;
+            }
+            AvPacket::Audio(packet) => {
+                println!("audio {} ({:?})", packet.wall_clock(), packet.duration());
+                for sample in &packet.samples {
+                    audio_out.write_all(&sample.to_le_bytes())?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
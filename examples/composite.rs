@@ -0,0 +1,43 @@
+use eyre::Result;
+use h264_webcam_stream::{Compositor, Corner, FrameSource, Layout};
+use std::path::Path;
+
+/// Record two cameras into a single video, with the second camera picture-in-picture over the first.
+fn main() -> Result<()> {
+    let max_fps = 30;
+
+    let mut main_device = h264_webcam_stream::get_device(Path::new("/dev/video0"))?;
+    let mut inset_device = h264_webcam_stream::get_device(Path::new("/dev/video2"))?;
+
+    let main_stream = h264_webcam_stream::stream(&mut main_device, max_fps)?;
+    let inset_stream = h264_webcam_stream::stream(&mut inset_device, max_fps)?;
+
+    // Use Layout::SideBySide (eg. with a 1280x480 output) to show the cameras next to each other instead
+    let layout = Layout::Pip {
+        corner: Corner::BottomRight,
+        scale: 0.3,
+    };
+    let mut compositor = Compositor::new(
+        vec![Box::new(main_stream), Box::new(inset_stream)],
+        layout,
+        1280,
+        720,
+    )?;
+
+    // The compositor is a FrameSource like a single camera, so its frames can be recorded in the same way
+    let mut out = h264_webcam_stream::mp4::Mp4Writer::create(
+        "./composite.mp4",
+        compositor.width(),
+        compositor.height(),
+        compositor.fps(),
+    )?;
+
+    for _ in 0..300 {
+        let (h264_bytes, _) = compositor.next(false)?;
+        out.write(&h264_bytes[..])?;
+    }
+
+    out.finish()?;
+
+    Ok(())
+}
@@ -0,0 +1,42 @@
+use eyre::Result;
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_app as gst_app;
+use h264_webcam_stream::appsrc::AppSrcFeeder;
+use h264_webcam_stream::gstreamer::GstAppSrcAdapter;
+use std::path::Path;
+
+/// Display the webcam in a window through a GStreamer pipeline. Run with `--features gstreamer`, which needs the
+/// GStreamer development packages (eg. `libgstreamer-plugins-base1.0-dev`) and the libav plugin to decode the stream.
+fn main() -> Result<()> {
+    gst::init()?;
+
+    let device = h264_webcam_stream::get_device(Path::new("/dev/video0"))?;
+    let mut stream = h264_webcam_stream::WebcamH264Stream::from_device(device)
+        .max_fps(30)
+        .open()?;
+
+    let pipeline = gst::parse::launch(
+        "appsrc name=camera ! h264parse ! avdec_h264 ! videoconvert ! autovideosink",
+    )?
+    .downcast::<gst::Pipeline>()
+    .expect("The pipeline description is a pipeline");
+    let appsrc = pipeline
+        .by_name("camera")
+        .and_then(|element| element.downcast::<gst_app::AppSrc>().ok())
+        .expect("The pipeline has an appsrc named camera");
+
+    let feeder = AppSrcFeeder::new(stream.width, stream.height, stream.fps());
+    let mut adapter = GstAppSrcAdapter::new(&appsrc, feeder)?;
+    pipeline.set_state(gst::State::Playing)?;
+
+    loop {
+        // Resume sooner after the pipeline falls behind, rather than waiting for the camera's next GOP
+        if adapter.wants_keyframe() {
+            stream.request_keyframe()?;
+        }
+
+        let (meta, h264_bytes, _) = stream.next_with_meta(false)?;
+        adapter.push(&meta, h264_bytes)?;
+    }
+}
@@ -0,0 +1,45 @@
+use eyre::Result;
+use h264_webcam_stream::hls::{HlsConfig, HlsSegmenter};
+use std::path::Path;
+
+const INDEX_HTML: &str = r#"<!DOCTYPE html>
+<video id="video" controls autoplay muted playsinline></video>
+<script src="https://cdn.jsdelivr.net/npm/hls.js@1"></script>
+<script>
+  const video = document.getElementById("video");
+  if (video.canPlayType("application/vnd.apple.mpegurl")) {
+    // Safari plays HLS natively
+    video.src = "index.m3u8";
+  } else {
+    const hls = new Hls();
+    hls.loadSource("index.m3u8");
+    hls.attachMedia(video);
+  }
+</script>
+"#;
+
+/// Stream the webcam live over HLS from a directory of static files. Run with `--features hls` and serve the
+/// directory with any static file server, eg. `python3 -m http.server -d ./hls 8000`, then open
+/// http://localhost:8000 in Safari or any browser supported by hls.js.
+fn main() -> Result<()> {
+    let device = h264_webcam_stream::get_device(Path::new("/dev/video0"))?;
+    let mut stream = h264_webcam_stream::WebcamH264Stream::from_device(device)
+        .max_fps(30)
+        .open()?;
+
+    let mut hls = HlsSegmenter::new("./hls", HlsConfig::default())?;
+    std::fs::write("./hls/index.html", INDEX_HTML)?;
+    println!("Serve the stream with `python3 -m http.server -d ./hls 8000` and open http://localhost:8000");
+
+    loop {
+        let (meta, h264_bytes, _) = stream.next_with_meta(false)?;
+        hls.write_with_meta(&h264_bytes, &meta)?;
+
+        // Keeps the segments close to 2 seconds long when the camera's GOP is longer
+        if hls.should_request_keyframe() {
+            if let Err(err) = stream.request_keyframe() {
+                eprintln!("Unable to request a key frame: {:?}", err);
+            }
+        }
+    }
+}
@@ -0,0 +1,44 @@
+use eyre::Result;
+use h264_webcam_stream::overlay::{
+    Overlay, OverlayColor, OverlayPosition, OverlayText, TextOverlay,
+};
+use h264_webcam_stream::{FourCC, OutputDevice};
+use std::path::Path;
+
+/// Re-publishes a camera with a timestamp overlay as a virtual camera that Zoom, OBS etc. can use. Create the loopback
+/// device first with `sudo modprobe v4l2loopback video_nr=10 exclusive_caps=1`.
+fn main() -> Result<()> {
+    let device = h264_webcam_stream::get_device(Path::new("/dev/video0"))?;
+
+    // Overlays are drawn while transcoding so MJPEG is preferred over native H264
+    let mut stream = h264_webcam_stream::WebcamH264Stream::from_device(device)
+        .max_fps(30)
+        .prefer_fourcc(FourCC::new(b"MJPG"))
+        .overlay(Overlay::text(
+            TextOverlay::new(OverlayPosition::TopLeft, 2, OverlayColor::WHITE)
+                .background(OverlayColor::BLACK),
+            OverlayText::Timestamp("%Y-%m-%d %H:%M:%S".to_string()),
+        ))
+        .open()?;
+
+    let mut output = OutputDevice::open(
+        "/dev/video10",
+        stream.width,
+        stream.height,
+        FourCC::new(b"YUYV"),
+    )?;
+    println!(
+        "Writing {}x{} {} frames to /dev/video10",
+        output.width(),
+        output.height(),
+        output.fourcc()
+    );
+
+    loop {
+        let (_h264_bytes, yuv_frame) = stream.next(true)?;
+
+        if let Some(yuv_frame) = yuv_frame {
+            output.write_frame(&yuv_frame)?;
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use eyre::Result;
+use h264_webcam_stream::{FrameSource, Pattern, SyntheticSource};
+use std::time::{Duration, Instant};
+
+/// Compares the cost of getting a grayscale image for computer vision via `luma_packed` versus converting to RGB and
+/// back to gray. Uses synthetic frames so no camera is needed, run with `cargo run --release --example luma_benchmark`.
+fn main() -> Result<()> {
+    let iterations = 100;
+    let mut source = SyntheticSource::new(1920, 1080, 30.0, Pattern::MovingBox)?;
+
+    let (_, yuv_frame) = source.next(true)?;
+    let yuv_frame = yuv_frame.expect("synthetic sources always produce a YUV frame");
+
+    let mut gray = Vec::new();
+    let mut rgb = vec![0; yuv_frame.rgb_len()];
+
+    let packed = time(iterations, || yuv_frame.luma_packed(&mut gray));
+
+    let via_rgb = time(iterations, || {
+        yuv_frame.to_rgb(&mut rgb);
+
+        gray.clear();
+        gray.extend(rgb.chunks_exact(3).map(|pixel| {
+            ((77 * pixel[0] as u32 + 150 * pixel[1] as u32 + 29 * pixel[2] as u32) >> 8) as u8
+        }));
+    });
+
+    println!(
+        "{}x{} luma_packed: {:?} per frame",
+        yuv_frame.width(),
+        yuv_frame.height(),
+        packed
+    );
+    println!("to_rgb + grayscale: {:?} per frame", via_rgb);
+    println!(
+        "luma_packed is {:.1}x faster",
+        via_rgb.as_secs_f64() / packed.as_secs_f64()
+    );
+
+    Ok(())
+}
+
+fn time(iterations: u32, mut f: impl FnMut()) -> Duration {
+    let start = Instant::now();
+
+    for _ in 0..iterations {
+        f();
+    }
+
+    start.elapsed() / iterations
+}
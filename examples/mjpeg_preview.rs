@@ -0,0 +1,24 @@
+use eyre::Result;
+use h264_webcam_stream::http_preview::MjpegServer;
+use std::path::Path;
+
+/// Preview the camera in a browser at http://localhost:8080/stream. Run with `--features http_preview`.
+fn main() -> Result<()> {
+    let device = h264_webcam_stream::get_device(Path::new("/dev/video0"))?;
+    let mut stream = h264_webcam_stream::WebcamH264Stream::from_device(device)
+        .max_fps(30)
+        .open()?;
+
+    let server = MjpegServer::bind("0.0.0.0:8080")?;
+    println!("Serving http://{}/stream", server.local_addr());
+
+    loop {
+        // Only encode JPEGs while someone is watching
+        if server.client_count() == 0 {
+            stream.next(false)?;
+            continue;
+        }
+
+        server.push_jpeg(stream.snapshot_jpeg(80)?);
+    }
+}
@@ -0,0 +1,52 @@
+use eyre::Result;
+use h264_webcam_stream::{get_device, FourCC, WebcamH264Stream};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Compares the sustained frame rate of transcoding an MJPEG camera with and without a capture pipeline. Run with
+/// `cargo run --release --example pipeline_benchmark [device]`, ideally with a 1080p60 MJPEG camera.
+fn main() -> Result<()> {
+    let device_path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "/dev/video0".to_string());
+    let duration = Duration::from_secs(10);
+
+    for queue_depth in [None, Some(8)] {
+        let device = get_device(Path::new(&device_path))?;
+        let mut builder = WebcamH264Stream::from_device(device)
+            .prefer_fourcc(FourCC::new(b"MJPG"))
+            .max_fps(60);
+        if let Some(queue_depth) = queue_depth {
+            builder = builder.pipelined(queue_depth);
+        }
+        let mut stream = builder.open()?;
+
+        // Skip the camera's startup frames, which often arrive late while it adjusts its exposure
+        for _ in 0..30 {
+            stream.next(false)?;
+        }
+        stream.reset_stats();
+
+        let start = Instant::now();
+        while start.elapsed() < duration {
+            stream.next(false)?;
+        }
+
+        let stats = stream.stats();
+        println!(
+            "{} {}x{} at {:.0}fps: {:.1}fps sustained, {} frames dropped ({} from the pipeline queue)",
+            match queue_depth {
+                Some(_) => "Pipelined",
+                None => "Direct",
+            },
+            stream.width,
+            stream.height,
+            stream.fps(),
+            stats.frames as f64 / start.elapsed().as_secs_f64(),
+            stats.dropped_frames,
+            stream.dropped_raw_frames()
+        );
+    }
+
+    Ok(())
+}
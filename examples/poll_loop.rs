@@ -0,0 +1,43 @@
+use eyre::Result;
+use mio::unix::SourceFd;
+use mio::{Events, Interest, Poll, Token};
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+const CAMERA: Token = Token(0);
+
+/// Record a video from a mio event loop alongside a 1 second timeout, by registering the stream's readiness fd.
+fn main() -> Result<()> {
+    let device = h264_webcam_stream::get_device(Path::new("/dev/video0"))?;
+    let mut stream = h264_webcam_stream::WebcamH264Stream::from_device(device)
+        .max_fps(30)
+        .open()?;
+
+    let mut poll = Poll::new()?;
+    let mut events = Events::with_capacity(16);
+    poll.registry().register(
+        &mut SourceFd(&stream.readiness_fd()),
+        CAMERA,
+        Interest::READABLE,
+    )?;
+
+    let mut f = std::fs::File::create("./test.h264")?;
+    let mut frames = 0;
+
+    while frames < 120 {
+        poll.poll(&mut events, Some(Duration::from_secs(1)))?;
+        if events.is_empty() {
+            println!("Waiting for the camera, {} frames so far", frames);
+            continue;
+        }
+
+        // mio's readiness is edge-triggered, so every frame that is ready is read before polling again
+        while let Some((h264_bytes, _)) = stream.try_next(false)? {
+            f.write_all(&h264_bytes)?;
+            frames += 1;
+        }
+    }
+
+    Ok(())
+}
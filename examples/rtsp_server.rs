@@ -0,0 +1,21 @@
+use eyre::Result;
+use h264_webcam_stream::rtsp::RtspServer;
+use h264_webcam_stream::Broadcaster;
+use std::path::Path;
+
+/// Serve the webcam over RTSP. Run with `--features rtsp` and play it with `vlc --rtsp-tcp rtsp://localhost:8554/cam`.
+fn main() -> Result<()> {
+    let device = h264_webcam_stream::get_device(Path::new("/dev/video0"))?;
+    let mut stream = h264_webcam_stream::WebcamH264Stream::from_device(device)
+        .max_fps(30)
+        .open()?;
+
+    // Every RTSP session subscribes to the broadcaster, so they all share the camera
+    let broadcaster = Broadcaster::new();
+    let _server = RtspServer::bind("0.0.0.0:8554", broadcaster.clone(), stream.fps())?;
+
+    loop {
+        let (meta, h264_bytes, _) = stream.next_with_meta(false)?;
+        broadcaster.push(meta, h264_bytes);
+    }
+}
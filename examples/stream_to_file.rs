@@ -1,20 +1,25 @@
 use eyre::Result;
-use std::{io::Write, path::Path};
+use std::path::Path;
 
 fn main() -> Result<()> {
     let device_path = Path::new("/dev/video0");
     let max_fps = 60;
 
-    let mut device = h264_webcam_stream::get_device(&device_path)?;
-    let mut stream = h264_webcam_stream::stream(&mut device, max_fps)?;
+    let device = h264_webcam_stream::get_device(device_path)?;
+    let mut stream = h264_webcam_stream::WebcamH264Stream::from_device(device)
+        .max_fps(max_fps)
+        .open()?;
 
     let mut f = std::fs::File::create("./test.h264")?;
 
-    for _ in 0..120 {
-        let (h264_bytes, _) = stream.next(false)?;
-        // Record the h264 video to a file
-        f.write_all(&h264_bytes[..])?;
-    }
+    // Record 120 frames of h264 video to the file
+    let stats = stream.record_frames(120, &mut f)?;
+    println!(
+        "Recorded {} frames ({} bytes) at {:.1}fps",
+        stats.frames,
+        stats.bytes,
+        stats.average_fps()
+    );
 
     Ok(())
 }
@@ -0,0 +1,54 @@
+use eyre::Result;
+use h264_webcam_stream::{FrameSource, Pattern, ScaleFilter, SyntheticSource, TensorSpec};
+use ndarray::Array3;
+use std::time::{Duration, Instant};
+
+/// Compares `YUVFrame::to_tensor`'s fused scale, color conversion and normalization against doing each step separately
+/// with intermediate buffers. Uses synthetic frames so no camera is needed, run with
+/// `cargo run --release --features ndarray --example tensor_benchmark`.
+fn main() -> Result<()> {
+    let iterations = 100;
+    let spec = TensorSpec::new(320, 320);
+
+    let mut source = SyntheticSource::new(1280, 720, 30.0, Pattern::MovingBox)?;
+    let (_, yuv_frame) = source.next(true)?;
+    let yuv_frame = yuv_frame.expect("synthetic sources always produce a YUV frame");
+
+    let fused = time(iterations, || {
+        std::hint::black_box(yuv_frame.to_tensor(&spec));
+    });
+
+    let naive = time(iterations, || {
+        let scaled = yuv_frame.scale(320, 320, ScaleFilter::Bilinear).unwrap();
+        let rgb = h264_webcam_stream::YUVFrame::Buffer(scaled).to_rgb_vec();
+
+        let mut tensor = Array3::<f32>::zeros((3, 320, 320));
+        for (i, pixel) in rgb.chunks_exact(3).enumerate() {
+            for (channel, value) in pixel.iter().enumerate() {
+                tensor[[channel, i / 320, i % 320]] = *value as f32 / 255.0;
+            }
+        }
+        std::hint::black_box(tensor);
+    });
+
+    println!(
+        "{}x{} to 320x320 CHW: fused {:?}, naive {:?} per frame ({:.1}x faster)",
+        yuv_frame.width(),
+        yuv_frame.height(),
+        fused,
+        naive,
+        naive.as_secs_f64() / fused.as_secs_f64()
+    );
+
+    Ok(())
+}
+
+fn time(iterations: u32, mut f: impl FnMut()) -> Duration {
+    let start = Instant::now();
+
+    for _ in 0..iterations {
+        f();
+    }
+
+    start.elapsed() / iterations
+}
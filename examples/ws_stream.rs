@@ -0,0 +1,31 @@
+use eyre::Result;
+use h264_webcam_stream::ws_stream::WsStreamer;
+use std::path::Path;
+
+/// Stream the webcam live to browsers over a WebSocket. Run with `--features ws_stream` and open http://localhost:8080
+/// to watch.
+#[tokio::main]
+async fn main() -> Result<()> {
+    let mut ws = WsStreamer::bind("0.0.0.0:8080")?;
+
+    let device = h264_webcam_stream::get_device(Path::new("/dev/video0"))?;
+    let mut stream = h264_webcam_stream::WebcamH264Stream::from_device(device)
+        .max_fps(30)
+        .open()?;
+
+    // Reading frames blocks, so the stream runs on tokio's blocking thread pool
+    tokio::task::spawn_blocking(move || -> Result<()> {
+        loop {
+            // New clients start at a key frame, so ask for one rather than waiting for the camera's next GOP
+            if ws.waiting_for_keyframe() {
+                if let Err(err) = stream.request_keyframe() {
+                    eprintln!("Unable to request a key frame: {:?}", err);
+                }
+            }
+
+            let (meta, h264_bytes, _) = stream.next_with_meta(false)?;
+            ws.push_with_meta(&h264_bytes, &meta);
+        }
+    })
+    .await?
+}
@@ -0,0 +1,37 @@
+//! Splits arbitrary bytes into NAL units and runs them through the bitstream conversions and validator, as the stream
+//! does with the bytes a camera produces.
+
+#![no_main]
+
+use h264_webcam_stream::bitstream::{annexb_to_avcc, avcc_to_annexb, extract_parameter_sets};
+use h264_webcam_stream::nal::{self, NalParser, NalType, NalUnits};
+use h264_webcam_stream::BitstreamValidator;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    for nal in NalUnits::new(data) {
+        NalType::of(nal);
+    }
+    nal::is_keyframe(data);
+
+    // The first byte picks the size of the pieces the bitstream arrives in, so start codes are split between them
+    if let Some((&chunk_size, bitstream)) = data.split_first() {
+        let mut parser = NalParser::new();
+        for chunk in bitstream.chunks(usize::from(chunk_size).max(1)) {
+            parser.push(chunk);
+        }
+        parser.flush();
+    }
+
+    let mut avcc = Vec::new();
+    annexb_to_avcc(data, &mut avcc);
+    let mut annexb = Vec::new();
+    avcc_to_annexb(&avcc, &mut annexb).expect("Converted AVCC samples are never truncated");
+    let _ = avcc_to_annexb(data, &mut Vec::new());
+
+    extract_parameter_sets(data);
+
+    let mut validator = BitstreamValidator::new();
+    validator.validate(data);
+    validator.validate(data);
+});
@@ -0,0 +1,16 @@
+//! Parses arbitrary bytes as an SPS, both as-is and with a valid SPS header so that the parser gets past the header.
+
+#![no_main]
+
+use h264_webcam_stream::sps;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = sps::parse(data);
+
+    let mut with_header = vec![0x67];
+    with_header.extend_from_slice(data);
+    if let Ok(sps_info) = sps::parse(&with_header) {
+        sps_info.codec_string();
+    }
+});
@@ -0,0 +1,226 @@
+use crate::{StreamError, YUVBuffer, YUVFrame, YUVSource};
+
+/// How a [`FrameAccumulator`] combines frames.
+enum Mode {
+    /// The mean of each window of `frames_per_output` frames.
+    Average {
+        frames_per_output: usize,
+        sums: Vec<u32>,
+        frames: usize,
+    },
+    /// A running average with 8 fractional bits, blending in each new frame by `alpha` / 256.
+    Exponential { alpha: u32, average: Vec<u16> },
+}
+
+/// Averages consecutive YUV frames, eg. to reduce the noise of low light timelapse frames or as a long exposure.
+///
+/// By default every `frames_per_output` frames are averaged into a single output frame. With
+/// [`exponential`](Self::exponential) each frame is blended into a running average instead, which produces an output
+/// for every frame and works as a temporal denoiser for live streams (at the cost of motion blur).
+///
+/// Pass an accumulator to
+/// [`TimelapseRecorder::accumulator`](crate::TimelapseRecorder::accumulator) to average the frames of each timelapse
+/// frame.
+pub struct FrameAccumulator {
+    width: usize,
+    height: usize,
+    mode: Mode,
+}
+
+impl FrameAccumulator {
+    /// Creates an accumulator that averages every `frames_per_output` frames of the given dimensions (eg. the stream's
+    /// `width` and `height`).
+    pub fn new(width: u32, height: u32, frames_per_output: usize) -> Self {
+        let (width, height) = (width as usize, height as usize);
+
+        Self {
+            width,
+            height,
+            mode: Mode::Average {
+                frames_per_output: frames_per_output.max(1),
+                sums: vec![0; plane_samples(width, height)],
+                frames: 0,
+            },
+        }
+    }
+
+    /// Creates an accumulator that blends each frame into a running average, weighting the new frame by `alpha`
+    /// (clamped to 0 to 1). Lower values remove more noise but blur motion for longer, eg. 0.1 averages roughly the last
+    /// ten frames.
+    pub fn exponential(width: u32, height: u32, alpha: f32) -> Self {
+        let (width, height) = (width as usize, height as usize);
+
+        Self {
+            width,
+            height,
+            mode: Mode::Exponential {
+                alpha: (alpha.clamp(0.0, 1.0) * 256.0).round() as u32,
+                average: Vec::new(),
+            },
+        }
+    }
+
+    /// True for accumulators created with [`exponential`](Self::exponential), which output a frame for every frame
+    /// pushed.
+    pub fn is_exponential(&self) -> bool {
+        matches!(self.mode, Mode::Exponential { .. })
+    }
+
+    /// The number of frames pushed since the last averaged frame was returned. Always 0 in exponential mode.
+    pub fn pending_frames(&self) -> usize {
+        match &self.mode {
+            Mode::Average { frames, .. } => *frames,
+            Mode::Exponential { .. } => 0,
+        }
+    }
+
+    /// Adds a frame, returning the averaged frame once `frames_per_output` frames have been pushed (or for every frame
+    /// in exponential mode). Frames with padded strides (eg. from the decoder) are supported.
+    pub fn push(&mut self, yuv: &YUVFrame) -> Result<Option<YUVBuffer>, StreamError> {
+        if (yuv.width(), yuv.height()) != (self.width, self.height) {
+            return Err(StreamError::FrameSizeMismatch {
+                expected: (self.width as u32, self.height as u32),
+                actual: (yuv.width() as u32, yuv.height() as u32),
+            });
+        }
+
+        let (width, height) = (self.width, self.height);
+
+        match &mut self.mode {
+            Mode::Average {
+                frames_per_output,
+                sums,
+                frames,
+            } => {
+                for_each_sample(yuv, width, height, sums, |sum, sample| {
+                    *sum += sample as u32
+                });
+                *frames += 1;
+
+                if *frames < *frames_per_output {
+                    return Ok(None);
+                }
+            }
+            Mode::Exponential { alpha, average } => {
+                if average.is_empty() {
+                    // The first frame starts the average rather than being blended with black
+                    average.resize(plane_samples(width, height), 0);
+                    for_each_sample(yuv, width, height, average, |average, sample| {
+                        *average = (sample as u16) << 8
+                    });
+                } else {
+                    let alpha = *alpha as i32;
+                    for_each_sample(yuv, width, height, average, |average, sample| {
+                        let delta = ((sample as i32) << 8) - *average as i32;
+                        *average = (*average as i32 + ((delta * alpha) >> 8)) as u16;
+                    });
+                }
+            }
+        }
+
+        Ok(self.flush())
+    }
+
+    /// Returns the average of the frames pushed so far, eg. to keep the trailing frames at the end of a recording when
+    /// fewer than `frames_per_output` are pending. Returns `None` if no frames are pending.
+    ///
+    /// In average mode this starts a new window. In exponential mode the running average is kept.
+    pub fn flush(&mut self) -> Option<YUVBuffer> {
+        let mut output = YUVBuffer::new(self.width, self.height);
+
+        match &mut self.mode {
+            Mode::Average { sums, frames, .. } => {
+                if *frames == 0 {
+                    return None;
+                }
+
+                // Rounded to the nearest value rather than truncated so that the average isn't biased darker
+                let count = *frames as u32;
+                write_planes(
+                    &mut output,
+                    sums.iter().map(|sum| ((sum + count / 2) / count) as u8),
+                );
+                sums.iter_mut().for_each(|sum| *sum = 0);
+                *frames = 0;
+            }
+            Mode::Exponential { average, .. } => {
+                if average.is_empty() {
+                    return None;
+                }
+
+                write_planes(
+                    &mut output,
+                    average
+                        .iter()
+                        .map(|&average| ((average as u32 + 128) >> 8).min(255) as u8),
+                );
+            }
+        }
+
+        Some(output)
+    }
+
+    /// Discards the pending frames (or the running average in exponential mode), eg. after the scene changes.
+    pub fn reset(&mut self) {
+        match &mut self.mode {
+            Mode::Average { sums, frames, .. } => {
+                sums.iter_mut().for_each(|sum| *sum = 0);
+                *frames = 0;
+            }
+            Mode::Exponential { average, .. } => average.clear(),
+        }
+    }
+}
+
+/// The number of Y, U and V samples in a tightly packed I420 frame.
+fn plane_samples(width: usize, height: usize) -> usize {
+    width * height + 2 * (width / 2) * (height / 2)
+}
+
+/// Fills the output's Y, U and V planes, in that order, with tightly packed samples.
+fn write_planes(output: &mut YUVBuffer, mut samples: impl Iterator<Item = u8>) {
+    let uv = (output.width() as usize / 2) * (output.height() as usize / 2);
+
+    for out in output.y_mut().iter_mut() {
+        *out = samples.next().unwrap_or(0);
+    }
+    for out in output.u_mut()[..uv].iter_mut() {
+        *out = samples.next().unwrap_or(0);
+    }
+    for out in output.v_mut()[..uv].iter_mut() {
+        *out = samples.next().unwrap_or(0);
+    }
+}
+
+/// Calls `f` with each visible sample of the frame's Y, U and V planes (skipping stride padding) and the matching
+/// element of `acc`, which holds the planes tightly packed one after another.
+fn for_each_sample<T>(
+    yuv: &YUVFrame,
+    width: usize,
+    height: usize,
+    acc: &mut [T],
+    mut f: impl FnMut(&mut T, u8),
+) {
+    let (y_stride, u_stride, v_stride) = yuv.strides();
+    let (chroma_width, chroma_height) = (width / 2, height / 2);
+    let (luma, chroma) = acc.split_at_mut(width * height);
+    let (u_acc, v_acc) = chroma.split_at_mut(chroma_width * chroma_height);
+
+    let planes = [
+        (yuv.y(), y_stride, width, luma),
+        (yuv.u(), u_stride, chroma_width, u_acc),
+        (yuv.v(), v_stride, chroma_width, v_acc),
+    ];
+
+    for (plane, stride, plane_width, acc) in planes {
+        if plane_width == 0 {
+            continue;
+        }
+
+        for (acc_row, row) in acc.chunks_exact_mut(plane_width).zip(plane.chunks(stride)) {
+            for (acc, &sample) in acc_row.iter_mut().zip(&row[..plane_width]) {
+                f(acc, sample);
+            }
+        }
+    }
+}
@@ -0,0 +1,333 @@
+//! Feeding a stream into a GStreamer pipeline through an `appsrc` element, eg. for RTSP serving or display.
+//!
+//! [`AppSrcFeeder`] produces the caps string and the buffers' timestamps and flags, and handles the appsrc's
+//! `need-data` / `enough-data` signals by dropping whole GOPs when the pipeline falls behind. With the `gstreamer`
+//! feature `gstreamer::GstAppSrcAdapter` pushes its buffers into a `gstreamer_app::AppSrc`. The feeder itself doesn't
+//! depend on the GStreamer bindings, so it can also be wired up to other versions of them.
+
+use crate::{FrameMeta, SpsInfo};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Tracks the appsrc's `need-data` and `enough-data` signals. Clones share the same state so they can be moved into
+/// the appsrc's callbacks, which are called on GStreamer's threads.
+#[derive(Debug, Clone)]
+pub struct AppSrcFlow {
+    accepting: Arc<AtomicBool>,
+}
+
+impl AppSrcFlow {
+    /// Call from the appsrc's `need-data` callback.
+    pub fn need_data(&self) {
+        self.accepting.store(true, Ordering::Relaxed);
+    }
+
+    /// Call from the appsrc's `enough-data` callback.
+    pub fn enough_data(&self) {
+        self.accepting.store(false, Ordering::Relaxed);
+    }
+
+    /// False between `enough-data` and the next `need-data`.
+    pub fn is_accepting(&self) -> bool {
+        self.accepting.load(Ordering::Relaxed)
+    }
+}
+
+/// A buffer to push into the appsrc.
+#[derive(Debug, Clone)]
+pub struct AppSrcBuffer {
+    /// An Annex-B access unit.
+    pub data: Vec<u8>,
+    /// The presentation timestamp in nanoseconds (a `GstClockTime`), starting from 0 at the first buffer.
+    pub pts: u64,
+    /// The frame interval in nanoseconds.
+    pub duration: u64,
+    /// True if the frame is not a key frame, ie. the buffer should have `GST_BUFFER_FLAG_DELTA_UNIT` set.
+    pub delta_unit: bool,
+}
+
+/// Prepares a stream's frames for a GStreamer `appsrc`, see the [module documentation](self).
+///
+/// Buffers start at the first key frame. If the appsrc signals `enough-data` the rest of the current GOP is still
+/// pushed (appsrc queues it beyond its limit) and frames are then dropped from the next key frame until the appsrc
+/// signals `need-data` again and another key frame arrives, so the pipeline only ever receives whole GOPs.
+/// [`wants_keyframe`](Self::wants_keyframe) reports when a key frame should be requested to resume sooner.
+#[derive(Debug)]
+pub struct AppSrcFeeder {
+    width: u32,
+    height: u32,
+    fps: f64,
+    sps_info: Option<SpsInfo>,
+    flow: AppSrcFlow,
+    // The capture timestamp of the first buffer
+    origin: Option<Duration>,
+    // True while frames are dropped until the next key frame
+    dropping: bool,
+    dropped_frames: u64,
+}
+
+impl AppSrcFeeder {
+    /// Creates a feeder for a stream of `width` x `height` frames at `fps` (eg. the stream's `width`, `height` and
+    /// `fps()`).
+    pub fn new(width: u32, height: u32, fps: f64) -> Self {
+        Self {
+            width,
+            height,
+            fps,
+            sps_info: None,
+            flow: AppSrcFlow {
+                accepting: Arc::new(AtomicBool::new(true)),
+            },
+            origin: None,
+            dropping: true,
+            dropped_frames: 0,
+        }
+    }
+
+    /// Adds the profile and level from the stream's SPS (see
+    /// [`WebcamH264Stream::sps_info`](crate::WebcamH264Stream::sps_info)) to the caps.
+    pub fn sps_info(mut self, sps_info: SpsInfo) -> Self {
+        self.sps_info = Some(sps_info);
+        self
+    }
+
+    /// The caps to set on the appsrc, eg. `video/x-h264, stream-format=(string)byte-stream, alignment=(string)au,
+    /// width=(int)1280, height=(int)720, framerate=(fraction)30/1`.
+    pub fn caps(&self) -> String {
+        let (numerator, denominator) = fps_fraction(self.fps);
+        let mut caps = format!(
+            "video/x-h264, stream-format=(string)byte-stream, alignment=(string)au, width=(int){}, height=(int){}, \
+             framerate=(fraction){}/{}",
+            self.width, self.height, numerator, denominator
+        );
+
+        if let Some(sps_info) = &self.sps_info {
+            if let Some(profile) = profile_name(sps_info) {
+                caps.push_str(&format!(", profile=(string){}", profile));
+            }
+            caps.push_str(&format!(", level=(string){}", level_name(sps_info)));
+        }
+
+        caps
+    }
+
+    /// A handle to call from the appsrc's `need-data` and `enough-data` callbacks.
+    pub fn flow(&self) -> AppSrcFlow {
+        self.flow.clone()
+    }
+
+    /// Converts a frame to a buffer, or returns `None` if the frame is dropped.
+    pub fn push(&mut self, meta: &FrameMeta, h264_bytes: Vec<u8>) -> Option<AppSrcBuffer> {
+        if meta.is_keyframe {
+            self.dropping = !self.flow.is_accepting();
+        }
+
+        if self.dropping {
+            self.dropped_frames += 1;
+            return None;
+        }
+
+        let origin = *self.origin.get_or_insert(meta.timestamp);
+        let duration = if self.fps.is_finite() && self.fps > 0.0 {
+            (1e9 / self.fps).round() as u64
+        } else {
+            0
+        };
+
+        Some(AppSrcBuffer {
+            data: h264_bytes,
+            pts: meta.timestamp.saturating_sub(origin).as_nanos() as u64,
+            duration,
+            delta_unit: !meta.is_keyframe,
+        })
+    }
+
+    /// True if frames are being dropped while the appsrc has asked for more data, ie. the feeder is waiting for a key
+    /// frame to resume from (eg. request one with
+    /// [`WebcamH264Stream::request_keyframe`](crate::WebcamH264Stream::request_keyframe)).
+    pub fn wants_keyframe(&self) -> bool {
+        self.dropping && self.flow.is_accepting()
+    }
+
+    /// The number of frames dropped since the feeder was created, including those before the first key frame.
+    pub fn dropped_frames(&self) -> u64 {
+        self.dropped_frames
+    }
+}
+
+/// The frame rate as a fraction, eg. 30000/1001 for NTSC rates.
+fn fps_fraction(fps: f64) -> (u32, u32) {
+    if !fps.is_finite() || fps <= 0.0 {
+        // A variable frame rate
+        return (0, 1);
+    }
+
+    if (fps - fps.round()).abs() < 0.001 {
+        (fps.round() as u32, 1)
+    } else {
+        ((fps * 1001.0).round() as u32, 1001)
+    }
+}
+
+/// The GStreamer name of the SPS's profile.
+fn profile_name(sps_info: &SpsInfo) -> Option<&'static str> {
+    let constraint_set = |n: u8| sps_info.constraint_flags & (0x80 >> n) != 0;
+
+    Some(match sps_info.profile_idc {
+        66 if constraint_set(1) => "constrained-baseline",
+        66 => "baseline",
+        77 => "main",
+        88 => "extended",
+        100 => "high",
+        110 => "high-10",
+        122 => "high-4:2:2",
+        244 => "high-4:4:4",
+        _ => return None,
+    })
+}
+
+/// The GStreamer name of the SPS's level, eg. `3.1`.
+fn level_name(sps_info: &SpsInfo) -> String {
+    let level = sps_info.level_idc;
+
+    // Level 1b is signalled as 1.1 with constraint_set3 in the baseline and main profiles, or as 9 otherwise
+    let constraint_set3 = sps_info.constraint_flags & 0x10 != 0;
+    if level == 9 || (level == 11 && constraint_set3 && matches!(sps_info.profile_idc, 66 | 77)) {
+        return "1b".to_string();
+    }
+
+    match level % 10 {
+        0 => format!("{}", level / 10),
+        minor => format!("{}.{}", level / 10, minor),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ClockMapping;
+
+    fn sps_info(profile_idc: u8, constraint_flags: u8, level_idc: u8) -> SpsInfo {
+        SpsInfo {
+            width: 640,
+            height: 480,
+            profile_idc,
+            constraint_flags,
+            level_idc,
+            fps_from_vui: None,
+        }
+    }
+
+    fn meta(frame: u32, is_keyframe: bool) -> FrameMeta {
+        FrameMeta {
+            timestamp: Duration::from_secs(10) + Duration::from_secs(frame as u64) / 30,
+            clock: ClockMapping::default(),
+            sequence: frame,
+            dropped_since_last: 0,
+            bytesused: 0,
+            is_keyframe,
+        }
+    }
+
+    #[test]
+    fn builds_caps() {
+        assert_eq!(
+            AppSrcFeeder::new(1280, 720, 30.0).caps(),
+            "video/x-h264, stream-format=(string)byte-stream, alignment=(string)au, width=(int)1280, \
+             height=(int)720, framerate=(fraction)30/1"
+        );
+
+        let ntsc = AppSrcFeeder::new(640, 480, 30000.0 / 1001.0)
+            .sps_info(sps_info(66, 0x40, 31))
+            .caps();
+        assert!(
+            ntsc.ends_with(
+                ", framerate=(fraction)30000/1001, profile=(string)constrained-baseline, level=(string)3.1"
+            ),
+            "{ntsc}"
+        );
+
+        let variable = AppSrcFeeder::new(640, 480, 0.0).caps();
+        assert!(
+            variable.ends_with(", framerate=(fraction)0/1"),
+            "{variable}"
+        );
+    }
+
+    #[test]
+    fn names_profiles_and_levels() {
+        let profile = |profile_idc, constraint_flags| {
+            profile_name(&sps_info(profile_idc, constraint_flags, 30))
+        };
+        assert_eq!(profile(66, 0), Some("baseline"));
+        assert_eq!(profile(66, 0x40), Some("constrained-baseline"));
+        assert_eq!(profile(77, 0x40), Some("main"));
+        assert_eq!(profile(100, 0), Some("high"));
+        assert_eq!(profile(118, 0), None);
+
+        let level = |profile_idc, constraint_flags, level_idc| {
+            level_name(&sps_info(profile_idc, constraint_flags, level_idc))
+        };
+        assert_eq!(level(66, 0, 30), "3");
+        assert_eq!(level(100, 0, 41), "4.1");
+        assert_eq!(level(66, 0x10, 11), "1b");
+        assert_eq!(level(77, 0x10, 11), "1b");
+        assert_eq!(level(100, 0, 9), "1b");
+        // constraint_set3 only signals level 1b in the baseline and main profiles
+        assert_eq!(level(100, 0x10, 11), "1.1");
+        assert_eq!(level(66, 0, 11), "1.1");
+    }
+
+    #[test]
+    fn drops_whole_gops_until_data_is_needed() {
+        let mut feeder = AppSrcFeeder::new(640, 480, 30.0);
+        let flow = feeder.flow();
+        let mut push = |frame, is_keyframe| {
+            feeder
+                .push(&meta(frame, is_keyframe), vec![frame as u8])
+                .map(|buffer| buffer.pts)
+        };
+
+        // Frames before the first key frame can't be decoded
+        assert_eq!(push(0, false), None);
+        assert_eq!(push(1, true), Some(0));
+        assert_eq!(push(2, false), Some(33_333_333));
+
+        // The rest of the GOP is still pushed after enough-data, then frames are dropped from the next key frame
+        flow.enough_data();
+        assert_eq!(push(3, false), Some(66_666_667));
+        assert_eq!(push(4, true), None);
+        assert_eq!(push(5, false), None);
+
+        // Resumes from the next key frame after need-data, keeping the timeline
+        flow.need_data();
+        assert_eq!(push(6, false), None);
+        assert_eq!(push(7, true), Some(200_000_000));
+        assert_eq!(push(8, false), Some(233_333_333));
+
+        assert_eq!(feeder.dropped_frames(), 4);
+    }
+
+    #[test]
+    fn wants_a_keyframe_while_data_is_needed() {
+        let mut feeder = AppSrcFeeder::new(640, 480, 30.0);
+        let flow = feeder.flow();
+        assert!(feeder.wants_keyframe());
+
+        feeder.push(&meta(0, true), Vec::new()).unwrap();
+        assert!(!feeder.wants_keyframe());
+
+        flow.enough_data();
+        assert!(feeder.push(&meta(1, true), Vec::new()).is_none());
+        // Requesting a key frame is pointless until the appsrc needs data again
+        assert!(!feeder.wants_keyframe());
+
+        flow.need_data();
+        assert!(feeder.wants_keyframe());
+        let buffer = feeder.push(&meta(2, true), Vec::new()).unwrap();
+        assert!(!buffer.delta_unit);
+        assert_eq!(buffer.duration, 33_333_333);
+        assert!(!feeder.wants_keyframe());
+    }
+}
@@ -0,0 +1,502 @@
+//! Audio capture from ALSA devices (eg. a webcam's microphone), timestamped on the same clock as the video frames so
+//! that both tracks can be muxed in sync.
+//!
+//! libasound is loaded when the first device is opened rather than linked, so that binaries built with the `audio`
+//! feature still run on machines without it. Samples are captured as interleaved signed 16 bit PCM.
+
+use crate::{ClockMapping, Frame, StreamError, TimestampClock, WebcamH264Stream};
+use chrono::{DateTime, Utc};
+use std::collections::VecDeque;
+use std::ffi::{c_char, c_int, c_long, c_uint, c_ulong, c_void, CStr, CString};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{mpsc, Arc, OnceLock};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use thiserror::Error;
+use tracing::warn;
+
+const SND_PCM_STREAM_CAPTURE: c_int = 1;
+const SND_PCM_FORMAT_S16_LE: c_int = 2;
+const SND_PCM_ACCESS_RW_INTERLEAVED: c_int = 3;
+
+#[derive(Error, Debug)]
+pub enum AudioError {
+    #[error("libasound could not be loaded: {0}")]
+    LibraryUnavailable(String),
+    #[error("Invalid ALSA device name {0:?}")]
+    InvalidDeviceName(String),
+    #[error("ALSA {operation} failed: {message}")]
+    Alsa {
+        operation: &'static str,
+        /// The negative errno returned by ALSA.
+        code: i32,
+        message: String,
+    },
+}
+
+/// The libasound functions used, loaded with dlopen.
+struct Alsa {
+    pcm_open: unsafe extern "C" fn(*mut *mut c_void, *const c_char, c_int, c_int) -> c_int,
+    pcm_set_params:
+        unsafe extern "C" fn(*mut c_void, c_int, c_int, c_uint, c_uint, c_int, c_uint) -> c_int,
+    pcm_readi: unsafe extern "C" fn(*mut c_void, *mut c_void, c_ulong) -> c_long,
+    pcm_delay: unsafe extern "C" fn(*mut c_void, *mut c_long) -> c_int,
+    pcm_recover: unsafe extern "C" fn(*mut c_void, c_int, c_int) -> c_int,
+    pcm_close: unsafe extern "C" fn(*mut c_void) -> c_int,
+    strerror: unsafe extern "C" fn(c_int) -> *const c_char,
+}
+
+impl Alsa {
+    fn get() -> Result<&'static Alsa, AudioError> {
+        static ALSA: OnceLock<Result<Alsa, String>> = OnceLock::new();
+
+        ALSA.get_or_init(Self::load)
+            .as_ref()
+            .map_err(|err| AudioError::LibraryUnavailable(err.clone()))
+    }
+
+    fn load() -> Result<Alsa, String> {
+        // SAFETY: The library name is a valid C string. The library is never unloaded so the symbols stay valid.
+        let lib = unsafe { libc::dlopen(c"libasound.so.2".as_ptr(), libc::RTLD_NOW) };
+        if lib.is_null() {
+            return Err(dl_error());
+        }
+
+        // SAFETY: Each symbol is loaded as the function signature declared in alsa/pcm.h and alsa/error.h
+        unsafe {
+            Ok(Alsa {
+                pcm_open: symbol(lib, c"snd_pcm_open")?,
+                pcm_set_params: symbol(lib, c"snd_pcm_set_params")?,
+                pcm_readi: symbol(lib, c"snd_pcm_readi")?,
+                pcm_delay: symbol(lib, c"snd_pcm_delay")?,
+                pcm_recover: symbol(lib, c"snd_pcm_recover")?,
+                pcm_close: symbol(lib, c"snd_pcm_close")?,
+                strerror: symbol(lib, c"snd_strerror")?,
+            })
+        }
+    }
+
+    fn check(&self, operation: &'static str, code: c_int) -> Result<c_int, AudioError> {
+        if code >= 0 {
+            return Ok(code);
+        }
+
+        // SAFETY: snd_strerror returns a static string for any error code
+        let message = unsafe { CStr::from_ptr((self.strerror)(code)) }
+            .to_string_lossy()
+            .into_owned();
+
+        Err(AudioError::Alsa {
+            operation,
+            code,
+            message,
+        })
+    }
+}
+
+/// Looks up a function in a library loaded with dlopen.
+///
+/// # Safety
+///
+/// `T` must be a function pointer type matching the symbol's signature.
+unsafe fn symbol<T: Copy>(lib: *mut c_void, name: &CStr) -> Result<T, String> {
+    let symbol = libc::dlsym(lib, name.as_ptr());
+    if symbol.is_null() {
+        return Err(dl_error());
+    }
+
+    Ok(std::mem::transmute_copy::<*mut c_void, T>(&symbol))
+}
+
+fn dl_error() -> String {
+    // SAFETY: dlerror returns null or a valid C string describing the last error
+    let err = unsafe { libc::dlerror() };
+    if err.is_null() {
+        "unknown error".to_string()
+    } else {
+        // SAFETY: Checked for null above
+        unsafe { CStr::from_ptr(err) }
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+/// Returns the ALSA device of the sound card on the same USB device as a camera (eg. `/dev/video0`), ie. the webcam's
+/// microphone, as a `plughw:CARD=...` name. Returns `None` if the camera is not a USB device or has no sound card.
+pub fn camera_audio_device(video_device: &Path) -> Option<String> {
+    let video = video_device.canonicalize().ok()?;
+    let video_name = video.file_name()?.to_str()?;
+
+    // Both the camera and the microphone are interfaces of the same USB device
+    let usb_device = |class_device: &Path| {
+        let interface = class_device.join("device").canonicalize().ok()?;
+        interface.parent().map(Path::to_path_buf)
+    };
+
+    let camera = usb_device(&Path::new("/sys/class/video4linux").join(video_name))?;
+
+    std::fs::read_dir("/sys/class/sound")
+        .ok()?
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with("card"))
+        .find(|entry| usb_device(&entry.path()).as_deref() == Some(camera.as_path()))
+        .and_then(|entry| std::fs::read_to_string(entry.path().join("id")).ok())
+        .map(|id| format!("plughw:CARD={}", id.trim()))
+}
+
+/// A period of audio captured by an [`AudioCapture`].
+#[derive(Debug, Clone)]
+pub struct AudioPacket {
+    /// When the first sample was captured, on CLOCK_MONOTONIC like most drivers' video frames (see
+    /// [`FrameMeta::timestamp`](crate::FrameMeta::timestamp)).
+    pub timestamp: Duration,
+    /// Maps the timestamp to UTC, see [`wall_clock`](Self::wall_clock).
+    pub clock: ClockMapping,
+    /// Interleaved signed 16 bit samples.
+    pub samples: Vec<i16>,
+    pub sample_rate: u32,
+    pub channels: u32,
+}
+
+impl AudioPacket {
+    /// The number of samples per channel.
+    pub fn frames(&self) -> usize {
+        self.samples.len() / self.channels.max(1) as usize
+    }
+
+    /// The length of the packet.
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs_f64(self.frames() as f64 / self.sample_rate as f64)
+    }
+
+    /// The time the first sample was captured in UTC.
+    pub fn wall_clock(&self) -> DateTime<Utc> {
+        self.clock.to_utc(self.timestamp)
+    }
+}
+
+/// Configures and opens an [`AudioCapture`].
+pub struct AudioCaptureBuilder {
+    device: Option<String>,
+    camera: Option<std::path::PathBuf>,
+    sample_rate: u32,
+    channels: u32,
+    period: Duration,
+}
+
+impl AudioCaptureBuilder {
+    /// Captures from an ALSA device, eg. `plughw:CARD=C920` or `default` (the default if no camera is set either).
+    pub fn device(mut self, device: impl Into<String>) -> Self {
+        self.device = Some(device.into());
+        self
+    }
+
+    /// Captures from the microphone of a USB camera (eg. `/dev/video0`), see [`camera_audio_device`]. ALSA's default
+    /// device is used if the camera has no sound card.
+    pub fn camera(mut self, video_device: impl AsRef<Path>) -> Self {
+        self.camera = Some(video_device.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets the sample rate (defaults to 48kHz). `plughw` and `default` devices resample if the card doesn't support
+    /// it.
+    pub fn sample_rate(mut self, sample_rate: u32) -> Self {
+        self.sample_rate = sample_rate;
+        self
+    }
+
+    /// Sets the number of channels (defaults to 1, most webcam microphones are mono).
+    pub fn channels(mut self, channels: u32) -> Self {
+        self.channels = channels.max(1);
+        self
+    }
+
+    /// Sets the length of each packet (defaults to 20ms). Shorter periods lower the latency at the cost of more reads.
+    pub fn period(mut self, period: Duration) -> Self {
+        self.period = period;
+        self
+    }
+
+    pub fn open(self) -> Result<AudioCapture, AudioError> {
+        let alsa = Alsa::get()?;
+
+        let device = self
+            .device
+            .or_else(|| self.camera.as_deref().and_then(camera_audio_device))
+            .unwrap_or_else(|| "default".to_string());
+        let name =
+            CString::new(device.clone()).map_err(|_| AudioError::InvalidDeviceName(device))?;
+
+        let mut pcm = std::ptr::null_mut();
+        // SAFETY: `pcm` and `name` outlive the call
+        alsa.check("snd_pcm_open", unsafe {
+            (alsa.pcm_open)(&mut pcm, name.as_ptr(), SND_PCM_STREAM_CAPTURE, 0)
+        })?;
+
+        let capture = AudioCapture {
+            alsa,
+            pcm,
+            sample_rate: self.sample_rate,
+            channels: self.channels,
+            period_frames: ((self.period.as_secs_f64() * self.sample_rate as f64) as usize).max(1),
+            clock: ClockMapping::measure(TimestampClock::Monotonic),
+            overruns: 0,
+        };
+
+        // The buffer holds a few periods so that a late read doesn't overrun straight away
+        let latency_us = (self.period.as_micros() * 4).min(u32::MAX as u128) as c_uint;
+        // SAFETY: The PCM was opened above and is closed when the capture is dropped
+        alsa.check("snd_pcm_set_params", unsafe {
+            (alsa.pcm_set_params)(
+                capture.pcm,
+                SND_PCM_FORMAT_S16_LE,
+                SND_PCM_ACCESS_RW_INTERLEAVED,
+                capture.channels,
+                capture.sample_rate,
+                1,
+                latency_us,
+            )
+        })?;
+
+        Ok(capture)
+    }
+}
+
+/// Captures audio from an ALSA device.
+///
+/// Each packet is timestamped with the time its first sample was captured, worked out from when it was read and how
+/// many samples were still buffered in the driver. The timestamps are accurate to within a millisecond or so, which is
+/// well within what is noticeable as lip sync error.
+pub struct AudioCapture {
+    alsa: &'static Alsa,
+    pcm: *mut c_void,
+    sample_rate: u32,
+    channels: u32,
+    period_frames: usize,
+    clock: ClockMapping,
+    overruns: u64,
+}
+
+// SAFETY: The PCM handle is only used through the capture, which exclusively owns it
+unsafe impl Send for AudioCapture {}
+
+impl AudioCapture {
+    pub fn builder() -> AudioCaptureBuilder {
+        AudioCaptureBuilder {
+            device: None,
+            camera: None,
+            sample_rate: 48_000,
+            channels: 1,
+            period: Duration::from_millis(20),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn channels(&self) -> u32 {
+        self.channels
+    }
+
+    /// The number of times samples were lost because they were not read quickly enough. Capture continues after an
+    /// overrun, with a gap in the packets' timestamps.
+    pub fn overruns(&self) -> u64 {
+        self.overruns
+    }
+
+    /// Waits for the next period of audio.
+    pub fn read(&mut self) -> Result<AudioPacket, AudioError> {
+        let mut samples = vec![0i16; self.period_frames * self.channels as usize];
+
+        let frames = loop {
+            // SAFETY: The buffer holds `period_frames` frames of `channels` samples
+            let read = unsafe {
+                (self.alsa.pcm_readi)(
+                    self.pcm,
+                    samples.as_mut_ptr() as *mut c_void,
+                    self.period_frames as c_ulong,
+                )
+            };
+
+            if read >= 0 {
+                break read as usize;
+            }
+
+            if read as c_int == -libc::EPIPE {
+                self.overruns += 1;
+                warn!("Audio capture overrun, samples were lost");
+            }
+
+            // Recovers from overruns and interrupted reads, and returns other errors
+            // SAFETY: The PCM is open
+            self.alsa.check("snd_pcm_readi", unsafe {
+                (self.alsa.pcm_recover)(self.pcm, read as c_int, 1)
+            })?;
+        };
+        let read_at = TimestampClock::Monotonic.now();
+        samples.truncate(frames * self.channels as usize);
+
+        // The packet's first sample was captured before the packet and the samples still buffered behind it
+        let mut delay: c_long = 0;
+        // SAFETY: `delay` outlives the call
+        let buffered = match unsafe { (self.alsa.pcm_delay)(self.pcm, &mut delay) } {
+            0 => delay.max(0) as usize,
+            _ => 0,
+        };
+        let age = Duration::from_secs_f64((frames + buffered) as f64 / self.sample_rate as f64);
+
+        Ok(AudioPacket {
+            timestamp: read_at.saturating_sub(age),
+            clock: self.clock,
+            samples,
+            sample_rate: self.sample_rate,
+            channels: self.channels,
+        })
+    }
+}
+
+impl Drop for AudioCapture {
+    fn drop(&mut self) {
+        // SAFETY: The PCM is open and not used after this
+        if let Err(err) = self
+            .alsa
+            .check("snd_pcm_close", unsafe { (self.alsa.pcm_close)(self.pcm) })
+        {
+            warn!("Failed to close the audio device: {}", err);
+        }
+    }
+}
+
+/// An audio packet or a video frame from an [`AvStream`].
+pub enum AvPacket {
+    Audio(AudioPacket),
+    Video(Frame),
+}
+
+/// Reads a video stream and an audio capture together, returning audio packets and video frames interleaved in the
+/// order they were captured, eg. to mux both into an MP4 or MPEG-TS file.
+///
+/// Audio is captured on its own thread so that neither stream waits for the other. Compare the packets' timestamps
+/// with [`wall_clock`](AudioPacket::wall_clock) and [`FrameMeta::wall_clock`](crate::FrameMeta::wall_clock), which
+/// are on the same timeline even if the camera's driver doesn't use CLOCK_MONOTONIC.
+pub struct AvStream<'a> {
+    stream: WebcamH264Stream<'a>,
+    get_yuv_frame: bool,
+    audio: Receiver<Result<AudioPacket, AudioError>>,
+    audio_finished: bool,
+    pending_audio: VecDeque<AudioPacket>,
+    pending_video: Option<Frame>,
+    max_audio_delay: Duration,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl<'a> AvStream<'a> {
+    pub fn new(stream: WebcamH264Stream<'a>, mut audio: AudioCapture) -> Self {
+        // A frame is held back for up to a few audio periods, waiting for the audio captured before it
+        let period = Duration::from_secs_f64(audio.period_frames as f64 / audio.sample_rate as f64);
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = mpsc::channel();
+
+        let thread = {
+            let shutdown = Arc::clone(&shutdown);
+            std::thread::spawn(move || {
+                while !shutdown.load(Ordering::Relaxed) {
+                    let packet = audio.read();
+                    let failed = packet.is_err();
+
+                    if sender.send(packet).is_err() || failed {
+                        break;
+                    }
+                }
+            })
+        };
+
+        Self {
+            stream,
+            get_yuv_frame: false,
+            audio: receiver,
+            audio_finished: false,
+            pending_audio: VecDeque::new(),
+            pending_video: None,
+            max_audio_delay: period * 4,
+            shutdown,
+            thread: Some(thread),
+        }
+    }
+
+    /// Also decodes each video frame into [`Frame::yuv`] (disabled by default).
+    pub fn get_yuv_frame(mut self, get_yuv_frame: bool) -> Self {
+        self.get_yuv_frame = get_yuv_frame;
+        self
+    }
+
+    pub fn video(&self) -> &WebcamH264Stream<'a> {
+        &self.stream
+    }
+
+    /// Returns the next audio packet or video frame. An audio capture error is returned once, after which only video
+    /// frames are returned.
+    pub fn next_packet(&mut self) -> Result<AvPacket, StreamError> {
+        loop {
+            while let Ok(packet) = self.audio.try_recv() {
+                self.receive(packet)?;
+            }
+
+            let frame = match &self.pending_video {
+                Some(frame) => frame,
+                None => self
+                    .pending_video
+                    .insert(self.stream.next_frame(self.get_yuv_frame)?),
+            };
+            let frame_time = frame.meta.wall_clock();
+
+            if let Some(audio) = self.pending_audio.front() {
+                if audio.wall_clock() <= frame_time {
+                    return Ok(AvPacket::Audio(self.pending_audio.pop_front().unwrap()));
+                }
+            } else if !self.audio_finished {
+                // Audio captured before the frame may still be on its way
+                match self.audio.recv_timeout(self.max_audio_delay) {
+                    Ok(packet) => {
+                        self.receive(packet)?;
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => self.audio_finished = true,
+                }
+            }
+
+            return Ok(AvPacket::Video(self.pending_video.take().unwrap()));
+        }
+    }
+
+    fn receive(&mut self, packet: Result<AudioPacket, AudioError>) -> Result<(), StreamError> {
+        match packet {
+            Ok(packet) => {
+                self.pending_audio.push_back(packet);
+                Ok(())
+            }
+            Err(err) => {
+                self.audio_finished = true;
+                Err(err.into())
+            }
+        }
+    }
+}
+
+impl<'a> Drop for AvStream<'a> {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+
+        // The audio thread exits after its current read, which takes at most a period
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                warn!("The audio capture thread panicked");
+            }
+        }
+    }
+}
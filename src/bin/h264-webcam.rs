@@ -0,0 +1,336 @@
+//! A command line tool for inspecting cameras and capturing from them, built with the `cli` feature:
+//!
+//! ```text
+//! cargo install h264_webcam_stream --features cli
+//! h264-webcam record /dev/video0 --duration 30s --out out.mp4
+//! ```
+//!
+//! Only the library's public API is used so the tool also serves as an example of it.
+
+use h264_webcam_stream::mp4::Mp4Writer;
+use h264_webcam_stream::{controls, Device, OwnedWebcamH264Stream, WebcamH264Stream};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+const USAGE: &str = "\
+Usage: h264-webcam <command> [options]
+
+Commands:
+  devices                                  List the video capture devices
+  modes <device>                           List the device's supported formats, resolutions and frame rates
+  record <device> --out <file> [options]   Record H264 video to a .h264 or .mp4 file
+      --duration <time>                    Stop after eg. 30s, 500ms or 2m (defaults to recording until Ctrl-C)
+      --resolution <width>x<height>        Request an exact resolution
+      --max-fps <fps>                      Exclude configurations faster than <fps>
+  snapshot <device> --out <file.jpg>       Save a single frame as a JPEG
+      --quality <1-100>                    JPEG quality (defaults to 90)
+  controls <device> [--set <name>=<value>]...
+                                           Set controls and list the device's controls and their values
+
+<device> is a path such as /dev/video0 or /dev/v4l/by-id/..., or a device number.";
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_interrupt(_signal: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let result = match args.first().map(String::as_str) {
+        Some("devices") => devices(),
+        Some("modes") => Args::parse(&args[1..]).and_then(|args| modes(&args)),
+        Some("record") => Args::parse(&args[1..]).and_then(|args| record(&args)),
+        Some("snapshot") => Args::parse(&args[1..]).and_then(|args| snapshot(&args)),
+        Some("controls") => Args::parse(&args[1..]).and_then(|args| list_controls(&args)),
+        Some("-h" | "--help" | "help") => {
+            println!("{USAGE}");
+            Ok(())
+        }
+        _ => Err(USAGE.into()),
+    };
+
+    if let Err(err) = result {
+        eprintln!("Error: {err}");
+        std::process::exit(1);
+    }
+}
+
+/// A device followed by `--name value` options.
+struct Args {
+    device: PathBuf,
+    options: Vec<(String, String)>,
+}
+
+impl Args {
+    fn parse(args: &[String]) -> Result<Self> {
+        let (device, mut rest) = match args.split_first() {
+            Some((device, rest)) if !device.starts_with("--") => (device, rest),
+            _ => return Err(format!("Missing <device>\n\n{USAGE}").into()),
+        };
+
+        let mut options = Vec::new();
+        while let Some((name, tail)) = rest.split_first() {
+            let name = name
+                .strip_prefix("--")
+                .ok_or_else(|| format!("Unexpected argument {name:?}"))?;
+            let (value, tail) = tail
+                .split_first()
+                .ok_or_else(|| format!("Missing a value for --{name}"))?;
+
+            options.push((name.to_string(), value.clone()));
+            rest = tail;
+        }
+
+        let device = match device.parse::<u32>() {
+            Ok(index) => PathBuf::from(format!("/dev/video{index}")),
+            Err(_) => PathBuf::from(device),
+        };
+
+        Ok(Self { device, options })
+    }
+
+    /// The last value of an option.
+    fn get<'a>(&'a self, name: &'a str) -> Option<&'a str> {
+        self.values(name).last()
+    }
+
+    fn values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.options
+            .iter()
+            .filter(move |(option, _)| option == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn open_device(&self) -> Result<Device> {
+        h264_webcam_stream::get_device(&self.device)
+            .map_err(|err| format!("Failed to open {}: {err}", self.device.display()).into())
+    }
+}
+
+fn devices() -> Result<()> {
+    let devices = h264_webcam_stream::list_devices_info();
+    if devices.is_empty() {
+        println!("No video capture devices found");
+    }
+
+    for device in devices {
+        let formats: Vec<String> = device.formats.iter().map(ToString::to_string).collect();
+
+        println!("{}", device.path.display());
+        println!("  Card:    {}", device.card);
+        println!("  Driver:  {}", device.driver);
+        println!("  Bus:     {}", device.bus_info);
+        println!("  Formats: {}", formats.join(", "));
+    }
+
+    Ok(())
+}
+
+fn modes(args: &Args) -> Result<()> {
+    let device = args.open_device()?;
+
+    for config in h264_webcam_stream::enumerate_configurations(&device) {
+        println!(
+            "{} {}x{} @ {:.2}fps",
+            config.fourcc,
+            config.width,
+            config.height,
+            config.fps()
+        );
+    }
+
+    Ok(())
+}
+
+/// Opens a stream with the `--resolution` and `--max-fps` options and prints the negotiated format.
+fn open_stream(args: &Args) -> Result<OwnedWebcamH264Stream> {
+    let mut builder = WebcamH264Stream::from_device(args.open_device()?);
+
+    if let Some(resolution) = args.get("resolution") {
+        let (width, height) = resolution
+            .split_once('x')
+            .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+            .ok_or_else(|| format!("Invalid resolution {resolution:?}, expected eg. 1280x720"))?;
+        builder = builder.resolution(width, height);
+    }
+    if let Some(max_fps) = args.get("max-fps") {
+        builder = builder.max_fps(max_fps.parse()?);
+    }
+
+    let stream = builder.open()?;
+
+    let encoding = if stream.is_native_h264() {
+        "native H264"
+    } else {
+        "transcoded to H264"
+    };
+    eprintln!(
+        "Streaming {}x{} {} at {:.2}fps ({encoding})",
+        stream.width,
+        stream.height,
+        stream.fourcc(),
+        stream.fps()
+    );
+
+    Ok(stream)
+}
+
+fn record(args: &Args) -> Result<()> {
+    let out = args.get("out").ok_or("record requires --out <file>")?;
+    let duration = args.get("duration").map(parse_duration).transpose()?;
+
+    let mut stream = open_stream(args)?;
+
+    let mut writer = if Path::new(out).extension().is_some_and(|ext| ext == "mp4") {
+        Output::Mp4(Mp4Writer::create(
+            out,
+            stream.width,
+            stream.height,
+            stream.fps(),
+        )?)
+    } else {
+        Output::H264(BufWriter::new(File::create(out)?))
+    };
+
+    // SAFETY: The handler only stores to an atomic, which is async-signal-safe
+    unsafe {
+        libc::signal(
+            libc::SIGINT,
+            on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+
+    let started_at = Instant::now();
+    let mut result = Ok(());
+
+    while !INTERRUPTED.load(Ordering::SeqCst)
+        && duration.is_none_or(|duration| started_at.elapsed() < duration)
+    {
+        match stream.next(false) {
+            Ok((h264_bytes, _)) => {
+                if let Err(err) = writer.write(&h264_bytes) {
+                    result = Err(err);
+                    break;
+                }
+            }
+            // Ctrl-C can interrupt waiting for a frame
+            Err(_) if INTERRUPTED.load(Ordering::SeqCst) => break,
+            Err(err) => {
+                result = Err(err.into());
+                break;
+            }
+        }
+    }
+
+    // The file is finished even if streaming failed so that the frames recorded so far are playable
+    let stats = stream.stats();
+    stream.shutdown()?;
+    writer.finish()?;
+
+    eprintln!(
+        "Recorded {} frames ({} bytes) in {:.1}s, {} dropped by the driver",
+        stats.frames,
+        stats.bytes,
+        stats.elapsed.as_secs_f64(),
+        stats.dropped_frames
+    );
+
+    result
+}
+
+enum Output {
+    H264(BufWriter<File>),
+    Mp4(Mp4Writer<BufWriter<File>>),
+}
+
+impl Output {
+    fn write(&mut self, h264_bytes: &[u8]) -> Result<()> {
+        match self {
+            Self::H264(file) => file.write_all(h264_bytes)?,
+            Self::Mp4(mp4) => mp4.write(h264_bytes)?,
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        match self {
+            Self::H264(mut file) => file.flush()?,
+            Self::Mp4(mp4) => mp4.finish()?.flush()?,
+        }
+        Ok(())
+    }
+}
+
+fn snapshot(args: &Args) -> Result<()> {
+    let out = args.get("out").ok_or("snapshot requires --out <file>")?;
+    let quality = args
+        .get("quality")
+        .map(str::parse)
+        .transpose()?
+        .unwrap_or(90);
+
+    let mut stream = open_stream(args)?;
+    let jpeg = stream.snapshot_jpeg(quality)?;
+    std::fs::write(out, &jpeg)?;
+
+    eprintln!("Saved {out} ({} bytes)", jpeg.len());
+    stream.shutdown()?;
+
+    Ok(())
+}
+
+fn list_controls(args: &Args) -> Result<()> {
+    let device = args.open_device()?;
+
+    for assignment in args.values("set") {
+        let (name, value) = assignment
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid --set {assignment:?}, expected <name>=<value>"))?;
+        controls::set_control(&device, name.trim(), value.trim().parse()?)?;
+    }
+
+    for control in controls::list_controls(&device)? {
+        let value = control
+            .value
+            .map_or_else(|| "-".to_string(), |value| value.to_string());
+
+        println!(
+            "{:<32} {:>8}  ({:?}, {} to {}, default {})",
+            control.name, value, control.typ, control.minimum, control.maximum, control.default
+        );
+
+        for (index, item) in &control.menu_items {
+            println!("{:<32} {index:>8}: {item}", "");
+        }
+    }
+
+    Ok(())
+}
+
+/// Parses a duration such as `30s`, `500ms` or `2m`. Plain numbers are seconds.
+fn parse_duration(duration: &str) -> Result<Duration> {
+    let invalid = || format!("Invalid duration {duration:?}, expected eg. 30s, 500ms or 2m");
+
+    let (number, unit) = duration
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .map_or((duration, ""), |index| duration.split_at(index));
+    let number: f64 = number.parse().map_err(|_| invalid())?;
+
+    let seconds = match unit {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        _ => return Err(invalid().into()),
+    };
+
+    Duration::try_from_secs_f64(seconds).map_err(|_| invalid().into())
+}
@@ -0,0 +1,300 @@
+//! Conversion between Annex-B (start code delimited) and length-prefixed (AVCC) H264 bitstreams, eg. for muxers and
+//! decoders such as MP4, Matroska, FLV or VideoToolbox that expect AVCC samples and an AVCDecoderConfigurationRecord.
+//!
+//! Emulation prevention bytes are part of a NAL unit's payload in both formats so the NAL units are copied unchanged.
+
+use crate::mp4::boxes::{put_u16, put_u32};
+use crate::nal::{NalType, NalUnits};
+use thiserror::Error;
+
+/// A length prefix in an AVCC sample that extends past the end of the sample.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("The NAL unit at offset {offset} needs {needed} bytes but only {remaining} bytes remain in the sample")]
+pub struct TruncatedSample {
+    /// The offset of the NAL unit's length prefix in the sample.
+    pub offset: usize,
+    /// The size of the NAL unit including its length prefix.
+    pub needed: usize,
+    pub remaining: usize,
+}
+
+/// Parameter sets that can't be stored in an AVCDecoderConfigurationRecord.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidParameterSets {
+    #[error("An avcC record needs at least one SPS")]
+    MissingSps,
+    /// The SPS is too short for its profile and level.
+    #[error("The SPS is {len} bytes but needs at least 4")]
+    TruncatedSps { len: usize },
+    #[error("An avcC record holds at most 31 SPS and 255 PPS, not {sps} SPS and {pps} PPS")]
+    TooMany { sps: usize, pps: usize },
+    #[error("A parameter set is {len} bytes but an avcC record holds at most 65535")]
+    TooLong { len: usize },
+}
+
+/// Appends an Annex-B access unit to `out` as an AVCC sample, with each NAL unit (including any parameter sets)
+/// prefixed by its 4 byte big endian length.
+pub fn annexb_to_avcc(access_unit: &[u8], out: &mut Vec<u8>) {
+    for nal in NalUnits::new(access_unit) {
+        put_u32(out, nal.len() as u32);
+        out.extend_from_slice(nal);
+    }
+}
+
+/// Appends an AVCC sample with 4 byte NAL unit lengths to `out` as an Annex-B access unit, with each NAL unit
+/// preceded by a 4 byte start code.
+///
+/// If the sample is truncated an error is returned and `out` is left unchanged.
+pub fn avcc_to_annexb(sample: &[u8], out: &mut Vec<u8>) -> Result<(), TruncatedSample> {
+    let original_len = out.len();
+    let mut offset = 0;
+
+    while offset < sample.len() {
+        let remaining = sample.len() - offset;
+        let truncated = |needed| TruncatedSample {
+            offset,
+            needed,
+            remaining,
+        };
+
+        let Some(prefix) = sample.get(offset..offset + 4) else {
+            out.truncate(original_len);
+            return Err(truncated(4));
+        };
+        let length = u32::from_be_bytes(prefix.try_into().unwrap()) as usize;
+
+        let Some(nal) = sample.get(offset + 4..).and_then(|rest| rest.get(..length)) else {
+            out.truncate(original_len);
+            return Err(truncated(length.saturating_add(4)));
+        };
+
+        out.extend_from_slice(&[0, 0, 0, 1]);
+        out.extend_from_slice(nal);
+        offset += 4 + length;
+    }
+
+    Ok(())
+}
+
+/// Returns the SPS and PPS NAL units (without start codes) in an Annex-B bitstream, in the order they first appear.
+/// Repeated parameter sets are only returned once.
+pub fn extract_parameter_sets(bitstream: &[u8]) -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
+    let mut sps: Vec<Vec<u8>> = Vec::new();
+    let mut pps: Vec<Vec<u8>> = Vec::new();
+
+    for nal in NalUnits::new(bitstream) {
+        let sets = match NalType::of(nal) {
+            NalType::Sps => &mut sps,
+            NalType::Pps => &mut pps,
+            _ => continue,
+        };
+
+        if !sets.iter().any(|set| set == nal) {
+            sets.push(nal.to_vec());
+        }
+    }
+
+    (sps, pps)
+}
+
+/// Builds an AVCDecoderConfigurationRecord (the body of an avcC box, also known as extradata) with 4 byte NAL unit
+/// lengths. The profile and level are taken from the first SPS.
+///
+/// Returns an error if `sps` is empty, the first SPS is shorter than 4 bytes, there are more than 31 SPS or 255 PPS,
+/// or a parameter set is longer than 65535 bytes.
+pub fn build_avc_decoder_configuration_record<S: AsRef<[u8]>, P: AsRef<[u8]>>(
+    sps: &[S],
+    pps: &[P],
+) -> Result<Vec<u8>, InvalidParameterSets> {
+    let first_sps = sps
+        .first()
+        .ok_or(InvalidParameterSets::MissingSps)?
+        .as_ref();
+    // Profile, profile compatibility and level
+    let profile_and_level = first_sps
+        .get(1..4)
+        .ok_or(InvalidParameterSets::TruncatedSps {
+            len: first_sps.len(),
+        })?;
+    if sps.len() > 31 || pps.len() > 255 {
+        return Err(InvalidParameterSets::TooMany {
+            sps: sps.len(),
+            pps: pps.len(),
+        });
+    }
+    if let Some(set) = sps
+        .iter()
+        .map(AsRef::as_ref)
+        .chain(pps.iter().map(AsRef::as_ref))
+        .find(|set| set.len() > u16::MAX as usize)
+    {
+        return Err(InvalidParameterSets::TooLong { len: set.len() });
+    }
+    let (sps_count, pps_count) = (sps.len() as u8, pps.len() as u8);
+
+    let mut record = vec![1];
+    record.extend_from_slice(profile_and_level);
+    // 4 byte NAL unit lengths
+    record.push(0xff);
+
+    record.push(0xe0 | sps_count);
+    for sps in sps {
+        write_parameter_set(&mut record, sps.as_ref());
+    }
+
+    record.push(pps_count);
+    for pps in pps {
+        write_parameter_set(&mut record, pps.as_ref());
+    }
+
+    Ok(record)
+}
+
+fn write_parameter_set(record: &mut Vec<u8>, set: &[u8]) {
+    put_u16(record, set.len() as u16);
+    record.extend_from_slice(set);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A xorshift generator, so that the random bitstreams are the same on every run.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, max: usize) -> usize {
+            (self.next() % max as u64) as usize
+        }
+    }
+
+    /// A NAL unit with a random header and payload, emulation prevented as an encoder would so that it contains no
+    /// start codes. Zeros are common so that most payloads need emulation prevention.
+    fn random_nal(rng: &mut Rng) -> Vec<u8> {
+        let mut nal = vec![0x60 | (rng.below(23) as u8 + 1)];
+        for _ in 0..rng.below(300) {
+            let byte = match rng.below(4) {
+                0 | 1 => 0,
+                2 => rng.below(4) as u8,
+                _ => rng.next() as u8,
+            };
+            if nal.ends_with(&[0, 0]) && byte <= 3 {
+                nal.push(3);
+            }
+            nal.push(byte);
+        }
+        // The RBSP trailing bits end every NAL unit with a non-zero byte
+        nal.push(0x80);
+        nal
+    }
+
+    #[test]
+    fn round_trips_random_access_units() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        let mut emulation_prevented = 0;
+
+        for _ in 0..500 {
+            let nals: Vec<Vec<u8>> = (0..rng.below(5) + 1)
+                .map(|_| random_nal(&mut rng))
+                .collect();
+            emulation_prevented += nals
+                .iter()
+                .filter(|nal| nal.windows(3).any(|window| window == [0, 0, 3]))
+                .count();
+
+            // Mixes 3 and 4 byte start codes
+            let mut access_unit = Vec::new();
+            let mut expected = Vec::new();
+            for nal in &nals {
+                let start_code: &[u8] = match rng.below(2) {
+                    0 => &[0, 0, 1],
+                    _ => &[0, 0, 0, 1],
+                };
+                access_unit.extend_from_slice(start_code);
+                access_unit.extend_from_slice(nal);
+                expected.extend_from_slice(&[0, 0, 0, 1]);
+                expected.extend_from_slice(nal);
+            }
+
+            let mut avcc = Vec::new();
+            annexb_to_avcc(&access_unit, &mut avcc);
+            assert_eq!(
+                avcc.len(),
+                nals.iter().map(|nal| nal.len() + 4).sum::<usize>()
+            );
+
+            let mut annexb = Vec::new();
+            avcc_to_annexb(&avcc, &mut annexb).unwrap();
+            assert_eq!(annexb, expected);
+            assert_eq!(NalUnits::new(&annexb).collect::<Vec<_>>(), nals);
+        }
+
+        assert!(emulation_prevented > 100);
+    }
+
+    #[test]
+    fn rejects_truncated_samples() {
+        let mut avcc = Vec::new();
+        annexb_to_avcc(
+            &[0, 0, 0, 1, 0x65, 0x88, 0x80, 0, 0, 1, 0x41, 0x9a],
+            &mut avcc,
+        );
+
+        let mut out = vec![0xaa];
+        assert_eq!(
+            avcc_to_annexb(&avcc[..avcc.len() - 1], &mut out),
+            Err(TruncatedSample {
+                offset: 7,
+                needed: 6,
+                remaining: 5,
+            })
+        );
+        assert_eq!(out, [0xaa]);
+    }
+
+    #[test]
+    fn builds_avc_decoder_configuration_records() {
+        let sps = [0x67, 0x64, 0x00, 0x1f, 0xac, 0xd9];
+        let pps = [0x68, 0xeb, 0xe3, 0xcb];
+
+        assert_eq!(
+            build_avc_decoder_configuration_record(&[sps], &[pps]).unwrap(),
+            [
+                [1, 0x64, 0x00, 0x1f, 0xff, 0xe1, 0, 6].as_slice(),
+                &sps,
+                &[1, 0, 4],
+                &pps,
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_parameter_sets() {
+        let pps = [[0x68, 0xeb, 0xe3, 0xcb]];
+
+        assert_eq!(
+            build_avc_decoder_configuration_record::<[u8; 0], _>(&[], &pps),
+            Err(InvalidParameterSets::MissingSps)
+        );
+        assert_eq!(
+            build_avc_decoder_configuration_record(&[[0x67, 0x64, 0x00]], &pps),
+            Err(InvalidParameterSets::TruncatedSps { len: 3 })
+        );
+        assert_eq!(
+            build_avc_decoder_configuration_record(&[[0x67; 4]; 32], &pps),
+            Err(InvalidParameterSets::TooMany { sps: 32, pps: 1 })
+        );
+        assert_eq!(
+            build_avc_decoder_configuration_record(&[vec![0x67; 70_000]], &pps),
+            Err(InvalidParameterSets::TooLong { len: 70_000 })
+        );
+    }
+}
@@ -0,0 +1,204 @@
+use crate::nal::ParameterSets;
+use crate::{EncodedFrame, FrameMeta};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{
+    sync_channel, Receiver, RecvError, RecvTimeoutError, SyncSender, TryRecvError, TrySendError,
+};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Shares a stream's H264 access units with any number of subscribers, eg. the clients of a streaming server.
+///
+/// The broadcaster caches the stream's SPS and PPS and the current GOP, so a subscriber that joins mid-stream first
+/// receives the GOP from its key frame (with the parameter sets prepended if the camera sent them separately) and can
+/// decode immediately. Each subscriber then receives the live access units through a bounded queue; a subscriber that
+/// falls behind has frames dropped and skips ahead to the next key frame rather than buffering without limit.
+///
+/// Broadcasters can be cloned, eg. to subscribe from other threads. The clones share the same subscribers.
+#[derive(Clone)]
+pub struct Broadcaster {
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    parameter_sets: ParameterSets,
+    // The current GOP, starting with its key frame. Empty until the first key frame.
+    gop: Vec<EncodedFrame>,
+    gop_bytes: usize,
+    max_gop_bytes: Option<usize>,
+    subscribers: Vec<SubscriberSender>,
+}
+
+struct SubscriberSender {
+    sender: SyncSender<EncodedFrame>,
+    awaiting_keyframe: bool,
+    dropped_frames: Arc<AtomicU64>,
+}
+
+/// Receives a [`Broadcaster`]'s access units, starting with the current GOP. Dropping a subscriber unsubscribes it.
+pub struct Subscriber {
+    // The GOP cached when the subscriber joined, which is delivered before the live frames
+    backlog: VecDeque<EncodedFrame>,
+    receiver: Receiver<EncodedFrame>,
+    dropped_frames: Arc<AtomicU64>,
+}
+
+impl Default for Broadcaster {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Broadcaster {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                parameter_sets: ParameterSets::default(),
+                gop: Vec::new(),
+                gop_bytes: 0,
+                max_gop_bytes: None,
+                subscribers: Vec::new(),
+            })),
+        }
+    }
+
+    /// Limits the memory used to cache the current GOP, eg. for cameras with very long GOPs. If the GOP grows larger
+    /// than the limit it is discarded and new subscribers wait for the next key frame. Unlimited by default.
+    pub fn max_gop_bytes(self, max_gop_bytes: usize) -> Self {
+        self.state.lock().unwrap().max_gop_bytes = Some(max_gop_bytes);
+        self
+    }
+
+    /// Sends an access unit to every subscriber. Key frames are detected from the bitstream so `meta.is_keyframe`
+    /// does not need to be set, eg. for frames from a [`FilePlaybackStream`](crate::FilePlaybackStream).
+    pub fn push(&self, mut meta: FrameMeta, h264_bytes: Vec<u8>) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+
+        meta.is_keyframe = state.parameter_sets.update(&h264_bytes);
+
+        // Subscribers that skipped ahead receive the parameter sets with their first key frame, like new subscribers
+        let awaiting_keyframe = state.subscribers.iter().any(|s| s.awaiting_keyframe);
+        let with_parameter_sets = (meta.is_keyframe && awaiting_keyframe).then(|| {
+            let mut bytes = h264_bytes.clone();
+            state.parameter_sets.prepend_if_missing(&mut bytes, 0);
+            Arc::<[u8]>::from(bytes)
+        });
+        let frame: EncodedFrame = (meta, h264_bytes.into());
+
+        state.subscribers.retain_mut(|subscriber| {
+            let frame = match (&with_parameter_sets, subscriber.awaiting_keyframe) {
+                (Some(bytes), true) => (meta, Arc::clone(bytes)),
+                (None, true) => return true,
+                (_, false) => (meta, Arc::clone(&frame.1)),
+            };
+
+            match subscriber.sender.try_send(frame) {
+                Ok(()) => {
+                    subscriber.awaiting_keyframe = false;
+                    true
+                }
+                Err(TrySendError::Full(_)) => {
+                    subscriber.awaiting_keyframe = true;
+                    subscriber.dropped_frames.fetch_add(1, Ordering::Relaxed);
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+
+        if meta.is_keyframe {
+            state.gop.clear();
+            state.gop_bytes = 0;
+        } else if state.gop.is_empty() {
+            // The GOP can only be cached from its key frame
+            return;
+        }
+
+        state.gop_bytes += frame.1.len();
+        state.gop.push(frame);
+
+        if state
+            .max_gop_bytes
+            .is_some_and(|max_gop_bytes| state.gop_bytes > max_gop_bytes)
+        {
+            state.gop.clear();
+            state.gop_bytes = 0;
+        }
+    }
+
+    /// Adds a subscriber whose queue holds up to `capacity` live frames, in addition to the cached GOP.
+    pub fn subscribe(&self, capacity: usize) -> Subscriber {
+        let mut state = self.state.lock().unwrap();
+        let (sender, receiver) = sync_channel(capacity);
+        let dropped_frames = Arc::new(AtomicU64::new(0));
+
+        let mut backlog: VecDeque<EncodedFrame> = state.gop.iter().cloned().collect();
+
+        if let Some((meta, bytes)) = backlog.front_mut() {
+            let mut with_parameter_sets = bytes.to_vec();
+            state
+                .parameter_sets
+                .prepend_if_missing(&mut with_parameter_sets, 0);
+            *bytes = with_parameter_sets.into();
+            meta.is_keyframe = true;
+        }
+
+        state.subscribers.push(SubscriberSender {
+            sender,
+            awaiting_keyframe: backlog.is_empty(),
+            dropped_frames: Arc::clone(&dropped_frames),
+        });
+
+        Subscriber {
+            backlog,
+            receiver,
+            dropped_frames,
+        }
+    }
+
+    /// The most recent SPS and PPS (without start codes), eg. to describe the stream in an SDP. `None` until both have
+    /// been pushed.
+    pub fn parameter_sets(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        let state = self.state.lock().unwrap();
+        Some((
+            state.parameter_sets.sps.clone()?,
+            state.parameter_sets.pps.clone()?,
+        ))
+    }
+
+    /// The number of subscribers, including ones that have been dropped since the last frame was pushed.
+    pub fn subscriber_count(&self) -> usize {
+        self.state.lock().unwrap().subscribers.len()
+    }
+}
+
+impl Subscriber {
+    /// Blocks until the next access unit. Returns an error once every clone of the broadcaster has been dropped.
+    pub fn recv(&mut self) -> Result<EncodedFrame, RecvError> {
+        match self.backlog.pop_front() {
+            Some(frame) => Ok(frame),
+            None => self.receiver.recv(),
+        }
+    }
+
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<EncodedFrame, RecvTimeoutError> {
+        match self.backlog.pop_front() {
+            Some(frame) => Ok(frame),
+            None => self.receiver.recv_timeout(timeout),
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Result<EncodedFrame, TryRecvError> {
+        match self.backlog.pop_front() {
+            Some(frame) => Ok(frame),
+            None => self.receiver.try_recv(),
+        }
+    }
+
+    /// The number of frames dropped because the subscriber's queue was full.
+    pub fn dropped_frames(&self) -> u64 {
+        self.dropped_frames.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,932 @@
+use crate::controls;
+use crate::decimate::{Decimation, Decimator};
+use crate::mplane::{self, BufferStream};
+use crate::overlay::Overlay;
+use crate::pipeline::Pipeline;
+use crate::preset::{FormatPriority, StreamPreset};
+use crate::resize::FrameTransform;
+use crate::selection::{self, CaptureConfig, Selector};
+use crate::stats::{StatsCallback, StatsTracker};
+use crate::validate::StreamValidation;
+use crate::yuv::RawLayout;
+#[cfg(feature = "openh264")]
+use crate::EncoderOptions;
+use crate::{
+    BitstreamValidator, DequeuePolicy, EncoderMode, ErrorPolicy, FormatSummary, FrameMeta,
+    KeyframeAlignment, Rect, ScaleFilter, StreamError, StreamStats, Transform, TransformMode,
+    ValidationReport, WebcamH264Stream, YUVBuffer,
+};
+use std::cmp::Reverse;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+use v4l::video::capture::Parameters;
+use v4l::{Device, Format, FourCC, Fraction};
+
+/// Configures and opens a [`WebcamH264Stream`].
+///
+/// By default the largest resolution the camera supports is selected, followed by the highest frame rate, preferring
+/// H264 over MJPEG. Uncompressed formats (YUYV, UYVY or NV12) are only used if the camera supports neither, and raw
+/// Bayer formats only if it supports nothing else.
+pub struct StreamBuilder<'a> {
+    dev: StreamDevice<'a>,
+    config: StreamConfig,
+    max_yuv_attempts: usize,
+    error_policy: ErrorPolicy,
+    dequeue_policy: DequeuePolicy,
+    #[cfg(feature = "openh264")]
+    fill_dropped_frames: u32,
+    sei_timestamps: bool,
+    keyframe_alignment: KeyframeAlignment,
+    overlays: Vec<Overlay>,
+    stats_callback: Option<StatsCallback>,
+    stall_deadline: Option<Duration>,
+    decimation: Option<Decimation>,
+    validation: Option<StreamValidation>,
+    #[cfg(feature = "metrics")]
+    metrics: Option<crate::metrics::MetricsHandle>,
+}
+
+/// The settings a stream's format is negotiated from, which the stream keeps so that it can be reconfigured.
+#[derive(Clone)]
+pub(crate) struct StreamConfig {
+    pub(crate) resolution: Option<(u32, u32)>,
+    pub(crate) max_fps: Option<u32>,
+    pub(crate) preferred_fourcc: Option<FourCC>,
+    pub(crate) capture_config: Option<CaptureConfig>,
+    pub(crate) selector: Option<Selector>,
+    pub(crate) max_format_attempts: usize,
+    // None uses the latency profile's buffer count
+    pub(crate) buffer_count: Option<u32>,
+    pub(crate) latency_profile: LatencyProfile,
+    pub(crate) pipeline_depth: Option<usize>,
+    #[cfg(feature = "openh264")]
+    pub(crate) encoder_options: EncoderOptions,
+    pub(crate) transform: Transform,
+    pub(crate) crop: Option<Rect>,
+    pub(crate) scale: Option<(u32, u32, ScaleFilter)>,
+}
+
+/// A format applied to the camera along with the buffers and the encoder or decoder for it.
+pub(crate) struct Negotiated {
+    pub(crate) stream: BufferStream,
+    pub(crate) encoder_mode: EncoderMode,
+    /// The size of the stream's frames after any transform
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    pub(crate) frame_interval: Fraction,
+    pub(crate) fourcc: FourCC,
+    pub(crate) transform: Option<FrameTransform>,
+    pub(crate) transform_mode: Option<TransformMode>,
+    pub(crate) yuv_buffer: YUVBuffer,
+    pub(crate) raw_layout: RawLayout,
+}
+
+/// How much a stream buffers, see [`StreamBuilder::latency_profile`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LatencyProfile {
+    /// 4 driver buffers, which absorbs delays in reading frames at the cost of up to 3 frame intervals of latency.
+    #[default]
+    Default,
+    /// The fewest buffers the driver can stream with (2) and an encoder pinned to low complexity on a single thread
+    /// (see [`EncoderOptions::low_latency`](crate::EncoderOptions::low_latency)), eg. for remote control. Fewer frames can
+    /// queue up in the driver's buffers, so frames that are not read in time are dropped by the driver instead.
+    ///
+    /// With either profile openh264 never produces B frames, native H264 frames are decoded without any reordering
+    /// delay and frames are only decoded or converted when `get_yuv_frame` is true or they have to be transcoded. Use
+    /// [`WebcamH264Stream::measured_latency`] to measure the result.
+    Low,
+}
+
+impl LatencyProfile {
+    fn buffer_count(self) -> u32 {
+        match self {
+            Self::Default => 4,
+            Self::Low => 2,
+        }
+    }
+}
+
+/// The device a stream was opened from, which the stream either borrows or owns.
+pub(crate) enum StreamDevice<'a> {
+    Borrowed(&'a mut Device),
+    Owned(Device),
+}
+
+impl<'a> StreamDevice<'a> {
+    pub(crate) fn get(&self) -> &Device {
+        match self {
+            Self::Borrowed(dev) => dev,
+            Self::Owned(dev) => dev,
+        }
+    }
+}
+
+impl StreamBuilder<'static> {
+    pub(crate) fn with_device(dev: Device) -> Self {
+        Self::from_builder_device(StreamDevice::Owned(dev))
+    }
+}
+
+impl<'a> StreamBuilder<'a> {
+    pub(crate) fn new(dev: &'a mut Device) -> Self {
+        Self::from_builder_device(StreamDevice::Borrowed(dev))
+    }
+
+    fn from_builder_device(dev: StreamDevice<'a>) -> Self {
+        Self {
+            dev,
+            config: StreamConfig {
+                resolution: None,
+                max_fps: None,
+                preferred_fourcc: None,
+                capture_config: None,
+                selector: None,
+                max_format_attempts: 4,
+                buffer_count: None,
+                latency_profile: LatencyProfile::default(),
+                pipeline_depth: None,
+                #[cfg(feature = "openh264")]
+                encoder_options: EncoderOptions::default(),
+                transform: Transform::default(),
+                crop: None,
+                scale: None,
+            },
+            max_yuv_attempts: 120,
+            error_policy: ErrorPolicy::default(),
+            dequeue_policy: DequeuePolicy::default(),
+            sei_timestamps: false,
+            #[cfg(feature = "openh264")]
+            fill_dropped_frames: 0,
+            keyframe_alignment: KeyframeAlignment::default(),
+            overlays: Vec::new(),
+            stats_callback: None,
+            stall_deadline: None,
+            decimation: None,
+            validation: None,
+            #[cfg(feature = "metrics")]
+            metrics: None,
+        }
+    }
+
+    /// Applies a bundle of settings for a common use case, see [`StreamPreset::to_options`] for the settings. Builder
+    /// calls after `preset` override the preset's settings, so it should be called first.
+    pub fn preset(mut self, preset: StreamPreset) -> Self {
+        let options = preset.to_options();
+
+        self.config.latency_profile = options.latency_profile;
+        self.config.buffer_count = Some(options.buffer_count);
+        #[cfg(feature = "openh264")]
+        {
+            self.config.encoder_options = options.encoder_options;
+        }
+        self.config.selector = match options.format_priority {
+            FormatPriority::Resolution => None,
+            FormatPriority::FrameRate => Some(selection::selector(CaptureConfig::frame_rate_score)),
+        };
+        self.error_policy = options.error_policy;
+        self.keyframe_alignment = options.keyframe_alignment;
+        self
+    }
+
+    /// Requests an exact resolution. Opening the stream fails with `StreamError::ResolutionNotSupported` if the camera
+    /// does not support it.
+    pub fn resolution(mut self, width: u32, height: u32) -> Self {
+        self.config.resolution = Some((width, height));
+        self
+    }
+
+    /// Excludes any camera configurations faster than `max_fps`.
+    pub fn max_fps(mut self, max_fps: u32) -> Self {
+        self.config.max_fps = Some(max_fps);
+        self
+    }
+
+    /// Prefers the given pixel format (eg. `FourCC::new(b"MJPG")`) over any other format the camera supports.
+    pub fn prefer_fourcc(mut self, fourcc: FourCC) -> Self {
+        self.config.preferred_fourcc = Some(fourcc);
+        self
+    }
+
+    /// Overrides how the camera configuration is chosen. `score` is called for each configuration the camera supports
+    /// (after the `resolution` and `max_fps` filters) and they are tried from the highest score down, see
+    /// `max_format_attempts`. Eg. scoring by a tuple of the frame rate and resolution prefers smooth video over detail.
+    ///
+    /// The default is [`CaptureConfig::default_score`], with any `prefer_fourcc` format tried first.
+    pub fn selector<S: Ord>(
+        mut self,
+        score: impl Fn(&CaptureConfig) -> S + Send + Sync + 'static,
+    ) -> Self {
+        self.config.selector = Some(selection::selector(score));
+        self
+    }
+
+    /// Uses exactly the given configuration, eg. one returned by
+    /// [`enumerate_configurations`](crate::enumerate_configurations), instead of choosing one.
+    pub fn capture_config(mut self, config: CaptureConfig) -> Self {
+        self.config.capture_config = Some(config);
+        self
+    }
+
+    /// Sets how many of the best configurations are tried when opening the stream (defaults to 4). Some drivers
+    /// advertise formats that they then fail to set, so if configuring the camera fails the next best configuration is
+    /// tried, eg. MJPEG instead of H264. If every attempt fails opening the stream returns
+    /// `StreamError::NoSupportedConfiguration` with the error from each attempt.
+    pub fn max_format_attempts(mut self, max_format_attempts: usize) -> Self {
+        self.config.max_format_attempts = max_format_attempts;
+        self
+    }
+
+    /// Sets the number of mmap buffers requested from the driver (defaults to 4, or 2 with `LatencyProfile::Low`).
+    pub fn buffer_count(mut self, buffer_count: u32) -> Self {
+        self.config.buffer_count = Some(buffer_count);
+        self
+    }
+
+    /// Trades throughput for latency (defaults to `LatencyProfile::Default`), see [`LatencyProfile`].
+    pub fn latency_profile(mut self, latency_profile: LatencyProfile) -> Self {
+        self.config.latency_profile = latency_profile;
+        self
+    }
+
+    /// Dequeues the camera's frames on a dedicated thread, so that capturing never waits for the previous frame to be
+    /// decoded and encoded. The frames are still processed by `next()` on the calling thread.
+    ///
+    /// Without a pipeline the driver only has `buffer_count` buffers to fill while a frame is transcoded, so a camera
+    /// that is briefly faster than the encoder (eg. 1080p60 MJPEG) runs out of buffers and drops frames in bursts. The
+    /// capture thread copies each frame out of the driver's buffer and requeues it immediately, holding up to
+    /// `queue_depth` copies for `next()` and dropping the oldest when the queue is full (see
+    /// [`WebcamH264Stream::dropped_raw_frames`]). Dropping the stream stops the thread.
+    ///
+    /// This costs a copy of every frame and cannot make transcoding faster: if each frame takes longer to encode than
+    /// the frame interval the pipeline still drops frames, just evenly rather than in bursts.
+    pub fn pipelined(mut self, queue_depth: usize) -> Self {
+        self.config.pipeline_depth = Some(queue_depth);
+        self
+    }
+
+    /// Sets how many frames `WebcamH264Stream::next_yuv` will read while waiting for a YUV frame before giving up
+    /// (defaults to 120).
+    pub fn max_yuv_attempts(mut self, max_yuv_attempts: usize) -> Self {
+        self.max_yuv_attempts = max_yuv_attempts;
+        self
+    }
+
+    /// Makes `next()` (and the other methods that read frames) return `StreamError::Stalled` if the camera does not
+    /// deliver a frame within `deadline`, instead of blocking forever. Some UVC cameras silently stop streaming after a
+    /// USB hiccup. The stream can be read from again after it stalls, or reopened (`ReconnectingStream` does this
+    /// automatically).
+    pub fn stall_deadline(mut self, deadline: Duration) -> Self {
+        self.stall_deadline = Some(deadline);
+        self
+    }
+
+    /// Delivers only every `n`th frame, eg. to run inference on a fraction of the frames of a camera left at its native
+    /// frame rate (so that its auto exposure stays smooth).
+    ///
+    /// Frames that are transcoded (MJPEG, uncompressed and Bayer cameras) are skipped straight after they are dequeued,
+    /// so they are never decoded, converted or encoded. Frames of cameras that produce H264 natively cannot be dropped
+    /// individually since the other frames of their GOP reference them, so every `n`th GOP is delivered whole instead and
+    /// the stream starts at a key frame. Cameras that only send a single key frame are effectively passed through. The
+    /// skipped frames are counted by [`WebcamH264Stream::decimated_frames`]. Replaces any `output_fps`.
+    pub fn frame_divisor(mut self, n: u32) -> Self {
+        self.decimation = Some(Decimation::EveryNth(n.max(1)));
+        self
+    }
+
+    /// Delivers frames at no more than `fps` based on their capture timestamps, skipping the others like
+    /// `frame_divisor`. For native H264 cameras whole GOPs are skipped so that the frames delivered average `fps`.
+    /// Replaces any `frame_divisor`.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if `fps` is not a positive number.
+    pub fn output_fps(mut self, fps: f64) -> Self {
+        assert!(fps.is_finite() && fps > 0.0, "fps must be positive");
+        self.decimation = Some(Decimation::MaxFps(fps));
+        self
+    }
+
+    /// Configures the H264 encoder used for cameras that do not natively support H264. The frame rate hint defaults to
+    /// the camera's frame rate.
+    #[cfg(feature = "openh264")]
+    pub fn encoder_options(mut self, encoder_options: EncoderOptions) -> Self {
+        self.config.encoder_options = encoder_options;
+        self
+    }
+
+    /// Sets how frames that fail to decode are handled (defaults to `ErrorPolicy::FailFast`), see
+    /// [`WebcamH264Stream::set_error_policy`].
+    pub fn error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.error_policy = error_policy;
+        self
+    }
+
+    /// Sets which of the frames waiting in the camera's buffers are read (defaults to `DequeuePolicy::EveryFrame`). Use
+    /// `DequeuePolicy::LatestOnly` to always read the newest frame, eg. for a live preview.
+    pub fn dequeue_policy(mut self, dequeue_policy: DequeuePolicy) -> Self {
+        self.dequeue_policy = dequeue_policy;
+        self
+    }
+
+    /// Encodes transcoded frames again for each frame the driver dropped before them, for gaps of up to `max_gap`
+    /// frames (disabled by default). The returned H264 then holds the copies ahead of the frame, so that players and
+    /// muxers that assume a constant frame rate stay in sync across small gaps. Longer gaps are left as they are, see
+    /// [`FrameMeta::dropped_since_last`](crate::FrameMeta::dropped_since_last).
+    ///
+    /// Native H264 frames cannot be duplicated without re-encoding them, use a [`CfrAdapter`](crate::CfrAdapter) instead.
+    #[cfg(feature = "openh264")]
+    pub fn fill_dropped_frames(mut self, max_gap: u32) -> Self {
+        self.fill_dropped_frames = max_gap;
+        self
+    }
+
+    /// Embeds each frame's capture timestamp and sequence number in its H264 as an SEI NAL unit, so that it survives
+    /// remuxing (disabled by default). See the [`sei`](crate::sei) module for the format and for reading them back.
+    pub fn sei_timestamps(mut self, enabled: bool) -> Self {
+        self.sei_timestamps = enabled;
+        self
+    }
+
+    /// Discards native H264 frames until the first key frame so that the stream starts decodable (disabled by default).
+    pub fn keyframe_alignment(mut self, keyframe_alignment: KeyframeAlignment) -> Self {
+        self.keyframe_alignment = keyframe_alignment;
+        self
+    }
+
+    /// Draws text (eg. a timestamp) or an image onto each frame before it is encoded. See
+    /// [`WebcamH264Stream::add_overlay`].
+    pub fn overlay(mut self, overlay: Overlay) -> Self {
+        self.overlays.push(overlay);
+        self
+    }
+
+    /// Rotates and/or flips each frame, eg. for a camera that is mounted upside down.
+    ///
+    /// Flips and 180° rotations use the camera's horizontal and vertical flip controls if it has them, otherwise the
+    /// frames are transformed before they are encoded (see [`WebcamH264Stream::transform_mode`]). Like `crop`, software
+    /// transforms fail with `StreamError::TransformUnsupported` if the camera produces H264 natively.
+    pub fn transform(mut self, transform: impl Into<Transform>) -> Self {
+        self.config.transform = transform.into();
+        self
+    }
+
+    /// Crops each frame to `rect` before it is encoded, eg. to stream a region of interest. The rectangle is rounded
+    /// down to even values and is applied after any rotation and before any scaling.
+    ///
+    /// Like overlays, cropping and scaling require the frames to be transcoded so opening the stream fails with
+    /// `StreamError::TransformUnsupported` if the camera produces H264 natively.
+    pub fn crop(mut self, rect: Rect) -> Self {
+        self.config.crop = Some(rect);
+        self
+    }
+
+    /// Scales each frame (after cropping) to `width` x `height` before it is encoded. The stream's `width` and `height`
+    /// are the scaled size. See `crop`.
+    pub fn scale(mut self, width: u32, height: u32, filter: ScaleFilter) -> Self {
+        self.config.scale = Some((width, height, filter));
+        self
+    }
+
+    /// Calls `callback` with a snapshot of the stream's statistics (see [`WebcamH264Stream::stats`]) every `interval`,
+    /// eg. to export them to a monitoring system. The callback is called from `next()` so it should return quickly.
+    pub fn on_stats(
+        mut self,
+        interval: Duration,
+        callback: impl FnMut(&StreamStats) + Send + 'static,
+    ) -> Self {
+        self.stats_callback = Some(StatsCallback {
+            interval,
+            callback: Box::new(callback),
+            last_called: Instant::now(),
+        });
+        self
+    }
+
+    /// Checks each frame's H264 bitstream with `validator`, eg. to alert on a camera emitting garbage. Frames with issues
+    /// are logged and counted in [`StreamStats::invalid_frames`], and the latest report is available from
+    /// [`WebcamH264Stream::last_validation`]. Frames are still returned as-is.
+    pub fn validate_bitstream(mut self, validator: BitstreamValidator) -> Self {
+        let callback = self
+            .validation
+            .take()
+            .and_then(|validation| validation.callback);
+        self.validation = Some(StreamValidation {
+            validator,
+            callback,
+            last_report: None,
+        });
+        self
+    }
+
+    /// Calls `callback` with each frame that fails validation, enabling validation with a default
+    /// [`BitstreamValidator`] if `validate_bitstream` was not called. Like `on_stats` the callback is called from
+    /// `next()`.
+    pub fn on_invalid_frame(
+        mut self,
+        callback: impl FnMut(&FrameMeta, &ValidationReport) + Send + 'static,
+    ) -> Self {
+        self.validation
+            .get_or_insert_with(|| StreamValidation {
+                validator: BitstreamValidator::new(),
+                callback: None,
+                last_report: None,
+            })
+            .callback = Some(Box::new(callback));
+        self
+    }
+
+    /// Exports the stream's statistics as Prometheus metrics labelled with `device` (eg. the device's path), see
+    /// [`metrics`](crate::metrics). Requires the `metrics` feature.
+    #[cfg(feature = "metrics")]
+    pub fn metrics(mut self, metrics: &crate::metrics::StreamMetrics, device: &str) -> Self {
+        self.metrics = Some(crate::metrics::MetricsHandle::new(metrics, device));
+        self
+    }
+
+    /// Configures the camera and starts the stream.
+    pub fn open(self) -> Result<WebcamH264Stream<'a>, StreamError> {
+        let dev = self.dev.get();
+
+        let candidates = self.config.select(dev)?;
+        let negotiated = self.config.apply_first(dev, candidates)?;
+        let handle = dev.handle();
+
+        Ok(WebcamH264Stream {
+            encoder_mode: negotiated.encoder_mode,
+            stream: Some(negotiated.stream),
+            handle,
+            width: negotiated.width,
+            height: negotiated.height,
+            frame_interval: negotiated.frame_interval,
+            fourcc: negotiated.fourcc,
+            max_yuv_attempts: self.max_yuv_attempts,
+            error_policy: self.error_policy,
+            dequeue_policy: self.dequeue_policy,
+            sei_timestamps: self.sei_timestamps,
+            clock: None,
+            #[cfg(feature = "openh264")]
+            fill_dropped_frames: self.fill_dropped_frames,
+            corrupt_frames: 0,
+            parameter_sets: Default::default(),
+            #[cfg(feature = "openh264")]
+            decoder_recovery: Default::default(),
+            keyframe_requested: false,
+            keyframe_alignment: self.keyframe_alignment,
+            awaiting_keyframe: negotiated.fourcc == FourCC::new(b"H264")
+                && self.keyframe_alignment != KeyframeAlignment::Disabled,
+            overlays: self.overlays,
+            transform: negotiated.transform,
+            transform_mode: negotiated.transform_mode,
+            stats: StatsTracker::new(self.stats_callback),
+            stall_deadline: self.stall_deadline,
+            decimator: self
+                .decimation
+                .map(|decimation| Decimator::new(decimation, negotiated.frame_interval)),
+            last_frame_received: Instant::now(),
+            paused: false,
+            validation: self.validation,
+            events: Default::default(),
+            #[cfg(feature = "metrics")]
+            metrics: self.metrics,
+            yuv_buffer: negotiated.yuv_buffer,
+            raw_layout: negotiated.raw_layout,
+            config: self.config,
+            device: self.dev,
+        })
+    }
+}
+
+impl StreamConfig {
+    /// Ranks the formats the camera supports from best to worst. This only queries the camera so it can be used while
+    /// streaming.
+    pub(crate) fn select(&self, dev: &Device) -> Result<Vec<CaptureConfig>, StreamError> {
+        if let Some(config) = self.capture_config {
+            #[cfg(not(feature = "openh264"))]
+            return passthrough_only(vec![config]);
+            #[cfg(feature = "openh264")]
+            return Ok(vec![config]);
+        }
+
+        let candidates = selection::enumerate(dev, self.resolution);
+        #[cfg(not(feature = "openh264"))]
+        let candidates = passthrough_only(candidates)?;
+
+        let candidates = candidates
+            .into_iter()
+            // Filter out frame rates that exceed the max fps
+            .filter(|config| match self.max_fps {
+                Some(max_fps) => within_max_fps(config.interval, max_fps),
+                None => true,
+            })
+            .collect::<Vec<_>>();
+
+        if let Some((width, height)) = self.resolution {
+            if !candidates
+                .iter()
+                .any(|c| (c.width, c.height) == (width, height))
+            {
+                let mut closest = candidates
+                    .iter()
+                    .map(|c| (c.width, c.height))
+                    .collect::<Vec<_>>();
+
+                closest.sort_by_key(|&(w, h)| {
+                    let distance =
+                        (w as i64 - width as i64).abs() + (h as i64 - height as i64).abs();
+                    (distance, w, h)
+                });
+                closest.dedup();
+                closest.truncate(5);
+
+                return Err(StreamError::ResolutionNotSupported {
+                    width,
+                    height,
+                    closest,
+                });
+            }
+        }
+
+        let candidates = candidates
+            .into_iter()
+            .filter(|c| match self.resolution {
+                Some(resolution) => resolution == (c.width, c.height),
+                None => true,
+            })
+            .collect::<Vec<_>>();
+
+        let mut candidates = candidates;
+        match &self.selector {
+            Some(selector) => selector(&mut candidates),
+            // Prefer the user's requested format above all else
+            None => candidates.sort_by_cached_key(|c| {
+                Reverse((Some(c.fourcc) == self.preferred_fourcc, c.default_score()))
+            }),
+        }
+
+        if candidates.is_empty() {
+            return Err(StreamError::NoSupportedConfiguration {
+                attempts: Vec::new(),
+            });
+        }
+
+        Ok(candidates)
+    }
+
+    /// Applies the first of the `candidates` that the camera accepts, falling back to the next candidate (up to
+    /// `max_format_attempts`) if setting the format, frame rate or buffers fails. The camera must not be streaming.
+    pub(crate) fn apply_first(
+        &self,
+        dev: &Device,
+        candidates: Vec<CaptureConfig>,
+    ) -> Result<Negotiated, StreamError> {
+        let mut attempts = Vec::new();
+
+        for config in candidates.into_iter().take(self.max_format_attempts.max(1)) {
+            match self.apply(dev, config) {
+                Ok(negotiated) => return Ok(negotiated),
+                // Other configurations will not work either if the device itself is unavailable
+                Err(err @ (StreamError::DeviceBusy | StreamError::DeviceDisconnected)) => {
+                    return Err(err)
+                }
+                Err(err) => {
+                    warn!(
+                        "Unable to configure the camera for {}x{} {} at {:.1}fps, trying the next configuration: {}",
+                        config.width,
+                        config.height,
+                        config.fourcc,
+                        config.fps(),
+                        err
+                    );
+                    attempts.push((config, err));
+                }
+            }
+        }
+
+        Err(StreamError::NoSupportedConfiguration { attempts })
+    }
+
+    /// Applies a format and creates the buffers and encoder or decoder for it. The camera must not
+    /// be streaming.
+    pub(crate) fn apply(
+        &self,
+        dev: &Device,
+        CaptureConfig {
+            fourcc,
+            width,
+            height,
+            interval: frame_period,
+        }: CaptureConfig,
+    ) -> Result<Negotiated, StreamError> {
+        let h264 = FourCC::new(b"H264");
+
+        // Some SoC camera drivers only implement the multi-planar API
+        let multiplanar = mplane::is_multiplanar(dev);
+
+        // Explicitly request the video width, height and fps
+        let fmt = Format::new(width, height, fourcc);
+
+        // V4L2 drivers substitute the closest format they support rather than failing so the applied format is used
+        let actual = mplane::set_format(dev, multiplanar, &fmt).map_err(|err| {
+            StreamError::from_io(err, |source| StreamError::SetFormatFailed {
+                requested: FormatSummary {
+                    width,
+                    height,
+                    fourcc,
+                },
+                source,
+            })
+        })?;
+
+        check_applied_format(&fmt, &actual, self.resolution.is_some())?;
+        let (width, height, fourcc) = (actual.width, actual.height, actual.fourcc);
+
+        let transform_mode = if self.transform == Transform::default() {
+            None
+        } else if let Some((horizontal, vertical)) = self.transform.hardware_flips() {
+            match controls::set_flip(dev, horizontal, vertical) {
+                Ok(()) => Some(TransformMode::Hardware),
+                Err(err) => {
+                    debug!(
+                        "Camera flip controls are unavailable, transforming frames in software: {:?}",
+                        err
+                    );
+                    // Undo a partially applied flip
+                    let _ = controls::set_flip(dev, false, false);
+                    Some(TransformMode::Software)
+                }
+            }
+        } else {
+            Some(TransformMode::Software)
+        };
+        let orientation =
+            (transform_mode == Some(TransformMode::Software)).then_some(self.transform);
+
+        if fourcc == h264 && (orientation.is_some() || self.crop.is_some() || self.scale.is_some())
+        {
+            return Err(StreamError::TransformUnsupported);
+        }
+
+        let transform = FrameTransform::new((width, height), orientation, self.crop, self.scale)?;
+        // The encoder and the stream's frames are the transformed size
+        let (encoded_width, encoded_height) = transform
+            .as_ref()
+            .map_or((width, height), FrameTransform::size);
+
+        let params = Parameters::new(frame_period);
+        mplane::set_params(dev, multiplanar, &params).map_err(|err| {
+            StreamError::from_io(err, |source| StreamError::SetParamsFailed {
+                requested: frame_period,
+                source,
+            })
+        })?;
+
+        // The driver may round the requested frame interval to one it supports
+        let frame_interval: Fraction = mplane::params(dev, multiplanar)
+            .map_err(|err| {
+                StreamError::from_io(err, |source| StreamError::QueryFailed {
+                    query: "frame interval",
+                    source,
+                })
+            })?
+            .interval;
+
+        // Cross multiplied in 64 bits as drivers may report intervals in 100ns units, eg. 333333/10000000
+        if frame_interval.numerator as u64 * frame_period.denominator as u64
+            != frame_period.numerator as u64 * frame_interval.denominator as u64
+        {
+            warn!(
+                "Camera adjusted the requested frame interval from {} to {}",
+                frame_period, frame_interval
+            );
+        }
+
+        // The buffers are mapped for as long as the stream exists so it can be moved to a capture thread
+        let buffer_count = self
+            .buffer_count
+            .unwrap_or_else(|| self.latency_profile.buffer_count());
+        let stream: BufferStream = BufferStream::with_buffers(dev, multiplanar, buffer_count)
+            .map_err(|err| {
+                StreamError::from_io(err, |source| StreamError::RequestBuffersFailed {
+                    count: buffer_count,
+                    source,
+                })
+            })?;
+
+        // Native H264 frames are decoded by openh264 into its own buffers so no scratch buffer is needed
+        let yuv_buffer = if fourcc == h264 {
+            YUVBuffer::new(0, 0)
+        } else {
+            YUVBuffer::new(width as usize, height as usize)
+        };
+
+        #[cfg(feature = "openh264")]
+        let encoder_mode = if fourcc == h264 {
+            let h264_decoder = openh264::decoder::Decoder::new()?;
+            EncoderMode::H264Native(h264_decoder)
+        } else {
+            let fps = frame_interval.denominator as f32 / frame_interval.numerator as f32;
+            let mut encoder_options = self.encoder_options.frame_rate_or(fps);
+            if self.latency_profile == LatencyProfile::Low {
+                encoder_options = encoder_options.low_latency(true);
+            }
+            let h264_encoder = encoder_options.build(encoded_width, encoded_height)?;
+
+            match &fourcc.repr {
+                b"YUYV" => EncoderMode::YuyvNative(h264_encoder),
+                b"UYVY" => EncoderMode::UyvyNative(h264_encoder),
+                b"NV12" => EncoderMode::Nv12Native(h264_encoder),
+                b"MJPG" => EncoderMode::MjpegNative(h264_encoder),
+                _ => EncoderMode::BayerNative(h264_encoder),
+            }
+        };
+        // Only native H264 configurations are selected without openh264
+        #[cfg(not(feature = "openh264"))]
+        let encoder_mode = EncoderMode::H264Passthrough;
+
+        // The capture thread is started last so that it is not started for a configuration that fails
+        let stream = match self.pipeline_depth {
+            Some(queue_depth) => {
+                let frame_duration = Duration::from_secs_f64(
+                    frame_interval.numerator as f64 / frame_interval.denominator.max(1) as f64,
+                );
+                let pipeline = Pipeline::spawn(stream, queue_depth, frame_duration)
+                    .map_err(StreamError::PipelineFailed)?;
+                BufferStream::Pipelined(pipeline)
+            }
+            None => stream,
+        };
+
+        Ok(Negotiated {
+            stream,
+            encoder_mode,
+            width: encoded_width,
+            height: encoded_height,
+            frame_interval,
+            fourcc,
+            transform,
+            transform_mode,
+            yuv_buffer,
+            raw_layout: RawLayout::new(&actual),
+        })
+    }
+}
+
+/// Without openh264 every format other than H264 would have to be transcoded, so only native H264 configurations
+/// can be streamed.
+#[cfg(not(feature = "openh264"))]
+fn passthrough_only(candidates: Vec<CaptureConfig>) -> Result<Vec<CaptureConfig>, StreamError> {
+    let h264 = FourCC::new(b"H264");
+
+    if !candidates.is_empty() && candidates.iter().all(|c| c.fourcc != h264) {
+        return Err(StreamError::TranscodeUnavailable);
+    }
+
+    Ok(candidates
+        .into_iter()
+        .filter(|c| c.fourcc == h264)
+        .collect())
+}
+
+/// True if frames `interval` seconds apart are no faster than `max_fps`.
+fn within_max_fps(interval: Fraction, max_fps: u32) -> bool {
+    // The product overflows 32 bits for intervals in fine units, as in `apply`
+    u64::from(interval.numerator) * u64::from(max_fps) >= u64::from(interval.denominator)
+}
+
+/// Checks the format a driver applied for a `requested` one, which may have been substituted with the closest format
+/// the driver supports. Substituted resolutions are rejected if the resolution was requested explicitly.
+fn check_applied_format(
+    requested: &Format,
+    actual: &Format,
+    explicit_resolution: bool,
+) -> Result<(), StreamError> {
+    let (width, height, fourcc) = (requested.width, requested.height, requested.fourcc);
+    let format_rejected = || StreamError::FormatRejected {
+        requested: (width, height, fourcc),
+        actual: (actual.width, actual.height, actual.fourcc),
+    };
+
+    if !selection::FOURCCS.contains(&&actual.fourcc.repr)
+        // The transcoded formats are subsampled to 4:2:0 which requires even dimensions
+        || (actual.fourcc != FourCC::new(b"H264")
+            && !(actual.width.is_multiple_of(2) && actual.height.is_multiple_of(2)))
+    {
+        return Err(format_rejected());
+    }
+
+    if (actual.width, actual.height, actual.fourcc) != (width, height, fourcc) {
+        // Explicitly requested resolutions are not substituted
+        if explicit_resolution && (actual.width, actual.height) != (width, height) {
+            return Err(format_rejected());
+        }
+
+        warn!(
+            "Camera substituted {}x{} {} for the requested {}x{} {}",
+            actual.width, actual.height, actual.fourcc, width, height, fourcc
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_frame_rates_above_max_fps() {
+        assert!(within_max_fps(Fraction::new(1, 30), 30));
+        assert!(!within_max_fps(Fraction::new(1, 60), 30));
+
+        // 30fps in microseconds, which overflows 32 bits when multiplied by large limits
+        let interval = Fraction::new(1_000_000, 30_000_000);
+        assert!(within_max_fps(interval, 30));
+        assert!(within_max_fps(interval, 5_000));
+        assert!(!within_max_fps(interval, 29));
+    }
+
+    /// Simulates `VIDIOC_S_FMT` on a driver that only supports 640x480 and 1280x720 YUYV and MJPG, substituting the
+    /// closest of them (by area) for anything else as V4L2 drivers do.
+    fn mock_set_format(requested: &Format) -> Format {
+        let fourcc = match &requested.fourcc.repr {
+            b"YUYV" | b"MJPG" => requested.fourcc,
+            _ => FourCC::new(b"YUYV"),
+        };
+        let area = requested.width as i64 * requested.height as i64;
+        let (width, height) = [(640, 480), (1280, 720)]
+            .into_iter()
+            .min_by_key(|&(width, height)| (width as i64 * height as i64 - area).abs())
+            .unwrap();
+
+        Format::new(width, height, fourcc)
+    }
+
+    fn negotiate(
+        width: u32,
+        height: u32,
+        fourcc: &[u8; 4],
+        explicit_resolution: bool,
+    ) -> Result<(u32, u32, FourCC), StreamError> {
+        let requested = Format::new(width, height, FourCC::new(fourcc));
+        let actual = mock_set_format(&requested);
+
+        check_applied_format(&requested, &actual, explicit_resolution)?;
+        Ok((actual.width, actual.height, actual.fourcc))
+    }
+
+    #[test]
+    fn accepts_formats_the_driver_applies_unchanged() {
+        assert_eq!(
+            negotiate(1280, 720, b"MJPG", true).unwrap(),
+            (1280, 720, FourCC::new(b"MJPG"))
+        );
+    }
+
+    #[test]
+    fn uses_substituted_formats() {
+        // The stream is set up for the applied format rather than the requested one
+        assert_eq!(
+            negotiate(1920, 1080, b"MJPG", false).unwrap(),
+            (1280, 720, FourCC::new(b"MJPG"))
+        );
+        assert_eq!(
+            negotiate(640, 480, b"H264", false).unwrap(),
+            (640, 480, FourCC::new(b"YUYV"))
+        );
+        // Only the pixel format was substituted
+        assert_eq!(
+            negotiate(640, 480, b"H264", true).unwrap(),
+            (640, 480, FourCC::new(b"YUYV"))
+        );
+    }
+
+    #[test]
+    fn rejects_substituted_explicit_resolutions() {
+        assert!(matches!(
+            negotiate(1920, 1080, b"MJPG", true),
+            Err(StreamError::FormatRejected {
+                requested: (1920, 1080, _),
+                actual: (1280, 720, _),
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_unsupported_substitutes() {
+        let requested = Format::new(640, 480, FourCC::new(b"YUYV"));
+
+        // A pixel format the stream can't read
+        let rgb = Format::new(640, 480, FourCC::new(b"RGB3"));
+        assert!(matches!(
+            check_applied_format(&requested, &rgb, false),
+            Err(StreamError::FormatRejected { .. })
+        ));
+
+        // Odd dimensions can't be subsampled to 4:2:0 for transcoding
+        let odd = Format::new(639, 479, FourCC::new(b"YUYV"));
+        assert!(matches!(
+            check_applied_format(&requested, &odd, false),
+            Err(StreamError::FormatRejected { .. })
+        ));
+    }
+}
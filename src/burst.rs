@@ -0,0 +1,182 @@
+use crate::{
+    decode_jpeg, force_keyframe, prepare_for_encoding, BufferFlags, ClockMapping, EncoderMode,
+    ErrorPolicy, FrameMeta, HealthEventKind, OwnedYUVFrame, StreamError, WebcamH264Stream,
+    YUVBuffer, YUVFrame,
+};
+use std::time::Instant;
+use tracing::{debug, warn};
+
+impl<'a> WebcamH264Stream<'a> {
+    /// Captures `count` consecutive frames as fast as the camera delivers them, eg. for photogrammetry, and returns them
+    /// as owned YUV frames along with their capture metadata.
+    ///
+    /// The frames are copied out of the camera's buffers as they arrive and only decoded once the whole burst has been
+    /// captured, so that they are spaced by the camera's frame rate rather than by how long each frame takes to decode.
+    /// Until then each frame is kept in the camera's format, which for long bursts of large MJPEG frames adds up.
+    ///
+    /// In native H264 mode the frames are decoded in order by the stream's decoder. If it hasn't decoded the frame
+    /// before the burst (eg. because frames were read with `next(false)`) a key frame is requested and the frames before
+    /// it are skipped, giving up after the stream's `max_yuv_attempts`. The burst's frames are not returned by `next`,
+    /// so the stream's H264 is realigned to the next key frame (see
+    /// [`realign_to_keyframe`](Self::realign_to_keyframe)).
+    ///
+    /// Transcoded frames are cropped and scaled like the stream's frames, but overlays are not drawn and decimation is
+    /// ignored. With `ErrorPolicy::SkipCorruptFrames` frames that fail to decode are left out of the burst.
+    pub fn capture_burst(
+        &mut self,
+        count: usize,
+    ) -> Result<Vec<(FrameMeta, OwnedYUVFrame)>, StreamError> {
+        let native_h264 = self.is_native_h264();
+        let mut frames = Vec::with_capacity(count);
+        let mut resync = false;
+        let mut skipped = 0;
+
+        while frames.len() < count {
+            let (mut meta, buf) = self.dequeue_copy()?;
+
+            if native_h264 {
+                meta.is_keyframe = self.parameter_sets.update(&buf);
+
+                // The decoder can only continue from the frame it decoded last, otherwise the burst starts at a key
+                // frame. The camera is asked for one so that it doesn't take a whole GOP.
+                if frames.is_empty()
+                    && skipped == 0
+                    && !self.decoder_recovery.continues_from(meta.sequence)
+                {
+                    resync = true;
+                    if !meta.is_keyframe {
+                        match force_keyframe(&self.handle) {
+                            Ok(()) => self.events.emit(HealthEventKind::KeyframeForced),
+                            Err(err) => {
+                                debug!("Unable to request a key frame for a burst: {:?}", err)
+                            }
+                        }
+                    }
+                }
+
+                if resync && !meta.is_keyframe {
+                    skipped += 1;
+                    if skipped >= self.max_yuv_attempts {
+                        return Err(StreamError::NoYUVFrame(skipped));
+                    }
+                    continue;
+                }
+
+                // The key frame is decoded by a new decoder with the cached parameter sets
+                if std::mem::take(&mut resync) {
+                    self.decoder_recovery.resync();
+                }
+            }
+
+            frames.push((meta, buf));
+        }
+
+        if native_h264 {
+            self.realign_to_keyframe();
+        }
+
+        let mut decoded = Vec::with_capacity(frames.len());
+        for (meta, buf) in frames {
+            match self.decode_burst_frame(&meta, &buf) {
+                Ok(Some(yuv)) => decoded.push((meta, YUVFrame::Buffer(yuv))),
+                // The decoder is resyncing after a corrupt frame
+                Ok(None) => {}
+                Err(err) if self.error_policy == ErrorPolicy::SkipCorruptFrames => {
+                    self.corrupt_frames += 1;
+                    warn!("Skipping corrupt burst frame {}: {}", meta.sequence, err);
+                    self.events.emit(HealthEventKind::CorruptFrameSkipped {
+                        sequence: meta.sequence,
+                        error: err.to_string(),
+                    });
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(decoded)
+    }
+
+    /// Dequeues the next non-empty buffer and copies its payload out of the camera's buffer.
+    fn dequeue_copy(&mut self) -> Result<(FrameMeta, Vec<u8>), StreamError> {
+        if self.paused {
+            return Err(StreamError::Paused);
+        }
+
+        let Some(stream) = &mut self.stream else {
+            return Err(StreamError::NotConfigured);
+        };
+
+        match self.stall_deadline {
+            Some(timeout) => stream.set_timeout(timeout),
+            None => stream.clear_timeout(),
+        }
+
+        let mut dropped_since_last = 0u32;
+
+        loop {
+            let (buf, meta) = match stream.next() {
+                Ok(next) => next,
+                Err(err) => return Err(self.dequeue_failed(err, None)),
+            };
+            self.last_frame_received = Instant::now();
+            let dropped = self.stats.record_sequence(meta.sequence);
+            if dropped > 0 {
+                dropped_since_last = dropped_since_last.saturating_add(dropped);
+                self.events.emit(HealthEventKind::FramesDropped {
+                    count: dropped,
+                    at: meta.timestamp.into(),
+                });
+            }
+
+            let clock = *self.clock.get_or_insert_with(|| {
+                let monotonic =
+                    meta.flags & BufferFlags::TIMESTAMP_MASK == BufferFlags::TIMESTAMP_MONOTONIC;
+                ClockMapping::detect(meta.timestamp.into(), monotonic)
+            });
+
+            if meta.bytesused > 0 {
+                let frame_meta = FrameMeta {
+                    timestamp: meta.timestamp.into(),
+                    clock,
+                    sequence: meta.sequence,
+                    dropped_since_last,
+                    bytesused: meta.bytesused,
+                    // Every JPEG and uncompressed frame can be decoded on its own
+                    is_keyframe: true,
+                };
+
+                let buf = &buf[..buf.len().min(meta.bytesused as usize)];
+                return Ok((frame_meta, buf.to_vec()));
+            }
+        }
+    }
+
+    /// Decodes a burst frame, returning `None` if the H264 decoder produced no picture for it.
+    fn decode_burst_frame(
+        &mut self,
+        meta: &FrameMeta,
+        buf: &[u8],
+    ) -> Result<Option<YUVBuffer>, StreamError> {
+        match &mut self.encoder_mode {
+            EncoderMode::H264Native(h264_decoder) => {
+                let result = self
+                    .decoder_recovery
+                    .decode(h264_decoder, buf, &self.parameter_sets);
+                self.decoder_recovery.decoded(meta.sequence);
+
+                return Ok(result?.map(|yuv| YUVBuffer::from_source(&yuv)));
+            }
+            EncoderMode::MjpegNative(_) => decode_jpeg(buf, &mut self.yuv_buffer)?,
+            EncoderMode::YuyvNative(_)
+            | EncoderMode::UyvyNative(_)
+            | EncoderMode::Nv12Native(_)
+            | EncoderMode::BayerNative(_) => {
+                self.yuv_buffer
+                    .read_raw(self.fourcc, buf, self.raw_layout)?
+            }
+        }
+
+        let yuv_buffer = prepare_for_encoding(&mut self.yuv_buffer, &mut self.transform, &[]);
+        Ok(Some(yuv_buffer.clone()))
+    }
+}
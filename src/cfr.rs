@@ -0,0 +1,463 @@
+#[cfg(feature = "openh264")]
+use crate::yuv::YUVSource;
+use crate::FrameMeta;
+#[cfg(feature = "openh264")]
+use crate::{EncoderOptions, StreamError, YUVBuffer, YUVFrame};
+#[cfg(feature = "openh264")]
+use openh264::encoder::Encoder;
+use std::time::Duration;
+
+/// Gaps between frames longer than this (eg. while the stream was paused) restart the output timeline instead of being
+/// filled with duplicates.
+const MAX_GAP: Duration = Duration::from_secs(2);
+
+/// Converts a variable frame rate stream to a constant frame rate, eg. for muxers and players that assume CFR.
+///
+/// Output frames are evenly spaced at `target_fps` starting from the first frame's capture timestamp. Each input frame
+/// is assigned the output slot closest to its capture timestamp:
+///
+/// - With [`push_yuv`](Self::push_yuv) frames are re-encoded. Slots that no frame falls in are filled by re-encoding
+///   the previous frame again and frames that fall in an already filled slot are dropped, so exactly `target_fps`
+///   frames are produced per second of capture.
+/// - With [`push`](Self::push) the access units are passed through unchanged. H264 frames cannot be duplicated or
+///   dropped without re-encoding (later frames reference them) so every frame is kept with its timestamp moved onto
+///   the CFR grid, and [`CfrFrame::missing_before`] reports where frames would have been duplicated. Frames arriving
+///   faster than `target_fps` are pushed back into the following slots.
+///
+/// Timestamps that go backwards or jump forward more than 2 seconds (eg. after the stream was paused or restarted)
+/// continue the output from the next slot.
+pub struct CfrAdapter {
+    fps: f64,
+    // The slot and capture timestamp the timeline is measured from, which move on each discontinuity
+    origin: Option<(u64, Duration)>,
+    last_timestamp: Duration,
+    next_slot: u64,
+    stats: CfrStats,
+    #[cfg(feature = "openh264")]
+    encoder_options: EncoderOptions,
+    #[cfg(feature = "openh264")]
+    encoder: Option<Encoder>,
+    // The most recent frame, which is re-encoded to fill empty slots
+    #[cfg(feature = "openh264")]
+    previous: Option<(FrameMeta, YUVBuffer)>,
+}
+
+/// A frame produced by a [`CfrAdapter`].
+#[derive(Debug, Clone)]
+pub struct CfrFrame {
+    /// The frame's slot in the output, counting from 0 at the first frame.
+    pub index: u64,
+    /// The input frame's metadata with its timestamp replaced by the slot's evenly spaced timestamp. Timestamps keep the
+    /// capture clock's origin so they stay in sync with other streams captured at the same time, eg. audio.
+    pub meta: FrameMeta,
+    pub h264_bytes: Vec<u8>,
+    /// True if the frame is a re-encoded copy of the previous frame.
+    pub duplicate: bool,
+    /// The number of empty slots before this frame, ie. the number of duplicates that would have been needed. Always
+    /// 0 for re-encoded frames.
+    pub missing_before: u64,
+}
+
+/// Counts of the frames a [`CfrAdapter`] has processed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CfrStats {
+    pub input_frames: u64,
+    pub output_frames: u64,
+    pub duplicated_frames: u64,
+    pub dropped_frames: u64,
+    /// The number of empty slots left between passed through frames, see [`CfrFrame::missing_before`].
+    pub missing_frames: u64,
+    /// The number of times the output timeline was restarted because the timestamps went backwards or had a long gap.
+    pub discontinuities: u64,
+}
+
+impl CfrAdapter {
+    /// # Panics
+    ///
+    /// Will panic if `target_fps` is not a positive number.
+    pub fn new(target_fps: f64) -> Self {
+        assert!(
+            target_fps.is_finite() && target_fps > 0.0,
+            "target_fps must be positive"
+        );
+
+        Self {
+            fps: target_fps,
+            origin: None,
+            last_timestamp: Duration::ZERO,
+            next_slot: 0,
+            stats: CfrStats::default(),
+            #[cfg(feature = "openh264")]
+            encoder_options: EncoderOptions::default(),
+            #[cfg(feature = "openh264")]
+            encoder: None,
+            #[cfg(feature = "openh264")]
+            previous: None,
+        }
+    }
+
+    /// Configures the H264 encoder used by [`push_yuv`](Self::push_yuv). The frame rate hint defaults to the target
+    /// frame rate.
+    #[cfg(feature = "openh264")]
+    pub fn encoder_options(mut self, encoder_options: EncoderOptions) -> Self {
+        self.encoder_options = encoder_options;
+        self
+    }
+
+    /// The output frame rate, eg. to pass to [`Mp4Writer::create`](crate::mp4::Mp4Writer::create).
+    pub fn fps(&self) -> f64 {
+        self.fps
+    }
+
+    pub fn stats(&self) -> CfrStats {
+        self.stats
+    }
+
+    /// Re-encodes a frame captured at `meta.timestamp`, returning the frames for the slots up to and including the
+    /// frame's own: re-encoded copies of the previous frame for any empty slots, followed by the frame. Returns no
+    /// frames if the frame was dropped.
+    #[cfg(feature = "openh264")]
+    pub fn push_yuv(
+        &mut self,
+        meta: &FrameMeta,
+        yuv: &YUVFrame,
+    ) -> Result<Vec<CfrFrame>, StreamError> {
+        let (width, height) = (yuv.width() as u32, yuv.height() as u32);
+
+        if let Some((_, previous)) = &self.previous {
+            let expected = (previous.width() as u32, previous.height() as u32);
+            if (width, height) != expected {
+                return Err(StreamError::FrameSizeMismatch {
+                    expected,
+                    actual: (width, height),
+                });
+            }
+        }
+
+        self.stats.input_frames += 1;
+        let slot = self.slot_for(meta.timestamp);
+
+        let encoder = match &mut self.encoder {
+            Some(encoder) => encoder,
+            encoder => encoder.insert(
+                self.encoder_options
+                    .frame_rate_or(self.fps as f32)
+                    .build(width, height)?,
+            ),
+        };
+
+        let mut frames = Vec::new();
+
+        // The frame is still kept as the one to duplicate so that duplicates show the latest picture
+        if slot < self.next_slot {
+            self.stats.dropped_frames += 1;
+        } else {
+            if let Some((previous_meta, previous)) = &self.previous {
+                for index in self.next_slot..slot {
+                    frames.push(CfrFrame {
+                        index,
+                        meta: *previous_meta,
+                        h264_bytes: encoder.encode(previous)?.to_vec(),
+                        duplicate: true,
+                        missing_before: 0,
+                    });
+                    self.stats.duplicated_frames += 1;
+                }
+            }
+
+            frames.push(CfrFrame {
+                index: slot,
+                meta: *meta,
+                h264_bytes: yuv.encode_using(encoder)?.to_vec(),
+                duplicate: false,
+                missing_before: 0,
+            });
+            self.next_slot = slot + 1;
+        }
+
+        match &mut self.previous {
+            Some((previous_meta, previous)) => {
+                *previous_meta = *meta;
+                previous.copy_from(yuv.source());
+            }
+            previous => *previous = Some((*meta, YUVBuffer::from_source(yuv.source()))),
+        }
+
+        for frame in &mut frames {
+            frame.meta.timestamp = self.slot_timestamp(frame.index);
+            frame.meta.is_keyframe = crate::nal::is_keyframe(&frame.h264_bytes);
+        }
+        self.stats.output_frames += frames.len() as u64;
+
+        Ok(frames)
+    }
+
+    /// Passes an access unit captured at `meta.timestamp` through with its timestamp moved onto the CFR grid.
+    pub fn push(&mut self, meta: &FrameMeta, h264_bytes: Vec<u8>) -> CfrFrame {
+        self.stats.input_frames += 1;
+        let slot = self.slot_for(meta.timestamp);
+
+        // Frames can't be dropped so frames that arrive early take the next free slot
+        let index = slot.max(self.next_slot);
+        let missing_before = index - self.next_slot;
+        self.next_slot = index + 1;
+
+        self.stats.missing_frames += missing_before;
+        self.stats.output_frames += 1;
+
+        CfrFrame {
+            index,
+            meta: FrameMeta {
+                timestamp: self.slot_timestamp(index),
+                ..*meta
+            },
+            h264_bytes,
+            duplicate: false,
+            missing_before,
+        }
+    }
+
+    /// Returns the slot closest to `timestamp`, restarting the timeline from the next slot on a discontinuity.
+    fn slot_for(&mut self, timestamp: Duration) -> u64 {
+        let max_gap = MAX_GAP.max(Duration::from_secs_f64(2.0 / self.fps));
+        let last_timestamp = std::mem::replace(&mut self.last_timestamp, timestamp);
+
+        let (origin_slot, origin_timestamp) = match self.origin {
+            Some(origin)
+                if timestamp >= last_timestamp && timestamp - last_timestamp <= max_gap =>
+            {
+                origin
+            }
+            origin => {
+                if origin.is_some() {
+                    self.stats.discontinuities += 1;
+                }
+
+                // The timeline restarts with this frame in the next slot
+                self.origin = Some((self.next_slot, timestamp));
+                return self.next_slot;
+            }
+        };
+
+        let elapsed = timestamp - origin_timestamp;
+        origin_slot + (elapsed.as_secs_f64() * self.fps).round() as u64
+    }
+
+    /// The timestamp of a slot at or after the timeline's origin.
+    fn slot_timestamp(&self, index: u64) -> Duration {
+        let (origin_slot, origin_timestamp) = self.origin.unwrap_or_default();
+        origin_timestamp + Duration::from_secs_f64((index - origin_slot) as f64 / self.fps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ClockMapping;
+
+    /// Frame intervals between 22 and 30fps.
+    const JITTERY_INTERVALS_MS: [u64; 7] = [34, 45, 38, 41, 35, 44, 36];
+
+    fn meta(timestamp: Duration) -> FrameMeta {
+        FrameMeta {
+            timestamp,
+            clock: ClockMapping::default(),
+            sequence: 0,
+            dropped_since_last: 0,
+            bytesused: 0,
+            is_keyframe: false,
+        }
+    }
+
+    /// `count` jittery capture timestamps starting at `start`.
+    fn jittery_timestamps(start: Duration, count: usize) -> Vec<Duration> {
+        JITTERY_INTERVALS_MS
+            .iter()
+            .cycle()
+            .take(count)
+            .scan(start, |timestamp, &interval| {
+                let current = *timestamp;
+                *timestamp += Duration::from_millis(interval);
+                Some(current)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn passes_frames_through_onto_the_grid() {
+        let mut cfr = CfrAdapter::new(30.0);
+        let start = Duration::from_secs(10);
+
+        // A frame is missing before 100ms and the frame at 110ms arrives a slot early
+        let frames: Vec<_> = [0, 33, 100, 110]
+            .iter()
+            .map(|&ms| cfr.push(&meta(start + Duration::from_millis(ms)), vec![ms as u8]))
+            .collect();
+
+        let indices: Vec<_> = frames.iter().map(|frame| frame.index).collect();
+        let missing: Vec<_> = frames.iter().map(|frame| frame.missing_before).collect();
+        assert_eq!(indices, [0, 1, 3, 4]);
+        assert_eq!(missing, [0, 0, 1, 0]);
+        for frame in &frames {
+            assert_eq!(
+                frame.meta.timestamp,
+                start + Duration::from_secs_f64(frame.index as f64 / 30.0)
+            );
+            assert!(!frame.duplicate);
+        }
+        assert_eq!(frames[3].h264_bytes, [110]);
+        assert_eq!(
+            cfr.stats(),
+            CfrStats {
+                input_frames: 4,
+                output_frames: 4,
+                missing_frames: 1,
+                ..CfrStats::default()
+            }
+        );
+    }
+
+    #[test]
+    fn reports_missing_slots_of_jittery_frames() {
+        let mut cfr = CfrAdapter::new(30.0);
+        let timestamps = jittery_timestamps(Duration::from_secs(10), 90);
+
+        let frames: Vec<_> = timestamps
+            .iter()
+            .map(|&timestamp| cfr.push(&meta(timestamp), Vec::new()))
+            .collect();
+
+        // Every slot is either filled or reported missing
+        let last = frames.last().unwrap();
+        let missing: u64 = frames.iter().map(|frame| frame.missing_before).sum();
+        assert_eq!(last.index + 1, frames.len() as u64 + missing);
+        assert!(frames
+            .windows(2)
+            .all(|pair| pair[1].meta.timestamp > pair[0].meta.timestamp));
+
+        // Each frame is within half a slot of its capture time
+        for (frame, timestamp) in frames.iter().zip(&timestamps) {
+            let offset = frame.meta.timestamp.abs_diff(*timestamp);
+            assert!(offset <= Duration::from_secs_f64(0.5 / 30.0), "{offset:?}");
+        }
+
+        let stats = cfr.stats();
+        assert_eq!((stats.input_frames, stats.output_frames), (90, 90));
+        assert_eq!(stats.missing_frames, missing);
+        assert!(missing > 0);
+    }
+
+    #[test]
+    fn continues_after_the_clock_restarts() {
+        let mut cfr = CfrAdapter::new(30.0);
+        for i in 0..60 {
+            cfr.push(
+                &meta(Duration::from_secs(10) + Duration::from_secs(i) / 30),
+                Vec::new(),
+            );
+        }
+
+        // The device clock restarts near 0
+        let frames: Vec<_> = (0..30)
+            .map(|i| cfr.push(&meta(Duration::from_secs(i) / 30), Vec::new()))
+            .collect();
+
+        for (i, frame) in frames.iter().enumerate() {
+            assert_eq!(frame.index, 60 + i as u64);
+            assert_eq!(frame.missing_before, 0);
+            assert_eq!(
+                frame.meta.timestamp,
+                Duration::from_secs_f64(i as f64 / 30.0)
+            );
+        }
+        assert_eq!(cfr.stats().discontinuities, 1);
+        assert_eq!(cfr.stats().missing_frames, 0);
+    }
+
+    #[cfg(feature = "openh264")]
+    mod reencoded {
+        use super::*;
+
+        /// Re-encodes frames captured at `timestamps`, returning the output frames.
+        fn push_yuv(cfr: &mut CfrAdapter, timestamps: &[Duration]) -> Vec<CfrFrame> {
+            let yuv = YUVFrame::Buffer(YUVBuffer::new(64, 64));
+            timestamps
+                .iter()
+                .flat_map(|&timestamp| cfr.push_yuv(&meta(timestamp), &yuv).unwrap())
+                .collect()
+        }
+
+        /// Checks that the frames fill consecutive slots from `first` at the target frame rate.
+        fn assert_constant_rate(frames: &[CfrFrame], first: u64, fps: f64) {
+            for (i, frame) in frames.iter().enumerate() {
+                assert_eq!(frame.index, first + i as u64);
+            }
+            for pair in frames.windows(2) {
+                let interval = pair[1].meta.timestamp - pair[0].meta.timestamp;
+                assert!(
+                    interval.abs_diff(Duration::from_secs_f64(1.0 / fps))
+                        < Duration::from_micros(1),
+                    "{interval:?}"
+                );
+            }
+        }
+
+        #[test]
+        fn duplicates_frames_of_jittery_input() {
+            let mut cfr = CfrAdapter::new(30.0);
+            let frames = push_yuv(&mut cfr, &jittery_timestamps(Duration::from_secs(10), 60));
+
+            assert_constant_rate(&frames, 0, 30.0);
+            assert!(frames[0].meta.is_keyframe);
+
+            let duplicates = frames.iter().filter(|frame| frame.duplicate).count() as u64;
+            let stats = cfr.stats();
+            assert_eq!(stats.input_frames, 60);
+            assert_eq!(stats.dropped_frames, 0);
+            assert_eq!(stats.duplicated_frames, duplicates);
+            assert_eq!(stats.output_frames, 60 + duplicates);
+            assert!(duplicates > 0);
+        }
+
+        #[test]
+        fn drops_frames_of_faster_input() {
+            let mut cfr = CfrAdapter::new(20.0);
+            let timestamps: Vec<_> = (0..60)
+                .map(|i| Duration::from_secs(10) + Duration::from_secs(i) / 60)
+                .collect();
+            let frames = push_yuv(&mut cfr, &timestamps);
+
+            // The frames closest to each of the 20fps slots up to 59/60 seconds
+            assert_eq!(frames.len(), 21);
+            assert_constant_rate(&frames, 0, 20.0);
+            assert!(frames.iter().all(|frame| !frame.duplicate));
+            assert_eq!(
+                cfr.stats(),
+                CfrStats {
+                    input_frames: 60,
+                    output_frames: 21,
+                    dropped_frames: 39,
+                    ..CfrStats::default()
+                }
+            );
+        }
+
+        #[test]
+        fn keeps_reencoding_after_the_clock_restarts() {
+            let mut cfr = CfrAdapter::new(30.0);
+            let before: Vec<_> = (0..60)
+                .map(|i| Duration::from_secs(10) + Duration::from_secs(i) / 30)
+                .collect();
+            push_yuv(&mut cfr, &before);
+
+            // The device clock restarts near 0
+            let after: Vec<_> = (0..30).map(|i| Duration::from_secs(i) / 30).collect();
+            let frames = push_yuv(&mut cfr, &after);
+
+            assert_eq!(frames.len(), 30);
+            assert_constant_rate(&frames, 60, 30.0);
+            assert_eq!(cfr.stats().dropped_frames, 0);
+            assert_eq!(cfr.stats().duplicated_frames, 0);
+        }
+    }
+}
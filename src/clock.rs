@@ -0,0 +1,124 @@
+use chrono::{DateTime, TimeZone, Utc};
+use std::time::Duration;
+
+/// How many times the clocks are sampled together when measuring their offset. The pair read the fastest (ie. least
+/// likely to have been interrupted by the scheduler) is used.
+const SAMPLES: usize = 5;
+
+/// The clock a frame's kernel timestamp is relative to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimestampClock {
+    /// `CLOCK_MONOTONIC`, used by almost every driver.
+    #[default]
+    Monotonic,
+    /// `CLOCK_MONOTONIC_RAW`, which is not slewed by NTP.
+    MonotonicRaw,
+    /// `CLOCK_REALTIME`, ie. the driver already reports wall clock times.
+    Realtime,
+}
+
+impl TimestampClock {
+    fn id(self) -> libc::clockid_t {
+        match self {
+            Self::Monotonic => libc::CLOCK_MONOTONIC,
+            Self::MonotonicRaw => libc::CLOCK_MONOTONIC_RAW,
+            Self::Realtime => libc::CLOCK_REALTIME,
+        }
+    }
+
+    /// The clock's current time.
+    pub fn now(self) -> Duration {
+        let mut now = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        // SAFETY: `now` is a valid timespec and these clocks are always supported on Linux
+        unsafe {
+            libc::clock_gettime(self.id(), &mut now);
+        }
+        Duration::new(now.tv_sec as u64, now.tv_nsec as u32)
+    }
+}
+
+/// Converts timestamps from a [`TimestampClock`] to UTC, eg. to correlate frames with other sensors' logs.
+///
+/// The offset between the clock and UTC is measured once, when the stream reads its first frame. It is accurate to
+/// within [`uncertainty`](Self::uncertainty) (usually a few microseconds) at that moment, but the system clock can be
+/// stepped or slewed by NTP afterwards: monotonic timestamps drift from UTC by up to NTP's slew rate (0.05%) while it
+/// corrects the clock, and a stepped system clock is not followed. The kernel timestamp itself is usually taken when
+/// the driver finished receiving the frame, which for USB cameras is a few milliseconds after the exposure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ClockMapping {
+    pub clock: TimestampClock,
+    /// UTC minus the clock's time, in nanoseconds.
+    pub utc_offset_nanos: i64,
+    /// Half the time it took to sample both clocks, which bounds the error of the offset.
+    pub uncertainty: Duration,
+}
+
+impl ClockMapping {
+    /// Measures the offset between a clock and UTC by sampling them together.
+    pub fn measure(clock: TimestampClock) -> Self {
+        if clock == TimestampClock::Realtime {
+            return Self {
+                clock,
+                utc_offset_nanos: 0,
+                uncertainty: Duration::ZERO,
+            };
+        }
+
+        (0..SAMPLES)
+            .map(|_| {
+                let before = clock.now();
+                let utc = TimestampClock::Realtime.now();
+                let after = clock.now();
+
+                let midpoint = before + (after - before) / 2;
+                Self {
+                    clock,
+                    utc_offset_nanos: utc.as_nanos() as i64 - midpoint.as_nanos() as i64,
+                    uncertainty: (after - before) / 2,
+                }
+            })
+            .min_by_key(|mapping| mapping.uncertainty)
+            .unwrap_or_default()
+    }
+
+    /// Works out which clock a driver's timestamps use from a frame that was just dequeued, whose timestamp should be
+    /// slightly in the past on the right clock, and measures its offset. `flagged_monotonic` is whether the buffer's
+    /// flags claim the timestamp is monotonic.
+    ///
+    /// Drivers that flag their timestamps as monotonic are only checked for also being `CLOCK_MONOTONIC_RAW`. The two
+    /// clocks only differ by NTP's corrections so far, so they can't be told apart until they have drifted further
+    /// apart than the frame's latency, at which point the error of picking either is smaller than the latency.
+    pub(crate) fn detect(timestamp: Duration, flagged_monotonic: bool) -> Self {
+        let candidates: &[TimestampClock] = if flagged_monotonic {
+            &[TimestampClock::Monotonic, TimestampClock::MonotonicRaw]
+        } else {
+            &[
+                TimestampClock::Monotonic,
+                TimestampClock::MonotonicRaw,
+                TimestampClock::Realtime,
+            ]
+        };
+
+        let age = |clock: TimestampClock| {
+            let now = clock.now();
+            now.checked_sub(timestamp)
+                .unwrap_or_else(|| timestamp - now)
+        };
+
+        let clock = candidates
+            .iter()
+            .copied()
+            .min_by_key(|&clock| age(clock))
+            .unwrap_or_default();
+
+        Self::measure(clock)
+    }
+
+    /// Converts a timestamp from the mapping's clock to UTC.
+    pub fn to_utc(&self, timestamp: Duration) -> DateTime<Utc> {
+        Utc.timestamp_nanos(timestamp.as_nanos() as i64 + self.utc_offset_nanos)
+    }
+}
@@ -0,0 +1,369 @@
+use crate::resize::{scale_into, validate_size};
+use crate::yuv::YUVSource;
+use crate::{EncoderOptions, FrameSource, Rect, ScaleFilter, StreamError, YUVBuffer, YUVFrame};
+use openh264::encoder::Encoder;
+
+/// How a [`Compositor`] arranges its inputs in the output frame. Inputs are scaled to fit their area, keeping their
+/// aspect ratio, and any remaining space is filled with black.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Layout {
+    /// The inputs side by side in equal width columns, in order from left to right.
+    SideBySide,
+    /// The inputs in a grid of equally sized cells with as many columns as rows (or one more), filled row by row.
+    Grid,
+    /// The first input fills the frame and the others are drawn over it as insets stacked from the `corner`, each
+    /// `scale` times the size of the frame. Insets that don't fit in the frame are not drawn.
+    Pip { corner: Corner, scale: f32 },
+}
+
+/// A corner of the frame, see [`Layout::Pip`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+/// Composes the frames of several sources (eg. two cameras side by side, or one picture-in-picture) into a single
+/// H264 stream.
+///
+/// The compositor is itself a [`FrameSource`] so it can be recorded or muxed in the same way as a single camera. Each
+/// output frame takes the inputs' frames due at its time: frames from faster sources are skipped and the most recent
+/// frame of a slower source is repeated until its next frame is due. The inputs are read in turn so a slow or stalled
+/// input holds back the output.
+pub struct Compositor<'a> {
+    inputs: Vec<Input<'a>>,
+    width: u32,
+    height: u32,
+    fps: f64,
+    filter: ScaleFilter,
+    encoder_options: EncoderOptions,
+    encoder: Option<Encoder>,
+    output: YUVBuffer,
+    frame_number: u64,
+}
+
+struct Input<'a> {
+    source: Box<dyn FrameSource + 'a>,
+    frames_read: u64,
+    cell: Cell,
+}
+
+/// Where an input is drawn in the output frame.
+struct Cell {
+    // The area of the output frame the input is fitted into
+    area: Rect,
+    // Where in its area the picture is placed, or `None` to center it
+    corner: Option<Corner>,
+    // The most recent frame, already scaled, and its position in the output frame
+    picture: Option<(u32, u32, YUVBuffer)>,
+}
+
+impl<'a> Compositor<'a> {
+    /// Creates a compositor producing `width` x `height` frames. The output frame rate defaults to that of the fastest
+    /// input.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if there are no inputs, or for [`Layout::Pip`] if `scale` is not between 0 and 1.
+    pub fn new(
+        inputs: Vec<Box<dyn FrameSource + 'a>>,
+        layout: Layout,
+        width: u32,
+        height: u32,
+    ) -> Result<Self, StreamError> {
+        assert!(!inputs.is_empty(), "A compositor needs at least one input");
+        validate_size(width, height)?;
+
+        let cells = layout_cells(layout, inputs.len(), width, height);
+        let fps = inputs
+            .iter()
+            .map(|source| source.fps())
+            .filter(|fps| fps.is_finite() && *fps > 0.0)
+            .fold(None, |max: Option<f64>, fps| {
+                Some(max.map_or(fps, |max| max.max(fps)))
+            })
+            .unwrap_or(30.0);
+
+        let inputs = inputs
+            .into_iter()
+            .zip(cells)
+            .map(|(source, (area, corner))| Input {
+                source,
+                frames_read: 0,
+                cell: Cell {
+                    area,
+                    corner,
+                    picture: None,
+                },
+            })
+            .collect();
+
+        Ok(Self {
+            inputs,
+            width,
+            height,
+            fps,
+            filter: ScaleFilter::default(),
+            encoder_options: EncoderOptions::default(),
+            encoder: None,
+            output: YUVBuffer::new(width as usize, height as usize),
+            frame_number: 0,
+        })
+    }
+
+    /// Sets the output frame rate. Inputs are sampled at this rate regardless of their own frame rates.
+    pub fn output_fps(mut self, fps: f64) -> Self {
+        self.fps = fps;
+        self
+    }
+
+    /// Sets how the inputs are scaled (defaults to bilinear).
+    pub fn scale_filter(mut self, filter: ScaleFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Configures the H264 encoder. The frame rate hint defaults to the output frame rate.
+    pub fn encoder_options(mut self, encoder_options: EncoderOptions) -> Self {
+        self.encoder_options = encoder_options;
+        self
+    }
+
+    /// The number of frames read from each input, in the order they were given.
+    pub fn frames_read(&self) -> Vec<u64> {
+        self.inputs.iter().map(|input| input.frames_read).collect()
+    }
+
+    /// Reads the frames due from each input and draws the most recent one from each into the output frame.
+    fn compose(&mut self) -> Result<(), StreamError> {
+        let output_time = self.frame_number as f64 / self.fps;
+
+        for input in &mut self.inputs {
+            // The number of frames the input has captured by the time of the output frame
+            let input_fps = input.source.fps();
+            let due = if input_fps.is_finite() && input_fps > 0.0 {
+                (output_time * input_fps + 1e-6).floor() as u64 + 1
+            } else {
+                input.frames_read + 1
+            };
+
+            while input.frames_read < due {
+                // Only the last frame read is shown so the others aren't decoded
+                let get_yuv_frame = input.frames_read + 1 == due;
+                let (_, yuv) = input.source.next(get_yuv_frame)?;
+                input.frames_read += 1;
+
+                if let Some(yuv) = yuv {
+                    input.cell.place(&yuv, self.filter);
+                }
+            }
+        }
+
+        fill_black(&mut self.output);
+        for input in &self.inputs {
+            if let Some((x, y, picture)) = &input.cell.picture {
+                blit(picture, &mut self.output, *x as usize, *y as usize);
+            }
+        }
+
+        self.frame_number += 1;
+        Ok(())
+    }
+}
+
+impl Cell {
+    /// Scales a frame to fit the cell and positions it.
+    fn place(&mut self, yuv: &YUVFrame, filter: ScaleFilter) {
+        let cell = &self.area;
+        let (width, height) = fit((yuv.width() as u32, yuv.height() as u32), cell);
+
+        let (x, y) = match self.corner {
+            None => (
+                cell.x + (((cell.width - width) / 2) & !1),
+                cell.y + (((cell.height - height) / 2) & !1),
+            ),
+            Some(Corner::TopLeft) => (cell.x, cell.y),
+            Some(Corner::TopRight) => (cell.x + cell.width - width, cell.y),
+            Some(Corner::BottomLeft) => (cell.x, cell.y + cell.height - height),
+            Some(Corner::BottomRight) => {
+                (cell.x + cell.width - width, cell.y + cell.height - height)
+            }
+        };
+
+        let mut picture = match self.picture.take() {
+            Some((_, _, picture))
+                if (picture.width(), picture.height()) == (width as i32, height as i32) =>
+            {
+                picture
+            }
+            _ => YUVBuffer::new(width as usize, height as usize),
+        };
+        scale_into(yuv.source(), &mut picture, filter);
+
+        self.picture = Some((x, y, picture));
+    }
+}
+
+/// The area and alignment of each input's picture in the output frame.
+fn layout_cells(
+    layout: Layout,
+    inputs: usize,
+    width: u32,
+    height: u32,
+) -> Vec<(Rect, Option<Corner>)> {
+    let grid = |columns: u32, rows: u32| {
+        let (cell_width, cell_height) = ((width / columns) & !1, (height / rows) & !1);
+
+        (0..inputs as u32)
+            .map(|i| {
+                let cell = Rect {
+                    x: i % columns * cell_width,
+                    y: i / columns * cell_height,
+                    width: cell_width,
+                    height: cell_height,
+                };
+                (cell, None)
+            })
+            .collect()
+    };
+
+    match layout {
+        Layout::SideBySide => grid(inputs as u32, 1),
+        Layout::Grid => {
+            let columns = (inputs as f64).sqrt().ceil() as u32;
+            grid(columns, (inputs as u32).div_ceil(columns))
+        }
+        Layout::Pip { corner, scale } => {
+            assert!(
+                scale > 0.0 && scale <= 1.0,
+                "The picture-in-picture scale must be between 0 and 1"
+            );
+
+            let inset_width = ((width as f32 * scale) as u32 & !1).max(2);
+            let inset_height = ((height as f32 * scale) as u32 & !1).max(2);
+            let margin = (height / 32) & !1;
+
+            let background = Rect {
+                x: 0,
+                y: 0,
+                width,
+                height,
+            };
+
+            let insets = (0..inputs as u32 - 1).filter_map(|i| {
+                let offset = margin + i * (inset_height + margin);
+                if offset + inset_height + margin > height || inset_width + 2 * margin > width {
+                    return None;
+                }
+
+                let x = match corner {
+                    Corner::TopLeft | Corner::BottomLeft => margin,
+                    Corner::TopRight | Corner::BottomRight => width - margin - inset_width,
+                };
+                let y = match corner {
+                    Corner::TopLeft | Corner::TopRight => offset,
+                    Corner::BottomLeft | Corner::BottomRight => height - offset - inset_height,
+                };
+
+                let cell = Rect {
+                    x,
+                    y,
+                    width: inset_width,
+                    height: inset_height,
+                };
+                Some((cell, Some(corner)))
+            });
+
+            std::iter::once((background, None)).chain(insets).collect()
+        }
+    }
+}
+
+/// The even size of a `source` sized picture scaled to fit within the cell, keeping its aspect ratio.
+fn fit((source_width, source_height): (u32, u32), cell: &Rect) -> (u32, u32) {
+    let (source_width, source_height) = (source_width.max(1) as u64, source_height.max(1) as u64);
+    let (cell_width, cell_height) = (cell.width as u64, cell.height as u64);
+
+    let (width, height) = if source_width * cell_height > source_height * cell_width {
+        (cell_width, cell_width * source_height / source_width)
+    } else {
+        (cell_height * source_width / source_height, cell_height)
+    };
+
+    ((width as u32 & !1).max(2), (height as u32 & !1).max(2))
+}
+
+fn fill_black(frame: &mut YUVBuffer) {
+    frame.y_mut().fill(16);
+    frame.u_mut().fill(128);
+    frame.v_mut().fill(128);
+}
+
+/// Copies `picture` into `frame` with its top left corner at the (even) position `x`, `y`.
+fn blit(picture: &YUVBuffer, frame: &mut YUVBuffer, x: usize, y: usize) {
+    let (width, frame_width) = (picture.width() as usize, frame.width() as usize);
+
+    copy_plane(picture.y(), width, frame.y_mut(), frame_width, x, y);
+    copy_plane(
+        picture.u(),
+        width / 2,
+        frame.u_mut(),
+        frame_width / 2,
+        x / 2,
+        y / 2,
+    );
+    copy_plane(
+        picture.v(),
+        width / 2,
+        frame.v_mut(),
+        frame_width / 2,
+        x / 2,
+        y / 2,
+    );
+}
+
+fn copy_plane(src: &[u8], src_width: usize, dst: &mut [u8], dst_width: usize, x: usize, y: usize) {
+    for (row, src_row) in src.chunks_exact(src_width).enumerate() {
+        let start = (y + row) * dst_width + x;
+        dst[start..start + src_width].copy_from_slice(src_row);
+    }
+}
+
+impl<'a> FrameSource for Compositor<'a> {
+    fn next(
+        &mut self,
+        get_yuv_frame: bool,
+    ) -> Result<(Vec<u8>, Option<YUVFrame<'_>>), StreamError> {
+        self.compose()?;
+
+        let encoder = match &mut self.encoder {
+            Some(encoder) => encoder,
+            encoder => encoder.insert(
+                self.encoder_options
+                    .frame_rate_or(self.fps as f32)
+                    .build(self.width, self.height)?,
+            ),
+        };
+
+        let mut h264_bytes = Vec::new();
+        encoder.encode(&self.output)?.write_vec(&mut h264_bytes);
+
+        let yuv = get_yuv_frame.then_some(YUVFrame::Borrowed(&self.output));
+
+        Ok((h264_bytes, yuv))
+    }
+
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn fps(&self) -> f64 {
+        self.fps
+    }
+}
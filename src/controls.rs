@@ -0,0 +1,251 @@
+//! Camera controls such as brightness, exposure and white balance.
+
+use std::io;
+use thiserror::Error;
+use v4l::control::{Control, Description, Value};
+pub use v4l::control::{Flags as ControlFlags, MenuItem, Type as ControlType};
+use v4l::v4l_sys;
+use v4l::Device;
+
+#[derive(Error, Debug)]
+pub enum ControlError {
+    #[error("The camera does not have a {0} control")]
+    NotFound(String),
+    #[error("The {0} control is read-only")]
+    ReadOnly(String),
+    #[error("The {0} control is inactive, it may depend on another control (eg. auto exposure must be off to set the exposure)")]
+    Inactive(String),
+    #[error("The {0} control is disabled")]
+    Disabled(String),
+    #[error("{value} is outside of the {name} control's range of {minimum} to {maximum}")]
+    OutOfRange {
+        name: String,
+        value: i64,
+        minimum: i64,
+        maximum: i64,
+    },
+    #[error("The {0} control's type is not supported")]
+    UnsupportedType(String),
+    #[error("Failed to access the camera control")]
+    Io(#[from] io::Error),
+}
+
+/// Identifies a control by its V4L2 ID or its name (case-insensitive, eg. "Brightness").
+#[derive(Debug, Clone, Copy)]
+pub enum ControlKey<'a> {
+    Id(u32),
+    Name(&'a str),
+}
+
+impl From<u32> for ControlKey<'_> {
+    fn from(id: u32) -> Self {
+        Self::Id(id)
+    }
+}
+
+impl<'a> From<&'a str> for ControlKey<'a> {
+    fn from(name: &'a str) -> Self {
+        Self::Name(name)
+    }
+}
+
+/// A camera control and its current value.
+#[derive(Debug)]
+pub struct ControlInfo {
+    pub id: u32,
+    pub name: String,
+    pub typ: ControlType,
+    pub minimum: i64,
+    pub maximum: i64,
+    pub step: u64,
+    pub default: i64,
+    /// The current value of integer, boolean and menu controls. `None` for other types and write-only controls.
+    pub value: Option<i64>,
+    pub flags: ControlFlags,
+    /// The menu items and their values for menu controls.
+    pub menu_items: Vec<(u32, MenuItem)>,
+}
+
+impl ControlInfo {
+    fn from_description(dev: &Device, description: Description) -> Self {
+        let value = match dev.control(description.id).map(|control| control.value) {
+            Ok(Value::Integer(value)) => Some(value),
+            Ok(Value::Boolean(value)) => Some(value as i64),
+            _ => None,
+        };
+
+        Self {
+            id: description.id,
+            name: description.name,
+            typ: description.typ,
+            minimum: description.minimum,
+            maximum: description.maximum,
+            step: description.step,
+            default: description.default,
+            value,
+            flags: description.flags,
+            menu_items: description.items.unwrap_or_default(),
+        }
+    }
+}
+
+/// Lists the camera's controls and their current values.
+pub fn list_controls(dev: &Device) -> Result<Vec<ControlInfo>, ControlError> {
+    let controls = dev
+        .query_controls()?
+        .into_iter()
+        // Control classes are headings rather than controls
+        .filter(|description| description.typ != ControlType::CtrlClass)
+        .map(|description| ControlInfo::from_description(dev, description))
+        .collect();
+
+    Ok(controls)
+}
+
+/// Returns a single control and its current value.
+pub fn get_control<'a>(
+    dev: &Device,
+    key: impl Into<ControlKey<'a>>,
+) -> Result<ControlInfo, ControlError> {
+    let key = key.into();
+
+    let description = dev
+        .query_controls()?
+        .into_iter()
+        .find(|description| match key {
+            ControlKey::Id(id) => description.id == id,
+            ControlKey::Name(name) => description.name.eq_ignore_ascii_case(name),
+        })
+        .ok_or_else(|| {
+            ControlError::NotFound(match key {
+                ControlKey::Id(id) => format!("{:#x}", id),
+                ControlKey::Name(name) => name.to_string(),
+            })
+        })?;
+
+    Ok(ControlInfo::from_description(dev, description))
+}
+
+/// Sets a control's value. Boolean controls are set to true for any non-zero value and button controls are pressed
+/// regardless of the value.
+pub fn set_control<'a>(
+    dev: &Device,
+    key: impl Into<ControlKey<'a>>,
+    value: i64,
+) -> Result<(), ControlError> {
+    let control = get_control(dev, key)?;
+
+    if control.flags.contains(ControlFlags::READ_ONLY) {
+        return Err(ControlError::ReadOnly(control.name));
+    }
+    if control.flags.contains(ControlFlags::DISABLED) {
+        return Err(ControlError::Disabled(control.name));
+    }
+    if control.flags.contains(ControlFlags::INACTIVE) {
+        return Err(ControlError::Inactive(control.name));
+    }
+
+    let value = match control.typ {
+        ControlType::Boolean => Value::Boolean(value != 0),
+        ControlType::Button => Value::None,
+        ControlType::Integer
+        | ControlType::Integer64
+        | ControlType::Menu
+        | ControlType::IntegerMenu => {
+            if !(control.minimum..=control.maximum).contains(&value) {
+                return Err(ControlError::OutOfRange {
+                    name: control.name,
+                    value,
+                    minimum: control.minimum,
+                    maximum: control.maximum,
+                });
+            }
+
+            Value::Integer(value)
+        }
+        _ => return Err(ControlError::UnsupportedType(control.name)),
+    };
+
+    dev.set_control(Control {
+        id: control.id,
+        value,
+    })?;
+
+    Ok(())
+}
+
+/// Turns automatic exposure on or off. Many cameras only support automatic exposure in aperture priority mode, which
+/// is used if the fully automatic mode is not available.
+pub fn set_exposure_auto(dev: &Device, auto: bool) -> Result<(), ControlError> {
+    let control = get_control(dev, v4l_sys::V4L2_CID_EXPOSURE_AUTO)?;
+
+    let mode = if auto {
+        [
+            v4l_sys::v4l2_exposure_auto_type_V4L2_EXPOSURE_AUTO,
+            v4l_sys::v4l2_exposure_auto_type_V4L2_EXPOSURE_APERTURE_PRIORITY,
+            v4l_sys::v4l2_exposure_auto_type_V4L2_EXPOSURE_SHUTTER_PRIORITY,
+        ]
+        .into_iter()
+        .find(|mode| control.menu_items.iter().any(|(index, _)| index == mode))
+        .unwrap_or(v4l_sys::v4l2_exposure_auto_type_V4L2_EXPOSURE_AUTO)
+    } else {
+        v4l_sys::v4l2_exposure_auto_type_V4L2_EXPOSURE_MANUAL
+    };
+
+    set_control(dev, control.id, mode as i64)
+}
+
+/// Sets the exposure time in units of 100µs. Automatic exposure must be turned off first.
+pub fn set_exposure_absolute(dev: &Device, exposure: u32) -> Result<(), ControlError> {
+    set_control(dev, v4l_sys::V4L2_CID_EXPOSURE_ABSOLUTE, exposure as i64)
+}
+
+/// Returns the exposure time control, eg. for its current value and range. See `set_exposure_absolute`.
+pub fn get_exposure_absolute(dev: &Device) -> Result<ControlInfo, ControlError> {
+    get_control(dev, v4l_sys::V4L2_CID_EXPOSURE_ABSOLUTE)
+}
+
+/// Turns automatic white balance on or off.
+pub fn set_white_balance_auto(dev: &Device, auto: bool) -> Result<(), ControlError> {
+    set_control(dev, v4l_sys::V4L2_CID_AUTO_WHITE_BALANCE, auto as i64)
+}
+
+/// Mirrors the image horizontally and/or vertically. Flipping both is the same as rotating the image by 180°.
+pub fn set_flip(dev: &Device, horizontal: bool, vertical: bool) -> Result<(), ControlError> {
+    for (id, flip) in [
+        (v4l_sys::V4L2_CID_HFLIP, horizontal),
+        (v4l_sys::V4L2_CID_VFLIP, vertical),
+    ] {
+        match set_control(dev, id, flip as i64) {
+            // Cameras without a flip control already leave the image unflipped
+            Err(ControlError::NotFound(_)) if !flip => {}
+            result => result?,
+        }
+    }
+
+    Ok(())
+}
+
+/// H264 profiles for cameras that encode H264 in hardware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum H264Profile {
+    Baseline,
+    ConstrainedBaseline,
+    Main,
+    High,
+}
+
+impl H264Profile {
+    pub(crate) fn control_value(self) -> i64 {
+        let value = match self {
+            Self::Baseline => v4l_sys::v4l2_mpeg_video_h264_profile_V4L2_MPEG_VIDEO_H264_PROFILE_BASELINE,
+            Self::ConstrainedBaseline => {
+                v4l_sys::v4l2_mpeg_video_h264_profile_V4L2_MPEG_VIDEO_H264_PROFILE_CONSTRAINED_BASELINE
+            }
+            Self::Main => v4l_sys::v4l2_mpeg_video_h264_profile_V4L2_MPEG_VIDEO_H264_PROFILE_MAIN,
+            Self::High => v4l_sys::v4l2_mpeg_video_h264_profile_V4L2_MPEG_VIDEO_H264_PROFILE_HIGH,
+        };
+
+        value as i64
+    }
+}
@@ -0,0 +1,592 @@
+//! RGB24 and packed YUV 4:2:2 to I420 conversions, with SSE2 / SSSE3 and NEON implementations selected at runtime.
+//!
+//! The conversions work on pairs of rows since each chroma sample covers 2x2 pixels. The SIMD kernels convert as many
+//! 16 (or 32) pixel blocks of a row pair as fit and the scalar kernels convert the rest, so any even width is supported.
+//! The SIMD kernels use the same integer arithmetic as the scalar ones and produce identical output.
+
+/// The planes of one row pair of an I420 image.
+pub(crate) struct RowPair<'a> {
+    pub(crate) y_top: &'a mut [u8],
+    pub(crate) y_bottom: &'a mut [u8],
+    pub(crate) u: &'a mut [u8],
+    pub(crate) v: &'a mut [u8],
+}
+
+impl RowPair<'_> {
+    /// Checks that the rows are the same width, since the SIMD kernels don't check their bounds.
+    fn check(&self, top: &[u8], bottom: &[u8], bytes_per_pixel: usize) {
+        let width = self.y_top.len();
+
+        assert!(width.is_multiple_of(2), "width needs to be multiple of 2");
+        assert!(top.len() >= width * bytes_per_pixel && bottom.len() >= width * bytes_per_pixel);
+        assert!(
+            self.y_bottom.len() >= width && self.u.len() >= width / 2 && self.v.len() >= width / 2
+        );
+    }
+}
+
+/// The byte offsets of the samples in a 4 byte macropixel of a packed YUV 4:2:2 format.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Packed422 {
+    pub(crate) y: [usize; 2],
+    pub(crate) u: usize,
+    pub(crate) v: usize,
+}
+
+/// `Y0 U Y1 V`
+pub(crate) const YUYV: Packed422 = Packed422 {
+    y: [0, 2],
+    u: 1,
+    v: 3,
+};
+
+/// `U Y0 V Y1`
+pub(crate) const UYVY: Packed422 = Packed422 {
+    y: [1, 3],
+    u: 0,
+    v: 2,
+};
+
+/// Converts a pair of packed RGB rows. When `simd` is false only the scalar kernel is used.
+pub(crate) fn rgb_row_pair(top: &[u8], bottom: &[u8], mut out: RowPair, simd: bool) {
+    out.check(top, bottom, 3);
+
+    let converted = match simd {
+        true => rgb_row_pair_simd(top, bottom, &mut out),
+        false => 0,
+    };
+    scalar::rgb_row_pair(top, bottom, out, converted);
+}
+
+/// Converts as much of the row pair as the CPU's SIMD kernel can, returning the number of pixels converted.
+#[allow(unused_variables)]
+fn rgb_row_pair_simd(top: &[u8], bottom: &[u8], out: &mut RowPair) -> usize {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    if is_x86_feature_detected!("ssse3") {
+        // SAFETY: SSSE3 is supported and the rows' lengths have been checked
+        return unsafe { x86::rgb_row_pair(top, bottom, out) };
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    if std::arch::is_aarch64_feature_detected!("neon") {
+        // SAFETY: NEON is supported and the rows' lengths have been checked
+        return unsafe { neon::rgb_row_pair(top, bottom, out) };
+    }
+
+    0
+}
+
+/// Converts a pair of packed YUV 4:2:2 rows, averaging the rows' chroma. When `simd` is false only the scalar kernel
+/// is used.
+pub(crate) fn packed_422_row_pair(
+    top: &[u8],
+    bottom: &[u8],
+    format: Packed422,
+    mut out: RowPair,
+    simd: bool,
+) {
+    out.check(top, bottom, 2);
+
+    let converted = match simd {
+        true => packed_422_row_pair_simd(top, bottom, format, &mut out),
+        false => 0,
+    };
+    scalar::packed_422_row_pair(top, bottom, format, out, converted);
+}
+
+/// Converts as much of the row pair as the CPU's SIMD kernel can, returning the number of pixels converted.
+#[allow(unused_variables)]
+fn packed_422_row_pair_simd(
+    top: &[u8],
+    bottom: &[u8],
+    format: Packed422,
+    out: &mut RowPair,
+) -> usize {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    if is_x86_feature_detected!("sse2") {
+        // SAFETY: SSE2 is supported and the rows' lengths have been checked
+        return unsafe { x86::packed_422_row_pair(top, bottom, format, out) };
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    if std::arch::is_aarch64_feature_detected!("neon") {
+        // SAFETY: NEON is supported and the rows' lengths have been checked
+        return unsafe { neon::packed_422_row_pair(top, bottom, format, out) };
+    }
+
+    0
+}
+
+/// BT.601 limited range luma of a pixel, in 8 bit fixed point.
+fn luma(r: u8, g: u8, b: u8) -> u8 {
+    ((66 * r as u32 + 129 * g as u32 + 25 * b as u32 + (16 << 8)) >> 8) as u8
+}
+
+/// BT.601 limited range chroma of the sums of 4 pixels' colours, in 8 bit fixed point.
+fn chroma(r: i32, g: i32, b: i32) -> (u8, u8) {
+    let u = (-38 * r - 74 * g + 112 * b + (128 << 10)) >> 10;
+    let v = (112 * r - 94 * g - 18 * b + (128 << 10)) >> 10;
+    (u as u8, v as u8)
+}
+
+mod scalar {
+    use super::{chroma, luma, Packed422, RowPair};
+
+    /// Converts the row pair from pixel `start`, which must be even.
+    pub(super) fn rgb_row_pair(top: &[u8], bottom: &[u8], out: RowPair, start: usize) {
+        let width = out.y_top.len();
+
+        for x in (start..width).step_by(2) {
+            let mut sum = (0, 0, 0);
+
+            for (row, y_row) in [(top, &mut *out.y_top), (bottom, &mut *out.y_bottom)] {
+                for x in [x, x + 1] {
+                    let (r, g, b) = (row[x * 3], row[x * 3 + 1], row[x * 3 + 2]);
+                    y_row[x] = luma(r, g, b);
+                    sum = (sum.0 + r as i32, sum.1 + g as i32, sum.2 + b as i32);
+                }
+            }
+
+            (out.u[x / 2], out.v[x / 2]) = chroma(sum.0, sum.1, sum.2);
+        }
+    }
+
+    /// Converts the row pair from pixel `start`, which must be even.
+    pub(super) fn packed_422_row_pair(
+        top: &[u8],
+        bottom: &[u8],
+        format: Packed422,
+        out: RowPair,
+        start: usize,
+    ) {
+        let width = out.y_top.len();
+
+        for x in (start..width).step_by(2) {
+            let (top, bottom) = (&top[x * 2..x * 2 + 4], &bottom[x * 2..x * 2 + 4]);
+            let avg =
+                |offset: usize| (top[offset] as u16 + bottom[offset] as u16).div_ceil(2) as u8;
+
+            out.y_top[x] = top[format.y[0]];
+            out.y_top[x + 1] = top[format.y[1]];
+            out.y_bottom[x] = bottom[format.y[0]];
+            out.y_bottom[x + 1] = bottom[format.y[1]];
+            out.u[x / 2] = avg(format.u);
+            out.v[x / 2] = avg(format.v);
+        }
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod x86 {
+    use super::{Packed422, RowPair};
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::*;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::*;
+
+    /// The `pshufb` masks that gather one colour channel of 16 RGB pixels from each of the 3 registers holding them.
+    /// Negative indices zero the byte.
+    const fn channel_masks(channel: usize) -> [[i8; 16]; 3] {
+        let mut masks = [[-1; 16]; 3];
+        let mut i = 0;
+
+        while i < 16 {
+            let src = i * 3 + channel;
+            masks[src / 16][i] = (src % 16) as i8;
+            i += 1;
+        }
+
+        masks
+    }
+
+    const MASKS: [[[i8; 16]; 3]; 3] = [channel_masks(0), channel_masks(1), channel_masks(2)];
+
+    /// Loads 16 RGB pixels and splits them into a register per channel.
+    #[target_feature(enable = "ssse3")]
+    unsafe fn load_rgb(rgb: &[u8]) -> [__m128i; 3] {
+        let ptr = rgb.as_ptr() as *const __m128i;
+        let regs = [
+            _mm_loadu_si128(ptr),
+            _mm_loadu_si128(ptr.add(1)),
+            _mm_loadu_si128(ptr.add(2)),
+        ];
+
+        let mut channels = [_mm_setzero_si128(); 3];
+        for (channel, masks) in channels.iter_mut().zip(&MASKS) {
+            for (reg, mask) in regs.iter().zip(masks) {
+                let mask = _mm_loadu_si128(mask.as_ptr() as *const __m128i);
+                *channel = _mm_or_si128(*channel, _mm_shuffle_epi8(*reg, mask));
+            }
+        }
+
+        channels
+    }
+
+    /// The luma of 8 pixels whose channels are zero extended to 16 bits, see `super::luma`.
+    #[target_feature(enable = "sse2")]
+    unsafe fn luma_16(r: __m128i, g: __m128i, b: __m128i) -> __m128i {
+        let sum = _mm_add_epi16(
+            _mm_add_epi16(
+                _mm_mullo_epi16(r, _mm_set1_epi16(66)),
+                _mm_mullo_epi16(g, _mm_set1_epi16(129)),
+            ),
+            _mm_add_epi16(
+                _mm_mullo_epi16(b, _mm_set1_epi16(25)),
+                _mm_set1_epi16(16 << 8),
+            ),
+        );
+        // The sum is at most 60196 so it is shifted as unsigned
+        _mm_srli_epi16::<8>(sum)
+    }
+
+    /// The luma of 16 pixels, see `super::luma`.
+    #[target_feature(enable = "ssse3")]
+    unsafe fn luma([r, g, b]: [__m128i; 3]) -> __m128i {
+        let zero = _mm_setzero_si128();
+        let lo = luma_16(
+            _mm_unpacklo_epi8(r, zero),
+            _mm_unpacklo_epi8(g, zero),
+            _mm_unpacklo_epi8(b, zero),
+        );
+        let hi = luma_16(
+            _mm_unpackhi_epi8(r, zero),
+            _mm_unpackhi_epi8(g, zero),
+            _mm_unpackhi_epi8(b, zero),
+        );
+
+        _mm_packus_epi16(lo, hi)
+    }
+
+    /// The chroma of 8 2x2 blocks given the sums of their channels, see `super::chroma`. The products don't fit in 16
+    /// bits so they are summed in 32 bit lanes.
+    #[target_feature(enable = "sse2")]
+    unsafe fn chroma(r: __m128i, g: __m128i, b: __m128i, coefficients: [i16; 3]) -> __m128i {
+        let [r_coefficient, g_coefficient, b_coefficient] = coefficients.map(|c| c as u16 as i32);
+        // Pairs the red and green coefficients to multiply interleaved red and green sums
+        let rg = _mm_set1_epi32((g_coefficient << 16) | r_coefficient);
+        let b_coefficient = _mm_set1_epi32(b_coefficient);
+        let offset = _mm_set1_epi32(128 << 10);
+        let zero = _mm_setzero_si128();
+
+        let lo = _mm_add_epi32(
+            _mm_madd_epi16(_mm_unpacklo_epi16(r, g), rg),
+            _mm_madd_epi16(_mm_unpacklo_epi16(b, zero), b_coefficient),
+        );
+        let hi = _mm_add_epi32(
+            _mm_madd_epi16(_mm_unpackhi_epi16(r, g), rg),
+            _mm_madd_epi16(_mm_unpackhi_epi16(b, zero), b_coefficient),
+        );
+
+        let chroma = _mm_packs_epi32(
+            _mm_srai_epi32::<10>(_mm_add_epi32(lo, offset)),
+            _mm_srai_epi32::<10>(_mm_add_epi32(hi, offset)),
+        );
+        _mm_packus_epi16(chroma, chroma)
+    }
+
+    /// Converts 16 pixel blocks of the row pair, returning the number of pixels converted.
+    #[target_feature(enable = "ssse3")]
+    pub(super) unsafe fn rgb_row_pair(top: &[u8], bottom: &[u8], out: &mut RowPair) -> usize {
+        let blocks = out.y_top.len() / 16;
+        let ones = _mm_set1_epi8(1);
+
+        for block in 0..blocks {
+            let (rgb, x) = (block * 48..block * 48 + 48, block * 16);
+            let top = load_rgb(&top[rgb.clone()]);
+            let bottom = load_rgb(&bottom[rgb]);
+
+            _mm_storeu_si128(out.y_top.as_mut_ptr().add(x) as *mut __m128i, luma(top));
+            _mm_storeu_si128(
+                out.y_bottom.as_mut_ptr().add(x) as *mut __m128i,
+                luma(bottom),
+            );
+
+            // The sums of each 2x2 block's channels
+            let mut sums = [_mm_setzero_si128(); 3];
+            for (sum, (top, bottom)) in sums.iter_mut().zip(top.iter().zip(&bottom)) {
+                *sum = _mm_add_epi16(
+                    _mm_maddubs_epi16(*top, ones),
+                    _mm_maddubs_epi16(*bottom, ones),
+                );
+            }
+            let [r, g, b] = sums;
+
+            let chroma_x = block * 8;
+            _mm_storel_epi64(
+                out.u.as_mut_ptr().add(chroma_x) as *mut __m128i,
+                chroma(r, g, b, [-38, -74, 112]),
+            );
+            _mm_storel_epi64(
+                out.v.as_mut_ptr().add(chroma_x) as *mut __m128i,
+                chroma(r, g, b, [112, -94, -18]),
+            );
+        }
+
+        blocks * 16
+    }
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn load_32(bytes: &[u8]) -> (__m128i, __m128i) {
+        let ptr = bytes.as_ptr() as *const __m128i;
+        (_mm_loadu_si128(ptr), _mm_loadu_si128(ptr.add(1)))
+    }
+
+    /// Splits the bytes of 2 registers into their even and odd bytes.
+    #[target_feature(enable = "sse2")]
+    unsafe fn split(a: __m128i, b: __m128i) -> (__m128i, __m128i) {
+        let low_bytes = _mm_set1_epi16(0xff);
+        (
+            _mm_packus_epi16(_mm_and_si128(a, low_bytes), _mm_and_si128(b, low_bytes)),
+            _mm_packus_epi16(_mm_srli_epi16::<8>(a), _mm_srli_epi16::<8>(b)),
+        )
+    }
+
+    /// Converts 16 pixel blocks of the row pair, returning the number of pixels converted.
+    #[target_feature(enable = "sse2")]
+    pub(super) unsafe fn packed_422_row_pair(
+        top: &[u8],
+        bottom: &[u8],
+        format: Packed422,
+        out: &mut RowPair,
+    ) -> usize {
+        let blocks = out.y_top.len() / 16;
+        let luma_first = format.y[0] == 0;
+        let u_first = format.u < format.v;
+
+        for block in 0..blocks {
+            let (bytes, x) = (block * 32, block * 16);
+            let top = load_32(&top[bytes..bytes + 32]);
+            let bottom = load_32(&bottom[bytes..bytes + 32]);
+
+            for ((a, b), y_row) in [
+                (top, out.y_top.as_mut_ptr()),
+                (bottom, out.y_bottom.as_mut_ptr()),
+            ] {
+                let (even, odd) = split(a, b);
+                let luma = if luma_first { even } else { odd };
+                _mm_storeu_si128(y_row.add(x) as *mut __m128i, luma);
+            }
+
+            // Rounds up like the scalar average
+            let (even, odd) = split(_mm_avg_epu8(top.0, bottom.0), _mm_avg_epu8(top.1, bottom.1));
+            let uv = if luma_first { odd } else { even };
+            let (first, second) = split(uv, uv);
+            let (u, v) = if u_first {
+                (first, second)
+            } else {
+                (second, first)
+            };
+
+            let chroma_x = block * 8;
+            _mm_storel_epi64(out.u.as_mut_ptr().add(chroma_x) as *mut __m128i, u);
+            _mm_storel_epi64(out.v.as_mut_ptr().add(chroma_x) as *mut __m128i, v);
+        }
+
+        blocks * 16
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use super::{Packed422, RowPair};
+    use std::arch::aarch64::*;
+
+    /// The luma of 16 pixels, see `super::luma`.
+    #[target_feature(enable = "neon")]
+    unsafe fn luma(rgb: uint8x16x3_t) -> uint8x16_t {
+        let half = |r: uint8x8_t, g: uint8x8_t, b: uint8x8_t| {
+            let sum = vmull_u8(r, vdup_n_u8(66));
+            let sum = vmlal_u8(sum, g, vdup_n_u8(129));
+            let sum = vmlal_u8(sum, b, vdup_n_u8(25));
+            vshrn_n_u16::<8>(vaddq_u16(sum, vdupq_n_u16(16 << 8)))
+        };
+
+        vcombine_u8(
+            half(vget_low_u8(rgb.0), vget_low_u8(rgb.1), vget_low_u8(rgb.2)),
+            half(
+                vget_high_u8(rgb.0),
+                vget_high_u8(rgb.1),
+                vget_high_u8(rgb.2),
+            ),
+        )
+    }
+
+    /// Converts 16 pixel blocks of the row pair, returning the number of pixels converted.
+    #[target_feature(enable = "neon")]
+    pub(super) unsafe fn rgb_row_pair(top: &[u8], bottom: &[u8], out: &mut RowPair) -> usize {
+        let blocks = out.y_top.len() / 16;
+
+        for block in 0..blocks {
+            let (rgb, x) = (block * 48, block * 16);
+            let top = vld3q_u8(top.as_ptr().add(rgb));
+            let bottom = vld3q_u8(bottom.as_ptr().add(rgb));
+
+            vst1q_u8(out.y_top.as_mut_ptr().add(x), luma(top));
+            vst1q_u8(out.y_bottom.as_mut_ptr().add(x), luma(bottom));
+
+            // The sums of each 2x2 block's channels
+            let sum = |top: uint8x16_t, bottom: uint8x16_t| {
+                vreinterpretq_s16_u16(vaddq_u16(vpaddlq_u8(top), vpaddlq_u8(bottom)))
+            };
+            let (r, g, b) = (
+                sum(top.0, bottom.0),
+                sum(top.1, bottom.1),
+                sum(top.2, bottom.2),
+            );
+
+            // See `super::chroma`, in 32 bit lanes since the products don't fit in 16 bits
+            let chroma = |coefficients: [i16; 3]| {
+                let half = |r: int16x4_t, g: int16x4_t, b: int16x4_t| {
+                    let sum = vmull_n_s16(r, coefficients[0]);
+                    let sum = vmlal_n_s16(sum, g, coefficients[1]);
+                    let sum = vmlal_n_s16(sum, b, coefficients[2]);
+                    vmovn_s32(vshrq_n_s32::<10>(vaddq_s32(sum, vdupq_n_s32(128 << 10))))
+                };
+
+                vqmovun_s16(vcombine_s16(
+                    half(vget_low_s16(r), vget_low_s16(g), vget_low_s16(b)),
+                    half(vget_high_s16(r), vget_high_s16(g), vget_high_s16(b)),
+                ))
+            };
+
+            let chroma_x = block * 8;
+            vst1_u8(out.u.as_mut_ptr().add(chroma_x), chroma([-38, -74, 112]));
+            vst1_u8(out.v.as_mut_ptr().add(chroma_x), chroma([112, -94, -18]));
+        }
+
+        blocks * 16
+    }
+
+    /// Converts 32 pixel blocks of the row pair, returning the number of pixels converted.
+    #[target_feature(enable = "neon")]
+    pub(super) unsafe fn packed_422_row_pair(
+        top: &[u8],
+        bottom: &[u8],
+        format: Packed422,
+        out: &mut RowPair,
+    ) -> usize {
+        let blocks = out.y_top.len() / 32;
+        let luma_first = format.y[0] == 0;
+        let u_first = format.u < format.v;
+
+        for block in 0..blocks {
+            let (bytes, x) = (block * 64, block * 32);
+            // Deinterleaves the 4 bytes of each macropixel
+            let top = vld4q_u8(top.as_ptr().add(bytes));
+            let bottom = vld4q_u8(bottom.as_ptr().add(bytes));
+
+            for (samples, y_row) in [
+                (top, out.y_top.as_mut_ptr()),
+                (bottom, out.y_bottom.as_mut_ptr()),
+            ] {
+                let luma = match luma_first {
+                    true => uint8x16x2_t(samples.0, samples.2),
+                    false => uint8x16x2_t(samples.1, samples.3),
+                };
+                vst2q_u8(y_row.add(x), luma);
+            }
+
+            // Rounds up like the scalar average
+            let (first, second) = match luma_first {
+                true => (vrhaddq_u8(top.1, bottom.1), vrhaddq_u8(top.3, bottom.3)),
+                false => (vrhaddq_u8(top.0, bottom.0), vrhaddq_u8(top.2, bottom.2)),
+            };
+            let (u, v) = if u_first {
+                (first, second)
+            } else {
+                (second, first)
+            };
+
+            let chroma_x = block * 16;
+            vst1q_u8(out.u.as_mut_ptr().add(chroma_x), u);
+            vst1q_u8(out.v.as_mut_ptr().add(chroma_x), v);
+        }
+
+        blocks * 32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Widths that are not a multiple of 16 so the scalar kernels convert the end of each row after the SIMD ones.
+    const WIDTHS: [usize; 5] = [2, 18, 46, 110, 1922];
+
+    /// Fills a buffer using xorshift.
+    fn random_bytes(seed: &mut u64, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                *seed ^= *seed << 13;
+                *seed ^= *seed >> 7;
+                *seed ^= *seed << 17;
+                (*seed >> 56) as u8
+            })
+            .collect()
+    }
+
+    /// The Y (top and bottom), U and V planes of a row pair converted by `convert`.
+    fn convert_planes(width: usize, convert: impl FnOnce(RowPair)) -> [Vec<u8>; 4] {
+        let mut planes = [width, width, width / 2, width / 2].map(|len| vec![0; len]);
+        let [y_top, y_bottom, u, v] = &mut planes;
+        convert(RowPair {
+            y_top,
+            y_bottom,
+            u,
+            v,
+        });
+        planes
+    }
+
+    /// Checks that the SIMD and scalar conversions of a row pair match within 1 per sample.
+    fn check_simd_matches_scalar(format: &str, width: usize, convert: impl Fn(RowPair, bool)) {
+        let simd = convert_planes(width, |out| convert(out, true));
+        let scalar = convert_planes(width, |out| convert(out, false));
+
+        for (plane, (simd, scalar)) in ["Y top", "Y bottom", "U", "V"]
+            .iter()
+            .zip(simd.iter().zip(scalar.iter()))
+        {
+            let max_difference = simd
+                .iter()
+                .zip(scalar)
+                .map(|(a, b)| a.abs_diff(*b))
+                .max()
+                .unwrap_or(0);
+            assert!(
+                max_difference <= 1,
+                "The {format} {plane} plane of a {width} pixel wide row pair differs by {max_difference}"
+            );
+        }
+    }
+
+    #[test]
+    fn simd_rgb_matches_scalar() {
+        let mut seed = 0x2545_f491_4f6c_dd1d;
+        for width in WIDTHS {
+            let (top, bottom) = (
+                random_bytes(&mut seed, width * 3),
+                random_bytes(&mut seed, width * 3),
+            );
+            check_simd_matches_scalar("RGB24", width, |out, simd| {
+                rgb_row_pair(&top, &bottom, out, simd)
+            });
+        }
+    }
+
+    #[test]
+    fn simd_packed_422_matches_scalar() {
+        let mut seed = 0x9e37_79b9_7f4a_7c15;
+        for width in WIDTHS {
+            let (top, bottom) = (
+                random_bytes(&mut seed, width * 2),
+                random_bytes(&mut seed, width * 2),
+            );
+            for (name, format) in [("YUYV", YUYV), ("UYVY", UYVY)] {
+                check_simd_matches_scalar(name, width, |out, simd| {
+                    packed_422_row_pair(&top, &bottom, format, out, simd)
+                });
+            }
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use std::time::Duration;
+use v4l::Fraction;
+
+/// Which frames a stream delivers, see [`StreamBuilder::frame_divisor`](crate::StreamBuilder::frame_divisor) and
+/// [`StreamBuilder::output_fps`](crate::StreamBuilder::output_fps).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Decimation {
+    EveryNth(u32),
+    MaxFps(f64),
+}
+
+/// Decides which captured frames are delivered by a decimated stream.
+///
+/// Transcoded frames are decimated individually. Native H264 frames can only be dropped a whole GOP at a time since the
+/// frames in a GOP depend on the ones before them, so the GOPs are decimated instead (by count, or by their number of
+/// frames for a target frame rate).
+pub(crate) struct Decimator {
+    decimation: Decimation,
+    // Half a frame interval, so that jitter in the capture timestamps doesn't push a due frame to the next one
+    tolerance: Duration,
+    // The number of frames (or GOPs) until the next one is kept
+    countdown: u32,
+    // The capture time from which the next frame (or GOP) is kept
+    next_due: Option<Duration>,
+    // While decimating GOPs, the start time and number of frames of the GOP being delivered
+    kept_gop: Option<(Duration, u32)>,
+    decimated_frames: u64,
+}
+
+impl Decimator {
+    /// `frame_interval` is the stream's negotiated frame interval.
+    pub(crate) fn new(decimation: Decimation, frame_interval: Fraction) -> Self {
+        let mut decimator = Self {
+            decimation,
+            tolerance: Duration::ZERO,
+            countdown: 0,
+            next_due: None,
+            kept_gop: None,
+            decimated_frames: 0,
+        };
+        decimator.restart(frame_interval);
+        decimator
+    }
+
+    /// Starts decimating from the next frame, eg. after the stream was reconfigured.
+    pub(crate) fn restart(&mut self, frame_interval: Fraction) {
+        let frame_duration = Duration::from_secs_f64(
+            frame_interval.numerator as f64 / frame_interval.denominator.max(1) as f64,
+        );
+
+        self.tolerance = frame_duration / 2;
+        self.countdown = 0;
+        self.next_due = None;
+        self.kept_gop = None;
+    }
+
+    /// Returns true if a transcoded frame captured at `timestamp` should be delivered.
+    pub(crate) fn keep_frame(&mut self, timestamp: Duration) -> bool {
+        let keep = match self.decimation {
+            Decimation::EveryNth(n) => self.count_down(n),
+            Decimation::MaxFps(fps) => {
+                let period = Duration::from_secs_f64(1.0 / fps);
+                let keep = self.is_due(timestamp);
+
+                if keep {
+                    // Frames are scheduled relative to the previous one so the frame rate doesn't drift, unless the
+                    // stream has fallen more than a whole period behind (eg. after being paused)
+                    let next_due = self.next_due.unwrap_or(timestamp) + period;
+                    self.next_due = Some(if next_due <= timestamp {
+                        timestamp + period
+                    } else {
+                        next_due
+                    });
+                }
+
+                keep
+            }
+        };
+
+        self.record(keep)
+    }
+
+    /// Returns true if a native H264 frame captured at `timestamp` should be delivered. Frames before the first key
+    /// frame are never delivered.
+    pub(crate) fn keep_gop_frame(&mut self, timestamp: Duration, is_keyframe: bool) -> bool {
+        if is_keyframe {
+            let keep = match self.decimation {
+                Decimation::EveryNth(n) => self.count_down(n),
+                Decimation::MaxFps(fps) => {
+                    // The GOP delivered last used up enough time for its frames at the target frame rate
+                    if let Some((start, frames)) = self.kept_gop {
+                        self.next_due = Some(start + Duration::from_secs_f64(frames as f64 / fps));
+                    }
+
+                    self.is_due(timestamp)
+                }
+            };
+
+            self.kept_gop = keep.then_some((timestamp, 0));
+        }
+
+        if let Some((_, frames)) = &mut self.kept_gop {
+            *frames += 1;
+        }
+
+        self.record(self.kept_gop.is_some())
+    }
+
+    /// The number of frames that were not delivered.
+    pub(crate) fn decimated_frames(&self) -> u64 {
+        self.decimated_frames
+    }
+
+    fn count_down(&mut self, n: u32) -> bool {
+        let keep = self.countdown == 0;
+        self.countdown = match self.countdown {
+            0 => n.max(1) - 1,
+            countdown => countdown - 1,
+        };
+        keep
+    }
+
+    fn is_due(&self, timestamp: Duration) -> bool {
+        self.next_due
+            .is_none_or(|next_due| timestamp + self.tolerance >= next_due)
+    }
+
+    fn record(&mut self, keep: bool) -> bool {
+        if !keep {
+            self.decimated_frames += 1;
+        }
+        keep
+    }
+}
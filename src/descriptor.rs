@@ -0,0 +1,102 @@
+use crate::rtp::CLOCK_RATE;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The dynamic RTP payload type the stream is described with.
+pub(crate) const PAYLOAD_TYPE: u8 = 96;
+
+/// A description of an H264 stream's format, eg. for answering an NVR's capability queries, for the `codecs`
+/// parameter of a player or as an SDP via [`to_sdp`](Self::to_sdp).
+///
+/// Get an up to date descriptor from [`WebcamH264Stream::descriptor`](crate::WebcamH264Stream::descriptor). With the
+/// `serde` feature descriptors can be serialized, eg. with `to_json`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StreamDescriptor {
+    /// The RFC 6381 codec string, eg. `avc1.64001f`.
+    pub codec: String,
+    pub width: u32,
+    pub height: u32,
+    pub fps: f64,
+    /// The estimated bitrate in bits per second, or `None` if it is not known yet.
+    pub bitrate: Option<f64>,
+    /// The base64 encoded SPS and PPS separated by a comma, as in the SDP's `sprop-parameter-sets`.
+    pub sprop_parameter_sets: String,
+    /// The URI the stream is served at, filled in by the server.
+    pub stream_uri: Option<String>,
+    /// The URI a JPEG snapshot of the stream is served at, filled in by the server.
+    pub snapshot_uri: Option<String>,
+}
+
+impl StreamDescriptor {
+    /// Describes a stream from its SPS and PPS (without start codes). Returns None if the SPS could not be parsed.
+    pub fn new(sps: &[u8], pps: &[u8], fps: f64) -> Option<Self> {
+        let info = crate::sps::parse(sps).ok()?;
+
+        Some(Self {
+            codec: info.codec_string(),
+            width: info.width,
+            height: info.height,
+            fps,
+            bitrate: None,
+            sprop_parameter_sets: format!(
+                "{},{}",
+                crate::base64::encode(sps),
+                crate::base64::encode(pps)
+            ),
+            stream_uri: None,
+            snapshot_uri: None,
+        })
+    }
+
+    /// The `profile-level-id` of the SDP's format parameters: the profile, constraint flags and level in hex, as in
+    /// the codec string.
+    pub fn profile_level_id(&self) -> &str {
+        self.codec.strip_prefix("avc1.").unwrap_or(&self.codec)
+    }
+
+    /// An SDP (RFC 4566) describing the stream as a single H264 track (`trackID=0`) sent with dynamic payload type 96,
+    /// with `address` as the origin and connection address.
+    pub fn to_sdp(&self, address: IpAddr) -> String {
+        let address_type = match address {
+            IpAddr::V4(_) => "IP4",
+            IpAddr::V6(_) => "IP6",
+        };
+        // RFC 4566 recommends an NTP timestamp for the session ID
+        let session_id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs() + 2_208_988_800);
+        let bandwidth = self
+            .bitrate
+            .map(|bitrate| format!("b=AS:{}\r\n", (bitrate / 1000.0).ceil() as u64))
+            .unwrap_or_default();
+
+        format!(
+            "v=0\r\n\
+             o=- {session_id} 1 IN {address_type} {address}\r\n\
+             s=h264_webcam_stream\r\n\
+             c=IN {address_type} {address}\r\n\
+             t=0 0\r\n\
+             a=control:*\r\n\
+             a=range:npt=0-\r\n\
+             m=video 0 RTP/AVP {PAYLOAD_TYPE}\r\n\
+             {bandwidth}\
+             a=rtpmap:{PAYLOAD_TYPE} H264/{CLOCK_RATE}\r\n\
+             a=fmtp:{PAYLOAD_TYPE} packetization-mode=1;profile-level-id={};sprop-parameter-sets={}\r\n\
+             a=framerate:{}\r\n\
+             a=x-dimensions:{},{}\r\n\
+             a=control:trackID=0\r\n",
+            self.profile_level_id(),
+            self.sprop_parameter_sets,
+            self.fps,
+            self.width,
+            self.height,
+        )
+    }
+
+    /// Serializes the descriptor as pretty printed JSON. Requires the `serde` feature.
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Stream descriptors are always serializable")
+    }
+}
@@ -0,0 +1,195 @@
+use crate::StreamError;
+pub use openh264::encoder::RateControlMode;
+use openh264::encoder::{Encoder, EncoderConfig};
+use openh264_sys2::{
+    SBitrateInfo, SEncParamExt, ENCODER_OPTION_IDR_INTERVAL, ENCODER_OPTION_MAX_BITRATE,
+    ENCODER_OPTION_SVC_ENCODE_PARAM_EXT, HIGH_COMPLEXITY, LOW_COMPLEXITY, MEDIUM_COMPLEXITY,
+    SPATIAL_LAYER_ALL,
+};
+
+/// How much effort the encoder spends on each frame. Higher complexities improve quality at a given bitrate at the cost
+/// of more CPU time per frame.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EncoderComplexity {
+    /// openh264's default.
+    #[default]
+    Low,
+    Medium,
+    High,
+}
+
+/// Settings for the openh264 encoder used when transcoding MJPEG or uncompressed cameras (or re-encoding YUV frames, eg.
+/// for a timelapse).
+///
+/// The defaults match openh264's own defaults.
+#[derive(Debug, Clone, Copy)]
+pub struct EncoderOptions {
+    bitrate: u32,
+    max_bitrate: Option<u32>,
+    keyframe_interval: Option<u32>,
+    rate_control_mode: RateControlMode,
+    frame_rate: Option<f32>,
+    low_latency: bool,
+    complexity: EncoderComplexity,
+}
+
+impl Default for EncoderOptions {
+    fn default() -> Self {
+        Self {
+            bitrate: 120_000,
+            max_bitrate: None,
+            keyframe_interval: None,
+            rate_control_mode: RateControlMode::Quality,
+            frame_rate: None,
+            low_latency: false,
+            complexity: EncoderComplexity::default(),
+        }
+    }
+}
+
+impl EncoderOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the target bitrate in bits per second.
+    pub fn bitrate(mut self, bps: u32) -> Self {
+        self.bitrate = bps;
+        self
+    }
+
+    /// Caps the bitrate in bits per second.
+    pub fn max_bitrate(mut self, bps: u32) -> Self {
+        self.max_bitrate = Some(bps);
+        self
+    }
+
+    /// Sets the number of frames between key frames (the GOP size).
+    pub fn keyframe_interval(mut self, frames: u32) -> Self {
+        self.keyframe_interval = Some(frames);
+        self
+    }
+
+    /// Sets how the encoder trades off quality and bitrate.
+    pub fn rate_control_mode(mut self, mode: RateControlMode) -> Self {
+        self.rate_control_mode = mode;
+        self
+    }
+
+    /// Sets the frame rate hint used for rate control. Streams default to the camera's frame rate.
+    pub fn frame_rate(mut self, fps: f32) -> Self {
+        self.frame_rate = Some(fps);
+        self
+    }
+
+    /// Pins the encoder to low complexity on a single thread so that each frame is encoded as quickly as possible
+    /// after it is captured, see [`LatencyProfile::Low`](crate::LatencyProfile::Low). These match the defaults of
+    /// current openh264 releases. openh264 does not produce B frames so frames are never reordered.
+    pub fn low_latency(mut self, low_latency: bool) -> Self {
+        self.low_latency = low_latency;
+        self
+    }
+
+    /// Sets the encoder's complexity (defaults to `EncoderComplexity::Low`). `low_latency` always uses low complexity.
+    pub fn complexity(mut self, complexity: EncoderComplexity) -> Self {
+        self.complexity = complexity;
+        self
+    }
+
+    pub(crate) fn frame_rate_or(mut self, fps: f32) -> Self {
+        self.frame_rate.get_or_insert(fps);
+        self
+    }
+
+    /// Creates an encoder for frames of the given dimensions.
+    pub fn build(&self, width: u32, height: u32) -> Result<Encoder, StreamError> {
+        if self.bitrate == 0 && !matches!(self.rate_control_mode, RateControlMode::Off) {
+            return Err(StreamError::InvalidEncoderOptions(
+                "bitrate must be greater than 0",
+            ));
+        }
+
+        if let Some(max_bitrate) = self.max_bitrate {
+            if max_bitrate < self.bitrate {
+                return Err(StreamError::InvalidEncoderOptions(
+                    "max_bitrate must not be less than bitrate",
+                ));
+            }
+        }
+
+        if self
+            .frame_rate
+            .is_some_and(|fps| !fps.is_finite() || fps <= 0.0)
+        {
+            return Err(StreamError::InvalidEncoderOptions(
+                "frame_rate must be greater than 0",
+            ));
+        }
+
+        let config = EncoderConfig::new(width, height)
+            .set_bitrate_bps(self.bitrate)
+            .rate_control_mode(self.rate_control_mode)
+            .max_frame_rate(self.frame_rate.unwrap_or(0.0));
+
+        let mut encoder = Encoder::with_config(config)?;
+
+        // SAFETY: The options are read by openh264 during the call and are not relied upon by the Rust wrapper
+        unsafe {
+            // Changing the parameters reinitializes the encoder so this is done before setting the other options
+            if self.low_latency || self.complexity != EncoderComplexity::Low {
+                let mut params = SEncParamExt::default();
+                let result = encoder.raw_api().get_option(
+                    ENCODER_OPTION_SVC_ENCODE_PARAM_EXT,
+                    &mut params as *mut _ as *mut std::os::raw::c_void,
+                );
+                check_option(result, "Failed to get the encoder parameters")?;
+
+                if self.low_latency {
+                    params.iComplexityMode = LOW_COMPLEXITY;
+                    params.iMultipleThreadIdc = 1;
+                } else {
+                    params.iComplexityMode = match self.complexity {
+                        EncoderComplexity::Low => LOW_COMPLEXITY,
+                        EncoderComplexity::Medium => MEDIUM_COMPLEXITY,
+                        EncoderComplexity::High => HIGH_COMPLEXITY,
+                    };
+                }
+                let result = encoder.raw_api().set_option(
+                    ENCODER_OPTION_SVC_ENCODE_PARAM_EXT,
+                    &mut params as *mut _ as *mut std::os::raw::c_void,
+                );
+                check_option(result, "Failed to set the encoder complexity")?;
+            }
+
+            if let Some(keyframe_interval) = self.keyframe_interval {
+                let mut keyframe_interval = keyframe_interval as i32;
+                let result = encoder.raw_api().set_option(
+                    ENCODER_OPTION_IDR_INTERVAL,
+                    &mut keyframe_interval as *mut _ as *mut std::os::raw::c_void,
+                );
+                check_option(result, "Failed to set the key frame interval")?;
+            }
+
+            if let Some(max_bitrate) = self.max_bitrate {
+                let mut bitrate_info = SBitrateInfo {
+                    iLayer: SPATIAL_LAYER_ALL,
+                    iBitrate: max_bitrate as i32,
+                };
+                let result = encoder.raw_api().set_option(
+                    ENCODER_OPTION_MAX_BITRATE,
+                    &mut bitrate_info as *mut _ as *mut std::os::raw::c_void,
+                );
+                check_option(result, "Failed to set the max bitrate")?;
+            }
+        }
+
+        Ok(encoder)
+    }
+}
+
+fn check_option(result: i32, msg: &str) -> Result<(), StreamError> {
+    match result {
+        0 => Ok(()),
+        _ => Err(openh264::Error::msg(msg).into()),
+    }
+}
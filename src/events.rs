@@ -0,0 +1,126 @@
+use crate::{StreamFormat, ValidationIssue};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// The number of events each subscriber can fall behind by before events are dropped.
+const EVENT_QUEUE_DEPTH: usize = 256;
+
+/// An out-of-band notification about a stream's health, see [`WebcamH264Stream::subscribe_events`] and
+/// [`ReconnectingStream::subscribe_events`].
+///
+/// (Not to be confused with [`StreamEvent`], the frames and reconnections returned by [`ReconnectingStream::next`].)
+///
+/// [`WebcamH264Stream::subscribe_events`]: crate::WebcamH264Stream::subscribe_events
+/// [`ReconnectingStream::subscribe_events`]: crate::ReconnectingStream::subscribe_events
+/// [`StreamEvent`]: crate::StreamEvent
+/// [`ReconnectingStream::next`]: crate::ReconnectingStream::next
+#[derive(Debug, Clone)]
+pub struct HealthEvent {
+    /// When the event happened, as a monotonic instant for measuring intervals between events.
+    pub at: Instant,
+    /// When the event happened, as a wall clock time for logging.
+    pub timestamp: SystemTime,
+    pub kind: HealthEventKind,
+}
+
+/// What a [`HealthEvent`] reports. See [`is_fatal`](Self::is_fatal) for which events mean the stream has stopped.
+#[derive(Debug, Clone)]
+pub enum HealthEventKind {
+    /// Fatal: the camera was disconnected (including I/O errors from an unplugged camera). A [`WebcamH264Stream`]
+    /// has to be reopened, while a [`ReconnectingStream`] starts reconnecting.
+    ///
+    /// [`WebcamH264Stream`]: crate::WebcamH264Stream
+    /// [`ReconnectingStream`]: crate::ReconnectingStream
+    Disconnected { error: String },
+    /// Informational: an attempt to reopen a disconnected camera failed and will be retried.
+    ReconnectAttemptFailed { attempt: u32, error: String },
+    /// Informational: the camera was reopened after being disconnected or stalling.
+    Reconnected { width: u32, height: u32 },
+    /// Fatal: reconnecting gave up after `attempts` attempts (see
+    /// [`ReconnectingStream::max_attempts`](crate::ReconnectingStream::max_attempts)). Reading again starts a new round
+    /// of attempts.
+    ReconnectFailed { attempts: u32, error: String },
+    /// Informational: the stream's format changed, either through
+    /// [`reconfigure`](crate::WebcamH264Stream::reconfigure) or because a native H264 camera started encoding a
+    /// different resolution. The bitstream restarts with new parameter sets.
+    FormatChanged {
+        old: StreamFormat,
+        new: StreamFormat,
+    },
+    /// Informational: a corrupt frame was skipped (see
+    /// [`ErrorPolicy::SkipCorruptFrames`](crate::ErrorPolicy::SkipCorruptFrames)). A burst of these usually means a
+    /// failing cable or camera.
+    CorruptFrameSkipped { sequence: u32, error: String },
+    /// Informational: the driver dropped `count` frames before the frame captured at `at` (its kernel timestamp), which
+    /// was detected by a gap in the frames' sequence numbers. Usually frames were not read quickly enough.
+    FramesDropped { count: u32, at: Duration },
+    /// Informational: a frame failed [bitstream validation](crate::StreamBuilder::validate_bitstream). It was still
+    /// returned.
+    InvalidFrame {
+        sequence: u32,
+        issues: Vec<ValidationIssue>,
+    },
+    /// Recoverable: the camera did not deliver a frame within the stream's
+    /// [`stall_deadline`](crate::StreamBuilder::stall_deadline). The stream can be read from again, and a
+    /// `ReconnectingStream` reopens the camera. `since` is when the last frame was received.
+    Stalled { since: Instant },
+    /// Informational: a key frame was forced, either on request or to resync the decoder after a decode failure.
+    KeyframeForced,
+}
+
+impl HealthEventKind {
+    /// True for events after which the stream (or a `ReconnectingStream`'s current round of reconnection attempts) has
+    /// stopped, ie. a supervisor should restart it. `Stalled` streams can still recover so they are not fatal.
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            Self::Disconnected { .. } | Self::ReconnectFailed { .. }
+        )
+    }
+}
+
+/// Delivers health events to a stream's subscribers without ever blocking. Clones share the same subscribers so that a
+/// `ReconnectingStream` and each stream it opens emit to the same receivers.
+#[derive(Clone, Default)]
+pub(crate) struct EventBus {
+    subscribers: Arc<Mutex<Vec<SyncSender<HealthEvent>>>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl EventBus {
+    pub(crate) fn subscribe(&self) -> Receiver<HealthEvent> {
+        let (sender, receiver) = sync_channel(EVENT_QUEUE_DEPTH);
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Sends an event to each subscriber, dropping it for subscribers whose queue is full.
+    pub(crate) fn emit(&self, kind: HealthEventKind) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if subscribers.is_empty() {
+            return;
+        }
+
+        let event = HealthEvent {
+            at: Instant::now(),
+            timestamp: SystemTime::now(),
+            kind,
+        };
+
+        subscribers.retain(|subscriber| match subscriber.try_send(event.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            // The receiver was dropped
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+
+    pub(crate) fn dropped_events(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
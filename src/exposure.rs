@@ -0,0 +1,254 @@
+use crate::controls::{self, ControlError};
+use crate::{WebcamH264Stream, YUVFrame};
+use std::time::{Duration, Instant};
+use tracing::warn;
+use v4l::v4l_sys;
+use v4l::Device;
+
+/// The automatic controls that are locked, the manual control holding each one's value, and the automatic control's
+/// manual setting. Manual controls are inactive until their automatic control is off, so they are always set after it.
+const LOCKABLE: [(u32, u32, i64); 3] = [
+    (
+        v4l_sys::V4L2_CID_EXPOSURE_AUTO,
+        v4l_sys::V4L2_CID_EXPOSURE_ABSOLUTE,
+        v4l_sys::v4l2_exposure_auto_type_V4L2_EXPOSURE_MANUAL as i64,
+    ),
+    (v4l_sys::V4L2_CID_AUTOGAIN, v4l_sys::V4L2_CID_GAIN, 0),
+    (
+        v4l_sys::V4L2_CID_AUTO_WHITE_BALANCE,
+        v4l_sys::V4L2_CID_WHITE_BALANCE_TEMPERATURE,
+        0,
+    ),
+];
+
+/// A manual control and the value a [`TimelapseSession`] locked it to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockedControl {
+    pub name: String,
+    pub value: i64,
+}
+
+/// Returned by [`TimelapseSession::observe`] when the scene's brightness moved away from the brightness it was locked
+/// at by more than the drift threshold, eg. because the sun set. Call [`TimelapseSession::relock`] to re-measure.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExposureDrift {
+    /// The mean luma of the first frame observed after locking.
+    pub reference_luma: f32,
+    pub luma: f32,
+}
+
+/// An automatic control and its manual control, with the values they had before the session started.
+struct ControlLock {
+    auto_id: u32,
+    manual_setting: i64,
+    original_auto: i64,
+    value_id: u32,
+    original_value: i64,
+    locked: LockedControl,
+}
+
+/// Locks a camera's auto exposure, gain and white balance at their current values for the length of a timelapse, so
+/// that frames don't flicker as the camera's automatic controls hunt between them.
+///
+/// Starting a session measures the values the automatic controls have settled on and switches them to manual with
+/// those values (some cameras reset the exposure when auto exposure is turned off, so the value is always written
+/// back). The original control state is restored by [`finish`](Self::finish), or when the session is dropped (eg.
+/// during a panic). Controls the camera doesn't have are skipped.
+///
+/// The session uses its own handle to the camera, so frames can be read from the stream while it is active.
+pub struct TimelapseSession {
+    dev: Device,
+    locks: Vec<ControlLock>,
+    drift_threshold: f32,
+    settle_time: Duration,
+    relocking_at: Option<Instant>,
+    reference_luma: Option<f32>,
+    drifted: bool,
+    restored: bool,
+}
+
+impl TimelapseSession {
+    /// Locks the controls of the camera opened as `dev`, eg. `Device::with_path("/dev/video0")?`. Cameras can be opened
+    /// more than once so this can be a second handle to a streaming camera, see [`for_stream`](Self::for_stream).
+    pub fn start(dev: Device) -> Result<Self, ControlError> {
+        let mut session = Self {
+            dev,
+            locks: Vec::new(),
+            drift_threshold: 24.0,
+            settle_time: Duration::from_secs(3),
+            relocking_at: None,
+            reference_luma: None,
+            drifted: false,
+            restored: false,
+        };
+
+        for (auto_id, value_id, manual_setting) in LOCKABLE {
+            // Without the manual control turning the automatic one off would lose its value
+            let (auto, value) = match (
+                controls::get_control(&session.dev, auto_id),
+                controls::get_control(&session.dev, value_id),
+            ) {
+                (Ok(auto), Ok(value)) => (auto, value),
+                (Err(ControlError::NotFound(_)), _) | (_, Err(ControlError::NotFound(_))) => {
+                    continue
+                }
+                (Err(err), _) | (_, Err(err)) => return Err(err),
+            };
+
+            let (Some(original_auto), Some(original_value)) = (auto.value, value.value) else {
+                continue;
+            };
+
+            session.locks.push(ControlLock {
+                auto_id,
+                manual_setting,
+                original_auto,
+                value_id,
+                original_value,
+                locked: LockedControl {
+                    name: value.name,
+                    value: original_value,
+                },
+            });
+        }
+
+        // Anything locked so far is restored by dropping the session if locking fails
+        for index in 0..session.locks.len() {
+            session.lock(index)?;
+        }
+
+        Ok(session)
+    }
+
+    /// Locks the controls of the camera a stream is reading from, through a second handle to the same device.
+    pub fn for_stream(stream: &WebcamH264Stream) -> Result<Self, ControlError> {
+        let fd = stream.device().handle().fd();
+        Self::start(Device::with_path(format!("/proc/self/fd/{}", fd))?)
+    }
+
+    /// Sets how far the mean luma (0 to 255) can drift from the luma it was locked at before
+    /// [`observe`](Self::observe) reports it (defaults to 24).
+    pub fn drift_threshold(mut self, drift_threshold: f32) -> Self {
+        self.drift_threshold = drift_threshold;
+        self
+    }
+
+    /// Sets how long the automatic controls are given to settle by [`relock`](Self::relock) (defaults to 3 seconds).
+    pub fn settle_time(mut self, settle_time: Duration) -> Self {
+        self.settle_time = settle_time;
+        self
+    }
+
+    /// The manual controls and the values they are locked to.
+    pub fn locked_controls(&self) -> impl Iterator<Item = &LockedControl> {
+        self.locks.iter().map(|lock| &lock.locked)
+    }
+
+    /// True unless the session is waiting for the automatic controls to settle after [`relock`](Self::relock). Frames
+    /// captured while relocking are auto exposed and may flicker.
+    pub fn is_locked(&self) -> bool {
+        self.relocking_at.is_none()
+    }
+
+    /// Checks a frame's brightness, returning an [`ExposureDrift`] when it first drifts further than the drift threshold
+    /// from the brightness of the first frame observed after locking. It is reported again if the brightness returns
+    /// within the threshold and then drifts again.
+    ///
+    /// While relocking this locks the controls once the settle time has passed.
+    pub fn observe(&mut self, yuv: &YUVFrame) -> Result<Option<ExposureDrift>, ControlError> {
+        if let Some(relocking_at) = self.relocking_at {
+            if relocking_at.elapsed() < self.settle_time {
+                return Ok(None);
+            }
+
+            for index in 0..self.locks.len() {
+                self.lock(index)?;
+            }
+            self.relocking_at = None;
+        }
+
+        let luma = yuv.mean_luma();
+        let reference_luma = *self.reference_luma.get_or_insert(luma);
+
+        let drifted = (luma - reference_luma).abs() > self.drift_threshold;
+        let newly_drifted = drifted && !self.drifted;
+        self.drifted = drifted;
+
+        Ok(newly_drifted.then_some(ExposureDrift {
+            reference_luma,
+            luma,
+        }))
+    }
+
+    /// Turns the automatic controls back on so that they can adjust to a legitimate change in lighting (eg. from day to
+    /// night), and locks them again at the first [`observe`](Self::observe) after the settle time.
+    ///
+    /// Controls that were already manual before the session started keep their values.
+    pub fn relock(&mut self) -> Result<(), ControlError> {
+        for lock in &self.locks {
+            if lock.original_auto != lock.manual_setting {
+                controls::set_control(&self.dev, lock.auto_id, lock.original_auto)?;
+            }
+        }
+
+        self.relocking_at = Some(Instant::now());
+        self.reference_luma = None;
+        self.drifted = false;
+
+        Ok(())
+    }
+
+    /// Restores the controls to their state before the session started, returning the first error if any control could
+    /// not be restored. The other controls are still restored.
+    pub fn finish(mut self) -> Result<(), ControlError> {
+        self.restore()
+    }
+
+    /// Measures a control's automatic value and locks it there.
+    fn lock(&mut self, index: usize) -> Result<(), ControlError> {
+        let lock = &mut self.locks[index];
+
+        // The value is read before turning the automatic control off since some cameras reset it to its default
+        let value = controls::get_control(&self.dev, lock.value_id)?
+            .value
+            .unwrap_or(lock.locked.value);
+
+        controls::set_control(&self.dev, lock.auto_id, lock.manual_setting)?;
+        controls::set_control(&self.dev, lock.value_id, value)?;
+        lock.locked.value = value;
+
+        Ok(())
+    }
+
+    fn restore(&mut self) -> Result<(), ControlError> {
+        self.restored = true;
+        let mut result = Ok(());
+
+        for lock in self.locks.iter().rev() {
+            // The manual value can only be written while the automatic control is off, which it still is
+            let restored = controls::set_control(&self.dev, lock.auto_id, lock.manual_setting)
+                .and_then(|()| controls::set_control(&self.dev, lock.value_id, lock.original_value))
+                .and_then(|()| controls::set_control(&self.dev, lock.auto_id, lock.original_auto));
+
+            if let Err(err) = restored {
+                warn!(
+                    "Failed to restore the {} control: {}",
+                    lock.locked.name, err
+                );
+                if result.is_ok() {
+                    result = Err(err);
+                }
+            }
+        }
+
+        result
+    }
+}
+
+impl Drop for TimelapseSession {
+    fn drop(&mut self) {
+        if !self.restored {
+            let _ = self.restore();
+        }
+    }
+}
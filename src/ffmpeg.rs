@@ -0,0 +1,293 @@
+use crate::nal;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStderr, ChildStdin, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// The number of lines of ffmpeg's output kept for errors.
+const STDERR_LINES: usize = 50;
+
+#[derive(Error, Debug)]
+pub enum FfmpegError {
+    #[error("ffmpeg was not found, is it installed and on the PATH?")]
+    NotInstalled,
+    #[error("Failed to start ffmpeg")]
+    SpawnFailed(#[source] io::Error),
+    /// ffmpeg exited before it was finished, or with an error. `stderr` is the end of its output.
+    #[error("ffmpeg exited with {status}: {stderr}")]
+    Exited { status: ExitStatus, stderr: String },
+    #[error(
+        "ffmpeg did not exit within {timeout:?} of its input being closed and was killed: {stderr}"
+    )]
+    Timeout { timeout: Duration, stderr: String },
+}
+
+/// The ffmpeg command an [`FfmpegSink`] runs.
+#[derive(Debug, Clone)]
+pub struct FfmpegSinkConfig {
+    /// A file path or URL, eg. `out.mkv` or `rtmp://a.rtmp.youtube.com/live2/<key>`.
+    pub output_url: String,
+    /// The output format passed to `-f`, eg. `matroska`, `flv` or `mpegts`. If `None` ffmpeg guesses it from the
+    /// output's extension.
+    pub container: Option<String>,
+    /// Output options added before the output, eg. `["-movflags", "+faststart"]`.
+    pub extra_args: Vec<String>,
+    /// The stream's frame rate. Raw H264 has no timestamps so ffmpeg times the frames at this rate.
+    pub fps: f64,
+    /// Scales the video to this size, which re-encodes it with ffmpeg's default H264 encoder. By default the H264 is
+    /// copied to the output unchanged.
+    pub resolution: Option<(u32, u32)>,
+    /// The ffmpeg executable (defaults to `ffmpeg` on the PATH).
+    pub program: PathBuf,
+    /// The number of access units queued for ffmpeg before frames are dropped (defaults to 60).
+    pub queue_depth: usize,
+    /// How long [`FfmpegSink::finish`] waits for ffmpeg to exit (defaults to 10 seconds).
+    pub finish_timeout: Duration,
+}
+
+impl FfmpegSinkConfig {
+    pub fn new(output_url: impl Into<String>, fps: f64) -> Self {
+        Self {
+            output_url: output_url.into(),
+            container: None,
+            extra_args: Vec::new(),
+            fps,
+            resolution: None,
+            program: PathBuf::from("ffmpeg"),
+            queue_depth: 60,
+            finish_timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// The arguments ffmpeg is run with.
+    pub fn args(&self) -> Vec<String> {
+        let mut args: Vec<String> = ["-hide_banner", "-loglevel", "warning", "-y"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+
+        args.extend(["-f", "h264", "-r"].map(String::from));
+        args.push(self.fps.to_string());
+        args.extend(["-i", "-"].map(String::from));
+
+        match self.resolution {
+            Some((width, height)) => {
+                args.push("-vf".to_string());
+                args.push(format!("scale={}:{}", width, height));
+                args.extend(["-c:v", "h264"].map(String::from));
+            }
+            None => args.extend(["-c:v", "copy"].map(String::from)),
+        }
+
+        args.extend(self.extra_args.iter().cloned());
+
+        if let Some(container) = &self.container {
+            args.push("-f".to_string());
+            args.push(container.clone());
+        }
+        args.push(self.output_url.clone());
+
+        args
+    }
+}
+
+/// Pipes a stream's Annex-B access units into an ffmpeg process, eg. to mux formats the crate doesn't support or to
+/// push to an RTMP server.
+///
+/// Access units are written to ffmpeg's stdin from a separate thread so that a slow output doesn't hold up capturing.
+/// If ffmpeg falls behind by more than `queue_depth` frames the frame is dropped, along with the rest of its GOP, and
+/// writing resumes at the next key frame so that ffmpeg only ever receives whole GOPs.
+pub struct FfmpegSink {
+    child: Child,
+    sender: Option<SyncSender<Vec<u8>>>,
+    writer: Option<JoinHandle<()>>,
+    stderr_reader: Option<JoinHandle<()>>,
+    stderr: Arc<Mutex<VecDeque<String>>>,
+    finish_timeout: Duration,
+    awaiting_keyframe: bool,
+    dropped_frames: u64,
+}
+
+impl FfmpegSink {
+    /// Starts ffmpeg. Returns `FfmpegError::NotInstalled` if the program can't be found.
+    pub fn spawn(config: FfmpegSinkConfig) -> Result<Self, FfmpegError> {
+        let mut child = Command::new(&config.program)
+            .args(config.args())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| match err.kind() {
+                io::ErrorKind::NotFound => FfmpegError::NotInstalled,
+                _ => FfmpegError::SpawnFailed(err),
+            })?;
+
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stderr_pipe = child.stderr.take().expect("stderr is piped");
+
+        let stderr = Arc::new(Mutex::new(VecDeque::new()));
+        let stderr_reader = {
+            let stderr = stderr.clone();
+            std::thread::spawn(move || read_stderr(stderr_pipe, &stderr))
+        };
+
+        let (sender, receiver) = sync_channel::<Vec<u8>>(config.queue_depth.max(1));
+        let writer = std::thread::spawn(move || {
+            let mut stdin: ChildStdin = stdin;
+
+            // Stops once the sink is finished or ffmpeg closes its input (eg. because it exited)
+            for h264_bytes in receiver {
+                if stdin.write_all(&h264_bytes).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Self {
+            child,
+            sender: Some(sender),
+            writer: Some(writer),
+            stderr_reader: Some(stderr_reader),
+            stderr,
+            finish_timeout: config.finish_timeout,
+            awaiting_keyframe: false,
+            dropped_frames: 0,
+        })
+    }
+
+    /// Queues an access unit for ffmpeg. Returns `FfmpegError::Exited` if ffmpeg has exited, eg. because the output
+    /// failed.
+    pub fn write(&mut self, h264_bytes: &[u8]) -> Result<(), FfmpegError> {
+        if self.awaiting_keyframe && !nal::is_keyframe(h264_bytes) {
+            self.dropped_frames += 1;
+            return Ok(());
+        }
+
+        let sender = self
+            .sender
+            .as_ref()
+            .expect("sender is only taken when finishing");
+
+        match sender.try_send(h264_bytes.to_vec()) {
+            Ok(()) => self.awaiting_keyframe = false,
+            Err(TrySendError::Full(_)) => {
+                self.awaiting_keyframe = true;
+                self.dropped_frames += 1;
+            }
+            Err(TrySendError::Disconnected(_)) => return Err(self.exited()),
+        }
+
+        Ok(())
+    }
+
+    /// The number of access units dropped because ffmpeg fell behind.
+    pub fn dropped_frames(&self) -> u64 {
+        self.dropped_frames
+    }
+
+    /// The last lines ffmpeg has printed, eg. warnings about the output.
+    pub fn stderr_tail(&self) -> String {
+        stderr_tail(&self.stderr)
+    }
+
+    /// Closes ffmpeg's input once the queued access units have been written, and waits for it to finish writing the
+    /// output. ffmpeg is killed if it doesn't exit within the `finish_timeout`.
+    pub fn finish(mut self) -> Result<(), FfmpegError> {
+        // Dropping the sender lets the writer thread drain the queue and then close ffmpeg's stdin
+        self.sender = None;
+        let deadline = Instant::now() + self.finish_timeout;
+
+        let status = loop {
+            match self.child.try_wait() {
+                Ok(Some(status)) => break status,
+                Ok(None) if Instant::now() < deadline => {
+                    std::thread::sleep(Duration::from_millis(20))
+                }
+                // The writer thread is unblocked by ffmpeg's stdin closing when it is killed
+                _ => {
+                    let _ = self.child.kill();
+                    let _ = self.child.wait();
+                    self.join_threads();
+
+                    return Err(FfmpegError::Timeout {
+                        timeout: self.finish_timeout,
+                        stderr: self.stderr_tail(),
+                    });
+                }
+            }
+        };
+
+        self.join_threads();
+
+        if !status.success() {
+            return Err(FfmpegError::Exited {
+                status,
+                stderr: self.stderr_tail(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// The error for ffmpeg exiting while it was being written to.
+    fn exited(&mut self) -> FfmpegError {
+        // ffmpeg closing its stdin without exiting is unexpected, so it is stopped
+        let status = match self.child.try_wait() {
+            Ok(Some(status)) => Ok(status),
+            _ => {
+                let _ = self.child.kill();
+                self.child.wait()
+            }
+        };
+        self.join_threads();
+
+        match status {
+            Ok(status) => FfmpegError::Exited {
+                status,
+                stderr: self.stderr_tail(),
+            },
+            Err(err) => FfmpegError::SpawnFailed(err),
+        }
+    }
+
+    fn join_threads(&mut self) {
+        for thread in [self.writer.take(), self.stderr_reader.take()]
+            .into_iter()
+            .flatten()
+        {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for FfmpegSink {
+    fn drop(&mut self) {
+        // The sink was not finished, so the output is abandoned rather than waiting for ffmpeg
+        if self.writer.is_some() {
+            self.sender = None;
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+            self.join_threads();
+        }
+    }
+}
+
+fn read_stderr(pipe: ChildStderr, stderr: &Mutex<VecDeque<String>>) {
+    for line in BufReader::new(pipe).lines().map_while(Result::ok) {
+        let mut stderr = stderr.lock().unwrap();
+        if stderr.len() == STDERR_LINES {
+            stderr.pop_front();
+        }
+        stderr.push_back(line);
+    }
+}
+
+fn stderr_tail(stderr: &Mutex<VecDeque<String>>) -> String {
+    let stderr = stderr.lock().unwrap();
+    stderr.iter().cloned().collect::<Vec<_>>().join("\n")
+}
@@ -0,0 +1,104 @@
+use crate::{Frame, StreamError, WebcamH264Stream};
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// An iterator over a stream's frames, returned by [`WebcamH264Stream::frames`].
+///
+/// The iterator ends after the first error, eg. if the camera is unplugged.
+pub struct Frames<'s, 'a> {
+    stream: &'s mut WebcamH264Stream<'a>,
+    get_yuv_frames: bool,
+    failed: bool,
+}
+
+impl<'s, 'a> Iterator for Frames<'s, 'a> {
+    type Item = Result<Frame, StreamError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        let result = self.stream.next_frame(self.get_yuv_frames);
+        self.failed = result.is_err();
+
+        Some(result)
+    }
+}
+
+/// Statistics for a recording made with [`WebcamH264Stream::record_for`] or [`WebcamH264Stream::record_frames`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RecordingStats {
+    pub frames: u64,
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl RecordingStats {
+    /// The average number of frames recorded per second.
+    pub fn average_fps(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            secs if secs > 0.0 => self.frames as f64 / secs,
+            _ => 0.0,
+        }
+    }
+}
+
+impl<'a> WebcamH264Stream<'a> {
+    /// Returns an iterator over the stream's frames. If `get_yuv_frames` is true each frame is requested with a YUV
+    /// frame, as with `next(true)`.
+    pub fn frames(&mut self, get_yuv_frames: bool) -> Frames<'_, 'a> {
+        Frames {
+            stream: self,
+            get_yuv_frames,
+            failed: false,
+        }
+    }
+
+    /// Writes the H264 bitstream to `writer` for `duration`.
+    pub fn record_for<W: Write>(
+        &mut self,
+        duration: Duration,
+        writer: &mut W,
+    ) -> Result<RecordingStats, StreamError> {
+        self.record(writer, |stats| stats.elapsed < duration)
+    }
+
+    /// Writes `frames` frames of the H264 bitstream to `writer`.
+    pub fn record_frames<W: Write>(
+        &mut self,
+        frames: u64,
+        writer: &mut W,
+    ) -> Result<RecordingStats, StreamError> {
+        self.record(writer, |stats| stats.frames < frames)
+    }
+
+    fn record<W: Write>(
+        &mut self,
+        writer: &mut W,
+        mut keep_recording: impl FnMut(&RecordingStats) -> bool,
+    ) -> Result<RecordingStats, StreamError> {
+        let start = Instant::now();
+        let mut h264_bytes = Vec::new();
+        let mut stats = RecordingStats {
+            frames: 0,
+            bytes: 0,
+            elapsed: Duration::ZERO,
+        };
+
+        while keep_recording(&stats) {
+            self.next_into(&mut h264_bytes, None)?;
+            writer
+                .write_all(&h264_bytes)
+                .map_err(StreamError::WriteFailure)?;
+
+            stats.frames += 1;
+            stats.bytes += h264_bytes.len() as u64;
+            stats.elapsed = start.elapsed();
+        }
+
+        writer.flush().map_err(StreamError::WriteFailure)?;
+
+        Ok(stats)
+    }
+}
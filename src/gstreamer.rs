@@ -0,0 +1,169 @@
+//! Feeding a stream into a GStreamer `appsrc` element, enabled with the `gstreamer` feature.
+
+use crate::appsrc::AppSrcFeeder;
+use crate::FrameMeta;
+use ::gstreamer as gst;
+use ::gstreamer_app as gst_app;
+use gst::glib;
+use std::str::FromStr;
+
+/// Pushes a stream's frames into a GStreamer `appsrc`, eg. one created by `gst_rtsp_server` or in a pipeline that
+/// displays the stream.
+///
+/// The adapter sets the appsrc's caps from the [`AppSrcFeeder`] (start with `appsrc ! h264parse` or a decoder that
+/// accepts byte-stream H264), puts it in live time format and handles its `need-data` / `enough-data` signals, so
+/// whole GOPs are dropped while the pipeline is behind. This replaces any callbacks already set on the appsrc.
+pub struct GstAppSrcAdapter {
+    appsrc: gst_app::AppSrc,
+    feeder: AppSrcFeeder,
+}
+
+impl GstAppSrcAdapter {
+    /// Configures `appsrc` for the stream described by `feeder`, eg.
+    /// `AppSrcFeeder::new(stream.width, stream.height, stream.fps())`. Fails if GStreamer can't parse the feeder's
+    /// caps.
+    pub fn new(appsrc: &gst_app::AppSrc, feeder: AppSrcFeeder) -> Result<Self, glib::BoolError> {
+        let caps = gst::Caps::from_str(&feeder.caps())?;
+        appsrc.set_caps(Some(&caps));
+        appsrc.set_format(gst::Format::Time);
+        appsrc.set_is_live(true);
+
+        let need_data = feeder.flow();
+        let enough_data = feeder.flow();
+        appsrc.set_callbacks(
+            gst_app::AppSrcCallbacks::builder()
+                .need_data(move |_, _| need_data.need_data())
+                .enough_data(move |_| enough_data.enough_data())
+                .build(),
+        );
+
+        Ok(Self {
+            appsrc: appsrc.clone(),
+            feeder,
+        })
+    }
+
+    /// Pushes an access unit into the appsrc, timing it using the frame's capture timestamp. Returns false if the
+    /// frame was dropped.
+    pub fn push(&mut self, meta: &FrameMeta, h264_bytes: Vec<u8>) -> Result<bool, gst::FlowError> {
+        let Some(frame) = self.feeder.push(meta, h264_bytes) else {
+            return Ok(false);
+        };
+
+        let mut buffer = gst::Buffer::from_mut_slice(frame.data);
+        {
+            let buffer = buffer
+                .get_mut()
+                .expect("New buffers are only referenced once");
+            buffer.set_pts(gst::ClockTime::from_nseconds(frame.pts));
+            buffer.set_duration(gst::ClockTime::from_nseconds(frame.duration));
+            if frame.delta_unit {
+                buffer.set_flags(gst::BufferFlags::DELTA_UNIT);
+            }
+        }
+
+        self.appsrc.push_buffer(buffer)?;
+        Ok(true)
+    }
+
+    /// True if a key frame should be requested (eg. with
+    /// [`WebcamH264Stream::request_keyframe`](crate::WebcamH264Stream::request_keyframe)) for the appsrc to resume
+    /// sooner, see [`AppSrcFeeder::wants_keyframe`].
+    pub fn wants_keyframe(&self) -> bool {
+        self.feeder.wants_keyframe()
+    }
+
+    /// The number of frames dropped since the adapter was created, including those before the first key frame.
+    pub fn dropped_frames(&self) -> u64 {
+        self.feeder.dropped_frames()
+    }
+
+    /// Signals the end of the stream to the pipeline.
+    pub fn end_of_stream(&self) -> Result<(), gst::FlowError> {
+        self.appsrc.end_of_stream()?;
+        Ok(())
+    }
+
+    /// The appsrc the frames are pushed into.
+    pub fn appsrc(&self) -> &gst_app::AppSrc {
+        &self.appsrc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ClockMapping;
+    use gst::prelude::*;
+    use std::time::Duration;
+
+    fn meta(frame: u32, is_keyframe: bool) -> FrameMeta {
+        FrameMeta {
+            timestamp: Duration::from_secs(10) + Duration::from_secs(frame as u64) / 30,
+            clock: ClockMapping::default(),
+            sequence: frame,
+            dropped_since_last: 0,
+            bytesused: 0,
+            is_keyframe,
+        }
+    }
+
+    #[test]
+    fn pushes_timed_buffers() {
+        gst::init().unwrap();
+        let appsrc = gst_app::AppSrc::builder().build();
+        let appsink = gst_app::AppSink::builder().sync(false).build();
+        let pipeline = gst::Pipeline::new();
+        pipeline
+            .add_many([appsrc.upcast_ref::<gst::Element>(), appsink.upcast_ref()])
+            .unwrap();
+        appsrc.link(&appsink).unwrap();
+
+        let mut adapter =
+            GstAppSrcAdapter::new(&appsrc, AppSrcFeeder::new(640, 480, 30.0)).unwrap();
+        pipeline.set_state(gst::State::Playing).unwrap();
+
+        // Frames are dropped until the first key frame
+        assert!(!adapter
+            .push(&meta(0, false), vec![0, 0, 0, 1, 0x41])
+            .unwrap());
+        assert!(adapter
+            .push(&meta(1, true), vec![0, 0, 0, 1, 0x65])
+            .unwrap());
+        assert!(adapter
+            .push(&meta(2, false), vec![0, 0, 0, 1, 0x41])
+            .unwrap());
+        adapter.end_of_stream().unwrap();
+
+        let samples: Vec<_> = (0..2)
+            .map(|_| {
+                appsink
+                    .try_pull_sample(gst::ClockTime::from_seconds(5))
+                    .unwrap()
+            })
+            .collect();
+        pipeline.set_state(gst::State::Null).unwrap();
+
+        let caps = samples[0].caps().unwrap().structure(0).unwrap();
+        assert_eq!(caps.name(), "video/x-h264");
+        assert_eq!(caps.get::<i32>("width").unwrap(), 640);
+        assert_eq!(caps.get::<&str>("stream-format").unwrap(), "byte-stream");
+
+        let buffers: Vec<_> = samples
+            .iter()
+            .map(|sample| sample.buffer().unwrap())
+            .collect();
+        assert_eq!(buffers[0].pts(), Some(gst::ClockTime::ZERO));
+        assert_eq!(
+            buffers[1].pts(),
+            Some(gst::ClockTime::from_nseconds(33_333_333))
+        );
+        assert_eq!(
+            buffers[1].duration(),
+            Some(gst::ClockTime::from_nseconds(33_333_333))
+        );
+        assert!(!buffers[0].flags().contains(gst::BufferFlags::DELTA_UNIT));
+        assert!(buffers[1].flags().contains(gst::BufferFlags::DELTA_UNIT));
+        assert_eq!(adapter.dropped_frames(), 1);
+    }
+}
@@ -0,0 +1,361 @@
+//! HTTP Live Streaming: a rolling playlist and its segments written to a directory, so that any static file server
+//! (eg. nginx) can serve the camera to Safari or hls.js without a custom server.
+
+use crate::mp4::{FragmentedMp4Muxer, Segment};
+use crate::mpegts::TsMuxer;
+use crate::nal::{NalType, NalUnits};
+use crate::FrameMeta;
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::collections::VecDeque;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::warn;
+
+const PLAYLIST: &str = "index.m3u8";
+const INIT_SEGMENT: &str = "init.mp4";
+
+/// The container of the segments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HlsFormat {
+    /// MPEG-TS segments (`.ts`), supported by every HLS client.
+    #[default]
+    Ts,
+    /// Fragmented MP4 segments (`.m4s`) with an `init.mp4` initialization segment, which have less overhead but need
+    /// iOS 10 / macOS 10.12 or later.
+    Fmp4,
+}
+
+/// How an [`HlsSegmenter`] cuts and keeps segments.
+#[derive(Debug, Clone)]
+pub struct HlsConfig {
+    /// Segments are cut at the first key frame after they reach this duration.
+    pub segment_duration: Duration,
+    /// How many segments are listed in the playlist.
+    pub playlist_length: usize,
+    pub format: HlsFormat,
+}
+
+impl Default for HlsConfig {
+    /// 2 second MPEG-TS segments with 6 in the playlist.
+    fn default() -> Self {
+        Self {
+            segment_duration: Duration::from_secs(2),
+            playlist_length: 6,
+            format: HlsFormat::Ts,
+        }
+    }
+}
+
+enum Muxer {
+    Ts(TsMuxer<Vec<u8>>),
+    Fmp4(FragmentedMp4Muxer),
+}
+
+struct SegmentEntry {
+    sequence: u64,
+    duration: Duration,
+    started_at: DateTime<Utc>,
+}
+
+struct OpenSegment {
+    data: Vec<u8>,
+    start: Duration,
+    started_at: DateTime<Utc>,
+}
+
+/// Writes H264 access units from [`WebcamH264Stream::next_with_meta`](crate::WebcamH264Stream::next_with_meta) to a
+/// directory as HLS segments and an `index.m3u8` playlist.
+///
+/// Segments start at key frames, so with a camera whose GOP is longer than the segment duration they are as long as
+/// the GOP unless key frames are requested when [`should_request_keyframe`](Self::should_request_keyframe) says so.
+/// Segment files and the playlist are written to a temporary file and renamed so that the server never serves a
+/// partial file. Segments that fall out of the playlist are deleted once they have been out of it for another
+/// `playlist_length` segments, giving clients that loaded an older playlist time to fetch them.
+pub struct HlsSegmenter {
+    dir: PathBuf,
+    config: HlsConfig,
+    muxer: Option<Muxer>,
+    segment: Option<OpenSegment>,
+    segments: VecDeque<SegmentEntry>,
+    next_sequence: u64,
+    first_timestamp: Option<Duration>,
+    last_time: Duration,
+    frame_interval: Duration,
+    keyframe_requested: bool,
+}
+
+impl HlsSegmenter {
+    /// Creates a segmenter that writes to `dir`, creating it if necessary.
+    pub fn new(dir: impl Into<PathBuf>, config: HlsConfig) -> io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+
+        Ok(Self {
+            dir,
+            config,
+            muxer: None,
+            segment: None,
+            segments: VecDeque::new(),
+            next_sequence: 0,
+            first_timestamp: None,
+            last_time: Duration::ZERO,
+            frame_interval: Duration::ZERO,
+            keyframe_requested: false,
+        })
+    }
+
+    /// The path of the playlist.
+    pub fn playlist_path(&self) -> PathBuf {
+        self.dir.join(PLAYLIST)
+    }
+
+    /// Returns true (once per segment) when the current segment is a frame short of the segment duration and a key
+    /// frame should be requested with [`WebcamH264Stream::request_keyframe`](crate::WebcamH264Stream::request_keyframe)
+    /// to end it on time.
+    pub fn should_request_keyframe(&mut self) -> bool {
+        let Some(segment) = &self.segment else {
+            return false;
+        };
+
+        // The capture timestamps may go backwards, eg. if the driver resets its clock when the camera reconnects
+        let elapsed = self.last_time.saturating_sub(segment.start) + self.frame_interval;
+        if self.keyframe_requested || elapsed < self.config.segment_duration {
+            return false;
+        }
+
+        self.keyframe_requested = true;
+        true
+    }
+
+    /// Adds an access unit, timing it using the frame's capture timestamp. Frames before the first key frame with
+    /// parameter sets are skipped.
+    pub fn write_with_meta(&mut self, h264_bytes: &[u8], meta: &FrameMeta) -> io::Result<()> {
+        let is_keyframe = crate::nal::is_keyframe(h264_bytes);
+
+        if self.muxer.is_none() {
+            let Some(muxer) = is_keyframe.then(|| self.create_muxer(h264_bytes)).flatten() else {
+                return Ok(());
+            };
+            self.muxer = Some(muxer);
+        }
+
+        let first_timestamp = *self.first_timestamp.get_or_insert(meta.timestamp);
+        let time = meta.timestamp.saturating_sub(first_timestamp);
+        self.frame_interval = time.saturating_sub(self.last_time);
+        self.last_time = time;
+
+        let cut = is_keyframe
+            && self.segment.as_ref().is_none_or(|segment| {
+                time.saturating_sub(segment.start) >= self.config.segment_duration
+            });
+
+        // TS segments start with the key frame, fMP4 segments end when the key frame completes the previous GOP
+        let is_ts = matches!(self.muxer, Some(Muxer::Ts(_)));
+        if cut && is_ts {
+            self.finish_segment(time)?;
+            self.start_segment(time, meta);
+        }
+
+        let mut media = Vec::new();
+        match self.muxer.as_mut() {
+            Some(Muxer::Ts(muxer)) => {
+                if cut {
+                    muxer.repeat_pat_pmt();
+                }
+                muxer.write_with_meta(h264_bytes, meta)?;
+                media.append(muxer.get_mut());
+            }
+            Some(Muxer::Fmp4(muxer)) => {
+                for fragment in muxer.push_with_meta(h264_bytes, meta) {
+                    match fragment {
+                        Segment::Init(init) => write_atomic(&self.dir.join(INIT_SEGMENT), &init)?,
+                        Segment::Media(fragment) => media.extend_from_slice(&fragment),
+                    }
+                }
+            }
+            None => {}
+        }
+
+        if let Some(segment) = &mut self.segment {
+            segment.data.extend_from_slice(&media);
+        }
+
+        if cut && !is_ts {
+            self.finish_segment(time)?;
+            self.start_segment(time, meta);
+        }
+
+        Ok(())
+    }
+
+    /// Writes the last segment and ends the playlist, so that players stop at its end instead of waiting for more.
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Some(Muxer::Fmp4(muxer)) = &mut self.muxer {
+            if let (Some(Segment::Media(media)), Some(segment)) = (muxer.flush(), &mut self.segment)
+            {
+                segment.data.extend_from_slice(&media);
+            }
+        }
+
+        if let Some(segment) = self.segment.take() {
+            let end = self.last_time + self.frame_interval;
+            self.write_segment(segment, end)?;
+        }
+
+        self.write_playlist(true)
+    }
+
+    fn create_muxer(&self, h264_bytes: &[u8]) -> Option<Muxer> {
+        let sps = NalUnits::new(h264_bytes).find(|nal| NalType::of(nal) == NalType::Sps)?;
+        let info = crate::sps::parse(sps).ok()?;
+        // Only used for the duration of the last frame
+        let fps = info.fps_from_vui.unwrap_or(30.0);
+
+        Some(match self.config.format {
+            HlsFormat::Ts => Muxer::Ts(TsMuxer::new(Vec::new(), fps)),
+            HlsFormat::Fmp4 => Muxer::Fmp4(FragmentedMp4Muxer::new(info.width, info.height, fps)),
+        })
+    }
+
+    fn start_segment(&mut self, time: Duration, meta: &FrameMeta) {
+        self.keyframe_requested = false;
+        self.segment = Some(OpenSegment {
+            data: Vec::new(),
+            start: time,
+            started_at: meta.wall_clock(),
+        });
+    }
+
+    fn finish_segment(&mut self, end: Duration) -> io::Result<()> {
+        let Some(segment) = self.segment.take() else {
+            return Ok(());
+        };
+
+        self.write_segment(segment, end)?;
+        self.write_playlist(false)
+    }
+
+    fn write_segment(&mut self, segment: OpenSegment, end: Duration) -> io::Result<()> {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+
+        write_atomic(&self.dir.join(self.segment_name(sequence)), &segment.data)?;
+        self.segments.push_back(SegmentEntry {
+            sequence,
+            duration: end.saturating_sub(segment.start),
+            started_at: segment.started_at,
+        });
+
+        // Segments are kept on disk for a playlist's length after they leave it
+        let playlist_length = self.config.playlist_length.max(1);
+        while self.segments.len() > playlist_length * 2 {
+            if let Some(expired) = self.segments.pop_front() {
+                let path = self.dir.join(self.segment_name(expired.sequence));
+                if let Err(err) = std::fs::remove_file(&path) {
+                    warn!("Unable to delete expired HLS segment {:?}: {:?}", path, err);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_playlist(&self, ended: bool) -> io::Result<()> {
+        let playlist_length = self.config.playlist_length.max(1);
+        let listed = self
+            .segments
+            .iter()
+            .skip(self.segments.len().saturating_sub(playlist_length));
+
+        // Every segment's rounded duration has to fit within the target duration
+        let target_duration = listed
+            .clone()
+            .map(|segment| segment.duration.as_secs_f64().round() as u64)
+            .chain([self.config.segment_duration.as_secs_f64().ceil() as u64])
+            .max()
+            .unwrap_or(1)
+            .max(1);
+
+        let (version, map) = match self.config.format {
+            HlsFormat::Ts => (3, String::new()),
+            HlsFormat::Fmp4 => (7, format!("#EXT-X-MAP:URI=\"{INIT_SEGMENT}\"\n")),
+        };
+
+        let mut playlist = format!(
+            "#EXTM3U\n#EXT-X-VERSION:{version}\n#EXT-X-TARGETDURATION:{target_duration}\n#EXT-X-MEDIA-SEQUENCE:{}\n\
+             #EXT-X-INDEPENDENT-SEGMENTS\n{map}",
+            listed.clone().next().map_or(0, |segment| segment.sequence),
+        );
+
+        for segment in listed {
+            playlist += &format!(
+                "#EXT-X-PROGRAM-DATE-TIME:{}\n#EXTINF:{:.3},\n{}\n",
+                segment
+                    .started_at
+                    .to_rfc3339_opts(SecondsFormat::Millis, true),
+                segment.duration.as_secs_f64(),
+                self.segment_name(segment.sequence),
+            );
+        }
+
+        if ended {
+            playlist += "#EXT-X-ENDLIST\n";
+        }
+
+        write_atomic(&self.dir.join(PLAYLIST), playlist.as_bytes())
+    }
+
+    fn segment_name(&self, sequence: u64) -> String {
+        match self.config.format {
+            HlsFormat::Ts => format!("segment-{sequence:06}.ts"),
+            HlsFormat::Fmp4 => format!("segment-{sequence:06}.m4s"),
+        }
+    }
+}
+
+/// Writes a file by renaming a temporary file over it, so that readers see either the old or the new file.
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+
+    std::fs::write(&tmp, data)?;
+    std::fs::rename(&tmp, path)
+}
+
+#[cfg(all(test, feature = "openh264"))]
+mod tests {
+    use super::*;
+    use crate::synthetic::tests::access_units;
+    use crate::{ClockMapping, TimestampClock};
+
+    #[test]
+    fn survives_timestamps_going_backwards() {
+        let dir = std::env::temp_dir().join(format!("hls-backwards-{}", std::process::id()));
+        let mut hls = HlsSegmenter::new(&dir, HlsConfig::default()).unwrap();
+        let clock = ClockMapping::measure(TimestampClock::Monotonic);
+
+        for (i, access_unit) in access_units(64, 64, 150, 15).iter().enumerate() {
+            // The clock steps back to before the start of the second segment
+            let step = if i < 90 { 0.0 } else { 1.5 };
+            let seconds = 10.0 + i as f64 / 30.0 - step;
+            let meta = FrameMeta {
+                timestamp: Duration::from_secs_f64(seconds),
+                clock,
+                sequence: i as u32,
+                dropped_since_last: 0,
+                bytesused: 0,
+                is_keyframe: false,
+            };
+
+            hls.write_with_meta(access_unit, &meta).unwrap();
+            hls.should_request_keyframe();
+        }
+        hls.finish().unwrap();
+
+        let playlist = std::fs::read_to_string(dir.join(PLAYLIST)).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(playlist.ends_with("#EXT-X-ENDLIST\n"), "{playlist}");
+        assert!(playlist.contains("segment"), "{playlist}");
+    }
+}
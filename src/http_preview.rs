@@ -0,0 +1,208 @@
+//! A minimal HTTP server for previewing the camera in a browser as an MJPEG stream.
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tracing::warn;
+
+const BOUNDARY: &str = "frame";
+/// How often idle threads check whether the server has been dropped.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serves JPEGs pushed to it over HTTP on a background thread.
+///
+/// - `GET /stream` streams every JPEG as `multipart/x-mixed-replace`, which browsers display as a video in an `<img>`.
+/// - `GET /snapshot` returns the latest JPEG.
+///
+/// Each client is served by its own thread. Clients that can't keep up skip frames rather than slowing down the camera
+/// or other clients. The server stops when it is dropped.
+pub struct MjpegServer {
+    shared: Arc<Shared>,
+    local_addr: SocketAddr,
+    accept_thread: Option<JoinHandle<()>>,
+}
+
+#[derive(Default)]
+struct Shared {
+    latest: Mutex<Option<Arc<[u8]>>>,
+    clients: Mutex<Vec<SyncSender<Arc<[u8]>>>>,
+    client_count: AtomicUsize,
+    stop: AtomicBool,
+}
+
+impl MjpegServer {
+    /// Starts listening for HTTP connections on `addr`, eg. `"0.0.0.0:8080"`.
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+
+        // The listener is polled so that the accept thread notices when the server is dropped
+        listener.set_nonblocking(true)?;
+
+        let shared = Arc::new(Shared::default());
+
+        let accept_thread = {
+            let shared = Arc::clone(&shared);
+            std::thread::spawn(move || accept(listener, shared))
+        };
+
+        Ok(Self {
+            shared,
+            local_addr,
+            accept_thread: Some(accept_thread),
+        })
+    }
+
+    /// The address the server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The number of clients currently connected to `/stream`.
+    pub fn client_count(&self) -> usize {
+        self.shared.client_count.load(Ordering::Relaxed)
+    }
+
+    /// Sends a JPEG to every connected `/stream` client and makes it the `/snapshot` image, eg. from
+    /// [`WebcamH264Stream::snapshot_jpeg`](crate::WebcamH264Stream::snapshot_jpeg) or from
+    /// [`WebcamH264Stream::next_raw`](crate::WebcamH264Stream::next_raw) for MJPEG cameras.
+    pub fn push_jpeg(&self, jpeg: impl Into<Arc<[u8]>>) {
+        let jpeg = jpeg.into();
+
+        *self.shared.latest.lock().unwrap() = Some(Arc::clone(&jpeg));
+
+        // Slow clients drop the frame and disconnected clients are removed
+        self.shared.clients.lock().unwrap().retain(|sender| {
+            match sender.try_send(Arc::clone(&jpeg)) {
+                Ok(()) | Err(TrySendError::Full(_)) => true,
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
+
+impl Drop for MjpegServer {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::Relaxed);
+
+        if let Some(thread) = self.accept_thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn accept(listener: TcpListener, shared: Arc<Shared>) {
+    while !shared.stop.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                let shared = Arc::clone(&shared);
+                std::thread::spawn(move || {
+                    if let Err(err) = serve_client(stream, &shared) {
+                        warn!("MJPEG preview client error: {:?}", err);
+                    }
+                });
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                std::thread::sleep(POLL_INTERVAL)
+            }
+            Err(err) => warn!("Failed to accept MJPEG preview client: {:?}", err),
+        }
+    }
+}
+
+fn serve_client(stream: TcpStream, shared: &Shared) -> io::Result<()> {
+    // Accepted sockets may inherit the listener's non-blocking mode
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // Skip the request headers
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut stream = &stream;
+    let mut parts = request_line.split_whitespace();
+
+    match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/stream")) => serve_stream(stream, shared),
+        (Some("GET"), Some("/snapshot")) => {
+            let latest = shared.latest.lock().unwrap().clone();
+
+            match latest {
+                Some(jpeg) => {
+                    write!(
+                        stream,
+                        "HTTP/1.0 200 OK\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\nCache-Control: no-cache\r\n\r\n",
+                        jpeg.len()
+                    )?;
+                    stream.write_all(&jpeg)
+                }
+                None => write_status(stream, "503 Service Unavailable"),
+            }
+        }
+        (Some("GET"), _) => write_status(stream, "404 Not Found"),
+        _ => write_status(stream, "405 Method Not Allowed"),
+    }
+}
+
+fn serve_stream(mut stream: &TcpStream, shared: &Shared) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.0 200 OK\r\nContent-Type: multipart/x-mixed-replace; boundary={}\r\nCache-Control: no-cache\r\n\r\n",
+        BOUNDARY
+    )?;
+
+    // Each client queues at most one frame so slow clients skip frames instead of falling behind
+    let (sender, receiver) = sync_channel(1);
+
+    if let Some(latest) = shared.latest.lock().unwrap().clone() {
+        let _ = sender.try_send(latest);
+    }
+    shared.clients.lock().unwrap().push(sender);
+
+    shared.client_count.fetch_add(1, Ordering::Relaxed);
+    let result = stream_frames(stream, &receiver, shared);
+    shared.client_count.fetch_sub(1, Ordering::Relaxed);
+
+    result
+}
+
+fn stream_frames(
+    mut stream: &TcpStream,
+    receiver: &Receiver<Arc<[u8]>>,
+    shared: &Shared,
+) -> io::Result<()> {
+    while !shared.stop.load(Ordering::Relaxed) {
+        let jpeg = match receiver.recv_timeout(POLL_INTERVAL) {
+            Ok(jpeg) => jpeg,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        write!(
+            stream,
+            "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+            BOUNDARY,
+            jpeg.len()
+        )?;
+        stream.write_all(&jpeg)?;
+        stream.write_all(b"\r\n")?;
+    }
+
+    Ok(())
+}
+
+fn write_status(mut stream: &TcpStream, status: &str) -> io::Result<()> {
+    write!(stream, "HTTP/1.0 {}\r\nContent-Length: 0\r\n\r\n", status)
+}
@@ -0,0 +1,42 @@
+//! Conversions to and from the `image` crate's buffers (requires the `image` feature).
+
+use crate::{StreamError, YUVBuffer, YUVFrame};
+use image::{DynamicImage, RgbImage};
+
+impl TryFrom<&YUVFrame<'_>> for RgbImage {
+    type Error = StreamError;
+
+    /// Converts the visible area of the frame using the same BT.601 limited range coefficients as
+    /// [`YUVFrame::to_rgb`].
+    fn try_from(frame: &YUVFrame<'_>) -> Result<Self, Self::Error> {
+        let (width, height) = (frame.width() as u32, frame.height() as u32);
+
+        RgbImage::from_raw(width, height, frame.to_rgb_vec())
+            .ok_or(StreamError::InvalidFrameSize(width, height))
+    }
+}
+
+impl TryFrom<&YUVFrame<'_>> for DynamicImage {
+    type Error = StreamError;
+
+    fn try_from(frame: &YUVFrame<'_>) -> Result<Self, Self::Error> {
+        RgbImage::try_from(frame).map(DynamicImage::ImageRgb8)
+    }
+}
+
+impl YUVBuffer {
+    /// Converts an RGB image, eg. a processed or generated frame to encode. The image's width and height must be even.
+    pub fn from_image(image: &RgbImage) -> Result<Self, StreamError> {
+        let (width, height) = image.dimensions();
+
+        if !width.is_multiple_of(2) || !height.is_multiple_of(2) {
+            return Err(StreamError::InvalidFrameSize(width, height));
+        }
+
+        Ok(Self::with_rgb(
+            width as usize,
+            height as usize,
+            image.as_raw(),
+        ))
+    }
+}
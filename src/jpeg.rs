@@ -0,0 +1,75 @@
+//! JPEG encoding of YUV frames for still images.
+
+use crate::yuv::YUVSource;
+use crate::StreamError;
+use jpeg_encoder::{Encoder, ImageBuffer, JpegColorType};
+
+/// Adapts a (possibly stride padded) limited range I420 source to the full range YCbCr rows expected by the JPEG
+/// encoder.
+struct JpegImage<'a, T: YUVSource> {
+    source: &'a T,
+    width: u16,
+    height: u16,
+}
+
+impl<'a, T: YUVSource> ImageBuffer for JpegImage<'a, T> {
+    fn get_jpeg_color_type(&self) -> JpegColorType {
+        JpegColorType::Ycbcr
+    }
+
+    fn width(&self) -> u16 {
+        self.width
+    }
+
+    fn height(&self) -> u16 {
+        self.height
+    }
+
+    fn fill_buffers(&self, y: u16, buffers: &mut [Vec<u8>; 4]) {
+        let source = self.source;
+        let y = y as usize;
+
+        let y_row = &source.y()[y * source.y_stride() as usize..];
+        let u_row = &source.u()[y / 2 * source.u_stride() as usize..];
+        let v_row = &source.v()[y / 2 * source.v_stride() as usize..];
+
+        for x in 0..self.width as usize {
+            buffers[0].push(expand_luma(y_row[x]));
+            buffers[1].push(expand_chroma(u_row[x / 2]));
+            buffers[2].push(expand_chroma(v_row[x / 2]));
+        }
+    }
+}
+
+fn expand_luma(y: u8) -> u8 {
+    ((y as f32 - 16.0) * (255.0 / 219.0))
+        .round()
+        .clamp(0.0, 255.0) as u8
+}
+
+fn expand_chroma(c: u8) -> u8 {
+    ((c as f32 - 128.0) * (255.0 / 224.0) + 128.0)
+        .round()
+        .clamp(0.0, 255.0) as u8
+}
+
+/// Encodes the top left `width` x `height` pixels of `source` as a JPEG. The area is clamped to the source's
+/// dimensions.
+pub(crate) fn encode<T: YUVSource>(
+    source: &T,
+    width: usize,
+    height: usize,
+    quality: u8,
+) -> Result<Vec<u8>, StreamError> {
+    // JPEG dimensions are limited to 16 bits, far beyond any V4L2 frame size
+    let image = JpegImage {
+        source,
+        width: width.min(source.width() as usize) as u16,
+        height: height.min(source.height() as usize) as u16,
+    };
+
+    let mut jpeg = Vec::new();
+    Encoder::new(&mut jpeg, quality).encode_image(image)?;
+
+    Ok(jpeg)
+}
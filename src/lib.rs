@@ -1,26 +1,164 @@
+mod accumulate;
+pub mod appsrc;
+#[cfg(feature = "audio")]
+pub mod audio;
+// Only used by the streaming servers
+mod base64;
+mod bayer;
+pub mod bitstream;
+mod broadcast;
+mod builder;
+#[cfg(feature = "openh264")]
+mod burst;
+mod cfr;
+mod clock;
+#[cfg(feature = "openh264")]
+mod compositor;
+pub mod controls;
+mod convert;
+mod decimate;
+mod descriptor;
+#[cfg(feature = "openh264")]
+mod encoder;
+mod events;
+mod exposure;
+mod ffmpeg;
+mod frames;
+#[cfg(feature = "gstreamer")]
+pub mod gstreamer;
+#[cfg(feature = "hls")]
+pub mod hls;
+#[cfg(feature = "http_preview")]
+pub mod http_preview;
+#[cfg(feature = "image")]
+mod image_interop;
+mod jpeg;
+mod manager;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "mkv")]
+pub mod mkv;
+mod motion;
+pub mod mp4;
+#[cfg(feature = "mpegts")]
+pub mod mpegts;
+mod mplane;
+pub mod nal;
+mod orientation;
+mod output;
+mod pipeline;
+// The crops, scales and overlays are only applied when transcoding
+#[cfg_attr(not(feature = "openh264"), allow(dead_code))]
+pub mod overlay;
+#[cfg(feature = "openh264")]
+mod playback;
+mod preroll;
+mod preset;
+mod profile;
+mod reconfigure;
+mod reconnect;
+mod recording_index;
+#[cfg(feature = "openh264")]
+mod recovery;
+#[cfg_attr(not(feature = "openh264"), allow(dead_code))]
+mod resize;
+pub mod rtp;
+#[cfg(feature = "rtsp")]
+pub mod rtsp;
+mod segment;
+pub mod sei;
+mod selection;
+mod source;
+pub mod sps;
+mod stats;
+#[cfg(feature = "openh264")]
+mod synthetic;
+mod tee;
+mod tensor;
+#[cfg(feature = "openh264")]
+mod timelapse;
+#[cfg(feature = "tokio")]
+pub mod tokio;
+mod validate;
+#[cfg(feature = "ws_stream")]
+pub mod ws_stream;
+mod y4m;
+mod yuv;
+
+pub use accumulate::FrameAccumulator;
+pub use bayer::BayerPattern;
+pub use broadcast::{Broadcaster, Subscriber};
+pub use builder::{LatencyProfile, StreamBuilder};
+pub use cfr::{CfrAdapter, CfrFrame, CfrStats};
+pub use chrono;
+use chrono::{DateTime, Utc};
+pub use clock::{ClockMapping, TimestampClock};
+#[cfg(feature = "openh264")]
+pub use compositor::{Compositor, Corner, Layout};
+pub use descriptor::StreamDescriptor;
+#[cfg(feature = "openh264")]
+pub use encoder::{EncoderComplexity, EncoderOptions, RateControlMode};
+pub use events::{HealthEvent, HealthEventKind};
+pub use exposure::{ExposureDrift, LockedControl, TimelapseSession};
+pub use ffmpeg::{FfmpegError, FfmpegSink, FfmpegSinkConfig};
+pub use frames::{Frames, RecordingStats};
+pub use manager::{
+    CameraEvent, CameraEventKind, CameraHandle, CameraId, CaptureManager, EventReceiver,
+};
+pub use motion::{MotionConfig, MotionDetector, MotionResult, Rect};
+#[cfg(feature = "openh264")]
 pub use openh264;
+#[cfg(feature = "openh264")]
 pub use openh264::decoder::DecodedYUV;
+#[cfg(feature = "openh264")]
 use openh264::encoder::EncodedBitStream;
-pub use openh264::formats::YUVBuffer;
+pub use orientation::{FlipAxis, Rotation, Transform, TransformMode};
+pub use output::OutputDevice;
+#[cfg(feature = "openh264")]
+pub use playback::FilePlaybackStream;
+pub use preroll::GopBuffer;
+pub use preset::{FormatPriority, PresetOptions, StreamPreset};
+pub use profile::{CameraProfile, ProfileControl, ProfileFormat, ProfileMenuItem, SkippedControl};
+pub use reconfigure::{Reconfigured, ResolutionRequest, StreamFormat};
+pub use reconnect::{DeviceSelector, ReconnectingStream, StreamEvent};
+pub use recording_index::{RecordingIndex, SeekPoint};
+pub use resize::ScaleFilter;
+pub use segment::{
+    RetentionEvent, RetentionPolicy, SegmentPolicy, SegmentStats, SegmentedRecorder,
+};
+pub use selection::{enumerate_configurations, CaptureConfig};
+pub use source::FrameSource;
+pub use sps::SpsInfo;
+pub use stats::StreamStats;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+#[cfg(feature = "openh264")]
+pub use synthetic::{Pattern, SyntheticSource};
+pub use tee::{EncodedFrame, SinkId, StreamTee};
+pub use tensor::{Normalize, TensorColor, TensorLayout, TensorSpec};
 use thiserror::Error;
-use tracing::warn;
-use v4l::buffer::Type;
-use v4l::frameinterval::FrameIntervalEnum;
-use v4l::frameinterval::Stepwise;
-use v4l::io::traits::CaptureStream;
-use v4l::prelude::MmapStream;
-use v4l::video::capture::Parameters;
-use v4l::video::Capture;
+#[cfg(feature = "openh264")]
+pub use timelapse::TimelapseRecorder;
+use tracing::{debug, warn};
+use v4l::buffer::Flags as BufferFlags;
+pub use v4l::capability::Flags as CapabilityFlags;
 pub use v4l::Device;
-use v4l::Format;
-use v4l::FourCC;
+pub use v4l::FourCC;
+pub use v4l::Fraction;
+pub use validate::{BitstreamValidator, ValidationIssue, ValidationReport};
+pub use y4m::Y4mWriter;
+pub use yuv::{ColorRange, YUVBuffer, YUVSource};
 
 #[derive(Error, Debug)]
 pub enum DeviceError {
     #[error("Video capture device failed to open")]
     CouldNotOpen(#[from] std::io::Error),
+    #[error("Permission denied opening {path:?}. {hint}")]
+    PermissionDenied { path: PathBuf, hint: &'static str },
     // #[error("the data for key `{0}` is not available")]
     // Redaction(String),
     // #[error("invalid header (expected {expected:?}, found {found:?})")]
@@ -30,6 +168,20 @@ pub enum DeviceError {
     // },
     #[error("Could not find video capture device")]
     DeviceNotFound,
+    #[error("Multiple video capture devices matched: {0:?}")]
+    MultipleMatches(Vec<PathBuf>),
+}
+
+impl DeviceError {
+    fn open_failed(path: &Path, err: std::io::Error) -> Self {
+        match err.raw_os_error() {
+            Some(libc::EACCES | libc::EPERM) => Self::PermissionDenied {
+                path: path.to_path_buf(),
+                hint: "Add the user to the video group (`sudo usermod -aG video $USER`) and log in again",
+            },
+            _ => Self::CouldNotOpen(err),
+        }
+    }
 }
 
 // .ok_or_else(|| eyre!("Unable to query webcam for supported resolutions"))?;
@@ -38,30 +190,365 @@ pub enum DeviceError {
 
 #[derive(Error, Debug)]
 pub enum StreamError {
-    #[error("No supported camera configurations were found")]
-    NoSupportedConfiguration,
-    #[error("Failed to configure camera settings")]
-    SettingsFailure(std::io::Error),
-    #[error("Failed to create buffer stream")]
-    BufferStreamFailure(std::io::Error),
+    /// No configuration matched the requested settings, or the camera failed to apply each one that was tried. The
+    /// attempts are listed best first.
+    #[error("No supported camera configurations were found{}", describe_attempts(.attempts))]
+    NoSupportedConfiguration {
+        attempts: Vec<(CaptureConfig, StreamError)>,
+    },
+    /// The camera only supports formats that have to be transcoded to H264, which requires the `openh264` feature.
+    #[error("The camera has no native H264 formats and transcoding requires the openh264 feature")]
+    TranscodeUnavailable,
+    #[error("Resolution {width}x{height} is not supported (closest available: {closest:?})")]
+    ResolutionNotSupported {
+        width: u32,
+        height: u32,
+        closest: Vec<(u32, u32)>,
+    },
+    #[error(
+        "Camera rejected the {}x{} {} format and substituted {}x{} {}",
+        requested.0, requested.1, requested.2, actual.0, actual.1, actual.2
+    )]
+    FormatRejected {
+        requested: (u32, u32, FourCC),
+        actual: (u32, u32, FourCC),
+    },
+    #[error("Failed to set the {requested} format")]
+    SetFormatFailed {
+        requested: FormatSummary,
+        source: std::io::Error,
+    },
+    #[error("Failed to set the frame interval to {requested}s")]
+    SetParamsFailed {
+        requested: Fraction,
+        source: std::io::Error,
+    },
+    #[error("Failed to query the camera's {query}")]
+    QueryFailed {
+        query: &'static str,
+        source: std::io::Error,
+    },
+    #[error("Failed to request {count} buffers from the camera")]
+    RequestBuffersFailed { count: u32, source: std::io::Error },
+    /// The device is in use, usually by another process that is streaming from it (`EBUSY`).
+    #[error("The camera is busy, it may be in use by another application")]
+    DeviceBusy,
+    /// The device has gone away, usually because the camera was unplugged (`ENODEV`).
+    #[error("The camera was disconnected")]
+    DeviceDisconnected,
     #[error("H264 encoder/decoder error")]
+    #[cfg(feature = "openh264")]
     H264EncoderError(#[from] openh264::Error),
+    /// A native H264 frame failed to decode, or the decoder panicked on it. The decoder is recreated and frames are
+    /// returned without a YUV frame until the next key frame.
+    #[error("Failed to decode an H264 frame: {reason}")]
+    #[cfg(feature = "openh264")]
+    DecodeFailed { reason: String },
     #[error("JPEG decoder error")]
     JPEGDecoderError(#[from] jpeg_decoder::Error),
-    #[error("Mmap Stream failed to read")]
-    StreamFailure(std::io::Error),
+    #[error("JPEG encoder error")]
+    JPEGEncoderError(#[from] jpeg_encoder::EncodingError),
+    #[error("Failed to read a frame from the camera")]
+    DequeueFailed { source: std::io::Error },
+    #[error("Failed to stop the stream")]
+    StopFailed { source: std::io::Error },
+    #[error("The camera does not support key frame requests")]
+    KeyframeRequestUnsupported(std::io::Error),
+    #[error("Timed out waiting for a frame")]
+    Timeout,
+    /// No frame was received within the stream's stall deadline (see `StreamBuilder::stall_deadline`).
+    #[error("The camera has not delivered a frame for {:?}", .since.elapsed())]
+    Stalled {
+        /// When the last frame was received, or when the stream was opened if no frames were received.
+        since: Instant,
+    },
+    #[error("Audio capture failed")]
+    #[cfg(feature = "audio")]
+    AudioError(#[from] audio::AudioError),
+    #[error("Failed to start the capture thread")]
+    PipelineFailed(std::io::Error),
+    #[error("The stream is paused")]
+    Paused,
+    #[error("The stream has no format because reconfiguring it failed and its previous format could not be restored")]
+    NotConfigured,
+    #[error("The camera is not capturing")]
+    NotCapturing,
+    #[error("No YUV frame was produced after {0} attempts")]
+    NoYUVFrame(usize),
+    #[error("Expected a {}x{} frame but got {}x{}", expected.0, expected.1, actual.0, actual.1)]
+    FrameSizeMismatch {
+        expected: (u32, u32),
+        actual: (u32, u32),
+    },
+    /// An uncompressed frame from the camera was shorter than its format requires, eg. a partial frame from a USB
+    /// camera.
+    #[error("The {fourcc} frame is {actual} bytes but its format requires {expected}")]
+    TruncatedFrame {
+        fourcc: FourCC,
+        expected: usize,
+        actual: usize,
+    },
+    #[error("The crop {rect:?} does not fit inside the {}x{} frame", frame.0, frame.1)]
+    CropOutOfBounds { rect: Rect, frame: (u32, u32) },
+    #[error("Invalid frame size {0}x{1}, the width and height must be even and non-zero")]
+    InvalidFrameSize(u32, u32),
+    #[error(
+        "Rotating, cropping and scaling are only supported for streams that are transcoded to H264"
+    )]
+    TransformUnsupported,
+    #[error("Failed to open the video capture device")]
+    DeviceError(#[from] DeviceError),
+    #[error("The camera does not support the {0} control")]
+    ControlUnsupported(&'static str),
+    #[error("Failed to set camera control")]
+    ControlFailure(#[from] controls::ControlError),
+    #[error("Failed to write the recording")]
+    WriteFailure(std::io::Error),
+    #[error("Failed to read the recording")]
+    ReadFailure(std::io::Error),
+    #[error("The end of the recording was reached")]
+    EndOfStream,
+    #[error("The disk is full, recording stopped while writing {0:?}")]
+    DiskFull(PathBuf),
+    #[error("The recording was stopped by an earlier error")]
+    RecorderStopped,
+    #[error("{0:?} is not a video output device")]
+    NotAnOutputDevice(PathBuf),
+    #[error("Output devices do not support the {0} format (expected YUYV, YU12 or MJPG)")]
+    OutputFormatUnsupported(FourCC),
+    #[error("The output device expects {expected} frames but was given {actual}")]
+    OutputFormatMismatch { expected: FourCC, actual: FourCC },
+    #[error("Failed to write to the output device")]
+    OutputFailure(std::io::Error),
+    #[error("Invalid encoder options: {0}")]
+    InvalidEncoderOptions(&'static str),
+}
+
+impl StreamError {
+    /// Maps the errors that applications commonly handle (busy and unplugged devices) to their own variants, and
+    /// otherwise uses `wrap` to report which operation failed.
+    pub(crate) fn from_io(err: std::io::Error, wrap: impl FnOnce(std::io::Error) -> Self) -> Self {
+        match err.raw_os_error() {
+            Some(libc::EBUSY) => Self::DeviceBusy,
+            Some(libc::ENODEV) => Self::DeviceDisconnected,
+            _ => wrap(err),
+        }
+    }
+}
+
+fn describe_attempts(attempts: &[(CaptureConfig, StreamError)]) -> String {
+    attempts
+        .iter()
+        .map(|(config, err)| {
+            format!(
+                "; {}x{} {} at {:.1}fps: {}",
+                config.width,
+                config.height,
+                config.fourcc,
+                config.fps(),
+                err
+            )
+        })
+        .collect()
+}
+
+/// A format requested from the camera, see [`StreamError::SetFormatFailed`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatSummary {
+    pub width: u32,
+    pub height: u32,
+    pub fourcc: FourCC,
+}
+
+impl std::fmt::Display for FormatSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}x{} {}", self.width, self.height, self.fourcc)
+    }
 }
 
 pub struct WebcamH264Stream<'a> {
-    stream: MmapStream<'a>,
+    // None if reconfiguring the stream failed and its previous format could not be restored
+    stream: Option<mplane::BufferStream>,
+    handle: Arc<v4l::device::Handle>,
     encoder_mode: EncoderMode,
     pub width: u32,
     pub height: u32,
+    frame_interval: Fraction,
+    fourcc: FourCC,
+    max_yuv_attempts: usize,
+    error_policy: ErrorPolicy,
+    dequeue_policy: DequeuePolicy,
+    #[cfg(feature = "openh264")]
+    fill_dropped_frames: u32,
+    sei_timestamps: bool,
+    // Measured when the first frame is read, since the clock the driver uses is only known from its timestamps
+    clock: Option<ClockMapping>,
+    corrupt_frames: u64,
+    parameter_sets: nal::ParameterSets,
+    #[cfg(feature = "openh264")]
+    decoder_recovery: recovery::DecoderRecovery,
+    keyframe_requested: bool,
+    keyframe_alignment: KeyframeAlignment,
+    awaiting_keyframe: bool,
+    overlays: Vec<overlay::Overlay>,
+    transform: Option<resize::FrameTransform>,
+    transform_mode: Option<TransformMode>,
+    stats: stats::StatsTracker,
+    stall_deadline: Option<Duration>,
+    decimator: Option<decimate::Decimator>,
+    last_frame_received: Instant,
+    paused: bool,
+    validation: Option<validate::StreamValidation>,
+    events: events::EventBus,
+    #[cfg(feature = "metrics")]
+    metrics: Option<metrics::MetricsHandle>,
+    // Reused between frames when transcoding MJPEG and uncompressed formats to H264
+    yuv_buffer: YUVBuffer,
+    raw_layout: yuv::RawLayout,
+    // The settings the format was negotiated from, for `reconfigure`
+    config: builder::StreamConfig,
+    // Declared last so that the stream is stopped before an owned device is closed
+    device: builder::StreamDevice<'a>,
 }
 
+/// A stream that owns its device. It has no borrowed lifetime so it can be stored alongside other state or moved to
+/// another thread.
+pub type OwnedWebcamH264Stream = WebcamH264Stream<'static>;
+
+/// An owned frame and its capture metadata, eg. for sending to other threads or tasks.
+#[derive(Clone)]
+pub struct Frame {
+    pub meta: FrameMeta,
+    pub h264_bytes: Vec<u8>,
+    /// The YUV frame, if one was requested and produced.
+    pub yuv: Option<YUVBuffer>,
+}
+
+/// Capture metadata reported by the kernel for a frame.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameMeta {
+    /// The kernel's capture timestamp, relative to `clock.clock` (usually CLOCK_MONOTONIC). See
+    /// [`wall_clock`](Self::wall_clock) for the time in UTC.
+    pub timestamp: Duration,
+    /// Maps the timestamp to UTC, measured when the stream started.
+    pub clock: ClockMapping,
+    /// The driver's frame counter. Gaps in the sequence indicate dropped frames.
+    pub sequence: u32,
+    /// The number of frames the driver dropped between the previous frame the stream dequeued and this one.
+    pub dropped_since_last: u32,
+    /// The size of the frame in the camera's native format (before any transcoding).
+    pub bytesused: u32,
+    /// True if the H264 access unit contains an IDR (key) frame. Passed through JPEGs are always key frames.
+    pub is_keyframe: bool,
+}
+
+impl FrameMeta {
+    /// The frame's capture time in UTC, eg. for correlating frames with other sensors' logs. See [`ClockMapping`] for
+    /// its accuracy.
+    pub fn wall_clock(&self) -> DateTime<Utc> {
+        self.clock.to_utc(self.timestamp)
+    }
+}
+
+/// A frame in the camera's native compressed format, returned by [`WebcamH264Stream::next_raw`].
+#[derive(Debug, Clone)]
+pub enum RawFrame {
+    /// An H264 access unit in Annex-B format.
+    H264(Vec<u8>),
+    /// A JPEG image from an MJPEG camera.
+    Jpeg(Vec<u8>),
+}
+
+/// How the stream handles frames that fail to decode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorPolicy {
+    /// Return the decode error from `next()` (the default).
+    #[default]
+    FailFast,
+    /// Log and count the corrupt frame and carry on streaming.
+    ///
+    /// Corrupt MJPEG frames and uncompressed frames that are too short for their format (see
+    /// [`StreamError::TruncatedFrame`]) are dropped and the next buffer is read instead. Native H264 frames that fail
+    /// to decode are still returned but without a YUV frame.
+    SkipCorruptFrames,
+}
+
+/// Which of the frames waiting in the camera's buffers the stream reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DequeuePolicy {
+    /// Read every frame in order (the default).
+    #[default]
+    EveryFrame,
+    /// Skip to the newest frame when more than one is waiting, eg. for a live preview whose latency shouldn't grow when
+    /// the reader falls behind. Unlike decimation this only skips frames when the reader is slower than the camera.
+    ///
+    /// Skipped frames are requeued without being decoded and counted in [`StreamStats::stale_frames`]. Native H264
+    /// frames depend on the frames before them, so they are still passed to the decoder (without converting the
+    /// picture) and their H264 is returned ahead of the newest frame's so that the bitstream stays decodable.
+    LatestOnly,
+}
+
+/// How the stream handles native H264 frames that precede its first key frame.
+///
+/// Cameras that encode H264 themselves may start streaming mid-GOP, so a recording of their first frames is not
+/// decodable until the next IDR frame. Transcoded streams always start at a key frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyframeAlignment {
+    /// Return every frame (the default).
+    #[default]
+    Disabled,
+    /// Discard frames until one containing an IDR slice.
+    SkipToKeyframe,
+    /// Same as `SkipToKeyframe` but the cached SPS and PPS are prepended to the first key frame if it does not
+    /// contain them, for cameras that only send their parameter sets occasionally.
+    SkipToKeyframeWithParameterSets,
+}
+
+/// How the camera's frames are turned into H264. Without the `openh264` feature only native H264 cameras are
+/// supported, and their bitstream is passed through without being decoded.
+#[allow(clippy::large_enum_variant)]
 pub enum EncoderMode {
+    #[cfg(feature = "openh264")]
     H264Native(openh264::decoder::Decoder),
+    #[cfg(not(feature = "openh264"))]
+    H264Passthrough,
+    #[cfg(feature = "openh264")]
     MjpegNative(openh264::encoder::Encoder),
+    #[cfg(feature = "openh264")]
+    YuyvNative(openh264::encoder::Encoder),
+    #[cfg(feature = "openh264")]
+    UyvyNative(openh264::encoder::Encoder),
+    #[cfg(feature = "openh264")]
+    Nv12Native(openh264::encoder::Encoder),
+    #[cfg(feature = "openh264")]
+    BayerNative(openh264::encoder::Encoder),
+}
+
+// SAFETY: openh264's encoder and decoder are only !Send because they hold raw pointers to their C++ instances. Each
+// instance is exclusively owned by the EncoderMode and openh264 does not depend on the thread it was created on.
+#[cfg(feature = "openh264")]
+unsafe impl Send for EncoderMode {}
+
+impl EncoderMode {
+    #[cfg(feature = "openh264")]
+    fn is_native_h264(&self) -> bool {
+        matches!(self, Self::H264Native(_))
+    }
+
+    #[cfg(not(feature = "openh264"))]
+    fn is_native_h264(&self) -> bool {
+        true
+    }
+
+    #[cfg(feature = "openh264")]
+    fn is_mjpeg(&self) -> bool {
+        matches!(self, Self::MjpegNative(_))
+    }
+
+    #[cfg(not(feature = "openh264"))]
+    fn is_mjpeg(&self) -> bool {
+        false
+    }
 }
 
 pub fn list_devices() -> Vec<PathBuf> {
@@ -73,125 +560,699 @@ pub fn list_devices() -> Vec<PathBuf> {
         .collect::<Vec<_>>()
 }
 
+/// Identifying information and capabilities of a video device node.
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    /// The device node, eg. `/dev/video0`.
+    pub path: PathBuf,
+    /// The card name, eg. "HD Pro Webcam C920".
+    pub card: String,
+    /// The kernel driver, eg. "uvcvideo".
+    pub driver: String,
+    /// The bus the device is attached to, eg. "usb-0000:00:14.0-1".
+    pub bus_info: String,
+    /// The capabilities of this device node.
+    pub capabilities: CapabilityFlags,
+    /// The pixel formats the device can capture in.
+    pub formats: Vec<FourCC>,
+}
+
+impl DeviceInfo {
+    /// Returns true if the node can be used to stream video (some drivers such as uvcvideo also create metadata-only
+    /// nodes which cannot).
+    pub fn is_streaming_capture(&self) -> bool {
+        self.capabilities.contains(CapabilityFlags::STREAMING)
+            && self
+                .capabilities
+                .intersects(CapabilityFlags::VIDEO_CAPTURE | CapabilityFlags::VIDEO_CAPTURE_MPLANE)
+    }
+}
+
+/// Lists the video capture devices along with their names and capabilities.
+///
+/// Nodes that do not support streaming video capture (eg. UVC metadata nodes) are excluded.
+pub fn list_devices_info() -> Vec<DeviceInfo> {
+    v4l::context::enum_devices()
+        .into_iter()
+        .filter_map(|node| {
+            let dev = Device::with_path(node.path())
+                .map_err(|err| warn!("Unable to open {:?}, skipping: {:?}", node.path(), err))
+                .ok()?;
+
+            let caps = dev
+                .query_caps()
+                .map_err(|err| warn!("Unable to query {:?}, skipping: {:?}", node.path(), err))
+                .ok()?;
+
+            let formats = mplane::enum_fourccs(&dev);
+
+            Some(DeviceInfo {
+                path: node.path().into(),
+                card: caps.card,
+                driver: caps.driver,
+                bus_info: caps.bus,
+                capabilities: caps.capabilities,
+                formats,
+            })
+        })
+        .filter(DeviceInfo::is_streaming_capture)
+        .collect()
+}
+
+/// Opens the video device at the given path.
+///
+/// Symlinks such as `/dev/v4l/by-id/...` are resolved to the device node they point to.
 pub fn get_device(device_path: &Path) -> Result<Device, DeviceError> {
     let devices = v4l::context::enum_devices();
 
+    let canonical = |path: &Path| path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let device_path = canonical(device_path);
+
     let node = devices
         .into_iter()
-        .find(|node| node.path() == device_path)
-        .ok_or_else(|| DeviceError::DeviceNotFound)?;
+        .find(|node| canonical(node.path()) == device_path)
+        .ok_or(DeviceError::DeviceNotFound)?;
 
-    Device::new(node.index()).map_err(|err| DeviceError::CouldNotOpen(err))
+    Device::new(node.index()).map_err(|err| DeviceError::open_failed(node.path(), err))
 }
 
-pub fn stream(mut dev: &mut Device, max_fps: u32) -> Result<WebcamH264Stream, StreamError> {
-    let h264 = FourCC::new(b"H264");
-    let mjpg = FourCC::new(b"MJPG");
-
-    // Find the largest resolution available for video capture on this device
-    let (fourcc, frame_period, width, height) = [h264, mjpg]
-        .into_iter()
-        // Get an iterator of stepwise or discrete frame sizes
-        .filter_map(|fourcc| dev.enum_framesizes(fourcc).ok())
-        .flatten()
-        // Normalize the frame sizes as discrete
-        .flat_map(|framesize| {
-            framesize
-                .size
-                .to_discrete()
-                .into_iter()
-                .map(move |discrete| (framesize.fourcc.clone(), discrete))
-        })
-        // Get the frame interval (1 / fps) for each frame size
-        .filter_map(|(fourcc, discrete)| {
-            (&dev)
-                .enum_frameintervals(fourcc, discrete.width, discrete.height)
-                .map_err(|err| {
-                    warn!(
-                        "Unable to get camera frame internals for {}x{}, skipping: {:?}",
-                        discrete.width, discrete.height, err
-                    )
-                })
-                .map(move |intervals| intervals.into_iter().map(move |f| (fourcc, f)))
-                .ok()
-        })
-        .flatten()
-        .map(|(fourcc, f)| {
-            let frame_period = match f.interval {
-                FrameIntervalEnum::Discrete(seconds) => seconds,
-                FrameIntervalEnum::Stepwise(Stepwise { min, .. }) => min,
-            };
-            (fourcc, frame_period, f.width, f.height)
-        })
-        // Filter out frame rates that exceed the max fps
-        .filter(|(_, frame_period, _, _)| {
-            frame_period.numerator <= max_fps * frame_period.denominator
-        })
-        // Get the highest resolution & fps within the spec'd fps
-        .max_by_key(|(fourcc, frame_period, width, height)| {
-            (
-                // Prefer larger resolutions
-                *width,
-                *height,
-                // Prefer higher framerates: Scale the fraction so that it doesn't round to zero for sorting purposes
-                frame_period.numerator * 1_000_000 / (frame_period.denominator),
-                // Prefer H264 over MJPEG
-                fourcc == &h264,
-            )
-        })
-        .ok_or_else(|| StreamError::NoSupportedConfiguration)?;
+/// Opens the video capture device whose card name contains `name`, eg. `get_device_by_name("C920")`.
+///
+/// Returns `DeviceError::MultipleMatches` if more than one device matches.
+pub fn get_device_by_name(name: &str) -> Result<Device, DeviceError> {
+    find_device(|info| info.card.contains(name))
+}
 
-    // Explicitly request the video width, height and fps
-    let mut fmt = Format::new(width, height, FourCC::new(b"H264"));
+/// Opens the video capture device attached at the given bus, eg. `get_device_by_bus_info("usb-0000:00:14.0-1")`.
+///
+/// Unlike `/dev/videoN` paths the bus info of a device stays the same across reboots as long as it is plugged into the
+/// same port.
+pub fn get_device_by_bus_info(bus_info: &str) -> Result<Device, DeviceError> {
+    find_device(|info| info.bus_info == bus_info)
+}
 
-    fmt.fourcc = fourcc;
+fn find_device(predicate: impl Fn(&DeviceInfo) -> bool) -> Result<Device, DeviceError> {
+    let mut matches = list_devices_info()
+        .into_iter()
+        .filter(|info| predicate(info))
+        .collect::<Vec<_>>();
 
-    dev.set_format(&fmt)
-        .map_err(|err| StreamError::SettingsFailure(err))?;
+    if matches.len() > 1 {
+        let paths = matches.into_iter().map(|info| info.path).collect();
+        return Err(DeviceError::MultipleMatches(paths));
+    }
 
-    let params = Parameters::new(frame_period);
-    dev.set_params(&params)
-        .map_err(|err| StreamError::SettingsFailure(err))?;
+    let info = matches.pop().ok_or(DeviceError::DeviceNotFound)?;
 
-    let stream = MmapStream::with_buffers(&mut dev, Type::VideoCapture, 4)
-        .map_err(|e| StreamError::BufferStreamFailure(e))?;
+    Device::with_path(&info.path).map_err(|err| DeviceError::open_failed(&info.path, err))
+}
 
-    let encoder_mode = if fourcc == h264 {
-        let h264_decoder = openh264::decoder::Decoder::new()?;
-        EncoderMode::H264Native(h264_decoder)
-    } else {
-        let h264_encoder_config = openh264::encoder::EncoderConfig::new(width, height);
-        let h264_encoder = openh264::encoder::Encoder::with_config(h264_encoder_config)?;
-        EncoderMode::MjpegNative(h264_encoder)
-    };
+pub fn stream(dev: &mut Device, max_fps: u32) -> Result<WebcamH264Stream<'_>, StreamError> {
+    WebcamH264Stream::builder(dev).max_fps(max_fps).open()
+}
 
-    Ok(WebcamH264Stream {
-        encoder_mode,
-        stream,
-        width,
-        height,
-    })
+/// Opens a stream with exactly the given configuration, eg. one returned by [`enumerate_configurations`]. Other
+/// options can be set with [`StreamBuilder::capture_config`].
+pub fn stream_with_config<'a>(
+    dev: &'a mut Device,
+    config: &CaptureConfig,
+) -> Result<WebcamH264Stream<'a>, StreamError> {
+    WebcamH264Stream::builder(dev)
+        .capture_config(*config)
+        .open()
 }
 
+/// A YUV frame, borrowed from the decoder or a stream's scratch buffer until the next frame is read, or in a buffer of
+/// its own.
 pub enum YUVFrame<'a> {
+    #[cfg(feature = "openh264")]
     Decoded(DecodedYUV<'a>),
+    /// A frame that was converted into the stream's scratch buffer (eg. from MJPEG or YUYV), which is reused for the
+    /// next frame.
+    Borrowed(&'a YUVBuffer),
     Buffer(YUVBuffer),
 }
 
+/// A borrowed grayscale view of a frame's Y plane, see [`YUVFrame::luma`].
+#[derive(Debug, Clone, Copy)]
+pub struct LumaView<'a> {
+    pub width: usize,
+    pub height: usize,
+    /// The distance between the start of each row in bytes, which may be larger than the width for decoded frames.
+    pub stride: usize,
+    /// The plane's rows including any stride padding.
+    pub data: &'a [u8],
+}
+
+impl<'a> LumaView<'a> {
+    /// Returns row `y` without its padding.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if `y` is not less than the height.
+    pub fn row(&self, y: usize) -> &'a [u8] {
+        assert!(y < self.height, "Row {} is outside of the frame", y);
+
+        let start = y * self.stride;
+        &self.data[start..start + self.width]
+    }
+
+    /// Iterates over the rows without their padding.
+    pub fn rows(&self) -> impl Iterator<Item = &'a [u8]> {
+        let luma = *self;
+        (0..luma.height).map(move |y| luma.row(y))
+    }
+
+    /// Returns true if the rows have no padding so `data` can be used as a packed grayscale image.
+    pub fn is_packed(&self) -> bool {
+        self.stride == self.width
+    }
+}
+
+/// A YUV frame that does not borrow from the stream's decoder. It can be held across calls to `next()` and sent to
+/// other threads.
+pub type OwnedYUVFrame = YUVFrame<'static>;
+
 impl<'a> YUVFrame<'a> {
+    /// The width of the visible picture in pixels. Decoded frames may be stored with larger strides.
+    pub fn width(&self) -> usize {
+        self.source().width() as usize
+    }
+
+    /// The height of the visible picture in pixels.
+    pub fn height(&self) -> usize {
+        self.source().height() as usize
+    }
+
+    /// The row strides of the (Y, U, V) planes in bytes.
+    pub fn strides(&self) -> (usize, usize, usize) {
+        let source = self.source();
+        (
+            source.y_stride() as usize,
+            source.u_stride() as usize,
+            source.v_stride() as usize,
+        )
+    }
+
+    /// The Y (luma) plane, including any stride padding.
+    pub fn y(&self) -> &[u8] {
+        self.source().y()
+    }
+
+    /// The U (blue projection) plane at half resolution, including any stride padding.
+    pub fn u(&self) -> &[u8] {
+        self.source().u()
+    }
+
+    /// The V (red projection) plane at half resolution, including any stride padding.
+    pub fn v(&self) -> &[u8] {
+        self.source().v()
+    }
+
+    /// Borrows the Y (luma) plane as a grayscale image without copying it, eg. for computer vision models.
+    pub fn luma(&self) -> LumaView<'_> {
+        let source = self.source();
+
+        LumaView {
+            width: source.width() as usize,
+            height: source.height() as usize,
+            stride: source.y_stride() as usize,
+            data: source.y(),
+        }
+    }
+
+    /// Copies the Y plane into `out` as a tightly packed grayscale image (ie. with a stride equal to the width), reusing
+    /// its allocation.
+    pub fn luma_packed(&self, out: &mut Vec<u8>) {
+        let luma = self.luma();
+
+        out.clear();
+        out.reserve(luma.width * luma.height);
+        luma.rows().for_each(|row| out.extend_from_slice(row));
+    }
+
+    fn source(&self) -> &dyn YUVSource {
+        match self {
+            #[cfg(feature = "openh264")]
+            Self::Decoded(yuv) => yuv,
+            Self::Borrowed(yuv) => *yuv,
+            Self::Buffer(yuv) => yuv,
+        }
+    }
+
+    /// Copies the frame's planes so that it no longer borrows from the decoder.
+    pub fn to_owned(&self) -> OwnedYUVFrame {
+        match self {
+            #[cfg(feature = "openh264")]
+            Self::Decoded(yuv) => YUVFrame::Buffer(YUVBuffer::from_source(yuv)),
+            Self::Borrowed(yuv) => YUVFrame::Buffer((*yuv).clone()),
+            Self::Buffer(yuv) => YUVFrame::Buffer(yuv.clone()),
+        }
+    }
+
+    /// Returns the number of bytes needed to hold the frame as packed RGB, ie. the size of the buffer for
+    /// [`YUVFrame::to_rgb`].
+    pub fn rgb_len(&self) -> usize {
+        self.width() * self.height() * 3
+    }
+
+    /// Returns the number of bytes needed to hold the frame as packed RGBA.
+    pub fn rgba_len(&self) -> usize {
+        self.width() * self.height() * 4
+    }
+
+    /// Converts the frame to packed `[rgb rgb rgb ...]` using BT.601 limited range coefficients.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if `out` is not [`YUVFrame::rgb_len`] bytes long.
+    pub fn to_rgb(&self, out: &mut [u8]) {
+        self.to_rgb_with_range(out, ColorRange::Limited)
+    }
+
+    /// Converts the frame to packed `[rgba rgba rgba ...]` (with an opaque alpha channel) using BT.601 limited range
+    /// coefficients.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if `out` is not [`YUVFrame::rgba_len`] bytes long.
+    pub fn to_rgba(&self, out: &mut [u8]) {
+        self.to_rgba_with_range(out, ColorRange::Limited)
+    }
+
+    /// Same as `to_rgb` but for frames in the given color range.
+    pub fn to_rgb_with_range(&self, out: &mut [u8], range: ColorRange) {
+        match self {
+            #[cfg(feature = "openh264")]
+            Self::Decoded(yuv) => yuv::write_rgb(yuv, out, 3, range),
+            Self::Borrowed(yuv) => yuv::write_rgb(*yuv, out, 3, range),
+            Self::Buffer(yuv) => yuv::write_rgb(yuv, out, 3, range),
+        }
+    }
+
+    /// Same as `to_rgba` but for frames in the given color range.
+    pub fn to_rgba_with_range(&self, out: &mut [u8], range: ColorRange) {
+        match self {
+            #[cfg(feature = "openh264")]
+            Self::Decoded(yuv) => yuv::write_rgb(yuv, out, 4, range),
+            Self::Borrowed(yuv) => yuv::write_rgb(*yuv, out, 4, range),
+            Self::Buffer(yuv) => yuv::write_rgb(yuv, out, 4, range),
+        }
+    }
+
+    /// Converts the frame to a newly allocated packed RGB buffer. See [`YUVFrame::to_rgb`].
+    pub fn to_rgb_vec(&self) -> Vec<u8> {
+        let mut rgb = vec![0; self.rgb_len()];
+        self.to_rgb(&mut rgb);
+        rgb
+    }
+
+    /// Counts the number of pixels with each luma (brightness) value. Stride padding is skipped.
+    pub fn luma_histogram(&self) -> [u32; 256] {
+        yuv::luma_histogram(self.source(), 1)
+    }
+
+    /// Same as `luma_histogram` but only counts every `step`th pixel horizontally and vertically, which is much faster
+    /// for large frames.
+    pub fn luma_histogram_sampled(&self, step: usize) -> [u32; 256] {
+        yuv::luma_histogram(self.source(), step)
+    }
+
+    /// The average luma of the frame, sampling every 4th pixel. Luma ranges from 16 (black) to 235 (white).
+    pub fn mean_luma(&self) -> f32 {
+        let histogram = self.luma_histogram_sampled(4);
+        let count = histogram.iter().sum::<u32>();
+
+        if count == 0 {
+            return 0.0;
+        }
+
+        let total = histogram
+            .iter()
+            .enumerate()
+            .map(|(luma, count)| luma as u64 * *count as u64)
+            .sum::<u64>();
+
+        total as f32 / count as f32
+    }
+
+    /// Returns true if the frame's mean luma is below `threshold`, eg. when the scene is dark.
+    pub fn is_underexposed(&self, threshold: u8) -> bool {
+        self.mean_luma() < threshold as f32
+    }
+
+    /// Returns true if the frame's mean luma is above `threshold`.
+    pub fn is_overexposed(&self, threshold: u8) -> bool {
+        self.mean_luma() > threshold as f32
+    }
+
+    /// Copies the `rect` region out of the frame. The rectangle's position and size are rounded down to even values so
+    /// that it lines up with the half resolution chroma planes.
+    pub fn crop(&self, rect: Rect) -> Result<YUVBuffer, StreamError> {
+        let rect = resize::validate_crop(rect, (self.width() as u32, self.height() as u32))?;
+
+        Ok(resize::crop(self.source(), rect))
+    }
+
+    /// Resizes the frame to `width` x `height`, which must be even.
+    pub fn scale(
+        &self,
+        width: u32,
+        height: u32,
+        filter: ScaleFilter,
+    ) -> Result<YUVBuffer, StreamError> {
+        resize::validate_size(width, height)?;
+
+        let mut out = YUVBuffer::new(width as usize, height as usize);
+        resize::scale_into(self.source(), &mut out, filter);
+
+        Ok(out)
+    }
+
+    /// Rotates the frame clockwise. 90° and 270° rotations swap the width and height.
+    pub fn rotate(&self, rotation: Rotation) -> YUVBuffer {
+        self.transform(rotation.into())
+    }
+
+    /// Mirrors the frame.
+    pub fn flip(&self, axis: FlipAxis) -> YUVBuffer {
+        self.transform(axis.into())
+    }
+
+    /// Flips and then rotates the frame.
+    pub fn transform(&self, transform: Transform) -> YUVBuffer {
+        let mut out = YUVBuffer::new(0, 0);
+        orientation::transform_into(self.source(), &mut out, transform);
+        out
+    }
+
+    /// Scales the frame to the spec's size, converts it to RGB or gray and normalizes it into `out` in a single pass,
+    /// eg. to prepare the input of a machine learning model.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if `out` is not [`TensorSpec::element_count`] values long.
+    pub fn write_tensor(&self, spec: &TensorSpec, out: &mut [f32]) {
+        tensor::write_tensor(self.source(), spec, out)
+    }
+
+    /// Same as `write_tensor` but returns a newly allocated array in the spec's layout (requires the `ndarray` feature).
+    #[cfg(feature = "ndarray")]
+    pub fn to_tensor(&self, spec: &TensorSpec) -> ndarray::Array3<f32> {
+        let mut tensor = ndarray::Array3::zeros(spec.shape());
+        // Newly allocated arrays are contiguous in standard (row major) order
+        self.write_tensor(spec, tensor.as_slice_mut().expect("tensor is contiguous"));
+        tensor
+    }
+
+    /// Encodes the frame as a JPEG still image. `quality` ranges from 1 to 100.
+    ///
+    /// Only the visible area of the frame is encoded, any stride padding is skipped.
+    pub fn to_jpeg(&self, quality: u8) -> Result<Vec<u8>, StreamError> {
+        self.to_jpeg_cropped(usize::MAX, usize::MAX, quality)
+    }
+
+    fn to_jpeg_cropped(
+        &self,
+        width: usize,
+        height: usize,
+        quality: u8,
+    ) -> Result<Vec<u8>, StreamError> {
+        match self {
+            #[cfg(feature = "openh264")]
+            Self::Decoded(yuv) => jpeg::encode(yuv, width, height, quality),
+            Self::Borrowed(yuv) => jpeg::encode(*yuv, width, height, quality),
+            Self::Buffer(yuv) => jpeg::encode(yuv, width, height, quality),
+        }
+    }
+
     /// Encodes the frame as h264 and returns the encoded bitstream.
+    #[cfg(feature = "openh264")]
     pub fn encode_using<'b>(
         &self,
         encoder: &'b mut openh264::encoder::Encoder,
     ) -> Result<EncodedBitStream<'b>, StreamError> {
         match self {
             Self::Decoded(yuv) => Ok(encoder.encode(yuv)?),
+            Self::Borrowed(yuv) => Ok(encoder.encode(*yuv)?),
             Self::Buffer(yuv) => Ok(encoder.encode(yuv)?),
         }
     }
 }
 
+impl WebcamH264Stream<'static> {
+    /// Returns a builder for a stream that takes ownership of the device.
+    ///
+    /// This is the recommended way to open a stream. The resulting [`OwnedWebcamH264Stream`] is `'static` and `Send`.
+    pub fn from_device(dev: Device) -> StreamBuilder<'static> {
+        StreamBuilder::with_device(dev)
+    }
+}
+
 impl<'a> WebcamH264Stream<'a> {
+    /// Returns a builder for configuring the resolution, frame rate and format of a stream that borrows the device.
+    pub fn builder(dev: &'a mut Device) -> StreamBuilder<'a> {
+        StreamBuilder::new(dev)
+    }
+
+    /// The frame interval (1 / fps) negotiated with the camera.
+    pub fn frame_interval(&self) -> Fraction {
+        self.frame_interval
+    }
+
+    /// The frame rate negotiated with the camera.
+    pub fn fps(&self) -> f64 {
+        self.frame_interval.denominator as f64 / self.frame_interval.numerator as f64
+    }
+
+    /// The pixel format the camera is streaming in (H264, MJPG, YUYV, UYVY, NV12 or 8-bit Bayer).
+    pub fn fourcc(&self) -> FourCC {
+        self.fourcc
+    }
+
+    /// The device the stream was opened from, eg. for changing its [`controls`] while streaming.
+    ///
+    /// Controls (exposure, focus, white balance etc.) and queries such as `query_caps` and the format enumerations are
+    /// safe to use while streaming. Changing the format or frame rate (`set_format`, `set_params`) is not, since the
+    /// stream's buffers and encoder were created for the negotiated format; use [`reconfigure`](Self::reconfigure)
+    /// instead. Every v4l operation takes `&Device` so there is no mutable accessor, which would also allow reading
+    /// from the device behind the stream's back.
+    pub fn device(&self) -> &Device {
+        self.device.get()
+    }
+
+    /// Sets how frames that fail to decode are handled.
+    pub fn set_error_policy(&mut self, error_policy: ErrorPolicy) {
+        self.error_policy = error_policy;
+    }
+
+    /// Sets which of the waiting frames are read, see [`DequeuePolicy`].
+    pub fn set_dequeue_policy(&mut self, dequeue_policy: DequeuePolicy) {
+        self.dequeue_policy = dequeue_policy;
+    }
+
+    /// Sets how frames preceding the first key frame are handled. This also realigns the stream to the next key frame
+    /// (see `realign_to_keyframe`).
+    ///
+    /// Streams reopened by a [`ReconnectingStream`] do not keep this setting so it should be set with
+    /// [`StreamBuilder::keyframe_alignment`] instead.
+    pub fn set_keyframe_alignment(&mut self, keyframe_alignment: KeyframeAlignment) {
+        self.keyframe_alignment = keyframe_alignment;
+        self.realign_to_keyframe();
+    }
+
+    /// Discards frames until the next key frame, eg. after `request_keyframe` to start a new recording. Has no effect
+    /// if keyframe alignment is disabled.
+    ///
+    /// Transcoded streams force their next frame to be a key frame instead so no frames are discarded.
+    pub fn realign_to_keyframe(&mut self) {
+        if self.keyframe_alignment == KeyframeAlignment::Disabled {
+            return;
+        }
+
+        if self.is_native_h264() {
+            self.awaiting_keyframe = true;
+        } else {
+            self.keyframe_requested = true;
+        }
+    }
+
+    /// Draws text (eg. a timestamp) or an image (eg. a logo) onto each frame before it is encoded. Overlays are drawn in
+    /// the order they were added and are also visible in the stream's YUV frames.
+    ///
+    /// Overlays require the frames to be encoded by openh264 so they have no effect on cameras that produce H264
+    /// natively (see [`StreamBuilder::prefer_fourcc`] to transcode MJPEG instead) or on passed through JPEGs.
+    pub fn add_overlay(&mut self, overlay: overlay::Overlay) {
+        self.overlays.push(overlay);
+    }
+
+    /// The stream's overlays, eg. to move an image overlay or change its text between frames.
+    pub fn overlays_mut(&mut self) -> &mut Vec<overlay::Overlay> {
+        &mut self.overlays
+    }
+
+    /// Removes all overlays.
+    pub fn clear_overlays(&mut self) {
+        self.overlays.clear();
+    }
+
+    /// Whether the stream's [`Transform`] (see [`StreamBuilder::transform`]) is applied by the camera or in software, or
+    /// `None` if no transform was configured.
+    pub fn transform_mode(&self) -> Option<TransformMode> {
+        self.transform_mode
+    }
+
+    /// The stream's capture statistics since it was opened or since `reset_stats` was last called.
+    pub fn stats(&self) -> StreamStats {
+        self.stats.snapshot(self.corrupt_frames)
+    }
+
+    /// The time from the kernel capturing the most recent frame to the stream returning it, ie. the time the frame was
+    /// queued in the driver's buffers (or a pipeline's queue) plus the time spent decoding and encoding it. Returns None
+    /// before the first frame or if the driver's timestamps are not CLOCK_MONOTONIC. See also
+    /// [`StreamStats::mean_latency`] and [`LatencyProfile::Low`].
+    pub fn measured_latency(&self) -> Option<Duration> {
+        self.stats.last_latency()
+    }
+
+    /// Restarts the stream's statistics from zero.
+    pub fn reset_stats(&mut self) {
+        self.stats.reset(self.corrupt_frames);
+    }
+
+    /// The number of corrupt frames skipped since the stream was opened (see [`ErrorPolicy::SkipCorruptFrames`]).
+    pub fn corrupt_frames(&self) -> u64 {
+        self.corrupt_frames
+    }
+
+    /// Returns a receiver of the stream's health events, eg. disconnections, stalls and skipped corrupt frames, for a
+    /// supervisor to act on. Events are emitted from `next()` (and the other methods that read frames) without ever
+    /// blocking it: if a receiver falls behind by more than 256 events further events are dropped for it and counted in
+    /// `dropped_events`.
+    pub fn subscribe_events(&self) -> Receiver<HealthEvent> {
+        self.events.subscribe()
+    }
+
+    /// The number of health events dropped because a receiver from `subscribe_events` was full.
+    pub fn dropped_events(&self) -> u64 {
+        self.events.dropped_events()
+    }
+
+    /// The validation report for the most recent frame, or `None` if the stream's bitstream is not validated (see
+    /// [`StreamBuilder::validate_bitstream`]) or no frame has been read yet.
+    pub fn last_validation(&self) -> Option<&ValidationReport> {
+        self.validation.as_ref()?.last_report.as_ref()
+    }
+
+    /// Requests that the next frame is encoded as a key frame (IDR), eg. when a new viewer joins a live stream.
+    ///
+    /// When transcoding to H264 the openh264 encoder is forced to produce an IDR frame. Native H264 cameras are sent the
+    /// V4L2 force key frame control, returning `StreamError::KeyframeRequestUnsupported` if the driver rejects it.
+    pub fn request_keyframe(&mut self) -> Result<(), StreamError> {
+        if self.is_native_h264() {
+            force_keyframe(&self.handle)?;
+        } else {
+            self.keyframe_requested = true;
+        }
+
+        self.events.emit(HealthEventKind::KeyframeForced);
+        Ok(())
+    }
+
+    /// Sets the bitrate of a native H264 camera's hardware encoder in bits per second. Can be changed while streaming.
+    ///
+    /// Returns `StreamError::ControlUnsupported` for transcoded streams (see [`EncoderOptions`]) and for cameras without
+    /// a bitrate control.
+    pub fn set_hw_bitrate(&mut self, bps: u32) -> Result<(), StreamError> {
+        self.set_hw_control(
+            "bitrate",
+            &[v4l::v4l_sys::V4L2_CID_MPEG_VIDEO_BITRATE],
+            bps as i64,
+        )
+    }
+
+    /// Sets the number of frames between key frames of a native H264 camera's hardware encoder.
+    pub fn set_hw_gop(&mut self, frames: u32) -> Result<(), StreamError> {
+        self.set_hw_control(
+            "GOP size",
+            &[
+                v4l::v4l_sys::V4L2_CID_MPEG_VIDEO_H264_I_PERIOD,
+                v4l::v4l_sys::V4L2_CID_MPEG_VIDEO_GOP_SIZE,
+            ],
+            frames as i64,
+        )
+    }
+
+    /// Sets the H264 profile of a native H264 camera's hardware encoder.
+    pub fn set_hw_profile(&mut self, profile: controls::H264Profile) -> Result<(), StreamError> {
+        self.set_hw_control(
+            "H264 profile",
+            &[v4l::v4l_sys::V4L2_CID_MPEG_VIDEO_H264_PROFILE],
+            profile.control_value(),
+        )
+    }
+
+    /// Sets the first of the given controls that the camera supports.
+    fn set_hw_control(
+        &self,
+        name: &'static str,
+        ids: &[u32],
+        value: i64,
+    ) -> Result<(), StreamError> {
+        if !self.is_native_h264() {
+            return Err(StreamError::ControlUnsupported(name));
+        }
+
+        for &id in ids {
+            match controls::set_control(self.device(), id, value) {
+                Err(controls::ControlError::NotFound(_)) => continue,
+                result => return Ok(result?),
+            }
+        }
+
+        Err(StreamError::ControlUnsupported(name))
+    }
+
+    /// The most recent SPS and PPS NAL units (without start codes) seen in the stream.
+    ///
+    /// Prepending these to the stream allows consumers that join mid-stream to start decoding at the next key frame.
+    /// Returns None until both have been received.
+    pub fn parameter_sets(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        let nal::ParameterSets { sps, pps, .. } = &self.parameter_sets;
+        Some((sps.clone()?, pps.clone()?))
+    }
+
+    /// The coded format parsed from the stream's current SPS, eg. for codec strings such as `avc1.64001f`. Returns
+    /// None until an SPS has been received or if it could not be parsed.
+    pub fn sps_info(&self) -> Option<SpsInfo> {
+        self.parameter_sets.sps_info
+    }
+
+    /// A description of the stream's current format and bitrate (over the last 10 seconds), eg. for answering capability
+    /// queries or building an SDP. Returns None until the SPS and PPS have been received.
+    pub fn descriptor(&self) -> Option<StreamDescriptor> {
+        let (sps, pps) = self.parameter_sets()?;
+        let bitrate = self.stats().bitrate_10s;
+
+        Some(StreamDescriptor {
+            bitrate: (bitrate > 0.0).then_some(bitrate),
+            ..StreamDescriptor::new(&sps, &pps, self.fps())?
+        })
+    }
+
+    /// The AVCDecoderConfigurationRecord (avcC box / extradata) for the stream's current parameter sets, for use by
+    /// muxers. Returns None until the stream has a complete SPS and PPS.
+    pub fn avc_decoder_configuration_record(&self) -> Option<Vec<u8>> {
+        let nal::ParameterSets { sps, pps, .. } = &self.parameter_sets;
+
+        bitstream::build_avc_decoder_configuration_record(&[sps.as_ref()?], &[pps.as_ref()?]).ok()
+    }
+
+    /// Returns true if the camera produces H264 itself, false if the frames are transcoded to H264 by openh264.
+    pub fn is_native_h264(&self) -> bool {
+        self.encoder_mode.is_native_h264()
+    }
+
     /// Gets the next H264-encoded bitstream.
     ///
     /// If get_yuv_frame is true then it also returns a YUV image of the latest frame in the returned bitstream.
@@ -204,41 +1265,874 @@ impl<'a> WebcamH264Stream<'a> {
     pub fn next(
         &mut self,
         get_yuv_frame: bool,
-    ) -> Result<(Vec<u8>, Option<YUVFrame>), StreamError> {
-        let (buf, _meta) = self
-            .stream
-            .next()
-            .map_err(|e| StreamError::StreamFailure(e))?;
+    ) -> Result<(Vec<u8>, Option<YUVFrame<'_>>), StreamError> {
+        let (_meta, h264_bytes, yuv_frame) = self.next_with_meta(get_yuv_frame)?;
+
+        Ok((h264_bytes, yuv_frame))
+    }
+
+    /// Gets the next YUV frame along with all of the H264 bytes read while waiting for it.
+    ///
+    /// Unlike `next(true)` this never returns a None frame; frames are read until one can be produced or the stream's
+    /// `max_yuv_attempts` is exceeded. The frame is owned so in native H264 mode it is copied out of the decoder.
+    pub fn next_yuv(&mut self) -> Result<(Vec<u8>, OwnedYUVFrame), StreamError> {
+        let mut h264_bytes = Vec::new();
+
+        for _ in 0..self.max_yuv_attempts {
+            let (bytes, yuv_frame) = self.next(true)?;
+            h264_bytes.extend_from_slice(&bytes);
+
+            let yuv_frame = match yuv_frame {
+                Some(YUVFrame::Buffer(yuv)) => YUVFrame::Buffer(yuv),
+                Some(yuv) => yuv.to_owned(),
+                None => continue,
+            };
+
+            return Ok((h264_bytes, yuv_frame));
+        }
+
+        Err(StreamError::NoYUVFrame(self.max_yuv_attempts))
+    }
+
+    /// Reads frames until a YUV frame is produced and returns it encoded as a JPEG. `quality` ranges from 1 to 100.
+    ///
+    /// The image is cropped to the stream's resolution in case the decoded picture is padded (eg. to a multiple of 16).
+    /// Like `next_yuv` this gives up after the stream's `max_yuv_attempts`.
+    pub fn snapshot_jpeg(&mut self, quality: u8) -> Result<Vec<u8>, StreamError> {
+        let (width, height) = (self.width as usize, self.height as usize);
+
+        for _ in 0..self.max_yuv_attempts {
+            if let (_, Some(yuv_frame)) = self.next(true)? {
+                return yuv_frame.to_jpeg_cropped(width, height, quality);
+            }
+        }
+
+        Err(StreamError::NoYUVFrame(self.max_yuv_attempts))
+    }
+
+    /// Same as `next` but returns `StreamError::Timeout` if the camera does not deliver a frame within `timeout`.
+    ///
+    /// The device is opened non-blocking and polled so a wedged camera will not block the calling thread indefinitely.
+    /// The stream can still be read from after a timeout.
+    pub fn next_timeout(
+        &mut self,
+        timeout: Duration,
+        get_yuv_frame: bool,
+    ) -> Result<(Vec<u8>, Option<YUVFrame<'_>>), StreamError> {
+        let mut h264_bytes = Vec::new();
+        let (_meta, yuv) = self.read_frame(&mut h264_bytes, get_yuv_frame, Some(timeout), false)?;
+
+        Ok((h264_bytes, yuv.map(RawYUV::into_frame)))
+    }
+
+    /// Reads the next frame without blocking, returning `None` if the camera has not delivered one yet.
+    ///
+    /// This is for driving the stream from an event loop (eg. mio, calloop or tokio's `AsyncFd`) by polling
+    /// [`readiness_fd`](Self::readiness_fd) for readability. The fd is level-triggered: it stays readable while frames
+    /// can be dequeued, so after it becomes readable call `try_next` until it returns `None`. It may still return `None`
+    /// after the fd was readable (eg. if a frame was skipped as corrupt or decimated), which is not an error.
+    #[allow(clippy::type_complexity)]
+    pub fn try_next(
+        &mut self,
+        get_yuv_frame: bool,
+    ) -> Result<Option<(Vec<u8>, Option<YUVFrame<'_>>)>, StreamError> {
+        let mut h264_bytes = Vec::new();
+        match self.read_frame(&mut h264_bytes, get_yuv_frame, Some(Duration::ZERO), false) {
+            Ok((_meta, yuv)) => Ok(Some((h264_bytes, yuv.map(RawYUV::into_frame)))),
+            Err(StreamError::Timeout) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// The file descriptor to poll for readability before calling [`try_next`](Self::try_next). This is the camera
+    /// itself, or for pipelined streams (see [`StreamBuilder::pipelined`]) an eventfd signalled by the capture thread.
+    ///
+    /// Reconfiguring a pipelined stream replaces its capture thread, so the fd should be re-registered afterwards.
+    /// Returns the camera's fd if the stream is not configured.
+    pub fn readiness_fd(&self) -> RawFd {
+        match &self.stream {
+            Some(stream) => stream.readiness_fd(),
+            None => self.handle.fd(),
+        }
+    }
+
+    /// Same as `next` but also returns the frame's capture timestamp and sequence number.
+    pub fn next_with_meta(
+        &mut self,
+        get_yuv_frame: bool,
+    ) -> Result<(FrameMeta, Vec<u8>, Option<YUVFrame<'_>>), StreamError> {
+        let mut h264_bytes = Vec::new();
+        let (meta, yuv) = self.read_frame(&mut h264_bytes, get_yuv_frame, None, false)?;
+
+        Ok((meta, h264_bytes, yuv.map(RawYUV::into_frame)))
+    }
+
+    /// Same as `next_with_meta` but returns an owned [`Frame`], copying the YUV frame out of the decoder if necessary.
+    pub fn next_frame(&mut self, get_yuv_frame: bool) -> Result<Frame, StreamError> {
+        let mut h264_bytes = Vec::new();
+        let (meta, yuv) = self.read_frame(&mut h264_bytes, get_yuv_frame, None, false)?;
+
+        let yuv = yuv.map(|yuv| match yuv {
+            #[cfg(feature = "openh264")]
+            RawYUV::Decoded(yuv) => YUVBuffer::from_source(&yuv),
+            RawYUV::Buffer(yuv) => yuv.clone(),
+        });
+
+        Ok(Frame {
+            meta,
+            h264_bytes,
+            yuv,
+        })
+    }
+
+    /// Reads the next frame in the camera's native compressed format.
+    ///
+    /// MJPEG cameras return each JPEG as-is without transcoding it to H264, and the JPEG is only decoded if
+    /// `get_yuv_frame` is true. H264 cameras return their bitstream as with `next`. Uncompressed (YUYV, UYVY and NV12)
+    /// cameras have no compressed format so their frames are still encoded as H264.
+    pub fn next_raw(
+        &mut self,
+        get_yuv_frame: bool,
+    ) -> Result<(RawFrame, Option<YUVFrame<'_>>), StreamError> {
+        let is_mjpeg = self.encoder_mode.is_mjpeg();
+
+        let mut bytes = Vec::new();
+        let (_meta, yuv) = self.read_frame(&mut bytes, get_yuv_frame, None, is_mjpeg)?;
+
+        let raw_frame = if is_mjpeg {
+            RawFrame::Jpeg(bytes)
+        } else {
+            RawFrame::H264(bytes)
+        };
+
+        Ok((raw_frame, yuv.map(RawYUV::into_frame)))
+    }
+
+    /// Same as `next_with_meta` but writes into caller-provided buffers so that they can be reused between frames.
+    ///
+    /// `h264_bytes` is cleared and filled with the next H264 bitstream. If `yuv_buffer` is given it is overwritten
+    /// (and resized if necessary) with the YUV frame; the returned bool is false if no YUV frame was produced.
+    pub fn next_into(
+        &mut self,
+        h264_bytes: &mut Vec<u8>,
+        yuv_buffer: Option<&mut YUVBuffer>,
+    ) -> Result<(FrameMeta, bool), StreamError> {
+        h264_bytes.clear();
+
+        let (meta, yuv) = self.read_frame(h264_bytes, yuv_buffer.is_some(), None, false)?;
+
+        let yuv_written = match (yuv, yuv_buffer) {
+            #[cfg(feature = "openh264")]
+            (Some(RawYUV::Decoded(yuv)), Some(yuv_buffer)) => {
+                yuv_buffer.copy_from(&yuv);
+                true
+            }
+            (Some(RawYUV::Buffer(yuv)), Some(yuv_buffer)) => {
+                yuv_buffer.copy_from(yuv);
+                true
+            }
+            _ => false,
+        };
+
+        Ok((meta, yuv_written))
+    }
+
+    /// Stops the camera streaming (`VIDIOC_STREAMOFF`), eg. while nobody is watching a preview, to save USB bandwidth.
+    /// The device stays configured so `resume` is instant. Reading frames while paused returns `StreamError::Paused`.
+    pub fn pause(&mut self) -> Result<(), StreamError> {
+        if !self.paused {
+            if let Some(stream) = &mut self.stream {
+                stream.stop().map_err(|err| {
+                    StreamError::from_io(err, |source| StreamError::StopFailed { source })
+                })?;
+            }
+            self.paused = true;
+        }
+
+        Ok(())
+    }
+
+    /// Restarts a paused stream with the same format. The buffers are requeued when the next frame is read.
+    ///
+    /// Native H264 cameras are asked for a key frame (and frames are discarded until one arrives if keyframe alignment
+    /// is enabled) so that the stream is decodable straight away. Transcoded streams keep their encoder state.
+    pub fn resume(&mut self) {
+        if !self.paused {
+            return;
+        }
+
+        self.paused = false;
+        self.last_frame_received = Instant::now();
+
+        if self.is_native_h264() {
+            if let Err(err) = self.request_keyframe() {
+                debug!("Unable to request a key frame on resume: {:?}", err);
+            }
+
+            self.realign_to_keyframe();
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// The number of captured frames dropped because a pipelined stream's queue was full, see
+    /// [`StreamBuilder::pipelined`]. Always 0 for streams that are not pipelined. The dropped frames leave gaps in the
+    /// sequence numbers so they are also counted in [`StreamStats::dropped_frames`]. Reset when the stream is
+    /// reconfigured.
+    pub fn dropped_raw_frames(&self) -> u64 {
+        match &self.stream {
+            Some(mplane::BufferStream::Pipelined(pipeline)) => pipeline.dropped_frames(),
+            _ => 0,
+        }
+    }
+
+    /// The number of captured frames skipped by [`StreamBuilder::frame_divisor`] or [`StreamBuilder::output_fps`].
+    pub fn decimated_frames(&self) -> u64 {
+        self.decimator
+            .as_ref()
+            .map_or(0, decimate::Decimator::decimated_frames)
+    }
+
+    /// Stops the stream and releases its buffers. An owned device is closed so the camera can be reopened immediately,
+    /// including by another process. Dropping the stream does the same on a best-effort basis, logging any errors
+    /// instead of returning them.
+    ///
+    /// Nothing is flushed: openh264 encodes each frame as soon as it is read, so every frame's H264 bytes have already
+    /// been returned by [`Self::next`].
+    pub fn shutdown(mut self) -> Result<(), StreamError> {
+        if let Some(stream) = &mut self.stream {
+            stream.stop().map_err(|err| {
+                StreamError::from_io(err, |source| StreamError::StopFailed { source })
+            })?;
+        }
+
+        // The buffers are released and the device closed when the stream is dropped
+        Ok(())
+    }
+
+    /// Reads the next frame, appending its H264 bitstream to `h264_bytes`.
+    ///
+    /// If `jpeg_passthrough` is true MJPEG frames are appended as-is instead of being transcoded.
+    #[cfg_attr(not(feature = "openh264"), allow(unused_variables, unused_mut))]
+    fn read_frame(
+        &mut self,
+        h264_bytes: &mut Vec<u8>,
+        get_yuv_frame: bool,
+        timeout: Option<Duration>,
+        jpeg_passthrough: bool,
+    ) -> Result<(FrameMeta, Option<RawYUV<'_>>), StreamError> {
+        if self.paused {
+            return Err(StreamError::Paused);
+        }
+
+        let Some(stream) = &mut self.stream else {
+            return Err(StreamError::NotConfigured);
+        };
+        let start = h264_bytes.len();
+
+        match timeout.or(self.stall_deadline) {
+            Some(timeout) => stream.set_timeout(timeout),
+            None => stream.clear_timeout(),
+        }
+
+        // A frame is stale if another one can be read without waiting
+        let latest_only = self.dequeue_policy == DequeuePolicy::LatestOnly;
+        let ready_fd = stream.readiness_fd();
+        // Whether the H264 returned for skipped native frames starts with a key frame
+        let mut starts_with_keyframe = false;
+        // The frames dropped by the driver before this frame, including before any frames that are skipped
+        let mut dropped_since_last = 0u32;
+
+        let (buf, mut meta, processing_start, monotonic_timestamp) = loop {
+            let (buf, meta) = match stream.next() {
+                Ok(next) => next,
+                Err(err) => return Err(self.dequeue_failed(err, timeout)),
+            };
+            let processing_start = Instant::now();
+            self.last_frame_received = processing_start;
+
+            let dropped = self.stats.record_sequence(meta.sequence);
+            if dropped > 0 {
+                dropped_since_last = dropped_since_last.saturating_add(dropped);
+                self.events.emit(HealthEventKind::FramesDropped {
+                    count: dropped,
+                    at: meta.timestamp.into(),
+                });
+            }
+
+            let monotonic_timestamp =
+                meta.flags & BufferFlags::TIMESTAMP_MASK == BufferFlags::TIMESTAMP_MONOTONIC;
+            let clock = *self.clock.get_or_insert_with(|| {
+                ClockMapping::detect(meta.timestamp.into(), monotonic_timestamp)
+            });
+
+            // Buffers are allocated at the maximum frame size so only the first bytesused bytes contain the frame. Some
+            // UVC drivers also deliver empty buffers on startup which are skipped.
+            if meta.bytesused > 0 {
+                let mut meta = FrameMeta {
+                    timestamp: meta.timestamp.into(),
+                    clock,
+                    sequence: meta.sequence,
+                    dropped_since_last,
+                    bytesused: meta.bytesused,
+                    is_keyframe: false,
+                };
+
+                let buf = &buf[..buf.len().min(meta.bytesused as usize)];
+
+                // Decimated frames are skipped before they are decoded, which is what makes decimating cheaper than
+                // discarding frames after reading them
+                if let Some(decimator) = &mut self.decimator {
+                    if !self.encoder_mode.is_native_h264() && !decimator.keep_frame(meta.timestamp)
+                    {
+                        continue;
+                    }
+                }
+
+                if latest_only
+                    && !self.encoder_mode.is_native_h264()
+                    && mplane::is_readable(ready_fd)
+                {
+                    self.stats.record_stale_frame();
+                    continue;
+                }
+
+                // JPEGs and uncompressed frames are read here rather than when encoding so that corrupt or short
+                // frames can be skipped by reading the next buffer. Passed through JPEGs are only decoded if a YUV
+                // frame is needed.
+                #[cfg(feature = "openh264")]
+                let decoded = match self.encoder_mode {
+                    EncoderMode::MjpegNative(_) if get_yuv_frame || !jpeg_passthrough => {
+                        decode_jpeg(buf, &mut self.yuv_buffer)
+                    }
+                    EncoderMode::YuyvNative(_)
+                    | EncoderMode::UyvyNative(_)
+                    | EncoderMode::Nv12Native(_)
+                    | EncoderMode::BayerNative(_) => {
+                        self.yuv_buffer.read_raw(self.fourcc, buf, self.raw_layout)
+                    }
+                    _ => Ok(()),
+                };
+                #[cfg(feature = "openh264")]
+                if let Err(err) = decoded {
+                    if self.error_policy == ErrorPolicy::SkipCorruptFrames {
+                        self.corrupt_frames += 1;
+                        warn!(
+                            "Skipping corrupt {} frame {}: {:?}",
+                            self.fourcc, meta.sequence, err
+                        );
+                        self.events.emit(HealthEventKind::CorruptFrameSkipped {
+                            sequence: meta.sequence,
+                            error: err.to_string(),
+                        });
+                        continue;
+                    }
+
+                    return Err(err);
+                }
+
+                if self.encoder_mode.is_native_h264() {
+                    meta.is_keyframe = self.parameter_sets.update(buf);
+
+                    // Some cameras encode a different resolution than the one negotiated (eg. after an internal
+                    // fallback) so the reported size follows the bitstream
+                    if let Some(sps) = self.parameter_sets.sps_info {
+                        if (sps.width, sps.height) != (self.width, self.height) {
+                            warn!(
+                                "The camera is encoding {}x{} H264 instead of the negotiated {}x{}",
+                                sps.width, sps.height, self.width, self.height
+                            );
+                            let old = StreamFormat {
+                                width: self.width,
+                                height: self.height,
+                                fourcc: self.fourcc,
+                                frame_interval: self.frame_interval,
+                            };
+                            self.width = sps.width;
+                            self.height = sps.height;
+                            self.events.emit(HealthEventKind::FormatChanged {
+                                old,
+                                new: StreamFormat {
+                                    width: sps.width,
+                                    height: sps.height,
+                                    ..old
+                                },
+                            });
+                        }
+                    }
+
+                    if let Some(decimator) = &mut self.decimator {
+                        if !decimator.keep_gop_frame(meta.timestamp, meta.is_keyframe) {
+                            continue;
+                        }
+                    }
+
+                    // Frames are discarded before decoding since the decoder cannot use them without the key frame
+                    if self.awaiting_keyframe && !meta.is_keyframe {
+                        debug!(
+                            "Discarding H264 frame {} before the key frame",
+                            meta.sequence
+                        );
+                        continue;
+                    }
 
-        match &mut self.encoder_mode {
+                    if latest_only && mplane::is_readable(ready_fd) {
+                        starts_with_keyframe |= h264_bytes.len() == start && meta.is_keyframe;
+                        h264_bytes.extend_from_slice(buf);
+
+                        if std::mem::take(&mut self.awaiting_keyframe)
+                            && self.keyframe_alignment
+                                == KeyframeAlignment::SkipToKeyframeWithParameterSets
+                        {
+                            self.parameter_sets.prepend_if_missing(h264_bytes, start);
+                        }
+
+                        // The decoder needs every frame, but the skipped frame's picture is not converted
+                        #[cfg(feature = "openh264")]
+                        if let (EncoderMode::H264Native(h264_decoder), true) =
+                            (&mut self.encoder_mode, get_yuv_frame)
+                        {
+                            let result = self.decoder_recovery.decode(
+                                h264_decoder,
+                                buf,
+                                &self.parameter_sets,
+                            );
+                            self.decoder_recovery.decoded(meta.sequence);
+
+                            if let Err(err) = result {
+                                match force_keyframe(&self.handle) {
+                                    Ok(()) => self.events.emit(HealthEventKind::KeyframeForced),
+                                    Err(err) => {
+                                        debug!("Unable to request a key frame to resync: {:?}", err)
+                                    }
+                                }
+
+                                if self.error_policy != ErrorPolicy::SkipCorruptFrames {
+                                    return Err(err);
+                                }
+                                self.corrupt_frames += 1;
+                                warn!("Failed to decode H264 frame {}: {}", meta.sequence, err);
+                                self.events.emit(HealthEventKind::CorruptFrameSkipped {
+                                    sequence: meta.sequence,
+                                    error: err.to_string(),
+                                });
+                            }
+                        }
+
+                        self.stats.record_stale_frame();
+                        continue;
+                    }
+
+                    meta.is_keyframe |= starts_with_keyframe;
+                }
+
+                break (buf, meta, processing_start, monotonic_timestamp);
+            }
+        };
+
+        // Transcoded frames after a small gap are encoded again for each dropped frame, so that the H264 has a frame for
+        // every frame interval
+        #[cfg(feature = "openh264")]
+        let duplicates = match meta.dropped_since_last {
+            dropped if dropped <= self.fill_dropped_frames => dropped,
+            _ => 0,
+        };
+
+        let yuv = match &mut self.encoder_mode {
+            // Without a decoder there is never a YUV frame
+            #[cfg(not(feature = "openh264"))]
+            EncoderMode::H264Passthrough => {
+                h264_bytes.extend_from_slice(buf);
+
+                if std::mem::take(&mut self.awaiting_keyframe)
+                    && self.keyframe_alignment == KeyframeAlignment::SkipToKeyframeWithParameterSets
+                {
+                    self.parameter_sets.prepend_if_missing(h264_bytes, start);
+                }
+
+                None
+            }
+            #[cfg(feature = "openh264")]
             EncoderMode::H264Native(h264_decoder) => {
-                let yuv_frame = if get_yuv_frame {
-                    h264_decoder
-                        .decode(buf)?
-                        .map(|decoded_yuv| YUVFrame::Decoded(decoded_yuv))
+                h264_bytes.extend_from_slice(buf);
+
+                if std::mem::take(&mut self.awaiting_keyframe)
+                    && self.keyframe_alignment == KeyframeAlignment::SkipToKeyframeWithParameterSets
+                {
+                    self.parameter_sets.prepend_if_missing(h264_bytes, start);
+                }
+
+                let yuv = if get_yuv_frame {
+                    let result =
+                        self.decoder_recovery
+                            .decode(h264_decoder, buf, &self.parameter_sets);
+                    self.decoder_recovery.decoded(meta.sequence);
+
+                    match result {
+                        Ok(yuv) => yuv.map(RawYUV::Decoded),
+                        Err(err) => {
+                            // The decoder resyncs at the next key frame, which the camera is asked for so that it
+                            // doesn't take a whole GOP
+                            match force_keyframe(&self.handle) {
+                                Ok(()) => self.events.emit(HealthEventKind::KeyframeForced),
+                                Err(err) => {
+                                    debug!("Unable to request a key frame to resync: {:?}", err)
+                                }
+                            }
+
+                            // The camera's bitstream is still passed through as-is, only the YUV frame is skipped
+                            if self.error_policy == ErrorPolicy::SkipCorruptFrames {
+                                self.corrupt_frames += 1;
+                                warn!("Failed to decode H264 frame {}: {}", meta.sequence, err);
+                                self.events.emit(HealthEventKind::CorruptFrameSkipped {
+                                    sequence: meta.sequence,
+                                    error: err.to_string(),
+                                });
+                                None
+                            } else {
+                                return Err(err);
+                            }
+                        }
+                    }
                 } else {
                     None
                 };
 
-                Ok((buf.to_vec(), yuv_frame))
+                yuv
+            }
+            #[cfg(feature = "openh264")]
+            EncoderMode::MjpegNative(_) if jpeg_passthrough => {
+                h264_bytes.extend_from_slice(buf);
+                // Every JPEG can be decoded on its own
+                meta.is_keyframe = true;
+
+                let yuv = get_yuv_frame.then_some(RawYUV::Buffer(&self.yuv_buffer));
+
+                yuv
             }
+            #[cfg(feature = "openh264")]
             EncoderMode::MjpegNative(h264_encoder) => {
-                let mut jpeg = jpeg_decoder::Decoder::new(buf);
-                let rgb_pixels = jpeg.decode()?;
+                force_keyframe_if_requested(h264_encoder, &mut self.keyframe_requested);
 
+                // The JPEG was already decoded into the YUV buffer while reading it
                 let yuv_buffer =
-                    YUVBuffer::with_rgb(self.width as usize, self.height as usize, &rgb_pixels[..]);
+                    prepare_for_encoding(&mut self.yuv_buffer, &mut self.transform, &self.overlays);
+                for _ in 0..duplicates {
+                    h264_encoder.encode(&*yuv_buffer)?.write_vec(h264_bytes);
+                }
+                self.stats.record_duplicated_frames(duplicates);
+                h264_encoder.encode(&*yuv_buffer)?.write_vec(h264_bytes);
+                meta.is_keyframe = self.parameter_sets.update(&h264_bytes[start..]);
 
-                let h264_bitstream = h264_encoder.encode(&yuv_buffer)?;
+                let yuv = get_yuv_frame.then_some(RawYUV::Buffer(yuv_buffer));
 
-                let yuv_frame = if get_yuv_frame {
-                    Some(YUVFrame::Buffer(yuv_buffer))
-                } else {
-                    None
-                };
+                yuv
+            }
+            #[cfg(feature = "openh264")]
+            EncoderMode::YuyvNative(h264_encoder)
+            | EncoderMode::UyvyNative(h264_encoder)
+            | EncoderMode::Nv12Native(h264_encoder)
+            | EncoderMode::BayerNative(h264_encoder) => {
+                // The raw frame was already read into the YUV buffer while reading it
+                let yuv_buffer =
+                    prepare_for_encoding(&mut self.yuv_buffer, &mut self.transform, &self.overlays);
+
+                force_keyframe_if_requested(h264_encoder, &mut self.keyframe_requested);
+
+                for _ in 0..duplicates {
+                    h264_encoder.encode(&*yuv_buffer)?.write_vec(h264_bytes);
+                }
+                self.stats.record_duplicated_frames(duplicates);
+                h264_encoder.encode(&*yuv_buffer)?.write_vec(h264_bytes);
+                meta.is_keyframe = self.parameter_sets.update(&h264_bytes[start..]);
+
+                let yuv = get_yuv_frame.then_some(RawYUV::Buffer(yuv_buffer));
+
+                yuv
+            }
+        };
 
-                Ok((h264_bitstream.to_vec(), yuv_frame))
+        if self.sei_timestamps && !jpeg_passthrough {
+            sei::insert_timestamp(h264_bytes, start, &meta);
+        }
+
+        // Passed through JPEGs are not H264 so there is nothing to validate
+        if let Some(validation) = self.validation.as_mut().filter(|_| !jpeg_passthrough) {
+            let report = validation.validate(&meta, &h264_bytes[start..]);
+            if !report.is_valid() {
+                warn!(
+                    "H264 frame {} failed validation: {}",
+                    meta.sequence,
+                    report
+                        .issues
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+                self.stats.record_invalid_frame();
+                self.events.emit(HealthEventKind::InvalidFrame {
+                    sequence: meta.sequence,
+                    issues: report.issues.clone(),
+                });
+
+                #[cfg(feature = "metrics")]
+                if let Some(metrics) = &self.metrics {
+                    metrics.record_invalid_frame();
+                }
             }
         }
+
+        let (bytes, processing_time) = (h264_bytes.len() - start, processing_start.elapsed());
+        let latency =
+            monotonic_timestamp.then(|| stats::monotonic_now().saturating_sub(meta.timestamp));
+        self.stats
+            .record_frame(bytes, processing_time, latency, self.corrupt_frames);
+
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &mut self.metrics {
+            metrics.record_frame(bytes, processing_time, &self.stats, self.corrupt_frames);
+        }
+
+        Ok((meta, yuv))
+    }
+
+    /// Converts an error dequeuing a buffer into a `StreamError`, emitting a health event for stalls and disconnects.
+    /// `timeout` is the caller's timeout, if any, which takes precedence over the stall deadline.
+    fn dequeue_failed(&mut self, err: std::io::Error, timeout: Option<Duration>) -> StreamError {
+        if err.kind() == std::io::ErrorKind::TimedOut {
+            if timeout.is_some() {
+                return StreamError::Timeout;
+            }
+
+            let since = self.last_frame_received;
+            self.events.emit(HealthEventKind::Stalled { since });
+            return StreamError::Stalled { since };
+        }
+
+        let err = StreamError::from_io(err, |source| StreamError::DequeueFailed { source });
+        if reconnect::is_disconnect(&err) {
+            self.events.emit(HealthEventKind::Disconnected {
+                error: err.to_string(),
+            });
+        }
+
+        err
+    }
+}
+
+/// Sends a native H264 camera the V4L2 force key frame control.
+fn force_keyframe(handle: &v4l::device::Handle) -> Result<(), StreamError> {
+    let mut control = v4l::v4l_sys::v4l2_control {
+        id: v4l::v4l_sys::V4L2_CID_MPEG_VIDEO_FORCE_KEY_FRAME,
+        value: 0,
+    };
+
+    // SAFETY: The control struct outlives the ioctl
+    unsafe {
+        v4l::v4l2::ioctl(
+            handle.fd(),
+            v4l::v4l2::vidioc::VIDIOC_S_CTRL,
+            &mut control as *mut _ as *mut std::os::raw::c_void,
+        )
+    }
+    .map_err(StreamError::KeyframeRequestUnsupported)
+}
+
+/// Crops and scales the captured frame (if configured) and draws the overlays onto it, returning the frame to encode.
+#[cfg(feature = "openh264")]
+fn prepare_for_encoding<'b>(
+    yuv_buffer: &'b mut YUVBuffer,
+    transform: &'b mut Option<resize::FrameTransform>,
+    overlays: &[overlay::Overlay],
+) -> &'b mut YUVBuffer {
+    let yuv_buffer = match transform {
+        Some(transform) => transform.apply(yuv_buffer),
+        None => yuv_buffer,
+    };
+
+    overlay::apply(overlays, yuv_buffer);
+    yuv_buffer
+}
+
+#[cfg(feature = "openh264")]
+fn force_keyframe_if_requested(
+    h264_encoder: &mut openh264::encoder::Encoder,
+    keyframe_requested: &mut bool,
+) {
+    if std::mem::take(keyframe_requested) {
+        // SAFETY: The encoder is initialized and exclusively borrowed
+        unsafe {
+            h264_encoder.raw_api().force_intra_frame(true);
+        }
+    }
+}
+
+/// A YUV frame borrowed from the stream's decoder or scratch buffer.
+#[cfg_attr(not(feature = "openh264"), allow(dead_code))]
+enum RawYUV<'a> {
+    #[cfg(feature = "openh264")]
+    Decoded(DecodedYUV<'a>),
+    Buffer(&'a YUVBuffer),
+}
+
+impl<'a> RawYUV<'a> {
+    fn into_frame(self) -> YUVFrame<'a> {
+        match self {
+            #[cfg(feature = "openh264")]
+            Self::Decoded(yuv) => YUVFrame::Decoded(yuv),
+            Self::Buffer(yuv) => YUVFrame::Borrowed(yuv),
+        }
+    }
+}
+
+/// Decodes a JPEG into the YUV buffer, failing if the JPEG is not the size of the buffer.
+#[cfg(feature = "openh264")]
+fn decode_jpeg(buf: &[u8], yuv_buffer: &mut YUVBuffer) -> Result<(), StreamError> {
+    let mut jpeg = jpeg_decoder::Decoder::new(buf);
+    jpeg.read_info()?;
+    let info = jpeg.info().expect("JPEG info is available after read_info");
+
+    // Cameras can send JPEGs of another size than the negotiated format, eg. while switching resolution
+    let expected = (yuv_buffer.width() as u32, yuv_buffer.height() as u32);
+    let actual = (info.width as u32, info.height as u32);
+    if actual != expected {
+        return Err(StreamError::FrameSizeMismatch { expected, actual });
+    }
+    if !actual.0.is_multiple_of(2) || !actual.1.is_multiple_of(2) {
+        return Err(StreamError::InvalidFrameSize(actual.0, actual.1));
+    }
+
+    match info.pixel_format {
+        jpeg_decoder::PixelFormat::RGB24 => {
+            // 3 component JPEGs are stored as YCbCr so "converting" them to RGB returns the YCbCr samples unchanged.
+            // This skips converting to RGB and then straight back to YUV.
+            jpeg.set_color_transform(jpeg_decoder::ColorTransform::RGB);
+            yuv_buffer.read_ycbcr(&jpeg.decode()?);
+        }
+        jpeg_decoder::PixelFormat::L8 => {
+            let rgb: Vec<u8> = jpeg.decode()?.iter().flat_map(|&luma| [luma; 3]).collect();
+            yuv_buffer.read_rgb(&rgb);
+        }
+        pixel_format => {
+            return Err(jpeg_decoder::Error::Format(format!(
+                "unsupported {pixel_format:?} JPEG pixel format"
+            ))
+            .into())
+        }
+    }
+
+    Ok(())
+}
+
+impl<'a> Drop for WebcamH264Stream<'a> {
+    fn drop(&mut self) {
+        if let Some(stream) = self.stream.take() {
+            stop_and_release(stream);
+        }
+    }
+}
+
+/// Stops the stream and then drops it, which releases its buffers.
+///
+/// v4l panics if the stream cannot be stopped (or its buffers released) when it is dropped, other than for unplugged
+/// devices. Stopping it first means that only happens if the driver fails to free its buffers, otherwise they are leaked.
+fn stop_and_release(mut stream: mplane::BufferStream) {
+    match stream.stop() {
+        Err(err) if err.raw_os_error() != Some(libc::ENODEV) => {
+            warn!("Failed to stop the stream, leaking its buffers: {:?}", err);
+            std::mem::forget(stream);
+        }
+        _ => drop(stream),
+    }
+}
+
+impl<'a> AsRawFd for WebcamH264Stream<'a> {
+    /// The camera's file descriptor. Event loops should poll [`readiness_fd`](WebcamH264Stream::readiness_fd) instead,
+    /// which also covers pipelined streams.
+    fn as_raw_fd(&self) -> RawFd {
+        self.handle.fd()
+    }
+}
+
+#[cfg(all(test, feature = "openh264"))]
+mod tests {
+    use super::*;
+    use crate::synthetic::tests::access_units;
+    use openh264::decoder::Decoder;
+
+    fn jpeg(width: u16, height: u16, color_type: jpeg_encoder::ColorType) -> Vec<u8> {
+        let bytes_per_pixel = match color_type {
+            jpeg_encoder::ColorType::Luma => 1,
+            _ => 3,
+        };
+        let pixels = vec![128; width as usize * height as usize * bytes_per_pixel];
+        let mut jpeg = Vec::new();
+        jpeg_encoder::Encoder::new(&mut jpeg, 90)
+            .encode(&pixels, width, height, color_type)
+            .unwrap();
+        jpeg
+    }
+
+    #[test]
+    fn decodes_jpegs_into_the_yuv_buffer() {
+        for color_type in [jpeg_encoder::ColorType::Rgb, jpeg_encoder::ColorType::Luma] {
+            let mut yuv_buffer = YUVBuffer::new(64, 48);
+            decode_jpeg(&jpeg(64, 48, color_type), &mut yuv_buffer).unwrap();
+
+            // Mid gray, with the luma in the limited range
+            let planes = [
+                (yuv_buffer.y(), 126),
+                (yuv_buffer.u(), 128),
+                (yuv_buffer.v(), 128),
+            ];
+            for (plane, expected) in planes {
+                assert!(
+                    plane.iter().all(|&sample| sample.abs_diff(expected) <= 2),
+                    "{color_type:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_jpegs_of_another_size() {
+        let mut yuv_buffer = YUVBuffer::new(64, 48);
+        let result = decode_jpeg(&jpeg(32, 24, jpeg_encoder::ColorType::Rgb), &mut yuv_buffer);
+        assert!(matches!(
+            result,
+            Err(StreamError::FrameSizeMismatch {
+                expected: (64, 48),
+                actual: (32, 24)
+            })
+        ));
+
+        let mut odd_buffer = YUVBuffer::new(63, 47);
+        let result = decode_jpeg(&jpeg(63, 47, jpeg_encoder::ColorType::Rgb), &mut odd_buffer);
+        assert!(matches!(result, Err(StreamError::InvalidFrameSize(63, 47))));
+    }
+
+    #[test]
+    fn decoded_frames_have_the_picture_dimensions() {
+        // 1080 rows are coded as 68 macroblock rows (1088 pixels) and cropped by the SPS
+        let access_unit = &access_units(1920, 1080, 1, 30)[0];
+        let mut decoder = Decoder::new().unwrap();
+        let frame = YUVFrame::Decoded(decoder.decode(access_unit).unwrap().unwrap());
+
+        assert_eq!((frame.width(), frame.height()), (1920, 1080));
+        let luma = frame.luma();
+        assert_eq!((luma.width, luma.height), (1920, 1080));
+        assert_eq!(luma.rows().count(), 1080);
+
+        let (y_stride, u_stride, v_stride) = frame.strides();
+        assert!(y_stride >= 1920 && u_stride >= 960 && v_stride >= 960);
+        assert!(frame.y().len() >= y_stride * 1079 + 1920);
+        assert!(frame.u().len() >= u_stride * 539 + 960);
+
+        let mut packed = Vec::new();
+        frame.luma_packed(&mut packed);
+        assert_eq!(packed.len(), 1920 * 1080);
     }
 }
@@ -0,0 +1,471 @@
+use crate::reconnect::{is_disconnect, Configure};
+use crate::{
+    DeviceSelector, Frame, OwnedWebcamH264Stream, StreamBuilder, StreamError, WebcamH264Stream,
+};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{
+    channel, sync_channel, Receiver, RecvError, RecvTimeoutError, Sender, SyncSender, TryRecvError,
+};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Identifies a camera added to a [`CaptureManager`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CameraId(usize);
+
+/// An event from one of a [`CaptureManager`]'s cameras.
+pub struct CameraEvent {
+    pub camera_id: CameraId,
+    pub kind: CameraEventKind,
+}
+
+pub enum CameraEventKind {
+    Frame(Frame),
+    /// The camera was opened, either when it was added or after `CameraHandle::start`.
+    Started {
+        width: u32,
+        height: u32,
+    },
+    /// The camera was closed by `CameraHandle::stop`.
+    Stopped,
+    /// The camera disconnected. It is reopened with exponential backoff (500ms doubling up to 10s).
+    Disconnected,
+    /// The camera was reopened after disconnecting or failing. Its bitstream restarts with new parameter sets.
+    Reconnected {
+        width: u32,
+        height: u32,
+    },
+    /// Opening or reading from the camera failed. The camera is reopened with the same backoff as disconnects.
+    Error(StreamError),
+}
+
+/// Captures any number of cameras, each on its own thread, and delivers their frames and lifecycle events to a single
+/// queue. Cameras that disconnect or fail are reopened automatically.
+///
+/// The queue holds up to `capacity` frames per camera. When a camera's frames are not received quickly enough its
+/// oldest queued frame is dropped (and counted, see [`CameraHandle::dropped_frames`]) so that a slow consumer cannot
+/// grow the queue without bound. Lifecycle events are never dropped.
+pub struct CaptureManager {
+    queue: Arc<EventQueue>,
+    cameras: Vec<CameraHandle>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+/// Controls one of a [`CaptureManager`]'s cameras. Handles can be cloned and sent to other threads.
+#[derive(Clone)]
+pub struct CameraHandle {
+    id: CameraId,
+    commands: Sender<Command>,
+    shared: Arc<CameraShared>,
+}
+
+/// Receives the events of a [`CaptureManager`]'s cameras. Receivers can be cloned, in which case each event is
+/// received by only one of them.
+#[derive(Clone)]
+pub struct EventReceiver {
+    queue: Arc<EventQueue>,
+}
+
+struct CameraShared {
+    yuv_frames: AtomicBool,
+    dropped_frames: AtomicU64,
+}
+
+enum Command {
+    Start,
+    Stop,
+    Snapshot {
+        quality: u8,
+        reply: SyncSender<Result<Vec<u8>, StreamError>>,
+    },
+    Shutdown,
+}
+
+struct EventQueue {
+    state: Mutex<QueueState>,
+    available: Condvar,
+    capacity: usize,
+}
+
+struct QueueState {
+    events: VecDeque<CameraEvent>,
+    // The events can no longer be added to once the manager and all of its camera threads are gone
+    manager_alive: bool,
+    threads: usize,
+}
+
+impl CaptureManager {
+    /// Creates a manager with no cameras whose queue holds up to `capacity` frames per camera.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            queue: Arc::new(EventQueue {
+                state: Mutex::new(QueueState {
+                    events: VecDeque::new(),
+                    manager_alive: true,
+                    threads: 0,
+                }),
+                available: Condvar::new(),
+                capacity: capacity.max(1),
+            }),
+            cameras: Vec::new(),
+            threads: Vec::new(),
+        }
+    }
+
+    /// Creates a manager and adds each of the cameras with their default configuration.
+    pub fn spawn(selectors: impl IntoIterator<Item = DeviceSelector>, capacity: usize) -> Self {
+        let mut manager = Self::new(capacity);
+
+        for selector in selectors {
+            manager.add_camera(selector, |builder| builder);
+        }
+
+        manager
+    }
+
+    /// Starts capturing a camera on a new thread. `configure` is applied to the builder each time the camera is
+    /// (re)opened, like [`ReconnectingStream::open`](crate::ReconnectingStream::open).
+    pub fn add_camera(
+        &mut self,
+        selector: DeviceSelector,
+        configure: impl FnMut(StreamBuilder<'static>) -> StreamBuilder<'static> + Send + 'static,
+    ) -> CameraHandle {
+        let id = CameraId(self.cameras.len());
+        let (commands, command_receiver) = channel();
+        let shared = Arc::new(CameraShared {
+            yuv_frames: AtomicBool::new(false),
+            dropped_frames: AtomicU64::new(0),
+        });
+
+        let camera = Camera {
+            id,
+            selector,
+            configure: Box::new(configure),
+            commands: command_receiver,
+            shared: Arc::clone(&shared),
+            queue: Arc::clone(&self.queue),
+        };
+
+        self.queue.state.lock().unwrap().threads += 1;
+        self.threads.push(std::thread::spawn(move || camera.run()));
+
+        let handle = CameraHandle {
+            id,
+            commands,
+            shared,
+        };
+        self.cameras.push(handle.clone());
+
+        handle
+    }
+
+    /// Returns a receiver for the cameras' events.
+    pub fn events(&self) -> EventReceiver {
+        EventReceiver {
+            queue: Arc::clone(&self.queue),
+        }
+    }
+
+    pub fn camera(&self, id: CameraId) -> Option<&CameraHandle> {
+        self.cameras.get(id.0)
+    }
+
+    pub fn cameras(&self) -> &[CameraHandle] {
+        &self.cameras
+    }
+
+    /// Stops every camera and waits for their threads to exit, which happens after their current frame.
+    pub fn shutdown(mut self) {
+        self.send_shutdown();
+
+        for thread in self.threads.drain(..) {
+            thread.join().expect("camera thread panicked");
+        }
+    }
+
+    fn send_shutdown(&self) {
+        for camera in &self.cameras {
+            let _ = camera.commands.send(Command::Shutdown);
+        }
+    }
+}
+
+impl Drop for CaptureManager {
+    /// Stops every camera without waiting for their threads to exit.
+    fn drop(&mut self) {
+        self.send_shutdown();
+
+        self.queue.state.lock().unwrap().manager_alive = false;
+        self.queue.available.notify_all();
+    }
+}
+
+impl CameraHandle {
+    pub fn id(&self) -> CameraId {
+        self.id
+    }
+
+    /// Reopens a camera closed with `stop`.
+    pub fn start(&self) {
+        let _ = self.commands.send(Command::Start);
+    }
+
+    /// Closes the camera until `start` is called, emitting `CameraEventKind::Stopped`.
+    pub fn stop(&self) {
+        let _ = self.commands.send(Command::Stop);
+    }
+
+    /// Captures a JPEG (see [`WebcamH264Stream::snapshot_jpeg`]), blocking until the camera's thread has read it. The
+    /// frames read for the snapshot are not delivered as events. Returns `StreamError::NotCapturing` if the camera is
+    /// stopped or disconnected.
+    pub fn snapshot(&self, quality: u8) -> Result<Vec<u8>, StreamError> {
+        let (reply, result) = sync_channel(1);
+
+        self.commands
+            .send(Command::Snapshot { quality, reply })
+            .map_err(|_| StreamError::NotCapturing)?;
+
+        result.recv().map_err(|_| StreamError::NotCapturing)?
+    }
+
+    /// Sets whether the camera's frame events include YUV frames (disabled by default).
+    pub fn set_yuv_frames(&self, yuv_frames: bool) {
+        self.shared.yuv_frames.store(yuv_frames, Ordering::Relaxed);
+    }
+
+    /// The number of the camera's frames dropped because the queue was full.
+    pub fn dropped_frames(&self) -> u64 {
+        self.shared.dropped_frames.load(Ordering::Relaxed)
+    }
+}
+
+impl EventReceiver {
+    /// Blocks until an event is available. Returns an error once the manager has been dropped, all of its camera
+    /// threads have exited and the remaining events have been received.
+    pub fn recv(&self) -> Result<CameraEvent, RecvError> {
+        let mut state = self.queue.state.lock().unwrap();
+
+        loop {
+            if let Some(event) = state.events.pop_front() {
+                return Ok(event);
+            }
+            if state.is_closed() {
+                return Err(RecvError);
+            }
+
+            state = self.queue.available.wait(state).unwrap();
+        }
+    }
+
+    /// Same as `recv` but gives up after `timeout`.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<CameraEvent, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.queue.state.lock().unwrap();
+
+        loop {
+            if let Some(event) = state.events.pop_front() {
+                return Ok(event);
+            }
+            if state.is_closed() {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(RecvTimeoutError::Timeout);
+            }
+
+            state = self
+                .queue
+                .available
+                .wait_timeout(state, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+
+    pub fn try_recv(&self) -> Result<CameraEvent, TryRecvError> {
+        let mut state = self.queue.state.lock().unwrap();
+
+        match state.events.pop_front() {
+            Some(event) => Ok(event),
+            None if state.is_closed() => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+}
+
+impl QueueState {
+    fn is_closed(&self) -> bool {
+        !self.manager_alive && self.threads == 0
+    }
+}
+
+impl EventQueue {
+    fn push(&self, event: CameraEvent, dropped_frames: &AtomicU64) {
+        let mut state = self.state.lock().unwrap();
+
+        if matches!(event.kind, CameraEventKind::Frame(_)) {
+            let is_camera_frame = |queued: &CameraEvent| {
+                queued.camera_id == event.camera_id
+                    && matches!(queued.kind, CameraEventKind::Frame(_))
+            };
+
+            if state
+                .events
+                .iter()
+                .filter(|queued| is_camera_frame(queued))
+                .count()
+                >= self.capacity
+            {
+                let oldest = state.events.iter().position(is_camera_frame);
+                state
+                    .events
+                    .remove(oldest.expect("the camera has queued frames"));
+                dropped_frames.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        state.events.push_back(event);
+        self.available.notify_one();
+    }
+}
+
+/// The state owned by a camera's capture thread.
+struct Camera {
+    id: CameraId,
+    selector: DeviceSelector,
+    configure: Configure,
+    commands: Receiver<Command>,
+    shared: Arc<CameraShared>,
+    queue: Arc<EventQueue>,
+}
+
+impl Camera {
+    fn run(mut self) {
+        self.capture();
+
+        let mut state = self.queue.state.lock().unwrap();
+        state.threads -= 1;
+        self.queue.available.notify_all();
+    }
+
+    fn capture(&mut self) {
+        let mut stream: Option<OwnedWebcamH264Stream> = None;
+        let mut running = true;
+        // Whether the camera is being reopened after disconnecting or failing, rather than started
+        let mut reconnecting = false;
+        let mut backoff = INITIAL_BACKOFF;
+        let mut retry_at: Option<Instant> = None;
+
+        loop {
+            // Commands are waited for while there is nothing to capture, otherwise they are handled between frames
+            let command = if !running {
+                self.commands.recv().ok()
+            } else if let (None, Some(retry_at)) = (&stream, retry_at) {
+                match self
+                    .commands
+                    .recv_timeout(retry_at.saturating_duration_since(Instant::now()))
+                {
+                    Ok(command) => Some(command),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            } else {
+                match self.commands.try_recv() {
+                    Ok(command) => Some(command),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => return,
+                }
+            };
+
+            match command {
+                Some(Command::Start) if !running => {
+                    running = true;
+                    reconnecting = false;
+                    backoff = INITIAL_BACKOFF;
+                    retry_at = None;
+                    continue;
+                }
+                Some(Command::Stop) if running => {
+                    running = false;
+                    stream = None;
+                    self.emit(CameraEventKind::Stopped);
+                    continue;
+                }
+                Some(Command::Snapshot { quality, reply }) => {
+                    let result = match &mut stream {
+                        Some(stream) => stream.snapshot_jpeg(quality),
+                        None => Err(StreamError::NotCapturing),
+                    };
+                    let _ = reply.send(result);
+                    continue;
+                }
+                Some(Command::Shutdown) => return,
+                Some(_) => continue,
+                // All of the handles were dropped while the camera was stopped
+                None if !running => return,
+                None => {}
+            }
+
+            let Some(current) = &mut stream else {
+                match self.open() {
+                    Ok(opened) => {
+                        let (width, height) = (opened.width, opened.height);
+                        self.emit(match reconnecting {
+                            true => CameraEventKind::Reconnected { width, height },
+                            false => CameraEventKind::Started { width, height },
+                        });
+
+                        stream = Some(opened);
+                        backoff = INITIAL_BACKOFF;
+                        retry_at = None;
+                    }
+                    Err(err) => {
+                        self.emit(CameraEventKind::Error(err));
+
+                        retry_at = Some(Instant::now() + backoff);
+                        backoff = (backoff * 2).min(MAX_BACKOFF);
+                    }
+                }
+                continue;
+            };
+
+            match current.next_frame(self.shared.yuv_frames.load(Ordering::Relaxed)) {
+                Ok(frame) => self.emit(CameraEventKind::Frame(frame)),
+                Err(err) => {
+                    // The stream is closed so that the camera can be reopened
+                    stream = None;
+                    reconnecting = true;
+                    retry_at = Some(Instant::now() + backoff);
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+
+                    self.emit(match is_disconnect(&err) {
+                        true => CameraEventKind::Disconnected,
+                        false => CameraEventKind::Error(err),
+                    });
+                }
+            }
+        }
+    }
+
+    fn open(&mut self) -> Result<OwnedWebcamH264Stream, StreamError> {
+        let device = self.selector.open()?;
+
+        (self.configure)(WebcamH264Stream::from_device(device)).open()
+    }
+
+    fn emit(&self, kind: CameraEventKind) {
+        let event = CameraEvent {
+            camera_id: self.id,
+            kind,
+        };
+
+        self.queue.push(event, &self.shared.dropped_frames);
+    }
+}
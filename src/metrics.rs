@@ -0,0 +1,186 @@
+//! Prometheus metrics for long-running capture services (requires the `metrics` feature).
+//!
+//! Every metric has a `device` label identifying the stream. The metric names are stable:
+//!
+//! | Name | Type | Description |
+//! |------|------|-------------|
+//! | `webcam_frames_total` | Counter | Frames returned by the stream |
+//! | `webcam_dropped_frames_total` | Counter | Frames dropped by the driver (gaps in the sequence numbers) |
+//! | `webcam_corrupt_frames_total` | Counter | Corrupt frames skipped |
+//! | `webcam_invalid_frames_total` | Counter | Frames that failed [bitstream validation](crate::StreamBuilder::validate_bitstream) |
+//! | `webcam_bytes_total` | Counter | H264 (or passed through JPEG) bytes returned by the stream |
+//! | `webcam_reconnects_total` | Counter | Reconnections by a [`ReconnectingStream`](crate::ReconnectingStream) |
+//! | `webcam_fps` | Gauge | Frame rate over the last 10 seconds |
+//! | `webcam_bitrate_bits_per_second` | Gauge | Bitrate over the last 10 seconds |
+//! | `webcam_frame_processing_seconds` | Histogram | Time spent decoding and encoding each frame |
+
+pub use prometheus;
+
+use crate::stats::StatsTracker;
+use prometheus::{
+    Gauge, GaugeVec, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts,
+    Registry, TextEncoder,
+};
+use std::time::{Duration, Instant};
+
+/// How often the gauges are recalculated from the stream's statistics.
+const GAUGE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The metrics shared by any number of streams, see [`StreamBuilder::metrics`](crate::StreamBuilder::metrics).
+/// Cloning is cheap and the clones update the same metrics.
+#[derive(Clone)]
+pub struct StreamMetrics {
+    registry: Registry,
+    frames: IntCounterVec,
+    dropped_frames: IntCounterVec,
+    corrupt_frames: IntCounterVec,
+    invalid_frames: IntCounterVec,
+    bytes: IntCounterVec,
+    reconnects: IntCounterVec,
+    fps: GaugeVec,
+    bitrate: GaugeVec,
+    processing_time: HistogramVec,
+}
+
+impl StreamMetrics {
+    /// Creates the metrics in a new registry.
+    pub fn new() -> Result<Self, prometheus::Error> {
+        Self::with_registry(&Registry::new())
+    }
+
+    /// Creates the metrics in an existing registry, eg. one that the service's other metrics are registered with.
+    pub fn with_registry(registry: &Registry) -> Result<Self, prometheus::Error> {
+        let counter = |name: &str, help: &str| -> Result<IntCounterVec, prometheus::Error> {
+            let counter = IntCounterVec::new(Opts::new(name, help), &["device"])?;
+            registry.register(Box::new(counter.clone()))?;
+            Ok(counter)
+        };
+        let gauge = |name: &str, help: &str| -> Result<GaugeVec, prometheus::Error> {
+            let gauge = GaugeVec::new(Opts::new(name, help), &["device"])?;
+            registry.register(Box::new(gauge.clone()))?;
+            Ok(gauge)
+        };
+
+        let processing_time = HistogramVec::new(
+            HistogramOpts::new(
+                "webcam_frame_processing_seconds",
+                "Time spent decoding and encoding each frame",
+            )
+            .buckets(vec![
+                0.001, 0.0025, 0.005, 0.01, 0.02, 0.033, 0.05, 0.1, 0.25,
+            ]),
+            &["device"],
+        )?;
+        registry.register(Box::new(processing_time.clone()))?;
+
+        Ok(Self {
+            frames: counter("webcam_frames_total", "Frames returned by the stream")?,
+            dropped_frames: counter(
+                "webcam_dropped_frames_total",
+                "Frames dropped by the driver",
+            )?,
+            corrupt_frames: counter("webcam_corrupt_frames_total", "Corrupt frames skipped")?,
+            invalid_frames: counter(
+                "webcam_invalid_frames_total",
+                "Frames that failed bitstream validation",
+            )?,
+            bytes: counter("webcam_bytes_total", "Bytes returned by the stream")?,
+            reconnects: counter("webcam_reconnects_total", "Camera reconnections")?,
+            fps: gauge("webcam_fps", "Frame rate over the last 10 seconds")?,
+            bitrate: gauge(
+                "webcam_bitrate_bits_per_second",
+                "Bitrate over the last 10 seconds",
+            )?,
+            processing_time,
+            registry: registry.clone(),
+        })
+    }
+
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// Renders the registry's metrics in the Prometheus text exposition format, eg. for a `/metrics` endpoint.
+    pub fn render(&self) -> Result<String, prometheus::Error> {
+        TextEncoder::new().encode_to_string(&self.registry.gather())
+    }
+
+    /// Counts a reconnection of the device.
+    pub fn record_reconnect(&self, device: &str) {
+        self.reconnects.with_label_values(&[device]).inc();
+    }
+}
+
+/// A stream's metrics with its label values resolved up front, so that updating them is a handful of atomic operations.
+pub(crate) struct MetricsHandle {
+    frames: IntCounter,
+    dropped_frames: IntCounter,
+    corrupt_frames: IntCounter,
+    invalid_frames: IntCounter,
+    bytes: IntCounter,
+    fps: Gauge,
+    bitrate: Gauge,
+    processing_time: Histogram,
+    last_dropped_frames: u64,
+    last_corrupt_frames: u64,
+    gauges_updated: Option<Instant>,
+}
+
+impl MetricsHandle {
+    pub(crate) fn new(metrics: &StreamMetrics, device: &str) -> Self {
+        let labels = &[device];
+
+        Self {
+            frames: metrics.frames.with_label_values(labels),
+            dropped_frames: metrics.dropped_frames.with_label_values(labels),
+            corrupt_frames: metrics.corrupt_frames.with_label_values(labels),
+            invalid_frames: metrics.invalid_frames.with_label_values(labels),
+            bytes: metrics.bytes.with_label_values(labels),
+            fps: metrics.fps.with_label_values(labels),
+            bitrate: metrics.bitrate.with_label_values(labels),
+            processing_time: metrics.processing_time.with_label_values(labels),
+            last_dropped_frames: 0,
+            last_corrupt_frames: 0,
+            gauges_updated: None,
+        }
+    }
+
+    /// Records a frame that failed bitstream validation.
+    pub(crate) fn record_invalid_frame(&self) {
+        self.invalid_frames.inc();
+    }
+
+    /// Records a frame returned by the stream, after it has been recorded in the stream's `stats`.
+    pub(crate) fn record_frame(
+        &mut self,
+        bytes: usize,
+        processing_time: Duration,
+        stats: &StatsTracker,
+        corrupt_frames: u64,
+    ) {
+        let dropped_frames = stats.lifetime_dropped_frames();
+
+        self.frames.inc();
+        self.bytes.inc_by(bytes as u64);
+        self.dropped_frames
+            .inc_by(dropped_frames - self.last_dropped_frames);
+        self.corrupt_frames
+            .inc_by(corrupt_frames - self.last_corrupt_frames);
+        self.processing_time.observe(processing_time.as_secs_f64());
+
+        self.last_dropped_frames = dropped_frames;
+        self.last_corrupt_frames = corrupt_frames;
+
+        // The gauges are derived from a snapshot of the stats, which is too expensive to take for every frame
+        let now = Instant::now();
+        if self
+            .gauges_updated
+            .is_none_or(|updated| now.duration_since(updated) >= GAUGE_INTERVAL)
+        {
+            let stats = stats.snapshot(corrupt_frames);
+            self.fps.set(stats.fps_10s);
+            self.bitrate.set(stats.bitrate_10s);
+            self.gauges_updated = Some(now);
+        }
+    }
+}
@@ -0,0 +1,567 @@
+//! Muxing of the H264 stream into Matroska (MKV) files that stay playable if the recording is cut short, eg. by a power
+//! cut during a long recording.
+//!
+//! Frames are grouped into clusters which are each written (and synced to disk by [`MkvWriter::create`]) as soon as
+//! they are complete, so a file that was never finished plays up to its last complete cluster. Finishing the file adds
+//! an index (Cues) and its duration so that players can seek in it.
+
+use crate::bitstream::{
+    annexb_to_avcc, build_avc_decoder_configuration_record, extract_parameter_sets,
+};
+use crate::FrameMeta;
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::Duration;
+
+const EBML: u32 = 0x1A45_DFA3;
+const EBML_VERSION: u32 = 0x4286;
+const EBML_READ_VERSION: u32 = 0x42F7;
+const EBML_MAX_ID_LENGTH: u32 = 0x42F2;
+const EBML_MAX_SIZE_LENGTH: u32 = 0x42F3;
+const DOC_TYPE: u32 = 0x4282;
+const DOC_TYPE_VERSION: u32 = 0x4287;
+const DOC_TYPE_READ_VERSION: u32 = 0x4285;
+const SEGMENT: u32 = 0x1853_8067;
+const SEEK_HEAD: u32 = 0x114D_9B74;
+const SEEK: u32 = 0x4DBB;
+const SEEK_ID: u32 = 0x53AB;
+const SEEK_POSITION: u32 = 0x53AC;
+const VOID: u32 = 0xEC;
+const INFO: u32 = 0x1549_A966;
+const TIMESTAMP_SCALE: u32 = 0x2A_D7B1;
+const DURATION: u32 = 0x4489;
+const MUXING_APP: u32 = 0x4D80;
+const WRITING_APP: u32 = 0x5741;
+const TRACKS: u32 = 0x1654_AE6B;
+const TRACK_ENTRY: u32 = 0xAE;
+const TRACK_NUMBER: u32 = 0xD7;
+const TRACK_UID: u32 = 0x73C5;
+const TRACK_TYPE: u32 = 0x83;
+const FLAG_LACING: u32 = 0x9C;
+const CODEC_ID: u32 = 0x86;
+const CODEC_PRIVATE: u32 = 0x63A2;
+const DEFAULT_DURATION: u32 = 0x23_E383;
+const VIDEO: u32 = 0xE0;
+const PIXEL_WIDTH: u32 = 0xB0;
+const PIXEL_HEIGHT: u32 = 0xBA;
+const CLUSTER: u32 = 0x1F43_B675;
+const TIMESTAMP: u32 = 0xE7;
+const SIMPLE_BLOCK: u32 = 0xA3;
+const CUES: u32 = 0x1C53_BB6B;
+const CUE_POINT: u32 = 0xBB;
+const CUE_TIME: u32 = 0xB3;
+const CUE_TRACK_POSITIONS: u32 = 0xB7;
+const CUE_TRACK: u32 = 0xF7;
+const CUE_CLUSTER_POSITION: u32 = 0xF1;
+
+/// Timestamps are in milliseconds.
+const TIMESTAMP_SCALE_NS: u64 = 1_000_000;
+/// The space reserved after the segment header for the SeekHead written by `finish`.
+const SEEK_HEAD_SPACE: usize = 96;
+/// Block timestamps are 16 bit offsets from their cluster's timestamp, so clusters can't be longer than this.
+const MAX_CLUSTER_DURATION: u64 = i16::MAX as u64;
+
+/// The video track of a Matroska file.
+#[derive(Debug, Clone)]
+pub struct VideoTrackInfo {
+    pub width: u32,
+    pub height: u32,
+    /// Used for the timing of frames written without metadata, and as the track's default frame duration.
+    pub fps: f64,
+    /// The AVCDecoderConfigurationRecord (avcC), eg. from [`build_avc_decoder_configuration_record`]. If empty it is
+    /// built from the parameter sets of the first key frame.
+    pub codec_private: Vec<u8>,
+}
+
+/// Writes H264 access units from [`WebcamH264Stream::next`](crate::WebcamH264Stream::next) to a Matroska file.
+///
+/// Frames before the first key frame are skipped since they cannot be decoded. A new cluster is started at the first
+/// key frame after each cluster duration (2 seconds by default), so every cluster can be decoded on its own.
+pub struct MkvWriter<W: Write + Seek> {
+    writer: W,
+    track: VideoTrackInfo,
+    cluster_duration: u64,
+    sync: fn(&mut W) -> io::Result<()>,
+    // Offsets of the segment's size field and its data, which element positions are relative to
+    segment_offset: u64,
+    segment_data: u64,
+    // Offsets of the Info and Tracks elements relative to the segment data, and of the Duration's value
+    info_position: u64,
+    tracks_position: u64,
+    duration_offset: u64,
+    header_written: bool,
+    cluster: Vec<u8>,
+    // The cluster's timestamp in milliseconds from the first frame
+    cluster_time: Option<u64>,
+    // The timestamp and segment position of each cluster
+    cues: Vec<(u64, u64)>,
+    last_time: Option<u64>,
+    frame_duration: f64,
+    first_timestamp: Option<Duration>,
+    sample: Vec<u8>,
+}
+
+impl MkvWriter<File> {
+    /// Creates a Matroska file at `path`. Each cluster is synced to disk once it is written.
+    pub fn create<P: AsRef<Path>>(path: P, track: VideoTrackInfo) -> io::Result<Self> {
+        let mut writer = Self::new(File::create(path)?, track)?;
+        writer.sync = |file| file.sync_data();
+        Ok(writer)
+    }
+}
+
+impl<W: Write + Seek> MkvWriter<W> {
+    /// Starts writing a Matroska file to `writer`. Each cluster is flushed once it is written.
+    pub fn new(mut writer: W, track: VideoTrackInfo) -> io::Result<Self> {
+        let mut header = Vec::new();
+        write_element(&mut header, EBML, |buf| {
+            write_uint(buf, EBML_VERSION, 1);
+            write_uint(buf, EBML_READ_VERSION, 1);
+            write_uint(buf, EBML_MAX_ID_LENGTH, 4);
+            write_uint(buf, EBML_MAX_SIZE_LENGTH, 8);
+            write_bytes(buf, DOC_TYPE, b"matroska");
+            write_uint(buf, DOC_TYPE_VERSION, 4);
+            write_uint(buf, DOC_TYPE_READ_VERSION, 2);
+        });
+
+        // The segment's size isn't known until the file is finished, and an unknown size keeps it valid until then
+        let segment_offset = writer.stream_position()? + header.len() as u64 + 4;
+        put_id(&mut header, SEGMENT);
+        header.extend_from_slice(&[0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        writer.write_all(&header)?;
+
+        Ok(Self {
+            writer,
+            frame_duration: 1000.0 / track.fps,
+            track,
+            cluster_duration: 2000,
+            sync: W::flush,
+            segment_offset,
+            segment_data: segment_offset + 8,
+            info_position: 0,
+            tracks_position: 0,
+            duration_offset: 0,
+            header_written: false,
+            cluster: Vec::new(),
+            cluster_time: None,
+            cues: Vec::new(),
+            last_time: None,
+            first_timestamp: None,
+            sample: Vec::new(),
+        })
+    }
+
+    /// Sets how long each cluster is (defaults to 2 seconds), ie. how much of the recording is lost if it is cut short.
+    /// Clusters start at key frames so they are at least a GOP long, and at most 32 seconds.
+    pub fn cluster_duration(mut self, cluster_duration: Duration) -> Self {
+        self.cluster_duration = (cluster_duration.as_millis() as u64).min(MAX_CLUSTER_DURATION);
+        self
+    }
+
+    /// Writes an access unit, timing it at the fixed frame rate of the track.
+    pub fn write(&mut self, h264_bytes: &[u8]) -> io::Result<()> {
+        let time = match self.last_time {
+            Some(last) => {
+                // The fractional frame durations are accumulated so that 30fps doesn't drift to 33ms frames
+                let frames = (last as f64 / self.frame_duration).round() + 1.0;
+                (frames * self.frame_duration).round() as u64
+            }
+            None => 0,
+        };
+
+        self.write_block(h264_bytes, time)
+    }
+
+    /// Writes an access unit, timing it using the frame's capture timestamp.
+    pub fn write_with_meta(&mut self, h264_bytes: &[u8], meta: &FrameMeta) -> io::Result<()> {
+        let first_timestamp = *self.first_timestamp.get_or_insert(meta.timestamp);
+        let time = meta.timestamp.saturating_sub(first_timestamp).as_millis() as u64;
+
+        self.write_block(h264_bytes, time)
+    }
+
+    fn write_block(&mut self, h264_bytes: &[u8], time: u64) -> io::Result<()> {
+        let is_keyframe = crate::nal::is_keyframe(h264_bytes);
+
+        if !self.header_written {
+            if !is_keyframe {
+                return Ok(());
+            }
+            self.write_header(h264_bytes)?;
+        }
+
+        // Timestamps must not go backwards
+        let time = self.last_time.map_or(time, |last| time.max(last));
+        self.last_time = Some(time);
+
+        let cluster_time = match self.cluster_time {
+            Some(cluster_time)
+                if time - cluster_time < MAX_CLUSTER_DURATION
+                    && !(is_keyframe && time - cluster_time >= self.cluster_duration) =>
+            {
+                cluster_time
+            }
+            _ => {
+                self.flush_cluster()?;
+                self.start_cluster(time);
+                time
+            }
+        };
+
+        self.sample.clear();
+        annexb_to_avcc(h264_bytes, &mut self.sample);
+
+        let mut block = Vec::with_capacity(self.sample.len() + 4);
+        // Track number 1 as a 1 byte vint
+        block.push(0x81);
+        block.extend_from_slice(&((time - cluster_time) as i16).to_be_bytes());
+        block.push(if is_keyframe { 0x80 } else { 0 });
+        block.extend_from_slice(&self.sample);
+        write_bytes(&mut self.cluster, SIMPLE_BLOCK, &block);
+
+        Ok(())
+    }
+
+    fn write_header(&mut self, h264_bytes: &[u8]) -> io::Result<()> {
+        if self.track.codec_private.is_empty() {
+            let (sps, pps) = extract_parameter_sets(h264_bytes);
+            if sps.is_empty() || pps.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "No SPS / PPS found in the first key frame",
+                ));
+            }
+            self.track.codec_private = build_avc_decoder_configuration_record(&sps, &pps)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        }
+
+        let mut header = Vec::new();
+
+        // Reserved for the SeekHead, which can only be written once the Cues' position is known
+        write_void(&mut header, SEEK_HEAD_SPACE);
+
+        self.info_position = header.len() as u64;
+        write_element(&mut header, INFO, |buf| {
+            write_uint(buf, TIMESTAMP_SCALE, TIMESTAMP_SCALE_NS);
+            write_bytes(buf, MUXING_APP, b"h264_webcam_stream");
+            write_bytes(buf, WRITING_APP, b"h264_webcam_stream");
+            // Last, so that finish can find its value at the end of the element
+            write_bytes(buf, DURATION, &0f64.to_be_bytes());
+        });
+        self.duration_offset = self.segment_data + header.len() as u64 - 8;
+
+        self.tracks_position = header.len() as u64;
+        write_element(&mut header, TRACKS, |buf| {
+            write_element(buf, TRACK_ENTRY, |buf| {
+                write_uint(buf, TRACK_NUMBER, 1);
+                write_uint(buf, TRACK_UID, 1);
+                write_uint(buf, TRACK_TYPE, 1);
+                write_uint(buf, FLAG_LACING, 0);
+                write_bytes(buf, CODEC_ID, b"V_MPEG4/ISO/AVC");
+                write_bytes(buf, CODEC_PRIVATE, &self.track.codec_private);
+                write_uint(
+                    buf,
+                    DEFAULT_DURATION,
+                    (1_000_000_000.0 / self.track.fps).round() as u64,
+                );
+                write_element(buf, VIDEO, |buf| {
+                    write_uint(buf, PIXEL_WIDTH, self.track.width as u64);
+                    write_uint(buf, PIXEL_HEIGHT, self.track.height as u64);
+                });
+            });
+        });
+
+        self.writer.write_all(&header)?;
+        self.header_written = true;
+        Ok(())
+    }
+
+    fn start_cluster(&mut self, time: u64) {
+        self.cluster.clear();
+        self.cluster_time = Some(time);
+        write_uint(&mut self.cluster, TIMESTAMP, time);
+    }
+
+    /// Writes the current cluster and syncs it to disk.
+    fn flush_cluster(&mut self) -> io::Result<()> {
+        let Some(cluster_time) = self.cluster_time.take() else {
+            return Ok(());
+        };
+
+        let position = self.writer.stream_position()? - self.segment_data;
+        self.cues.push((cluster_time, position));
+
+        let mut header = Vec::new();
+        put_id(&mut header, CLUSTER);
+        put_size(&mut header, self.cluster.len() as u64);
+
+        self.writer.write_all(&header)?;
+        self.writer.write_all(&self.cluster)?;
+        (self.sync)(&mut self.writer)
+    }
+
+    /// Writes the last cluster and the Cues, and fills in the file's duration, returning the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.header_written {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "No key frames were written",
+            ));
+        }
+
+        self.flush_cluster()?;
+
+        let cues_position = self.writer.stream_position()? - self.segment_data;
+        let mut cues = Vec::new();
+        write_element(&mut cues, CUES, |buf| {
+            for &(time, position) in &self.cues {
+                write_element(buf, CUE_POINT, |buf| {
+                    write_uint(buf, CUE_TIME, time);
+                    write_element(buf, CUE_TRACK_POSITIONS, |buf| {
+                        write_uint(buf, CUE_TRACK, 1);
+                        write_uint(buf, CUE_CLUSTER_POSITION, position);
+                    });
+                });
+            }
+        });
+        self.writer.write_all(&cues)?;
+        let end = self.writer.stream_position()?;
+
+        // The last frame is given the track's frame duration
+        let duration = self.last_time.unwrap_or(0) as f64 + self.frame_duration;
+        self.writer.seek(SeekFrom::Start(self.duration_offset))?;
+        self.writer.write_all(&duration.to_be_bytes())?;
+
+        let mut seek_head = Vec::new();
+        write_element(&mut seek_head, SEEK_HEAD, |buf| {
+            for (id, position) in [
+                (INFO, self.info_position),
+                (TRACKS, self.tracks_position),
+                (CUES, cues_position),
+            ] {
+                write_element(buf, SEEK, |buf| {
+                    let mut seek_id = Vec::new();
+                    put_id(&mut seek_id, id);
+                    write_bytes(buf, SEEK_ID, &seek_id);
+                    write_bytes(buf, SEEK_POSITION, &position.to_be_bytes());
+                });
+            }
+        });
+        let padding = SEEK_HEAD_SPACE - seek_head.len();
+        write_void(&mut seek_head, padding);
+        self.writer.seek(SeekFrom::Start(self.segment_data))?;
+        self.writer.write_all(&seek_head)?;
+
+        self.writer.seek(SeekFrom::Start(self.segment_offset))?;
+        self.writer
+            .write_all(&((end - self.segment_data) | 0x0100_0000_0000_0000).to_be_bytes())?;
+
+        self.writer.seek(SeekFrom::Start(end))?;
+        (self.sync)(&mut self.writer)?;
+
+        Ok(self.writer)
+    }
+}
+
+/// Appends an element ID, whose length is encoded in its leading bits.
+fn put_id(buf: &mut Vec<u8>, id: u32) {
+    let bytes = id.to_be_bytes();
+    let skip = bytes.iter().take_while(|&&byte| byte == 0).count();
+    buf.extend_from_slice(&bytes[skip..]);
+}
+
+/// Appends an element size as the shortest variable length integer that holds it.
+fn put_size(buf: &mut Vec<u8>, size: u64) {
+    // All ones is reserved for unknown sizes
+    let length = (1..8)
+        .find(|&length| size < (1 << (7 * length)) - 1)
+        .unwrap_or(8);
+    let marked = size | (1 << (7 * length));
+    buf.extend_from_slice(&marked.to_be_bytes()[8 - length..]);
+}
+
+/// Appends an element, calling `content` to write the body and then inserting its size.
+fn write_element(buf: &mut Vec<u8>, id: u32, content: impl FnOnce(&mut Vec<u8>)) {
+    put_id(buf, id);
+    let start = buf.len();
+
+    content(buf);
+
+    let mut size = Vec::new();
+    put_size(&mut size, (buf.len() - start) as u64);
+    buf.splice(start..start, size);
+}
+
+fn write_bytes(buf: &mut Vec<u8>, id: u32, bytes: &[u8]) {
+    put_id(buf, id);
+    put_size(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn write_uint(buf: &mut Vec<u8>, id: u32, value: u64) {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().take_while(|&&byte| byte == 0).count().min(7);
+    write_bytes(buf, id, &bytes[skip..]);
+}
+
+/// Appends a Void element that takes up exactly `len` bytes (at least 2).
+fn write_void(buf: &mut Vec<u8>, len: usize) {
+    put_id(buf, VOID);
+    // A fixed 8 byte size keeps the element's length predictable for any padding longer than 9 bytes
+    if len >= 9 {
+        buf.extend_from_slice(&((len - 9) as u64 | 0x0100_0000_0000_0000).to_be_bytes());
+        buf.resize(buf.len() + len - 9, 0);
+    } else {
+        put_size(buf, len as u64 - 2);
+        buf.resize(buf.len() + len - 2, 0);
+    }
+}
+
+#[cfg(all(test, feature = "openh264"))]
+mod tests {
+    use super::*;
+    use crate::synthetic::tests::access_units;
+    use std::io::Cursor;
+
+    /// An element's ID, its size (None if unknown) and the offset of its data.
+    fn read_header(data: &[u8], offset: usize) -> (u32, Option<u64>, usize) {
+        let vint_len = |byte: u8| byte.leading_zeros() as usize + 1;
+
+        let id_len = vint_len(data[offset]);
+        let id = data[offset..offset + id_len]
+            .iter()
+            .fold(0, |id, &byte| (id << 8) | byte as u32);
+
+        let size_offset = offset + id_len;
+        let size_len = vint_len(data[size_offset]);
+        let marker = 1u64 << (7 * size_len);
+        let size = data[size_offset..size_offset + size_len]
+            .iter()
+            .fold(0, |size, &byte| (size << 8) | byte as u64)
+            ^ marker;
+
+        let unknown = size == marker - 1;
+        (id, (!unknown).then_some(size), size_offset + size_len)
+    }
+
+    /// The segment's top level elements as (ID, data offset, data end), the last of which may extend past the end of
+    /// the data.
+    fn segment_children(data: &[u8]) -> Vec<(u32, usize, usize)> {
+        let (id, size, ebml_data) = read_header(data, 0);
+        assert_eq!(id, EBML);
+        let (id, _, mut offset) = read_header(data, ebml_data + size.unwrap() as usize);
+        assert_eq!(id, SEGMENT);
+
+        let mut children = Vec::new();
+        while offset < data.len() {
+            let (id, size, data_offset) = read_header(data, offset);
+            let end = data_offset + size.expect("Only the segment has an unknown size") as usize;
+            children.push((id, data_offset, end));
+            offset = end;
+        }
+        children
+    }
+
+    /// Checks that a cluster's children exactly fill it, returning its timestamp and its blocks' keyframe flags.
+    fn read_cluster(data: &[u8], start: usize, end: usize) -> (u64, Vec<bool>) {
+        let mut offset = start;
+        let mut timestamp = None;
+        let mut keyframes = Vec::new();
+
+        while offset < end {
+            let (id, size, data_offset) = read_header(data, offset);
+            let child_end = data_offset + size.unwrap() as usize;
+            assert!(child_end <= end, "Cluster children fit in the cluster");
+
+            let body = &data[data_offset..child_end];
+            match id {
+                TIMESTAMP => {
+                    timestamp = Some(body.iter().fold(0, |t, &byte| (t << 8) | byte as u64))
+                }
+                SIMPLE_BLOCK => {
+                    assert_eq!(body[0], 0x81, "Blocks are on track 1");
+                    keyframes.push(body[3] & 0x80 != 0);
+                }
+                _ => panic!("Unexpected cluster child {id:#x}"),
+            }
+            offset = child_end;
+        }
+
+        assert_eq!(offset, end);
+        (
+            timestamp.expect("Clusters start with a timestamp"),
+            keyframes,
+        )
+    }
+
+    fn track() -> VideoTrackInfo {
+        VideoTrackInfo {
+            width: 64,
+            height: 64,
+            fps: 30.0,
+            codec_private: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn keeps_complete_clusters_when_cut_short() {
+        let mut file = Vec::new();
+        let mut writer = MkvWriter::new(Cursor::new(&mut file), track())
+            .unwrap()
+            .cluster_duration(Duration::from_millis(500));
+        for access_unit in access_units(64, 64, 90, 10) {
+            writer.write(&access_unit).unwrap();
+        }
+        // The recording is cut short without finishing the file
+        drop(writer);
+
+        let children = segment_children(&file);
+        let clusters: Vec<_> = children.iter().filter(|child| child.0 == CLUSTER).collect();
+        // Clusters start at the key frame after each 500ms: frames 0, 20, 40, 60 and the unwritten 80
+        assert_eq!(clusters.len(), 4);
+        let &&(_, last_start, last_end) = clusters.last().unwrap();
+        assert_eq!(last_end, file.len());
+
+        // A power cut part way through writing the last cluster
+        file.truncate((last_start + last_end) / 2);
+        let children = segment_children(&file);
+        let (complete, truncated): (Vec<_>, Vec<_>) = children
+            .iter()
+            .filter(|child| child.0 == CLUSTER)
+            .partition(|child| child.2 <= file.len());
+        assert_eq!((complete.len(), truncated.len()), (3, 1));
+
+        for (i, &&(_, start, end)) in complete.iter().enumerate() {
+            let (timestamp, keyframes) = read_cluster(&file, start, end);
+            assert_eq!(timestamp, (i as f64 * 20.0 * 1000.0 / 30.0).round() as u64);
+            assert_eq!(keyframes.len(), 20);
+            assert!(keyframes[0], "Cluster {i} starts with a key frame");
+        }
+    }
+
+    #[test]
+    fn indexes_finished_files() {
+        let mut writer = MkvWriter::new(Cursor::new(Vec::new()), track()).unwrap();
+        for access_unit in access_units(64, 64, 90, 30) {
+            writer.write(&access_unit).unwrap();
+        }
+        let file = writer.finish().unwrap().into_inner();
+
+        // The segment's size is filled in
+        let (_, size, ebml_data) = read_header(&file, 0);
+        let (_, segment_size, segment_data) =
+            read_header(&file, ebml_data + size.unwrap() as usize);
+        assert_eq!(segment_data + segment_size.unwrap() as usize, file.len());
+
+        let children = segment_children(&file);
+        let ids: Vec<u32> = children.iter().map(|child| child.0).collect();
+        assert_eq!(ids, [SEEK_HEAD, VOID, INFO, TRACKS, CLUSTER, CLUSTER, CUES]);
+        let blocks: usize = children
+            .iter()
+            .filter(|child| child.0 == CLUSTER)
+            .map(|&(_, start, end)| read_cluster(&file, start, end).1.len())
+            .sum();
+        assert_eq!(blocks, 90);
+    }
+}
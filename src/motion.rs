@@ -0,0 +1,247 @@
+use crate::{StreamError, YUVFrame};
+
+/// How much a frame must change for a [`MotionDetector`] to report motion.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MotionConfig {
+    /// The difference in brightness (0 - 255) from the background for a block of the image to count as changed.
+    pub threshold: u8,
+    /// The fraction of the image's blocks (0.0 - 1.0) that must change for a frame to contain motion.
+    pub min_changed_fraction: f32,
+    /// The size of the blocks, in pixels, that the image is averaged into before comparing it to the background.
+    pub downscale: usize,
+    /// The number of consecutive frames with motion before motion is reported, so that sensor noise doesn't trigger it.
+    pub trigger_frames: u32,
+    /// The number of consecutive frames without motion before motion stops being reported.
+    pub release_frames: u32,
+}
+
+impl Default for MotionConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 25,
+            min_changed_fraction: 0.01,
+            downscale: 8,
+            trigger_frames: 3,
+            release_frames: 15,
+        }
+    }
+}
+
+/// A rectangle in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// The result of [`MotionDetector::detect`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct MotionResult {
+    /// True while motion is ongoing, after applying the trigger and release frame counts.
+    pub motion: bool,
+    /// The fraction of the frame's blocks that differed from the background.
+    pub changed_fraction: f32,
+    /// The bounding boxes of each connected area of changed blocks in this frame.
+    pub regions: Vec<Rect>,
+}
+
+/// Detects motion by comparing the brightness of each frame to a slowly updated background.
+///
+/// Only the Y plane is used, averaged into blocks of `downscale` pixels for speed and to smooth out noise.
+pub struct MotionDetector {
+    width: usize,
+    height: usize,
+    config: MotionConfig,
+    grid_width: usize,
+    grid_height: usize,
+    background: Option<Vec<f32>>,
+    blocks: Vec<f32>,
+    motion: bool,
+    motion_frames: u32,
+    still_frames: u32,
+}
+
+/// How quickly the background adapts to changes in the scene, eg. lighting.
+const BACKGROUND_RATE: f32 = 0.1;
+
+impl MotionDetector {
+    /// Creates a detector for frames of the given dimensions.
+    pub fn new(width: u32, height: u32, config: MotionConfig) -> Self {
+        let downscale = config.downscale.max(1);
+        let config = MotionConfig {
+            downscale,
+            ..config
+        };
+
+        let grid_width = (width as usize).div_ceil(downscale);
+        let grid_height = (height as usize).div_ceil(downscale);
+
+        Self {
+            width: width as usize,
+            height: height as usize,
+            config,
+            grid_width,
+            grid_height,
+            background: None,
+            blocks: vec![0.0; grid_width * grid_height],
+            motion: false,
+            motion_frames: 0,
+            still_frames: 0,
+        }
+    }
+
+    /// Compares a frame to the background and then updates the background with it. The first frame only initializes
+    /// the background.
+    ///
+    /// Decoded frames larger than the detector's dimensions (eg. due to padding) are cropped. Returns
+    /// `StreamError::FrameSizeMismatch` if the frame is smaller.
+    pub fn detect(&mut self, frame: &YUVFrame) -> Result<MotionResult, StreamError> {
+        if frame.width() < self.width || frame.height() < self.height {
+            return Err(StreamError::FrameSizeMismatch {
+                expected: (self.width as u32, self.height as u32),
+                actual: (frame.width() as u32, frame.height() as u32),
+            });
+        }
+
+        self.downscale(frame);
+
+        let Some(background) = &mut self.background else {
+            self.background = Some(self.blocks.clone());
+
+            return Ok(MotionResult {
+                motion: false,
+                changed_fraction: 0.0,
+                regions: Vec::new(),
+            });
+        };
+
+        let threshold = self.config.threshold as f32;
+        let changed = self
+            .blocks
+            .iter()
+            .zip(background.iter())
+            .map(|(block, background)| (block - background).abs() > threshold)
+            .collect::<Vec<_>>();
+
+        for (background, block) in background.iter_mut().zip(&self.blocks) {
+            *background += (block - *background) * BACKGROUND_RATE;
+        }
+
+        let changed_fraction =
+            changed.iter().filter(|&&changed| changed).count() as f32 / changed.len() as f32;
+
+        if changed_fraction >= self.config.min_changed_fraction && changed_fraction > 0.0 {
+            self.motion_frames += 1;
+            self.still_frames = 0;
+
+            if self.motion_frames >= self.config.trigger_frames {
+                self.motion = true;
+            }
+        } else {
+            self.still_frames += 1;
+            self.motion_frames = 0;
+
+            if self.still_frames >= self.config.release_frames {
+                self.motion = false;
+            }
+        }
+
+        Ok(MotionResult {
+            motion: self.motion,
+            changed_fraction,
+            regions: self.regions(&changed),
+        })
+    }
+
+    /// Forgets the background and any ongoing motion, eg. after the camera is moved.
+    pub fn reset(&mut self) {
+        self.background = None;
+        self.motion = false;
+        self.motion_frames = 0;
+        self.still_frames = 0;
+    }
+
+    /// Averages the visible area of the Y plane into blocks.
+    fn downscale(&mut self, frame: &YUVFrame) {
+        let downscale = self.config.downscale;
+        let (y_stride, _, _) = frame.strides();
+
+        self.blocks.fill(0.0);
+
+        for (y, row) in frame.y().chunks(y_stride).take(self.height).enumerate() {
+            let blocks = &mut self.blocks[y / downscale * self.grid_width..];
+
+            for (x, luma) in row[..self.width].iter().enumerate() {
+                blocks[x / downscale] += *luma as f32;
+            }
+        }
+
+        for (i, block) in self.blocks.iter_mut().enumerate() {
+            let (block_x, block_y) = (i % self.grid_width, i / self.grid_width);
+
+            // Blocks on the right and bottom edges may be partial
+            let block_width = downscale.min(self.width - block_x * downscale);
+            let block_height = downscale.min(self.height - block_y * downscale);
+
+            *block /= (block_width * block_height) as f32;
+        }
+    }
+
+    /// Finds the bounding boxes of the 4-connected areas of changed blocks.
+    fn regions(&self, changed: &[bool]) -> Vec<Rect> {
+        let mut visited = vec![false; changed.len()];
+        let mut regions = Vec::new();
+        let mut stack = Vec::new();
+
+        for start in 0..changed.len() {
+            if !changed[start] || visited[start] {
+                continue;
+            }
+
+            visited[start] = true;
+            stack.push(start);
+
+            let (mut min_x, mut min_y) = (usize::MAX, usize::MAX);
+            let (mut max_x, mut max_y) = (0, 0);
+
+            while let Some(i) = stack.pop() {
+                let (x, y) = (i % self.grid_width, i / self.grid_width);
+
+                min_x = min_x.min(x);
+                min_y = min_y.min(y);
+                max_x = max_x.max(x);
+                max_y = max_y.max(y);
+
+                let neighbours = [
+                    (x > 0).then(|| i - 1),
+                    (x + 1 < self.grid_width).then(|| i + 1),
+                    (y > 0).then(|| i - self.grid_width),
+                    (y + 1 < self.grid_height).then(|| i + self.grid_width),
+                ];
+
+                for neighbour in neighbours.into_iter().flatten() {
+                    if changed[neighbour] && !visited[neighbour] {
+                        visited[neighbour] = true;
+                        stack.push(neighbour);
+                    }
+                }
+            }
+
+            // Convert from blocks to pixels, clamping the edge blocks to the frame
+            let downscale = self.config.downscale;
+            let x = min_x * downscale;
+            let y = min_y * downscale;
+
+            regions.push(Rect {
+                x: x as u32,
+                y: y as u32,
+                width: (((max_x + 1) * downscale).min(self.width) - x) as u32,
+                height: (((max_y + 1) * downscale).min(self.height) - y) as u32,
+            });
+        }
+
+        regions
+    }
+}
@@ -0,0 +1,234 @@
+//! Serialization of the ISO-BMFF boxes shared by the MP4 writer and the fragmented MP4 muxer.
+
+use crate::bitstream::build_avc_decoder_configuration_record;
+use crate::nal::{NalType, NalUnits};
+
+/// The timescale (ticks per second) used for all video tracks.
+pub(crate) const TIMESCALE: u32 = 90_000;
+
+/// Appends a box to `buf`, calling `content` to write the body and then patching in the box size.
+pub(crate) fn write_box(buf: &mut Vec<u8>, name: &[u8; 4], content: impl FnOnce(&mut Vec<u8>)) {
+    let start = buf.len();
+    buf.extend_from_slice(&[0, 0, 0, 0]);
+    buf.extend_from_slice(name);
+
+    content(buf);
+
+    let size = (buf.len() - start) as u32;
+    buf[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+/// Appends a "full box" which starts with a version and 24 bits of flags.
+pub(crate) fn write_full_box(
+    buf: &mut Vec<u8>,
+    name: &[u8; 4],
+    version: u8,
+    flags: u32,
+    content: impl FnOnce(&mut Vec<u8>),
+) {
+    write_box(buf, name, |buf| {
+        buf.extend_from_slice(&((version as u32) << 24 | flags).to_be_bytes());
+        content(buf);
+    })
+}
+
+pub(crate) fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+pub(crate) fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+pub(crate) fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+/// The parameters of a single H264 video track.
+pub(crate) struct VideoTrack<'a> {
+    pub width: u32,
+    pub height: u32,
+    pub sps: &'a [u8],
+    pub pps: &'a [u8],
+    /// The track duration in `TIMESCALE` units (0 for fragmented files).
+    pub duration: u64,
+}
+
+pub(crate) fn write_ftyp(buf: &mut Vec<u8>, major_brand: &[u8; 4], compatible_brands: &[&[u8; 4]]) {
+    write_box(buf, b"ftyp", |buf| {
+        buf.extend_from_slice(major_brand);
+        put_u32(buf, 0x200);
+        for brand in compatible_brands {
+            buf.extend_from_slice(*brand);
+        }
+    });
+}
+
+/// Writes a moov box containing a single video track whose sample table is written by `stbl`.
+///
+/// If `fragmented` is true an mvex box is added so that samples may follow in movie fragments.
+pub(crate) fn write_moov(
+    buf: &mut Vec<u8>,
+    track: &VideoTrack,
+    fragmented: bool,
+    stbl: impl FnOnce(&mut Vec<u8>),
+) {
+    write_box(buf, b"moov", |buf| {
+        write_full_box(buf, b"mvhd", 1, 0, |buf| {
+            // Creation and modification times
+            put_u64(buf, 0);
+            put_u64(buf, 0);
+            put_u32(buf, TIMESCALE);
+            put_u64(buf, track.duration);
+            // Playback rate 1.0 and volume 1.0
+            put_u32(buf, 0x0001_0000);
+            put_u16(buf, 0x0100);
+            buf.extend_from_slice(&[0; 10]);
+            write_matrix(buf);
+            buf.extend_from_slice(&[0; 24]);
+            // Next track ID
+            put_u32(buf, 2);
+        });
+
+        write_box(buf, b"trak", |buf| {
+            // Flags: track enabled, in movie and in preview
+            write_full_box(buf, b"tkhd", 1, 0x7, |buf| {
+                put_u64(buf, 0);
+                put_u64(buf, 0);
+                // Track ID
+                put_u32(buf, 1);
+                put_u32(buf, 0);
+                put_u64(buf, track.duration);
+                buf.extend_from_slice(&[0; 8]);
+                // Layer, alternate group and volume
+                put_u16(buf, 0);
+                put_u16(buf, 0);
+                put_u16(buf, 0);
+                put_u16(buf, 0);
+                write_matrix(buf);
+                // Width and height as 16.16 fixed point
+                put_u32(buf, track.width << 16);
+                put_u32(buf, track.height << 16);
+            });
+
+            write_box(buf, b"mdia", |buf| {
+                write_full_box(buf, b"mdhd", 1, 0, |buf| {
+                    put_u64(buf, 0);
+                    put_u64(buf, 0);
+                    put_u32(buf, TIMESCALE);
+                    put_u64(buf, track.duration);
+                    // Language "und"
+                    put_u16(buf, 0x55c4);
+                    put_u16(buf, 0);
+                });
+
+                write_full_box(buf, b"hdlr", 0, 0, |buf| {
+                    put_u32(buf, 0);
+                    buf.extend_from_slice(b"vide");
+                    buf.extend_from_slice(&[0; 12]);
+                    buf.extend_from_slice(b"VideoHandler\0");
+                });
+
+                write_box(buf, b"minf", |buf| {
+                    write_full_box(buf, b"vmhd", 0, 1, |buf| {
+                        buf.extend_from_slice(&[0; 8]);
+                    });
+
+                    write_box(buf, b"dinf", |buf| {
+                        write_full_box(buf, b"dref", 0, 0, |buf| {
+                            put_u32(buf, 1);
+                            // Flag 1: the media data is in the same file
+                            write_full_box(buf, b"url ", 0, 1, |_| {});
+                        });
+                    });
+
+                    write_box(buf, b"stbl", |buf| {
+                        write_stsd(buf, track);
+                        stbl(buf);
+                    });
+                });
+            });
+        });
+
+        if fragmented {
+            write_box(buf, b"mvex", |buf| {
+                write_full_box(buf, b"trex", 0, 0, |buf| {
+                    // Track ID and default sample description index, duration, size and flags
+                    put_u32(buf, 1);
+                    put_u32(buf, 1);
+                    put_u32(buf, 0);
+                    put_u32(buf, 0);
+                    put_u32(buf, 0);
+                });
+            });
+        }
+    });
+}
+
+fn write_matrix(buf: &mut Vec<u8>) {
+    for value in [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000u32] {
+        put_u32(buf, value);
+    }
+}
+
+fn write_stsd(buf: &mut Vec<u8>, track: &VideoTrack) {
+    write_full_box(buf, b"stsd", 0, 0, |buf| {
+        put_u32(buf, 1);
+
+        write_box(buf, b"avc1", |buf| {
+            buf.extend_from_slice(&[0; 6]);
+            // Data reference index
+            put_u16(buf, 1);
+            buf.extend_from_slice(&[0; 16]);
+            put_u16(buf, track.width as u16);
+            put_u16(buf, track.height as u16);
+            // 72 dpi horizontal and vertical resolution
+            put_u32(buf, 0x0048_0000);
+            put_u32(buf, 0x0048_0000);
+            put_u32(buf, 0);
+            // Frame count
+            put_u16(buf, 1);
+            // Compressor name (a zero length pascal string padded to 32 bytes)
+            buf.extend_from_slice(&[0; 32]);
+            // Depth and pre-defined
+            put_u16(buf, 0x0018);
+            put_u16(buf, 0xffff);
+
+            write_box(buf, b"avcC", |buf| {
+                let record = build_avc_decoder_configuration_record(&[track.sps], &[track.pps])
+                    .expect("annex_b_to_sample only returns complete parameter sets");
+                buf.extend_from_slice(&record);
+            });
+        });
+    });
+}
+
+/// Converts an Annex-B access unit into a length-prefixed MP4 sample.
+///
+/// Parameter sets and access unit delimiters are dropped since they are stored in the avcC box instead. Returns the
+/// SPS and PPS (if present) and whether the access unit contains an IDR slice. Truncated SPS, which can't be stored in
+/// the avcC box, are ignored.
+pub(crate) fn annex_b_to_sample<'a>(
+    access_unit: &'a [u8],
+    sample: &mut Vec<u8>,
+) -> (Option<&'a [u8]>, Option<&'a [u8]>, bool) {
+    let mut sps = None;
+    let mut pps = None;
+    let mut is_keyframe = false;
+
+    for nal in NalUnits::new(access_unit) {
+        match NalType::of(nal) {
+            NalType::Sps if nal.len() >= 4 && nal.len() <= u16::MAX as usize => sps = Some(nal),
+            NalType::Pps if nal.len() <= u16::MAX as usize => pps = Some(nal),
+            NalType::Sps | NalType::Pps => {}
+            NalType::Aud => {}
+            nal_type => {
+                is_keyframe |= nal_type == NalType::Idr;
+                put_u32(sample, nal.len() as u32);
+                sample.extend_from_slice(nal);
+            }
+        }
+    }
+
+    (sps, pps, is_keyframe)
+}
@@ -0,0 +1,210 @@
+use super::boxes::{self, put_u32, put_u64, write_box, write_full_box, VideoTrack, TIMESCALE};
+use crate::FrameMeta;
+use std::time::Duration;
+
+/// A piece of a fragmented MP4 stream, eg. to be appended to a Media Source Extensions SourceBuffer.
+#[derive(Debug, Clone)]
+pub enum Segment {
+    /// The initialization segment (ftyp and moov). This is always emitted first, exactly once.
+    Init(Vec<u8>),
+    /// A media segment (moof and mdat) containing one GOP, starting with a key frame, or a single frame when
+    /// [`fragment_per_frame`](FragmentedMp4Muxer::fragment_per_frame) is set.
+    Media(Vec<u8>),
+}
+
+struct Sample {
+    data: Vec<u8>,
+    time: u64,
+    is_keyframe: bool,
+}
+
+/// Muxes H264 access units from [`WebcamH264Stream::next`](crate::WebcamH264Stream::next) into fragmented MP4
+/// segments for live streaming.
+///
+/// Access units are buffered until the next key frame so that each media segment contains a whole GOP. Frames before
+/// the first key frame are dropped.
+pub struct FragmentedMp4Muxer {
+    width: u32,
+    height: u32,
+    frame_duration: u64,
+    init_sent: bool,
+    fragment_per_frame: bool,
+    gop: Vec<Sample>,
+    sequence_number: u32,
+    last_time: Option<u64>,
+    first_timestamp: Option<Duration>,
+}
+
+impl FragmentedMp4Muxer {
+    /// Creates a muxer for a stream of the given dimensions. `fps` is used for the sample timing of frames pushed
+    /// without metadata.
+    pub fn new(width: u32, height: u32, fps: f64) -> Self {
+        Self {
+            width,
+            height,
+            frame_duration: (TIMESCALE as f64 / fps).round() as u64,
+            init_sent: false,
+            fragment_per_frame: false,
+            gop: Vec::new(),
+            sequence_number: 0,
+            last_time: None,
+            first_timestamp: None,
+        }
+    }
+
+    /// Emits each frame as its own media segment (once the next frame gives its duration) instead of buffering whole
+    /// GOPs, so that live viewers are only a frame behind the camera.
+    pub fn fragment_per_frame(mut self, fragment_per_frame: bool) -> Self {
+        self.fragment_per_frame = fragment_per_frame;
+        self
+    }
+
+    /// Adds an access unit, timing it at the fixed frame rate given when the muxer was created.
+    ///
+    /// Returns any segments that were completed by this access unit.
+    pub fn push(&mut self, h264_bytes: &[u8]) -> Vec<Segment> {
+        let time = self
+            .last_time
+            .map(|time| time + self.frame_duration)
+            .unwrap_or(0);
+
+        self.push_sample(h264_bytes, time)
+    }
+
+    /// Adds an access unit, timing it using the frame's capture timestamp.
+    ///
+    /// Returns any segments that were completed by this access unit.
+    pub fn push_with_meta(&mut self, h264_bytes: &[u8], meta: &FrameMeta) -> Vec<Segment> {
+        let first_timestamp = *self.first_timestamp.get_or_insert(meta.timestamp);
+        let elapsed = meta.timestamp.saturating_sub(first_timestamp);
+        let mut time = (elapsed.as_secs_f64() * TIMESCALE as f64).round() as u64;
+
+        // Sample times must be strictly increasing
+        if let Some(last) = self.last_time {
+            time = time.max(last + 1);
+        }
+
+        self.push_sample(h264_bytes, time)
+    }
+
+    /// Emits the buffered GOP as a media segment, eg. when the stream is ending.
+    pub fn flush(&mut self) -> Option<Segment> {
+        let end_time = self.gop.last()?.time + self.frame_duration;
+        Some(self.media_segment(end_time))
+    }
+
+    fn push_sample(&mut self, h264_bytes: &[u8], time: u64) -> Vec<Segment> {
+        let mut data = Vec::new();
+        let (sps, pps, is_keyframe) = boxes::annex_b_to_sample(h264_bytes, &mut data);
+
+        let mut segments = Vec::new();
+
+        if !self.init_sent {
+            // The first segment has to start at a key frame that carries the parameter sets
+            let (Some(sps), Some(pps), true) = (sps, pps, is_keyframe) else {
+                return segments;
+            };
+
+            segments.push(Segment::Init(self.init_segment(sps, pps)));
+            self.init_sent = true;
+        }
+
+        if data.is_empty() {
+            return segments;
+        }
+
+        if (is_keyframe || self.fragment_per_frame) && !self.gop.is_empty() {
+            segments.push(self.media_segment(time));
+        }
+
+        self.last_time = Some(time);
+        self.gop.push(Sample {
+            data,
+            time,
+            is_keyframe,
+        });
+
+        segments
+    }
+
+    fn init_segment(&self, sps: &[u8], pps: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        boxes::write_ftyp(&mut buf, b"iso5", &[b"iso5", b"iso6", b"avc1", b"mp41"]);
+
+        let track = VideoTrack {
+            width: self.width,
+            height: self.height,
+            sps,
+            pps,
+            duration: 0,
+        };
+
+        // Samples are described by the movie fragments so the sample tables are empty
+        boxes::write_moov(&mut buf, &track, true, |buf| {
+            for name in [b"stts", b"stsc", b"stco"] {
+                write_full_box(buf, name, 0, 0, |buf| put_u32(buf, 0));
+            }
+            write_full_box(buf, b"stsz", 0, 0, |buf| {
+                put_u32(buf, 0);
+                put_u32(buf, 0);
+            });
+        });
+
+        buf
+    }
+
+    /// Writes the buffered GOP as a moof and mdat. `end_time` is the time of the sample following the GOP.
+    fn media_segment(&mut self, end_time: u64) -> Segment {
+        let gop = std::mem::take(&mut self.gop);
+        self.sequence_number += 1;
+
+        let mut buf = Vec::new();
+        let mut data_offset_position = 0;
+
+        write_box(&mut buf, b"moof", |buf| {
+            write_full_box(buf, b"mfhd", 0, 0, |buf| put_u32(buf, self.sequence_number));
+
+            write_box(buf, b"traf", |buf| {
+                // Flag 0x020000: sample data offsets are relative to the start of the moof
+                write_full_box(buf, b"tfhd", 0, 0x02_0000, |buf| put_u32(buf, 1));
+
+                write_full_box(buf, b"tfdt", 1, 0, |buf| put_u64(buf, gop[0].time));
+
+                // Flags: data offset, sample duration, sample size and sample flags are present
+                write_full_box(buf, b"trun", 0, 0x000701, |buf| {
+                    put_u32(buf, gop.len() as u32);
+                    data_offset_position = buf.len();
+                    put_u32(buf, 0);
+
+                    let next_times = gop.iter().skip(1).map(|sample| sample.time);
+
+                    for (sample, next_time) in gop.iter().zip(next_times.chain([end_time])) {
+                        put_u32(buf, (next_time - sample.time) as u32);
+                        put_u32(buf, sample.data.len() as u32);
+
+                        // Key frames do not depend on other samples, all other frames do and are not sync samples
+                        let flags = if sample.is_keyframe {
+                            0x0200_0000
+                        } else {
+                            0x0101_0000
+                        };
+                        put_u32(buf, flags);
+                    }
+                });
+            });
+        });
+
+        // The sample data starts after the moof and the mdat header
+        let data_offset = buf.len() as u32 + 8;
+        buf[data_offset_position..data_offset_position + 4]
+            .copy_from_slice(&data_offset.to_be_bytes());
+
+        write_box(&mut buf, b"mdat", |buf| {
+            for sample in &gop {
+                buf.extend_from_slice(&sample.data);
+            }
+        });
+
+        Segment::Media(buf)
+    }
+}
@@ -0,0 +1,222 @@
+//! Muxing of the H264 stream into MP4 files that can be played without post-processing.
+
+pub(crate) mod boxes;
+mod fragmented;
+
+pub use fragmented::{FragmentedMp4Muxer, Segment};
+
+use crate::FrameMeta;
+use boxes::{put_u32, write_full_box, VideoTrack, TIMESCALE};
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::Duration;
+
+/// Writes H264 access units from [`WebcamH264Stream::next`](crate::WebcamH264Stream::next) to an MP4 file.
+///
+/// Frames before the first key frame are skipped since they cannot be decoded. The file is only playable once
+/// [`Mp4Writer::finish`] has been called.
+pub struct Mp4Writer<W: Write + Seek> {
+    writer: W,
+    width: u32,
+    height: u32,
+    frame_duration: u64,
+    sps: Option<Vec<u8>>,
+    pps: Option<Vec<u8>>,
+    // Offset of the mdat box's 64 bit size field
+    mdat_offset: u64,
+    mdat_size: u64,
+    sample: Vec<u8>,
+    sample_sizes: Vec<u32>,
+    // Decode timestamps in TIMESCALE units relative to the first sample
+    sample_times: Vec<u64>,
+    sync_samples: Vec<u32>,
+    first_timestamp: Option<Duration>,
+}
+
+impl Mp4Writer<BufWriter<File>> {
+    /// Creates an MP4 file at `path`.
+    ///
+    /// `fps` is used for the sample timing of frames written without metadata.
+    pub fn create<P: AsRef<Path>>(path: P, width: u32, height: u32, fps: f64) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?), width, height, fps)
+    }
+}
+
+impl<W: Write + Seek> Mp4Writer<W> {
+    /// Starts writing an MP4 file to `writer`.
+    pub fn new(mut writer: W, width: u32, height: u32, fps: f64) -> io::Result<Self> {
+        let mut header = Vec::new();
+        boxes::write_ftyp(&mut header, b"isom", &[b"isom", b"iso2", b"avc1", b"mp41"]);
+
+        let mdat_offset = writer.stream_position()? + header.len() as u64 + 8;
+
+        // The mdat size isn't known until the file is finished so a 64 bit size is reserved
+        put_u32(&mut header, 1);
+        header.extend_from_slice(b"mdat");
+        header.extend_from_slice(&[0; 8]);
+        writer.write_all(&header)?;
+
+        Ok(Self {
+            writer,
+            width,
+            height,
+            frame_duration: (TIMESCALE as f64 / fps).round() as u64,
+            sps: None,
+            pps: None,
+            mdat_offset,
+            mdat_size: 16,
+            sample: Vec::new(),
+            sample_sizes: Vec::new(),
+            sample_times: Vec::new(),
+            sync_samples: Vec::new(),
+            first_timestamp: None,
+        })
+    }
+
+    /// Writes an access unit, timing it at the fixed frame rate given when the writer was created.
+    pub fn write(&mut self, h264_bytes: &[u8]) -> io::Result<()> {
+        let time = self
+            .sample_times
+            .last()
+            .map(|time| time + self.frame_duration)
+            .unwrap_or(0);
+
+        self.write_sample(h264_bytes, time)
+    }
+
+    /// Writes an access unit, timing it using the frame's capture timestamp.
+    pub fn write_with_meta(&mut self, h264_bytes: &[u8], meta: &FrameMeta) -> io::Result<()> {
+        let first_timestamp = *self.first_timestamp.get_or_insert(meta.timestamp);
+        let elapsed = meta.timestamp.saturating_sub(first_timestamp);
+        let mut time = (elapsed.as_secs_f64() * TIMESCALE as f64).round() as u64;
+
+        // Sample times must be strictly increasing
+        if let Some(last) = self.sample_times.last() {
+            time = time.max(last + 1);
+        }
+
+        self.write_sample(h264_bytes, time)
+    }
+
+    fn write_sample(&mut self, h264_bytes: &[u8], time: u64) -> io::Result<()> {
+        self.sample.clear();
+        let (sps, pps, is_keyframe) = boxes::annex_b_to_sample(h264_bytes, &mut self.sample);
+
+        if self.sps.is_none() {
+            self.sps = sps.map(<[u8]>::to_vec);
+        }
+        if self.pps.is_none() {
+            self.pps = pps.map(<[u8]>::to_vec);
+        }
+
+        if self.sample.is_empty() || (self.sample_sizes.is_empty() && !is_keyframe) {
+            return Ok(());
+        }
+
+        self.writer.write_all(&self.sample)?;
+        self.mdat_size += self.sample.len() as u64;
+
+        if is_keyframe {
+            self.sync_samples.push(self.sample_sizes.len() as u32 + 1);
+        }
+        self.sample_sizes.push(self.sample.len() as u32);
+        self.sample_times.push(time);
+
+        Ok(())
+    }
+
+    /// Writes the moov box and patches the mdat size, returning the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        let (Some(sps), Some(pps)) = (&self.sps, &self.pps) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "No SPS / PPS found in the H264 stream",
+            ));
+        };
+
+        let durations = self
+            .sample_times
+            .windows(2)
+            .map(|times| (times[1] - times[0]) as u32)
+            .collect::<Vec<_>>();
+
+        // The last sample is given the average frame duration of the stream
+        let last_duration = match self.sample_times.as_slice() {
+            [first, .., last] => (last - first) / durations.len() as u64,
+            _ => self.frame_duration,
+        } as u32;
+
+        let track = VideoTrack {
+            width: self.width,
+            height: self.height,
+            sps,
+            pps,
+            duration: self
+                .sample_times
+                .last()
+                .map(|time| time + last_duration as u64)
+                .unwrap_or(0),
+        };
+
+        let mut moov = Vec::new();
+        boxes::write_moov(&mut moov, &track, false, |buf| {
+            // Run-length encoded sample durations
+            let mut stts = Vec::<(u32, u32)>::new();
+            let last_duration = (!self.sample_times.is_empty()).then_some(last_duration);
+
+            for duration in durations.iter().copied().chain(last_duration) {
+                match stts.last_mut() {
+                    Some((count, last)) if *last == duration => *count += 1,
+                    _ => stts.push((1, duration)),
+                }
+            }
+
+            write_full_box(buf, b"stts", 0, 0, |buf| {
+                put_u32(buf, stts.len() as u32);
+                for (count, duration) in stts {
+                    put_u32(buf, count);
+                    put_u32(buf, duration);
+                }
+            });
+
+            write_full_box(buf, b"stss", 0, 0, |buf| {
+                put_u32(buf, self.sync_samples.len() as u32);
+                for sample in &self.sync_samples {
+                    put_u32(buf, *sample);
+                }
+            });
+
+            // All samples are in a single chunk
+            write_full_box(buf, b"stsc", 0, 0, |buf| {
+                put_u32(buf, 1);
+                put_u32(buf, 1);
+                put_u32(buf, self.sample_sizes.len() as u32);
+                put_u32(buf, 1);
+            });
+
+            write_full_box(buf, b"stsz", 0, 0, |buf| {
+                put_u32(buf, 0);
+                put_u32(buf, self.sample_sizes.len() as u32);
+                for size in &self.sample_sizes {
+                    put_u32(buf, *size);
+                }
+            });
+
+            write_full_box(buf, b"co64", 0, 0, |buf| {
+                put_u32(buf, 1);
+                boxes::put_u64(buf, self.mdat_offset + 8);
+            });
+        });
+
+        self.writer.write_all(&moov)?;
+
+        let end = self.writer.stream_position()?;
+        self.writer.seek(SeekFrom::Start(self.mdat_offset))?;
+        self.writer.write_all(&self.mdat_size.to_be_bytes())?;
+        self.writer.seek(SeekFrom::Start(end))?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+}
@@ -0,0 +1,479 @@
+//! Muxing of the H264 stream into an MPEG transport stream, eg. for streaming to ffmpeg, VLC or an ingest server over
+//! TCP or UDP.
+
+use crate::nal::{self, NalType, NalUnits};
+use crate::FrameMeta;
+use std::io::{self, Write};
+use std::time::Duration;
+
+const PACKET_SIZE: usize = 188;
+const PMT_PID: u16 = 0x1000;
+const VIDEO_PID: u16 = 0x100;
+/// The 90kHz clock used for PTS values.
+const CLOCK_RATE: u64 = 90_000;
+/// How far the PTS leads the PCR, giving the decoder time to buffer each frame. Matches ffmpeg's default mux delay.
+const PTS_DELAY: u64 = CLOCK_RATE * 7 / 10;
+const MAX_TIMESTAMP: u64 = (1 << 33) - 1;
+const AUD: [u8; 6] = [0, 0, 0, 1, 0x09, 0xf0];
+
+/// Writes H264 access units from [`WebcamH264Stream::next`](crate::WebcamH264Stream::next) to a writer as 188 byte
+/// MPEG-TS packets.
+///
+/// Each access unit is sent as a PES packet with a PCR on the video PID. Access unit delimiters are inserted and the
+/// most recent SPS and PPS are repeated before key frames that lack them so that receivers can join mid-stream. Frames
+/// before the first key frame are skipped since they cannot be decoded.
+pub struct TsMuxer<W: Write> {
+    writer: W,
+    frame_duration: u64,
+    psi_interval: u64,
+    last_psi: Option<u64>,
+    last_time: Option<u64>,
+    first_timestamp: Option<Duration>,
+    parameter_sets: nal::ParameterSets,
+    started: bool,
+    pat_continuity: u8,
+    pmt_continuity: u8,
+    video_continuity: u8,
+    packets: Vec<u8>,
+}
+
+impl<W: Write> TsMuxer<W> {
+    /// Starts writing a transport stream to `writer`. `fps` is used for the timing of frames written without metadata.
+    pub fn new(writer: W, fps: f64) -> Self {
+        Self {
+            writer,
+            frame_duration: (CLOCK_RATE as f64 / fps).round() as u64,
+            psi_interval: CLOCK_RATE / 10,
+            last_psi: None,
+            last_time: None,
+            first_timestamp: None,
+            parameter_sets: Default::default(),
+            started: false,
+            pat_continuity: 0,
+            pmt_continuity: 0,
+            video_continuity: 0,
+            packets: Vec::new(),
+        }
+    }
+
+    /// Sets how often the PAT and PMT tables are repeated (defaults to 100ms). They are always sent before the first
+    /// frame.
+    pub fn pat_pmt_interval(mut self, interval: Duration) -> Self {
+        self.psi_interval = (interval.as_secs_f64() * CLOCK_RATE as f64).round() as u64;
+        self
+    }
+
+    /// Writes an access unit, timing it at the fixed frame rate given when the muxer was created.
+    pub fn write(&mut self, h264_bytes: &[u8]) -> io::Result<()> {
+        let time = self
+            .last_time
+            .map(|time| time + self.frame_duration)
+            .unwrap_or(0);
+
+        self.write_access_unit(h264_bytes, time)
+    }
+
+    /// Writes an access unit, timing it using the frame's capture timestamp.
+    pub fn write_with_meta(&mut self, h264_bytes: &[u8], meta: &FrameMeta) -> io::Result<()> {
+        let first_timestamp = *self.first_timestamp.get_or_insert(meta.timestamp);
+        let elapsed = meta.timestamp.saturating_sub(first_timestamp);
+        let mut time = (elapsed.as_secs_f64() * CLOCK_RATE as f64).round() as u64;
+
+        // Timestamps must be strictly increasing
+        if let Some(last) = self.last_time {
+            time = time.max(last + 1);
+        }
+
+        self.write_access_unit(h264_bytes, time)
+    }
+
+    /// Flushes and returns the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    // Only used by the HLS segmenter
+    #[cfg_attr(not(feature = "hls"), allow(dead_code))]
+    pub(crate) fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    /// Sends the PAT and PMT before the next frame, eg. at the start of an HLS segment.
+    #[cfg_attr(not(feature = "hls"), allow(dead_code))]
+    pub(crate) fn repeat_pat_pmt(&mut self) {
+        self.last_psi = None;
+    }
+
+    fn write_access_unit(&mut self, h264_bytes: &[u8], time: u64) -> io::Result<()> {
+        let is_keyframe = self.parameter_sets.update(h264_bytes);
+
+        if !self.started && !is_keyframe {
+            return Ok(());
+        }
+        self.started = true;
+        self.last_time = Some(time);
+
+        // Rebuild the access unit so that it starts with a delimiter followed by the parameter sets
+        let mut es = AUD.to_vec();
+        let has_sps = NalUnits::new(h264_bytes).any(|nal| NalType::of(nal) == NalType::Sps);
+
+        if is_keyframe && !has_sps {
+            if let nal::ParameterSets {
+                sps: Some(sps),
+                pps: Some(pps),
+                ..
+            } = &self.parameter_sets
+            {
+                write_nal(&mut es, sps);
+                write_nal(&mut es, pps);
+            }
+        }
+
+        for nal in NalUnits::new(h264_bytes).filter(|nal| NalType::of(nal) != NalType::Aud) {
+            write_nal(&mut es, nal);
+        }
+
+        self.packets.clear();
+
+        if self
+            .last_psi
+            .is_none_or(|last| time.saturating_sub(last) >= self.psi_interval)
+        {
+            self.last_psi = Some(time);
+            self.write_psi();
+        }
+
+        self.write_pes(&es, time, is_keyframe);
+
+        self.writer.write_all(&self.packets)
+    }
+
+    fn write_psi(&mut self) {
+        let mut pat = vec![
+            0x00,
+            0xb0,
+            0x0d,
+            // Transport stream ID
+            0x00,
+            0x01,
+            // Version 0, current
+            0xc1,
+            0x00,
+            0x00,
+            // Program 1
+            0x00,
+            0x01,
+            0xe0 | (PMT_PID >> 8) as u8,
+            PMT_PID as u8,
+        ];
+        pat.extend_from_slice(&crc32(&pat).to_be_bytes());
+
+        let mut pmt = vec![
+            0x02,
+            0xb0,
+            0x12,
+            // Program 1
+            0x00,
+            0x01,
+            0xc1,
+            0x00,
+            0x00,
+            // PCR PID
+            0xe0 | (VIDEO_PID >> 8) as u8,
+            VIDEO_PID as u8,
+            // No program descriptors
+            0xf0,
+            0x00,
+            // H264 stream
+            0x1b,
+            0xe0 | (VIDEO_PID >> 8) as u8,
+            VIDEO_PID as u8,
+            0xf0,
+            0x00,
+        ];
+        pmt.extend_from_slice(&crc32(&pmt).to_be_bytes());
+
+        for (pid, section) in [(0, pat), (PMT_PID, pmt)] {
+            // A pointer field, the section and then stuffing to fill the packet
+            let mut payload = vec![0x00];
+            payload.extend_from_slice(&section);
+            payload.resize(PACKET_SIZE - 4, 0xff);
+
+            self.write_packets(pid, &payload, None);
+        }
+    }
+
+    fn write_pes(&mut self, es: &[u8], time: u64, is_keyframe: bool) {
+        let pts = (time + PTS_DELAY) & MAX_TIMESTAMP;
+
+        // The PES packet length is left as 0 (unbounded), which is allowed for video streams
+        let mut pes = vec![0x00, 0x00, 0x01, 0xe0, 0x00, 0x00, 0x84, 0x80, 0x05];
+        pes.extend_from_slice(&[
+            0x21 | ((pts >> 29) & 0x0e) as u8,
+            (pts >> 22) as u8,
+            0x01 | ((pts >> 14) & 0xfe) as u8,
+            (pts >> 7) as u8,
+            0x01 | ((pts << 1) & 0xfe) as u8,
+        ]);
+        pes.extend_from_slice(es);
+
+        let pcr = time & MAX_TIMESTAMP;
+        let adaptation_field = vec![
+            // Flag a random access point on key frames and include the PCR
+            if is_keyframe { 0x50 } else { 0x10 },
+            (pcr >> 25) as u8,
+            (pcr >> 17) as u8,
+            (pcr >> 9) as u8,
+            (pcr >> 1) as u8,
+            // The PCR extension is always 0
+            ((pcr & 1) << 7) as u8 | 0x7e,
+            0x00,
+        ];
+
+        self.write_packets(VIDEO_PID, &pes, Some(adaptation_field));
+    }
+
+    /// Splits a payload into packets. The adaptation field (without its length byte) is added to the first packet.
+    fn write_packets(
+        &mut self,
+        pid: u16,
+        mut payload: &[u8],
+        mut adaptation_field: Option<Vec<u8>>,
+    ) {
+        let mut payload_start = true;
+
+        while !payload.is_empty() {
+            let mut adaptation_field = adaptation_field.take();
+
+            let room = PACKET_SIZE
+                - 4
+                - adaptation_field
+                    .as_ref()
+                    .map_or(0, |adaptation_field| 1 + adaptation_field.len());
+            let len = payload.len().min(room);
+
+            // The last packet is padded with stuffing bytes in its adaptation field
+            if len < room {
+                let stuffing = room - len;
+
+                match &mut adaptation_field {
+                    Some(adaptation_field) => {
+                        adaptation_field.resize(adaptation_field.len() + stuffing, 0xff)
+                    }
+                    // An empty adaptation field is just its length byte
+                    None if stuffing == 1 => adaptation_field = Some(Vec::new()),
+                    None => {
+                        let mut stuffing_field = vec![0xff; stuffing - 1];
+                        stuffing_field[0] = 0x00;
+                        adaptation_field = Some(stuffing_field);
+                    }
+                }
+            }
+
+            let continuity = match pid {
+                0 => &mut self.pat_continuity,
+                PMT_PID => &mut self.pmt_continuity,
+                _ => &mut self.video_continuity,
+            };
+
+            let start = self.packets.len();
+            self.packets.extend_from_slice(&[
+                0x47,
+                ((payload_start as u8) << 6) | (pid >> 8) as u8 & 0x1f,
+                pid as u8,
+                if adaptation_field.is_some() {
+                    0x30
+                } else {
+                    0x10
+                } | *continuity,
+            ]);
+            *continuity = (*continuity + 1) & 0x0f;
+
+            if let Some(adaptation_field) = adaptation_field {
+                self.packets.push(adaptation_field.len() as u8);
+                self.packets.extend_from_slice(&adaptation_field);
+            }
+
+            self.packets.extend_from_slice(&payload[..len]);
+            debug_assert_eq!(self.packets.len() - start, PACKET_SIZE);
+
+            payload = &payload[len..];
+            payload_start = false;
+        }
+    }
+}
+
+fn write_nal(es: &mut Vec<u8>, nal: &[u8]) {
+    es.extend_from_slice(&[0, 0, 0, 1]);
+    es.extend_from_slice(nal);
+}
+
+/// The CRC-32/MPEG-2 checksum used by PSI sections.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffff_u32;
+
+    for byte in data {
+        crc ^= (*byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+#[cfg(all(test, feature = "openh264"))]
+mod tests {
+    use super::*;
+    use crate::synthetic::tests::access_units;
+    use std::collections::HashMap;
+
+    struct Packet<'a> {
+        pid: u16,
+        payload_start: bool,
+        continuity: u8,
+        adaptation_field: Option<&'a [u8]>,
+        payload: &'a [u8],
+    }
+
+    fn parse_packets(ts: &[u8]) -> Vec<Packet<'_>> {
+        assert_eq!(ts.len() % PACKET_SIZE, 0, "The stream is whole packets");
+
+        ts.chunks_exact(PACKET_SIZE)
+            .map(|packet| {
+                assert_eq!(packet[0], 0x47, "Every packet starts with the sync byte");
+                let has_adaptation_field = packet[3] & 0x20 != 0;
+                let payload_offset = if has_adaptation_field {
+                    5 + packet[4] as usize
+                } else {
+                    4
+                };
+
+                Packet {
+                    pid: u16::from_be_bytes([packet[1] & 0x1f, packet[2]]),
+                    payload_start: packet[1] & 0x40 != 0,
+                    continuity: packet[3] & 0x0f,
+                    adaptation_field: has_adaptation_field.then(|| &packet[5..payload_offset]),
+                    payload: &packet[payload_offset..],
+                }
+            })
+            .collect()
+    }
+
+    /// Muxes 25 frames with a key frame every 10, where the second and third key frames lack parameter sets.
+    fn mux() -> Vec<u8> {
+        let mut access_units = access_units(64, 64, 25, 10);
+        for keyframe in [10, 20] {
+            access_units[keyframe] = NalUnits::new(&access_units[keyframe])
+                .filter(|nal| !matches!(NalType::of(nal), NalType::Sps | NalType::Pps))
+                .flat_map(|nal| [&[0, 0, 0, 1], nal].concat())
+                .collect();
+        }
+
+        let mut muxer = TsMuxer::new(Vec::new(), 30.0);
+        for access_unit in &access_units {
+            muxer.write(access_unit).unwrap();
+        }
+        muxer.finish().unwrap()
+    }
+
+    #[test]
+    fn counts_continuity_per_pid() {
+        let ts = mux();
+        let mut continuity: HashMap<u16, u8> = HashMap::new();
+
+        for packet in parse_packets(&ts) {
+            if let Some(previous) = continuity.insert(packet.pid, packet.continuity) {
+                assert_eq!(
+                    packet.continuity,
+                    (previous + 1) & 0x0f,
+                    "PID {:#x}",
+                    packet.pid
+                );
+            }
+        }
+
+        let mut pids: Vec<u16> = continuity.into_keys().collect();
+        pids.sort();
+        assert_eq!(pids, [0, VIDEO_PID, PMT_PID]);
+    }
+
+    #[test]
+    fn writes_tables_with_valid_crcs() {
+        let ts = mux();
+        let packets = parse_packets(&ts);
+
+        let section = |pid| {
+            let packet = packets.iter().find(|packet| packet.pid == pid).unwrap();
+            assert!(packet.payload_start);
+            // After the pointer field
+            let section = &packet.payload[1..];
+            let len = u16::from_be_bytes([section[1] & 0x0f, section[2]]) as usize;
+            &section[..3 + len]
+        };
+
+        // The PAT is the same as ffmpeg's for a single program
+        let pat = section(0);
+        assert_eq!(&pat[pat.len() - 4..], [0x2a, 0xb1, 0x04, 0xb2]);
+        assert_eq!(crc32(pat), 0, "The PAT's CRC covers the section");
+
+        let pmt = section(PMT_PID);
+        assert_eq!(&pmt[pmt.len() - 4..], [0x15, 0xbd, 0x4d, 0x56]);
+        assert_eq!(crc32(pmt), 0, "The PMT's CRC covers the section");
+    }
+
+    #[test]
+    fn writes_frames_with_pcrs_delimiters_and_parameter_sets() {
+        let ts = mux();
+
+        // Reassembles the video PES packets, checking each one starts with a PCR
+        let mut pes_packets: Vec<(bool, Vec<u8>)> = Vec::new();
+        for packet in parse_packets(&ts)
+            .into_iter()
+            .filter(|packet| packet.pid == VIDEO_PID)
+        {
+            if packet.payload_start {
+                let adaptation_field = packet
+                    .adaptation_field
+                    .expect("PES packets start with a PCR");
+                assert_ne!(adaptation_field[0] & 0x10, 0, "The PCR flag is set");
+                let random_access = adaptation_field[0] & 0x40 != 0;
+                pes_packets.push((random_access, Vec::new()));
+            }
+            pes_packets
+                .last_mut()
+                .unwrap()
+                .1
+                .extend_from_slice(packet.payload);
+        }
+        assert_eq!(pes_packets.len(), 25);
+
+        for (frame, (random_access, pes)) in pes_packets.iter().enumerate() {
+            assert_eq!(pes[..4], [0, 0, 1, 0xe0]);
+            // After the header and PTS
+            let es = &pes[14..];
+            assert!(
+                es.starts_with(&AUD),
+                "Frame {frame} starts with a delimiter"
+            );
+
+            let types: Vec<NalType> = NalUnits::new(es).map(NalType::of).collect();
+            let is_keyframe = frame % 10 == 0;
+            assert_eq!(*random_access, is_keyframe);
+            if is_keyframe {
+                assert_eq!(
+                    types,
+                    [NalType::Aud, NalType::Sps, NalType::Pps, NalType::Idr],
+                    "Frame {frame} has its parameter sets"
+                );
+            } else {
+                assert_eq!(types, [NalType::Aud, NalType::NonIdr]);
+            }
+        }
+    }
+}
@@ -0,0 +1,542 @@
+//! Format negotiation and mmap streaming for both the single-planar capture API and the multi-planar API
+//! (`V4L2_CAP_VIDEO_CAPTURE_MPLANE`) used by eg. the i.MX and Rockchip camera drivers. v4l only supports the
+//! single-planar API, and its stream requeues buffers before it knows a frame is ready, so the ioctls are issued
+//! directly.
+
+use crate::pipeline::Pipeline;
+use std::os::unix::io::RawFd;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{io, mem, ptr, slice};
+use tracing::warn;
+use v4l::buffer::{Metadata, Type};
+use v4l::capability::Flags;
+use v4l::device::Handle;
+use v4l::memory::Memory;
+use v4l::v4l2::{self, vidioc};
+use v4l::v4l_sys::{
+    v4l2_buffer, v4l2_fmtdesc, v4l2_format, v4l2_format__bindgen_ty_1, v4l2_pix_format_mplane,
+    v4l2_plane, v4l2_requestbuffers, v4l2_streamparm, v4l2_streamparm__bindgen_ty_1,
+    VIDEO_MAX_PLANES,
+};
+use v4l::video::capture::Parameters;
+use v4l::video::Capture;
+use v4l::{Device, Format, FourCC};
+
+/// Returns true if the device only supports multi-planar capture.
+pub(crate) fn is_multiplanar(dev: &Device) -> bool {
+    dev.query_caps().is_ok_and(|caps| {
+        caps.capabilities.contains(Flags::VIDEO_CAPTURE_MPLANE)
+            && !caps.capabilities.contains(Flags::VIDEO_CAPTURE)
+    })
+}
+
+/// Lists the pixel formats the device can capture in.
+pub(crate) fn enum_fourccs(dev: &Device) -> Vec<FourCC> {
+    if !is_multiplanar(dev) {
+        return dev
+            .enum_formats()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|desc| desc.fourcc)
+            .collect();
+    }
+
+    (0..)
+        .map_while(|index| {
+            let mut desc = v4l2_fmtdesc {
+                index,
+                type_: Type::VideoCaptureMplane as u32,
+                // SAFETY: An all zero v4l2_fmtdesc is valid
+                ..unsafe { mem::zeroed() }
+            };
+
+            // SAFETY: The format description outlives the ioctl
+            unsafe { ioctl(&dev.handle(), vidioc::VIDIOC_ENUM_FMT, &mut desc) }
+                .ok()
+                .map(|()| FourCC::from(desc.pixelformat))
+        })
+        .collect()
+}
+
+/// Sets the capture format, returning the format the driver applied.
+pub(crate) fn set_format(dev: &Device, multiplanar: bool, fmt: &Format) -> io::Result<Format> {
+    if !multiplanar {
+        return dev.set_format(fmt);
+    }
+
+    // SAFETY: v4l2_format is plain old data and outlives the ioctl
+    unsafe {
+        let mut v4l2_fmt = v4l2_format {
+            type_: Type::VideoCaptureMplane as u32,
+            fmt: v4l2_format__bindgen_ty_1 {
+                pix_mp: v4l2_pix_format_mplane {
+                    width: fmt.width,
+                    height: fmt.height,
+                    pixelformat: fmt.fourcc.into(),
+                    field: fmt.field_order as u32,
+                    ..mem::zeroed()
+                },
+            },
+        };
+
+        ioctl(&dev.handle(), vidioc::VIDIOC_S_FMT, &mut v4l2_fmt)?;
+
+        Ok(format_from_mplane(&v4l2_fmt.fmt.pix_mp))
+    }
+}
+
+/// Gets the capture format.
+pub(crate) fn format(dev: &Device, multiplanar: bool) -> io::Result<Format> {
+    if !multiplanar {
+        return dev.format();
+    }
+
+    // SAFETY: v4l2_format is plain old data and outlives the ioctl
+    unsafe {
+        let mut v4l2_fmt = v4l2_format {
+            type_: Type::VideoCaptureMplane as u32,
+            ..mem::zeroed()
+        };
+
+        ioctl(&dev.handle(), vidioc::VIDIOC_G_FMT, &mut v4l2_fmt)?;
+
+        Ok(format_from_mplane(&v4l2_fmt.fmt.pix_mp))
+    }
+}
+
+/// Converts a multi-planar format, taking the stride and size from the first plane (which holds all of the planes of
+/// the single buffer formats that are supported).
+fn format_from_mplane(pix_mp: &v4l2_pix_format_mplane) -> Format {
+    let mut format = Format::new(
+        pix_mp.width,
+        pix_mp.height,
+        FourCC::from(pix_mp.pixelformat),
+    );
+    format.stride = pix_mp.plane_fmt[0].bytesperline;
+    format.size = pix_mp.plane_fmt[0].sizeimage;
+    format
+}
+
+/// Sets the frame interval.
+pub(crate) fn set_params(dev: &Device, multiplanar: bool, params: &Parameters) -> io::Result<()> {
+    if !multiplanar {
+        return dev.set_params(params).map(drop);
+    }
+
+    let mut v4l2_params = v4l2_streamparm {
+        type_: Type::VideoCaptureMplane as u32,
+        parm: v4l2_streamparm__bindgen_ty_1 {
+            capture: (*params).into(),
+        },
+    };
+
+    // SAFETY: v4l2_streamparm is plain old data and outlives the ioctl
+    unsafe { ioctl(&dev.handle(), vidioc::VIDIOC_S_PARM, &mut v4l2_params) }
+}
+
+/// Gets the frame interval.
+pub(crate) fn params(dev: &Device, multiplanar: bool) -> io::Result<Parameters> {
+    if !multiplanar {
+        return dev.params();
+    }
+
+    // SAFETY: v4l2_streamparm is plain old data and outlives the ioctl
+    unsafe {
+        let mut v4l2_params = v4l2_streamparm {
+            type_: Type::VideoCaptureMplane as u32,
+            ..mem::zeroed()
+        };
+
+        ioctl(&dev.handle(), vidioc::VIDIOC_G_PARM, &mut v4l2_params)?;
+
+        Ok(Parameters::from(v4l2_params.parm.capture))
+    }
+}
+
+/// The camera's mmap buffer stream, or a pipeline reading it on another thread.
+pub(crate) enum BufferStream {
+    Mapped(MmapStream),
+    Pipelined(Pipeline),
+    #[cfg(test)]
+    Mock(MockStream),
+}
+
+impl BufferStream {
+    pub(crate) fn with_buffers(dev: &Device, multiplanar: bool, count: u32) -> io::Result<Self> {
+        MmapStream::with_buffers(dev, multiplanar, count).map(Self::Mapped)
+    }
+
+    /// Sets how long `next` waits for a frame. A zero timeout returns `TimedOut` straight away if no frame is ready.
+    pub(crate) fn set_timeout(&mut self, timeout: Duration) {
+        match self {
+            Self::Mapped(stream) => stream.timeout = Some(timeout),
+            Self::Pipelined(pipeline) => pipeline.timeout = Some(timeout),
+            #[cfg(test)]
+            Self::Mock(stream) => stream.timeout = Some(timeout),
+        }
+    }
+
+    pub(crate) fn clear_timeout(&mut self) {
+        match self {
+            Self::Mapped(stream) => stream.timeout = None,
+            Self::Pipelined(pipeline) => pipeline.timeout = None,
+            #[cfg(test)]
+            Self::Mock(stream) => stream.timeout = None,
+        }
+    }
+
+    /// Requeues the previous buffer (starting the stream if needed) and dequeues the next frame. A timeout leaves the
+    /// stream ready to be read from again.
+    pub(crate) fn next(&mut self) -> io::Result<(&[u8], &Metadata)> {
+        match self {
+            Self::Mapped(stream) => stream.next(),
+            Self::Pipelined(pipeline) => pipeline.next(),
+            #[cfg(test)]
+            Self::Mock(stream) => stream.next(),
+        }
+    }
+
+    /// A file descriptor that is readable while `next` can return a frame without blocking: the camera itself, or an
+    /// eventfd signalled by the capture thread.
+    pub(crate) fn readiness_fd(&self) -> RawFd {
+        match self {
+            Self::Mapped(stream) => stream.handle.fd(),
+            Self::Pipelined(pipeline) => pipeline.readiness_fd(),
+            #[cfg(test)]
+            Self::Mock(_) => -1,
+        }
+    }
+
+    /// Stops streaming (`VIDIOC_STREAMOFF`), which returns every buffer to the application. The next frame read restarts
+    /// the stream.
+    pub(crate) fn stop(&mut self) -> io::Result<()> {
+        match self {
+            Self::Mapped(stream) => stream.stop(),
+            // The capture thread stops the stream itself
+            Self::Pipelined(pipeline) => {
+                pipeline.stop();
+                Ok(())
+            }
+            #[cfg(test)]
+            Self::Mock(_) => Ok(()),
+        }
+    }
+}
+
+/// A camera that delivers scripted frames, for testing the readers of a [`BufferStream`].
+#[cfg(test)]
+pub(crate) struct MockStream {
+    /// The frames still to be captured and how long after the previous one each of them is delivered.
+    pub(crate) frames: std::collections::VecDeque<(Duration, Vec<u8>)>,
+    pub(crate) timeout: Option<Duration>,
+    current: Vec<u8>,
+    meta: Metadata,
+    captured: u32,
+}
+
+#[cfg(test)]
+impl MockStream {
+    pub(crate) fn new(frames: impl IntoIterator<Item = (Duration, Vec<u8>)>) -> Self {
+        Self {
+            frames: frames.into_iter().collect(),
+            timeout: None,
+            current: Vec::new(),
+            meta: Metadata::default(),
+            captured: 0,
+        }
+    }
+
+    /// Waits for the next frame like `VIDIOC_DQBUF`, timing out if it is delivered after the timeout. Returns
+    /// `UnexpectedEof` once all of the frames have been captured.
+    fn next(&mut self) -> io::Result<(&[u8], &Metadata)> {
+        let Some((delay, current)) = self.frames.pop_front() else {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        };
+
+        if let Some(timeout) = self.timeout.filter(|&timeout| timeout < delay) {
+            std::thread::sleep(timeout);
+            self.frames.push_front((delay - timeout, current));
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "VIDIOC_DQBUF"));
+        }
+
+        std::thread::sleep(delay);
+        self.current = current;
+        self.meta.bytesused = self.current.len() as u32;
+        self.meta.sequence = self.captured;
+        self.captured += 1;
+        Ok((&self.current, &self.meta))
+    }
+}
+
+/// Returns true if a file descriptor is readable without waiting, ie. a [`BufferStream`] has a frame ready.
+pub(crate) fn is_readable(fd: RawFd) -> bool {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+
+    // SAFETY: The pollfd outlives the call
+    unsafe { libc::poll(&mut pollfd, 1, 0) > 0 }
+}
+
+/// An mmap buffer stream using either the single-planar or the multi-planar API. Frames with more than one plane (eg.
+/// `NM12`) are reassembled into a single contiguous buffer with the planes one after another.
+///
+/// The device is opened non-blocking, so dequeueing may return `EAGAIN` even after polling. The previous buffer is
+/// requeued before polling and forgotten, so that a timeout or `EAGAIN` never leaves a buffer to be queued twice.
+pub(crate) struct MmapStream {
+    handle: Arc<Handle>,
+    buf_type: Type,
+    // The mapped planes of each buffer
+    buffers: Vec<Vec<(*mut u8, usize)>>,
+    num_planes: usize,
+    // The buffer dequeued last, which is requeued by the next call to `next`
+    index: Option<usize>,
+    active: bool,
+    timeout: Option<Duration>,
+    frame: Vec<u8>,
+    meta: Metadata,
+}
+
+// SAFETY: The mapped planes are only accessed through the stream, which exclusively owns them
+unsafe impl Send for MmapStream {}
+
+impl MmapStream {
+    fn with_buffers(dev: &Device, multiplanar: bool, count: u32) -> io::Result<Self> {
+        let handle = dev.handle();
+
+        let mut stream = Self {
+            handle,
+            buf_type: if multiplanar {
+                Type::VideoCaptureMplane
+            } else {
+                Type::VideoCapture
+            },
+            buffers: Vec::new(),
+            num_planes: 1,
+            index: None,
+            active: false,
+            timeout: None,
+            frame: Vec::new(),
+            meta: Metadata::default(),
+        };
+
+        // SAFETY: The ioctl structs outlive the ioctls, and the mappings are unmapped when the stream is dropped
+        unsafe {
+            if multiplanar {
+                let mut v4l2_fmt = v4l2_format {
+                    type_: Type::VideoCaptureMplane as u32,
+                    ..mem::zeroed()
+                };
+                ioctl(&stream.handle, vidioc::VIDIOC_G_FMT, &mut v4l2_fmt)?;
+                stream.num_planes =
+                    (v4l2_fmt.fmt.pix_mp.num_planes as usize).clamp(1, VIDEO_MAX_PLANES as usize);
+            }
+
+            let mut reqbufs = v4l2_requestbuffers {
+                count,
+                type_: stream.buf_type as u32,
+                memory: Memory::Mmap as u32,
+                ..mem::zeroed()
+            };
+            ioctl(&stream.handle, vidioc::VIDIOC_REQBUFS, &mut reqbufs)?;
+
+            for index in 0..reqbufs.count {
+                let mut planes = [mem::zeroed::<v4l2_plane>(); VIDEO_MAX_PLANES as usize];
+                let mut v4l2_buf = stream.buffer_desc(&mut planes);
+                v4l2_buf.index = index;
+                ioctl(&stream.handle, vidioc::VIDIOC_QUERYBUF, &mut v4l2_buf)?;
+
+                // Single-planar buffers describe their only plane in the buffer itself
+                let layout: Vec<(u32, u32)> = match stream.buf_type {
+                    Type::VideoCaptureMplane => planes[..stream.num_planes]
+                        .iter()
+                        .map(|plane| (plane.m.mem_offset, plane.length))
+                        .collect(),
+                    _ => vec![(v4l2_buf.m.offset, v4l2_buf.length)],
+                };
+
+                let mut mapped = Vec::new();
+                for (offset, length) in layout {
+                    let ptr = v4l2::mmap(
+                        ptr::null_mut(),
+                        length as usize,
+                        libc::PROT_READ | libc::PROT_WRITE,
+                        libc::MAP_SHARED,
+                        stream.handle.fd(),
+                        offset as libc::off_t,
+                    );
+
+                    match ptr {
+                        Ok(ptr) => mapped.push((ptr as *mut u8, length as usize)),
+                        Err(err) => {
+                            // Unmap this buffer's planes, the other buffers are released when the stream is dropped
+                            stream.buffers.push(mapped);
+                            return Err(err);
+                        }
+                    }
+                }
+                stream.buffers.push(mapped);
+            }
+        }
+
+        Ok(stream)
+    }
+
+    /// A buffer description for the ioctls. For multi-planar buffers the driver fills in the `planes`.
+    fn buffer_desc(&self, planes: &mut [v4l2_plane; VIDEO_MAX_PLANES as usize]) -> v4l2_buffer {
+        // SAFETY: An all zero v4l2_buffer is valid
+        let mut v4l2_buf: v4l2_buffer = unsafe { mem::zeroed() };
+        v4l2_buf.type_ = self.buf_type as u32;
+        v4l2_buf.memory = Memory::Mmap as u32;
+        if matches!(self.buf_type, Type::VideoCaptureMplane) {
+            v4l2_buf.length = self.num_planes as u32;
+            v4l2_buf.m.planes = planes.as_mut_ptr();
+        }
+        v4l2_buf
+    }
+
+    fn queue(&mut self, index: usize) -> io::Result<()> {
+        let mut planes = [unsafe { mem::zeroed::<v4l2_plane>() }; VIDEO_MAX_PLANES as usize];
+        let mut v4l2_buf = self.buffer_desc(&mut planes);
+        v4l2_buf.index = index as u32;
+
+        // SAFETY: The buffer and its planes outlive the ioctl
+        unsafe { ioctl(&self.handle, vidioc::VIDIOC_QBUF, &mut v4l2_buf) }
+    }
+
+    fn next(&mut self) -> io::Result<(&[u8], &Metadata)> {
+        if !self.active {
+            // Enqueue all buffers once on stream start
+            for index in 0..self.buffers.len() {
+                self.queue(index)?;
+            }
+
+            let mut typ = self.buf_type as u32;
+            // SAFETY: The buffer type outlives the ioctl
+            unsafe { ioctl(&self.handle, vidioc::VIDIOC_STREAMON, &mut typ)? };
+            self.active = true;
+        } else if let Some(index) = self.index.take() {
+            self.queue(index)?;
+        }
+
+        let timeout = self.timeout.map_or(-1, |timeout| {
+            timeout.as_millis().try_into().unwrap_or(i32::MAX)
+        });
+        if self.handle.poll(libc::POLLIN, timeout)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "VIDIOC_DQBUF"));
+        }
+
+        let mut planes = [unsafe { mem::zeroed::<v4l2_plane>() }; VIDEO_MAX_PLANES as usize];
+        let mut v4l2_buf = self.buffer_desc(&mut planes);
+        // SAFETY: The buffer and its planes outlive the ioctl
+        match unsafe { ioctl(&self.handle, vidioc::VIDIOC_DQBUF, &mut v4l2_buf) } {
+            // The poll raced with the driver (or was woken spuriously), which is the same as timing out
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "VIDIOC_DQBUF"));
+            }
+            result => result?,
+        }
+
+        let index = v4l2_buf.index as usize;
+        self.index = Some(index);
+
+        // The payload of each plane starts at its data offset and ends at its bytesused
+        let payload = |(bytesused, data_offset): (u32, u32), &(ptr, len): &(*mut u8, usize)| {
+            let end = (bytesused as usize).min(len);
+            let start = (data_offset as usize).min(end);
+            // SAFETY: The plane is mapped and dequeued, so the driver is not writing to it
+            unsafe { &slice::from_raw_parts(ptr, len)[start..end] }
+        };
+
+        let frame = match self.buf_type {
+            Type::VideoCaptureMplane => {
+                let mut payloads = planes[..self.num_planes]
+                    .iter()
+                    .zip(&self.buffers[index])
+                    .map(|(plane, mapped)| payload((plane.bytesused, plane.data_offset), mapped));
+
+                if self.num_planes == 1 {
+                    payloads.next().unwrap_or_default()
+                } else {
+                    self.frame.clear();
+                    payloads.for_each(|payload| self.frame.extend_from_slice(payload));
+                    &self.frame
+                }
+            }
+            _ => payload((v4l2_buf.bytesused, 0), &self.buffers[index][0]),
+        };
+
+        self.meta = Metadata {
+            bytesused: frame.len() as u32,
+            flags: v4l2_buf.flags.into(),
+            field: v4l2_buf.field,
+            timestamp: v4l2_buf.timestamp.into(),
+            sequence: v4l2_buf.sequence,
+        };
+
+        Ok((frame, &self.meta))
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        let mut typ = self.buf_type as u32;
+        // SAFETY: The buffer type outlives the ioctl
+        unsafe { ioctl(&self.handle, vidioc::VIDIOC_STREAMOFF, &mut typ)? };
+
+        self.active = false;
+        self.index = None;
+        Ok(())
+    }
+}
+
+impl Drop for MmapStream {
+    fn drop(&mut self) {
+        if self.active {
+            if let Err(err) = self.stop() {
+                if err.raw_os_error() != Some(libc::ENODEV) {
+                    // The driver may still write to the buffers so they cannot be unmapped
+                    warn!("Failed to stop the stream, leaking its buffers: {:?}", err);
+                    return;
+                }
+            }
+        }
+
+        for &(ptr, len) in self.buffers.iter().flatten() {
+            // SAFETY: The stream is stopped so the driver no longer writes to the buffers
+            if let Err(err) = unsafe { v4l2::munmap(ptr as *mut std::os::raw::c_void, len) } {
+                warn!("Failed to unmap a stream buffer: {:?}", err);
+            }
+        }
+
+        // SAFETY: The requestbuffers struct outlives the ioctl
+        let released = unsafe {
+            let mut reqbufs = v4l2_requestbuffers {
+                count: 0,
+                type_: self.buf_type as u32,
+                memory: Memory::Mmap as u32,
+                ..mem::zeroed()
+            };
+            ioctl(&self.handle, vidioc::VIDIOC_REQBUFS, &mut reqbufs)
+        };
+
+        match released {
+            Err(err) if err.raw_os_error() != Some(libc::ENODEV) => {
+                warn!("Failed to release the stream's buffers: {:?}", err)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// # Safety
+///
+/// `arg` must be the struct that `request` expects.
+unsafe fn ioctl<T>(handle: &Handle, request: vidioc::_IOC_TYPE, arg: &mut T) -> io::Result<()> {
+    v4l2::ioctl(
+        handle.fd(),
+        request,
+        arg as *mut T as *mut std::os::raw::c_void,
+    )
+}
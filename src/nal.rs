@@ -0,0 +1,282 @@
+//! Splitting of Annex-B H264 bitstreams into NAL units.
+
+use crate::sps::SpsInfo;
+
+/// The type of an H264 NAL unit, from the lower 5 bits of its header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NalType {
+    /// A slice of a non-IDR picture (eg. a P frame).
+    NonIdr,
+    /// Data partition A of a non-IDR slice.
+    PartitionA,
+    /// Data partition B of a non-IDR slice.
+    PartitionB,
+    /// Data partition C of a non-IDR slice.
+    PartitionC,
+    /// A slice of an IDR picture (a key frame).
+    Idr,
+    /// Supplemental enhancement information.
+    Sei,
+    /// Sequence parameter set.
+    Sps,
+    /// Picture parameter set.
+    Pps,
+    /// Access unit delimiter.
+    Aud,
+    /// End of sequence.
+    EndOfSequence,
+    /// End of stream.
+    EndOfStream,
+    /// Filler data.
+    Filler,
+    /// Any other (reserved or unspecified) NAL unit type.
+    Other(u8),
+}
+
+impl NalType {
+    /// Parses the type from a NAL unit's header byte.
+    pub fn from_header(header: u8) -> Self {
+        match header & 0x1f {
+            1 => Self::NonIdr,
+            2 => Self::PartitionA,
+            3 => Self::PartitionB,
+            4 => Self::PartitionC,
+            5 => Self::Idr,
+            6 => Self::Sei,
+            7 => Self::Sps,
+            8 => Self::Pps,
+            9 => Self::Aud,
+            10 => Self::EndOfSequence,
+            11 => Self::EndOfStream,
+            12 => Self::Filler,
+            other => Self::Other(other),
+        }
+    }
+
+    /// Returns the type of a NAL unit (without its start code).
+    ///
+    /// # Panics
+    ///
+    /// Will panic if `nal` is empty. NAL units returned by [`NalUnits`] and [`NalParser`] are never empty.
+    pub fn of(nal: &[u8]) -> Self {
+        Self::from_header(nal[0])
+    }
+}
+
+/// An iterator over the NAL units in an Annex-B bitstream, excluding their start codes.
+///
+/// Both 3 byte (`00 00 01`) and 4 byte (`00 00 00 01`) start codes are supported.
+pub struct NalUnits<'a> {
+    data: &'a [u8],
+    next_start: Option<usize>,
+}
+
+impl<'a> NalUnits<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            next_start: find_start_code(data, 0),
+        }
+    }
+}
+
+impl<'a> Iterator for NalUnits<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        loop {
+            let start = self.next_start? + 3;
+            self.next_start = find_start_code(self.data, start);
+
+            let end = self.next_start.unwrap_or(self.data.len());
+            let nal = trim_trailing_zeros(&self.data[start..end]);
+
+            if !nal.is_empty() {
+                return Some(nal);
+            }
+        }
+    }
+}
+
+/// A stateful NAL unit parser for bitstreams that arrive in pieces, eg. where a start code is split across
+/// successive buffers.
+///
+/// The end of a NAL unit is only known once the next start code is found so the last NAL unit is held back until
+/// more data is pushed or the parser is flushed.
+#[derive(Default)]
+pub struct NalParser {
+    buf: Vec<u8>,
+}
+
+impl NalParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds data to the parser, returning any NAL units that are now complete.
+    pub fn push(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        self.buf.extend_from_slice(data);
+
+        let Some(last_start) = last_start_code(&self.buf) else {
+            return Vec::new();
+        };
+
+        let nal_units = NalUnits::new(&self.buf[..last_start])
+            .map(<[u8]>::to_vec)
+            .collect();
+
+        self.buf.drain(..last_start);
+
+        nal_units
+    }
+
+    /// Returns the remaining buffered NAL unit, eg. at the end of the stream.
+    pub fn flush(&mut self) -> Option<Vec<u8>> {
+        let nal = NalUnits::new(&self.buf).next().map(<[u8]>::to_vec);
+        self.buf.clear();
+        nal
+    }
+}
+
+/// The most recent SPS and PPS seen in a bitstream.
+#[derive(Default)]
+pub(crate) struct ParameterSets {
+    pub sps: Option<Vec<u8>>,
+    pub pps: Option<Vec<u8>>,
+    /// The parsed SPS, if it could be parsed.
+    pub sps_info: Option<SpsInfo>,
+}
+
+impl ParameterSets {
+    /// Caches any parameter sets at the start of the access unit, returning true if it is a key frame.
+    pub fn update(&mut self, access_unit: &[u8]) -> bool {
+        // Parameter sets always precede the slices of an access unit so the rest of the frame is not scanned
+        for nal in NalUnits::new(access_unit) {
+            match NalType::of(nal) {
+                // SPS's shorter than the profile and level fields are corrupt. Cameras usually repeat the same SPS with
+                // every key frame so it is only parsed when it changes.
+                NalType::Sps if nal.len() >= 4 && self.sps.as_deref() != Some(nal) => {
+                    self.sps_info = crate::sps::parse(nal).ok();
+                    self.sps = Some(nal.to_vec());
+                }
+                NalType::Pps => self.pps = Some(nal.to_vec()),
+                NalType::Idr => return true,
+                NalType::NonIdr => return false,
+                _ => {}
+            }
+        }
+
+        false
+    }
+
+    /// Inserts the cached parameter sets at `start` if the access unit from there on does not contain an SPS.
+    pub fn prepend_if_missing(&self, h264_bytes: &mut Vec<u8>, start: usize) {
+        let (Some(sps), Some(pps)) = (&self.sps, &self.pps) else {
+            return;
+        };
+
+        if NalUnits::new(&h264_bytes[start..]).any(|nal| NalType::of(nal) == NalType::Sps) {
+            return;
+        }
+
+        let mut parameter_sets = Vec::with_capacity(sps.len() + pps.len() + 8);
+        for nal in [sps, pps] {
+            parameter_sets.extend_from_slice(&[0, 0, 0, 1]);
+            parameter_sets.extend_from_slice(nal);
+        }
+
+        h264_bytes.splice(start..start, parameter_sets);
+    }
+}
+
+/// Returns true if the access unit contains an IDR slice.
+pub fn is_keyframe(access_unit: &[u8]) -> bool {
+    NalUnits::new(access_unit).any(|nal| NalType::of(nal) == NalType::Idr)
+}
+
+fn find_start_code(data: &[u8], from: usize) -> Option<usize> {
+    data.get(from..)?
+        .windows(3)
+        .position(|window| window == [0, 0, 1])
+        .map(|i| from + i)
+}
+
+fn last_start_code(data: &[u8]) -> Option<usize> {
+    data.windows(3).rposition(|window| window == [0, 0, 1])
+}
+
+// A NAL unit never ends with a zero byte so trailing zeros belong to the next (4 byte) start code or are padding
+fn trim_trailing_zeros(mut nal: &[u8]) -> &[u8] {
+    while let [rest @ .., 0] = nal {
+        nal = rest;
+    }
+    nal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pushes `data` into a parser `chunk_len` bytes at a time, returning the NAL units including the flushed one.
+    fn parse_in_chunks(data: &[u8], chunk_len: usize) -> Vec<Vec<u8>> {
+        let mut parser = NalParser::new();
+        let mut nal_units: Vec<_> = data
+            .chunks(chunk_len)
+            .flat_map(|chunk| parser.push(chunk))
+            .collect();
+        nal_units.extend(parser.flush());
+        nal_units
+    }
+
+    #[test]
+    fn parses_start_codes_split_across_pushes() {
+        let data = [
+            &[0, 0, 0, 1, 0x67, 0x42, 0x00, 0x1f][..],
+            &[0, 0, 1, 0x68, 0xce, 0x3c, 0x80],
+            &[0, 0, 0, 1, 0x65, 0x88, 0x84, 0x00, 0x03, 0x21],
+        ]
+        .concat();
+        let expected = [
+            vec![0x67, 0x42, 0x00, 0x1f],
+            vec![0x68, 0xce, 0x3c, 0x80],
+            vec![0x65, 0x88, 0x84, 0x00, 0x03, 0x21],
+        ];
+
+        // Every chunk size splits some start code
+        for chunk_len in 1..=data.len() {
+            assert_eq!(
+                parse_in_chunks(&data, chunk_len),
+                expected,
+                "{chunk_len} byte chunks"
+            );
+        }
+    }
+
+    #[test]
+    fn trims_trailing_zeros() {
+        // Zeros before a start code and at the end of the stream are padding, not part of the NAL units
+        let data = [0, 0, 1, 0x09, 0xf0, 0, 0, 0, 0, 1, 0x41, 0x9a, 0, 0];
+
+        for chunk_len in 1..=data.len() {
+            assert_eq!(
+                parse_in_chunks(&data, chunk_len),
+                [vec![0x09, 0xf0], vec![0x41, 0x9a]],
+                "{chunk_len} byte chunks"
+            );
+        }
+    }
+
+    #[test]
+    fn holds_back_the_last_nal_unit_until_flushed() {
+        let mut parser = NalParser::new();
+
+        assert!(parser.push(&[0, 0, 0, 1, 0x67, 0x42]).is_empty());
+        assert_eq!(parser.push(&[0, 0, 1, 0x68]), [vec![0x67, 0x42]]);
+        assert_eq!(parser.flush(), Some(vec![0x68]));
+
+        // Flushing empties the parser
+        assert_eq!(parser.flush(), None);
+        assert!(parser.push(&[0x41, 0x9a]).is_empty());
+        assert_eq!(parser.flush(), None);
+    }
+}
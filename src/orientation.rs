@@ -0,0 +1,168 @@
+use crate::yuv::YUVSource;
+use crate::YUVBuffer;
+
+/// A clockwise rotation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    R90,
+    R180,
+    R270,
+}
+
+/// The axis a frame is mirrored across.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlipAxis {
+    /// Mirrors left to right.
+    Horizontal,
+    /// Mirrors top to bottom.
+    Vertical,
+}
+
+/// A flip followed by a rotation, eg. to correct for how the camera is mounted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Transform {
+    pub flip: Option<FlipAxis>,
+    pub rotation: Option<Rotation>,
+}
+
+impl From<Rotation> for Transform {
+    fn from(rotation: Rotation) -> Self {
+        Self {
+            flip: None,
+            rotation: Some(rotation),
+        }
+    }
+}
+
+impl From<FlipAxis> for Transform {
+    fn from(flip: FlipAxis) -> Self {
+        Self {
+            flip: Some(flip),
+            rotation: None,
+        }
+    }
+}
+
+impl Transform {
+    /// The size of a transformed `width` x `height` frame.
+    pub fn output_size(&self, width: u32, height: u32) -> (u32, u32) {
+        match self.rotation {
+            Some(Rotation::R90 | Rotation::R270) => (height, width),
+            _ => (width, height),
+        }
+    }
+
+    /// The camera's (horizontal, vertical) flip controls that are equivalent to this transform, or `None` if it
+    /// includes a quarter turn.
+    pub(crate) fn hardware_flips(&self) -> Option<(bool, bool)> {
+        let (horizontal, vertical) = match self.flip {
+            None => (false, false),
+            Some(FlipAxis::Horizontal) => (true, false),
+            Some(FlipAxis::Vertical) => (false, true),
+        };
+
+        match self.rotation {
+            None => Some((horizontal, vertical)),
+            // Rotating by 180° is the same as flipping across both axes
+            Some(Rotation::R180) => Some((!horizontal, !vertical)),
+            Some(Rotation::R90 | Rotation::R270) => None,
+        }
+    }
+
+    /// The position in the source frame of each transformed pixel, for a `width` x `height` source.
+    fn source_position(&self, (width, height): (i64, i64), (x, y): (i64, i64)) -> (i64, i64) {
+        // Undo the rotation and then the flip
+        let (x, y) = match self.rotation {
+            None => (x, y),
+            Some(Rotation::R90) => (y, height - 1 - x),
+            Some(Rotation::R180) => (width - 1 - x, height - 1 - y),
+            Some(Rotation::R270) => (width - 1 - y, x),
+        };
+
+        match self.flip {
+            None => (x, y),
+            Some(FlipAxis::Horizontal) => (width - 1 - x, y),
+            Some(FlipAxis::Vertical) => (x, height - 1 - y),
+        }
+    }
+}
+
+/// How a stream's [`Transform`] is applied, see [`WebcamH264Stream::transform_mode`](crate::WebcamH264Stream::transform_mode).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransformMode {
+    /// The camera flips the frames itself using its horizontal and vertical flip controls.
+    Hardware,
+    /// The frames are transformed before they are encoded.
+    Software,
+}
+
+/// Transforms the source into `out`, which is resized if necessary.
+pub(crate) fn transform_into<T: YUVSource + ?Sized>(
+    source: &T,
+    out: &mut YUVBuffer,
+    transform: Transform,
+) {
+    let (width, height) = (source.width() as u32, source.height() as u32);
+    let (out_width, out_height) = transform.output_size(width, height);
+
+    if (out.width() as u32, out.height() as u32) != (out_width, out_height) {
+        *out = YUVBuffer::new(out_width as usize, out_height as usize);
+    }
+
+    let (width, height) = (width as usize, height as usize);
+    let (out_width, out_height) = (out_width as usize, out_height as usize);
+
+    transform_plane(
+        source.y(),
+        source.y_stride() as usize,
+        (width, height),
+        out.y_mut(),
+        (out_width, out_height),
+        transform,
+    );
+    transform_plane(
+        source.u(),
+        source.u_stride() as usize,
+        (width / 2, height / 2),
+        out.u_mut(),
+        (out_width / 2, out_height / 2),
+        transform,
+    );
+    transform_plane(
+        source.v(),
+        source.v_stride() as usize,
+        (width / 2, height / 2),
+        out.v_mut(),
+        (out_width / 2, out_height / 2),
+        transform,
+    );
+}
+
+fn transform_plane(
+    src: &[u8],
+    src_stride: usize,
+    (width, height): (usize, usize),
+    dst: &mut [u8],
+    (dst_width, dst_height): (usize, usize),
+    transform: Transform,
+) {
+    if dst_width == 0 || dst_height == 0 {
+        return;
+    }
+
+    // Flips and rotations map pixels linearly so each step in the output moves a fixed distance in the source
+    let index = |x, y| {
+        let (x, y) = transform.source_position((width as i64, height as i64), (x, y));
+        y * src_stride as i64 + x
+    };
+    let origin = index(0, 0);
+    let (step_x, step_y) = (index(1, 0) - origin, index(0, 1) - origin);
+
+    for (y, dst_row) in dst.chunks_exact_mut(dst_width).take(dst_height).enumerate() {
+        let row_start = origin + y as i64 * step_y;
+
+        for (x, dst) in dst_row.iter_mut().enumerate() {
+            *dst = src[(row_start + x as i64 * step_x) as usize];
+        }
+    }
+}
@@ -0,0 +1,178 @@
+use crate::{CapabilityFlags, FormatSummary, StreamError, YUVFrame};
+use std::fs::File;
+use std::io::Write;
+use std::mem::ManuallyDrop;
+use std::os::unix::io::FromRawFd;
+use std::path::Path;
+use tracing::warn;
+use v4l::video::Output;
+use v4l::{Device, Format, FourCC};
+
+/// A V4L2 video output device, eg. a [v4l2loopback](https://github.com/umlaeute/v4l2loopback) virtual camera that
+/// other applications (Zoom, OBS, browsers) can open like a webcam.
+pub struct OutputDevice {
+    device: Device,
+    width: u32,
+    height: u32,
+    fourcc: FourCC,
+    // Reused between frames when converting them to the device's format
+    buffer: Vec<u8>,
+}
+
+impl OutputDevice {
+    /// Opens the device and sets its format. `fourcc` may be `YUYV`, `YU12` (I420) or `MJPG`.
+    ///
+    /// If the device substitutes a different raw format it is used instead, with a warning (eg. a loopback device whose
+    /// format was already fixed by another producer). Any other change to the format is an error.
+    pub fn open(
+        path: impl AsRef<Path>,
+        width: u32,
+        height: u32,
+        fourcc: FourCC,
+    ) -> Result<Self, StreamError> {
+        if !is_supported(fourcc) {
+            return Err(StreamError::OutputFormatUnsupported(fourcc));
+        }
+
+        let path = path.as_ref();
+        let device = crate::get_device(path)?;
+
+        let caps = device.query_caps().map_err(|err| {
+            StreamError::from_io(err, |source| StreamError::QueryFailed {
+                query: "capabilities",
+                source,
+            })
+        })?;
+        if !caps.capabilities.contains(CapabilityFlags::VIDEO_OUTPUT) {
+            return Err(StreamError::NotAnOutputDevice(path.to_path_buf()));
+        }
+
+        let actual =
+            Output::set_format(&device, &Format::new(width, height, fourcc)).map_err(|err| {
+                StreamError::from_io(err, |source| StreamError::SetFormatFailed {
+                    requested: FormatSummary {
+                        width,
+                        height,
+                        fourcc,
+                    },
+                    source,
+                })
+            })?;
+
+        let raw = |fourcc| fourcc != FourCC::new(b"MJPG");
+        let substituted_raw_format =
+            raw(fourcc) && raw(actual.fourcc) && is_supported(actual.fourcc);
+
+        if (actual.width, actual.height) != (width, height)
+            || (actual.fourcc != fourcc && !substituted_raw_format)
+        {
+            return Err(StreamError::FormatRejected {
+                requested: (width, height, fourcc),
+                actual: (actual.width, actual.height, actual.fourcc),
+            });
+        }
+
+        if actual.fourcc != fourcc {
+            warn!(
+                "Output device {:?} substituted {} for the requested {} format",
+                path, actual.fourcc, fourcc
+            );
+        }
+
+        Ok(Self {
+            device,
+            width,
+            height,
+            fourcc: actual.fourcc,
+            buffer: Vec::new(),
+        })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// The format frames are written in, which may differ from the requested format (see `open`).
+    pub fn fourcc(&self) -> FourCC {
+        self.fourcc
+    }
+
+    /// Writes a frame, converting it to the device's YUYV or I420 format. The frame must be the device's size.
+    pub fn write_frame(&mut self, frame: &YUVFrame) -> Result<(), StreamError> {
+        let (width, height) = (frame.width(), frame.height());
+        if (width as u32, height as u32) != (self.width, self.height) {
+            return Err(StreamError::FrameSizeMismatch {
+                expected: (self.width, self.height),
+                actual: (width as u32, height as u32),
+            });
+        }
+
+        let (y_stride, u_stride, v_stride) = frame.strides();
+        let (y, u, v) = (frame.y(), frame.u(), frame.v());
+
+        self.buffer.clear();
+
+        if self.fourcc == FourCC::new(b"YUYV") {
+            for row in 0..height {
+                let y_row = &y[row * y_stride..][..width];
+                let u_row = &u[row / 2 * u_stride..];
+                let v_row = &v[row / 2 * v_stride..];
+
+                for (x, luma) in y_row.chunks_exact(2).enumerate() {
+                    self.buffer
+                        .extend_from_slice(&[luma[0], u_row[x], luma[1], v_row[x]]);
+                }
+            }
+        } else if self.fourcc == FourCC::new(b"YU12") {
+            // Packs the planes, skipping any stride padding
+            for (plane, stride, plane_width, plane_height) in [
+                (y, y_stride, width, height),
+                (u, u_stride, width / 2, height / 2),
+                (v, v_stride, width / 2, height / 2),
+            ] {
+                for row in plane.chunks(stride).take(plane_height) {
+                    self.buffer.extend_from_slice(&row[..plane_width]);
+                }
+            }
+        } else {
+            return Err(StreamError::OutputFormatMismatch {
+                expected: self.fourcc,
+                actual: FourCC::new(b"YU12"),
+            });
+        }
+
+        write_all(&self.device, &self.buffer)
+    }
+
+    /// Writes a JPEG to a device opened with the `MJPG` format.
+    pub fn write_mjpeg(&mut self, jpeg: &[u8]) -> Result<(), StreamError> {
+        let mjpg = FourCC::new(b"MJPG");
+
+        if self.fourcc != mjpg {
+            return Err(StreamError::OutputFormatMismatch {
+                expected: self.fourcc,
+                actual: mjpg,
+            });
+        }
+
+        write_all(&self.device, jpeg)
+    }
+}
+
+fn is_supported(fourcc: FourCC) -> bool {
+    [b"YUYV", b"YU12", b"MJPG"]
+        .iter()
+        .any(|supported| fourcc == FourCC::new(supported))
+}
+
+/// Writes a whole frame with `write(2)`, which output devices such as v4l2loopback accept instead of mmap buffers.
+fn write_all(device: &Device, bytes: &[u8]) -> Result<(), StreamError> {
+    // SAFETY: The fd is owned by the device, which outlives the file. ManuallyDrop prevents the file from closing it.
+    let mut file = ManuallyDrop::new(unsafe { File::from_raw_fd(device.handle().fd()) });
+
+    file.write_all(bytes).map_err(StreamError::OutputFailure)
+}
@@ -0,0 +1,835 @@
+//! Text and image overlays (eg. timestamps and logos) drawn directly onto YUV frames.
+
+use crate::yuv::YUVSource;
+use crate::YUVBuffer;
+use std::borrow::Cow;
+use std::sync::Arc;
+
+const GLYPH_WIDTH: usize = 5;
+const GLYPH_HEIGHT: usize = 7;
+/// The horizontal and vertical distance between characters, including a 1 pixel gap.
+const ADVANCE: usize = GLYPH_WIDTH + 1;
+const LINE_HEIGHT: usize = GLYPH_HEIGHT + 1;
+/// The distance between an image overlay and the edges of the frame when it is positioned in a corner.
+const IMAGE_MARGIN: i64 = 16;
+
+/// A 5x7 pixel font covering digits, upper case letters and common punctuation, sorted by character. Each row's bits
+/// are the pixels from left (most significant) to right.
+const FONT: [(char, [u8; GLYPH_HEIGHT]); 61] = [
+    (
+        ' ',
+        [
+            0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000,
+        ],
+    ),
+    (
+        '!',
+        [
+            0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00000, 0b00100,
+        ],
+    ),
+    (
+        '"',
+        [
+            0b01010, 0b01010, 0b01010, 0b00000, 0b00000, 0b00000, 0b00000,
+        ],
+    ),
+    (
+        '#',
+        [
+            0b01010, 0b01010, 0b11111, 0b01010, 0b11111, 0b01010, 0b01010,
+        ],
+    ),
+    (
+        '%',
+        [
+            0b11000, 0b11001, 0b00010, 0b00100, 0b01000, 0b10011, 0b00011,
+        ],
+    ),
+    (
+        '&',
+        [
+            0b01100, 0b10010, 0b10100, 0b01000, 0b10101, 0b10010, 0b01101,
+        ],
+    ),
+    (
+        '\'',
+        [
+            0b00100, 0b00100, 0b01000, 0b00000, 0b00000, 0b00000, 0b00000,
+        ],
+    ),
+    (
+        '(',
+        [
+            0b00010, 0b00100, 0b01000, 0b01000, 0b01000, 0b00100, 0b00010,
+        ],
+    ),
+    (
+        ')',
+        [
+            0b01000, 0b00100, 0b00010, 0b00010, 0b00010, 0b00100, 0b01000,
+        ],
+    ),
+    (
+        '*',
+        [
+            0b00000, 0b00100, 0b10101, 0b01110, 0b10101, 0b00100, 0b00000,
+        ],
+    ),
+    (
+        '+',
+        [
+            0b00000, 0b00100, 0b00100, 0b11111, 0b00100, 0b00100, 0b00000,
+        ],
+    ),
+    (
+        ',',
+        [
+            0b00000, 0b00000, 0b00000, 0b00000, 0b01100, 0b00100, 0b01000,
+        ],
+    ),
+    (
+        '-',
+        [
+            0b00000, 0b00000, 0b00000, 0b11111, 0b00000, 0b00000, 0b00000,
+        ],
+    ),
+    (
+        '.',
+        [
+            0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b01100, 0b01100,
+        ],
+    ),
+    (
+        '/',
+        [
+            0b00001, 0b00001, 0b00010, 0b00100, 0b01000, 0b10000, 0b10000,
+        ],
+    ),
+    (
+        '0',
+        [
+            0b01110, 0b10001, 0b10011, 0b10101, 0b11001, 0b10001, 0b01110,
+        ],
+    ),
+    (
+        '1',
+        [
+            0b00100, 0b01100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110,
+        ],
+    ),
+    (
+        '2',
+        [
+            0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b01000, 0b11111,
+        ],
+    ),
+    (
+        '3',
+        [
+            0b11111, 0b00010, 0b00100, 0b00010, 0b00001, 0b10001, 0b01110,
+        ],
+    ),
+    (
+        '4',
+        [
+            0b00010, 0b00110, 0b01010, 0b10010, 0b11111, 0b00010, 0b00010,
+        ],
+    ),
+    (
+        '5',
+        [
+            0b11111, 0b10000, 0b11110, 0b00001, 0b00001, 0b10001, 0b01110,
+        ],
+    ),
+    (
+        '6',
+        [
+            0b00110, 0b01000, 0b10000, 0b11110, 0b10001, 0b10001, 0b01110,
+        ],
+    ),
+    (
+        '7',
+        [
+            0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b01000, 0b01000,
+        ],
+    ),
+    (
+        '8',
+        [
+            0b01110, 0b10001, 0b10001, 0b01110, 0b10001, 0b10001, 0b01110,
+        ],
+    ),
+    (
+        '9',
+        [
+            0b01110, 0b10001, 0b10001, 0b01111, 0b00001, 0b00010, 0b01100,
+        ],
+    ),
+    (
+        ':',
+        [
+            0b00000, 0b00100, 0b00100, 0b00000, 0b00100, 0b00100, 0b00000,
+        ],
+    ),
+    (
+        '<',
+        [
+            0b00010, 0b00100, 0b01000, 0b10000, 0b01000, 0b00100, 0b00010,
+        ],
+    ),
+    (
+        '=',
+        [
+            0b00000, 0b00000, 0b11111, 0b00000, 0b11111, 0b00000, 0b00000,
+        ],
+    ),
+    (
+        '>',
+        [
+            0b01000, 0b00100, 0b00010, 0b00001, 0b00010, 0b00100, 0b01000,
+        ],
+    ),
+    (
+        '?',
+        [
+            0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b00000, 0b00100,
+        ],
+    ),
+    (
+        '@',
+        [
+            0b01110, 0b10001, 0b00001, 0b01101, 0b10101, 0b10101, 0b01110,
+        ],
+    ),
+    (
+        'A',
+        [
+            0b01110, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001,
+        ],
+    ),
+    (
+        'B',
+        [
+            0b11110, 0b10001, 0b10001, 0b11110, 0b10001, 0b10001, 0b11110,
+        ],
+    ),
+    (
+        'C',
+        [
+            0b01110, 0b10001, 0b10000, 0b10000, 0b10000, 0b10001, 0b01110,
+        ],
+    ),
+    (
+        'D',
+        [
+            0b11100, 0b10010, 0b10001, 0b10001, 0b10001, 0b10010, 0b11100,
+        ],
+    ),
+    (
+        'E',
+        [
+            0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b11111,
+        ],
+    ),
+    (
+        'F',
+        [
+            0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b10000,
+        ],
+    ),
+    (
+        'G',
+        [
+            0b01110, 0b10001, 0b10000, 0b10111, 0b10001, 0b10001, 0b01111,
+        ],
+    ),
+    (
+        'H',
+        [
+            0b10001, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001,
+        ],
+    ),
+    (
+        'I',
+        [
+            0b01110, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110,
+        ],
+    ),
+    (
+        'J',
+        [
+            0b00111, 0b00010, 0b00010, 0b00010, 0b00010, 0b10010, 0b01100,
+        ],
+    ),
+    (
+        'K',
+        [
+            0b10001, 0b10010, 0b10100, 0b11000, 0b10100, 0b10010, 0b10001,
+        ],
+    ),
+    (
+        'L',
+        [
+            0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b11111,
+        ],
+    ),
+    (
+        'M',
+        [
+            0b10001, 0b11011, 0b10101, 0b10101, 0b10001, 0b10001, 0b10001,
+        ],
+    ),
+    (
+        'N',
+        [
+            0b10001, 0b10001, 0b11001, 0b10101, 0b10011, 0b10001, 0b10001,
+        ],
+    ),
+    (
+        'O',
+        [
+            0b01110, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110,
+        ],
+    ),
+    (
+        'P',
+        [
+            0b11110, 0b10001, 0b10001, 0b11110, 0b10000, 0b10000, 0b10000,
+        ],
+    ),
+    (
+        'Q',
+        [
+            0b01110, 0b10001, 0b10001, 0b10001, 0b10101, 0b10010, 0b01101,
+        ],
+    ),
+    (
+        'R',
+        [
+            0b11110, 0b10001, 0b10001, 0b11110, 0b10100, 0b10010, 0b10001,
+        ],
+    ),
+    (
+        'S',
+        [
+            0b01111, 0b10000, 0b10000, 0b01110, 0b00001, 0b00001, 0b11110,
+        ],
+    ),
+    (
+        'T',
+        [
+            0b11111, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100,
+        ],
+    ),
+    (
+        'U',
+        [
+            0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110,
+        ],
+    ),
+    (
+        'V',
+        [
+            0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01010, 0b00100,
+        ],
+    ),
+    (
+        'W',
+        [
+            0b10001, 0b10001, 0b10001, 0b10101, 0b10101, 0b10101, 0b01010,
+        ],
+    ),
+    (
+        'X',
+        [
+            0b10001, 0b10001, 0b01010, 0b00100, 0b01010, 0b10001, 0b10001,
+        ],
+    ),
+    (
+        'Y',
+        [
+            0b10001, 0b10001, 0b01010, 0b00100, 0b00100, 0b00100, 0b00100,
+        ],
+    ),
+    (
+        'Z',
+        [
+            0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b10000, 0b11111,
+        ],
+    ),
+    (
+        '[',
+        [
+            0b01110, 0b01000, 0b01000, 0b01000, 0b01000, 0b01000, 0b01110,
+        ],
+    ),
+    (
+        ']',
+        [
+            0b01110, 0b00010, 0b00010, 0b00010, 0b00010, 0b00010, 0b01110,
+        ],
+    ),
+    (
+        '_',
+        [
+            0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b11111,
+        ],
+    ),
+    (
+        '|',
+        [
+            0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100,
+        ],
+    ),
+];
+
+/// Where a [`TextOverlay`] is drawn on the frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverlayPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    /// The top left corner of the overlay, in pixels. Overlays extending past the edges of the frame are clipped.
+    At {
+        x: i32,
+        y: i32,
+    },
+}
+
+impl OverlayPosition {
+    /// The top left corner of an overlay of `size` within the frame.
+    fn resolve(
+        self,
+        (frame_width, frame_height): (i64, i64),
+        (width, height): (i64, i64),
+        margin: i64,
+    ) -> (i64, i64) {
+        match self {
+            Self::TopLeft => (margin, margin),
+            Self::TopRight => (frame_width - margin - width, margin),
+            Self::BottomLeft => (margin, frame_height - margin - height),
+            Self::BottomRight => (frame_width - margin - width, frame_height - margin - height),
+            Self::At { x, y } => (x as i64, y as i64),
+        }
+    }
+}
+
+/// The color of overlay text in BT.601 limited range YUV.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OverlayColor {
+    pub y: u8,
+    /// The U and V values, or `None` to only draw onto the luma plane (leaving the frame's color beneath).
+    pub uv: Option<(u8, u8)>,
+}
+
+impl OverlayColor {
+    pub const WHITE: Self = Self {
+        y: 235,
+        uv: Some((128, 128)),
+    };
+    pub const BLACK: Self = Self {
+        y: 16,
+        uv: Some((128, 128)),
+    };
+
+    /// Converts an RGB color.
+    pub fn from_rgb(r: u8, g: u8, b: u8) -> Self {
+        let (r, g, b) = (r as f32, g as f32, b as f32);
+
+        let y = 16.0 + 0.257 * r + 0.504 * g + 0.098 * b;
+        let u = 128.0 - 0.148 * r - 0.291 * g + 0.439 * b;
+        let v = 128.0 + 0.439 * r - 0.368 * g - 0.071 * b;
+
+        Self {
+            y: y.round() as u8,
+            uv: Some((u.round() as u8, v.round() as u8)),
+        }
+    }
+}
+
+/// Draws text onto YUV frames using a built-in bitmap font.
+///
+/// Lower case letters are drawn in upper case and unsupported characters are drawn as `?`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextOverlay {
+    position: OverlayPosition,
+    scale: usize,
+    color: OverlayColor,
+    background: Option<OverlayColor>,
+}
+
+impl TextOverlay {
+    /// Creates an overlay whose 5x7 pixel characters are enlarged by `scale` (eg. 2 for 10x14 pixels).
+    pub fn new(position: OverlayPosition, scale: u32, color: OverlayColor) -> Self {
+        Self {
+            position,
+            scale: scale.max(1) as usize,
+            color,
+            background: None,
+        }
+    }
+
+    /// Fills a box behind the text so that it is legible on any background.
+    pub fn background(mut self, color: OverlayColor) -> Self {
+        self.background = Some(color);
+        self
+    }
+
+    /// The width and height of the text in pixels (excluding the background's padding).
+    pub fn text_size(&self, text: &str) -> (usize, usize) {
+        let columns = text
+            .lines()
+            .map(|line| line.chars().count())
+            .max()
+            .unwrap_or(0);
+        let rows = text.lines().count();
+
+        (
+            (columns * ADVANCE).saturating_sub(1) * self.scale,
+            (rows * LINE_HEIGHT).saturating_sub(1) * self.scale,
+        )
+    }
+
+    /// Draws `text` onto the frame. Lines are separated by `\n`.
+    pub fn draw(&self, yuv: &mut YUVBuffer, text: &str) {
+        let (frame_width, frame_height) = (yuv.width() as i64, yuv.height() as i64);
+        let (text_width, text_height) = self.text_size(text);
+        let (text_width, text_height) = (text_width as i64, text_height as i64);
+
+        // The corner positions leave a margin of one character
+        let scale = self.scale as i64;
+        let margin = ADVANCE as i64 * scale;
+        let padding = scale;
+
+        let (x, y) = self.position.resolve(
+            (frame_width, frame_height),
+            (text_width, text_height),
+            margin,
+        );
+
+        let mut canvas = Canvas::new(yuv);
+
+        if let Some(background) = self.background {
+            canvas.fill(
+                x - padding,
+                y - padding,
+                text_width + 2 * padding,
+                text_height + 2 * padding,
+                background,
+            );
+        }
+
+        for (row, line) in text.lines().enumerate() {
+            let line_y = y + (row * LINE_HEIGHT) as i64 * scale;
+
+            for (column, c) in line.chars().enumerate() {
+                let glyph_x = x + (column * ADVANCE) as i64 * scale;
+
+                for (glyph_row, bits) in glyph(c).iter().enumerate() {
+                    for glyph_column in 0..GLYPH_WIDTH {
+                        if bits >> (GLYPH_WIDTH - 1 - glyph_column) & 1 == 1 {
+                            canvas.fill(
+                                glyph_x + glyph_column as i64 * scale,
+                                line_y + glyph_row as i64 * scale,
+                                scale,
+                                scale,
+                                self.color,
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The text drawn by an overlay registered with a stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OverlayText {
+    Static(String),
+    /// The local time each frame is encoded, formatted with a strftime-style template (eg. `"%Y-%m-%d %H:%M:%S"`, see
+    /// [`chrono::format::strftime`]).
+    Timestamp(String),
+}
+
+impl OverlayText {
+    fn render(&self) -> Cow<'_, str> {
+        match self {
+            Self::Static(text) => Cow::Borrowed(text),
+            Self::Timestamp(template) => {
+                use std::fmt::Write;
+
+                // Invalid templates are drawn up to the first invalid specifier rather than panicking
+                let mut text = String::new();
+                let _ = write!(text, "{}", chrono::Local::now().format(template));
+                Cow::Owned(text)
+            }
+        }
+    }
+}
+
+/// An image (eg. a logo) with an alpha channel, pre-converted to YUV so that it can be composited onto every frame.
+#[derive(Debug, Clone)]
+pub struct ImageOverlay {
+    width: usize,
+    height: usize,
+    y: Vec<u8>,
+    u: Vec<u8>,
+    v: Vec<u8>,
+    alpha: Vec<u8>,
+}
+
+impl ImageOverlay {
+    /// Converts an image from 8 bit RGBA pixels (eg. a decoded PNG) with straight, non-premultiplied alpha.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pixels` is not `width * height * 4` bytes long.
+    pub fn from_rgba(width: u32, height: u32, pixels: &[u8]) -> Self {
+        let (width, height) = (width as usize, height as usize);
+        assert_eq!(
+            pixels.len(),
+            width * height * 4,
+            "Expected {}x{} RGBA pixels",
+            width,
+            height
+        );
+
+        let mut image = Self {
+            width,
+            height,
+            y: Vec::with_capacity(width * height),
+            u: Vec::with_capacity(width * height),
+            v: Vec::with_capacity(width * height),
+            alpha: Vec::with_capacity(width * height),
+        };
+
+        for pixel in pixels.chunks_exact(4) {
+            let (y, u, v) = rgb_to_yuv(pixel[0], pixel[1], pixel[2]);
+            image.y.push(y);
+            image.u.push(u);
+            image.v.push(v);
+            image.alpha.push(pixel[3]);
+        }
+
+        image
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width as u32
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height as u32
+    }
+
+    /// Alpha blends the image onto the frame with its top left corner at `x`, `y`. The image is clipped to the frame so
+    /// it may be partially (or entirely) outside of it.
+    ///
+    /// Each chroma sample covers a 2x2 block of pixels so it is blended using the block's average alpha.
+    pub fn composite(&self, yuv: &mut YUVBuffer, x: i32, y: i32) {
+        let (frame_width, frame_height) = (yuv.width() as i64, yuv.height() as i64);
+        let (x, y) = (x as i64, y as i64);
+        let (image_width, image_height) = (self.width as i64, self.height as i64);
+
+        let (x_start, x_end) = (
+            x.clamp(0, frame_width),
+            (x + image_width).clamp(0, frame_width),
+        );
+        let (y_start, y_end) = (
+            y.clamp(0, frame_height),
+            (y + image_height).clamp(0, frame_height),
+        );
+
+        if x_start >= x_end || y_start >= y_end {
+            return;
+        }
+
+        let frame_width = frame_width as usize;
+        let luma = yuv.y_mut();
+
+        for frame_y in y_start..y_end {
+            let image_row = (frame_y - y) as usize * self.width;
+            let frame_row = frame_y as usize * frame_width;
+
+            for frame_x in x_start..x_end {
+                let src = image_row + (frame_x - x) as usize;
+                let alpha = self.alpha[src] as u32;
+
+                if alpha != 0 {
+                    let dst = &mut luma[frame_row + frame_x as usize];
+                    *dst = blend(self.y[src], *dst, alpha, 255);
+                }
+            }
+        }
+
+        // The chroma samples that overlap the image, each covering frame pixels 2x..2x+1, 2y..2y+1
+        let chroma_width = frame_width / 2;
+        let (chroma_x_start, chroma_x_end) = (x_start / 2, (x_end + 1) / 2);
+        let (chroma_y_start, chroma_y_end) = (y_start / 2, (y_end + 1) / 2);
+
+        let blend_plane = |plane: &mut [u8], values: &[u8]| {
+            for chroma_y in chroma_y_start..chroma_y_end {
+                for chroma_x in chroma_x_start..chroma_x_end.min(chroma_width as i64) {
+                    // Pixels of the block outside of the image count as transparent
+                    let mut alpha_sum = 0;
+                    let mut value_sum = 0;
+
+                    for frame_y in chroma_y * 2..chroma_y * 2 + 2 {
+                        for frame_x in chroma_x * 2..chroma_x * 2 + 2 {
+                            let (image_x, image_y) = (frame_x - x, frame_y - y);
+
+                            if (0..image_width).contains(&image_x)
+                                && (0..image_height).contains(&image_y)
+                            {
+                                let src = image_y as usize * self.width + image_x as usize;
+                                let alpha = self.alpha[src] as u32;
+                                alpha_sum += alpha;
+                                value_sum += alpha * values[src] as u32;
+                            }
+                        }
+                    }
+
+                    if alpha_sum != 0 {
+                        let dst = &mut plane[chroma_y as usize * chroma_width + chroma_x as usize];
+                        *dst = ((value_sum + *dst as u32 * (4 * 255 - alpha_sum) + 510) / (4 * 255))
+                            as u8;
+                    }
+                }
+            }
+        };
+
+        blend_plane(yuv.u_mut(), &self.u);
+        blend_plane(yuv.v_mut(), &self.v);
+    }
+}
+
+/// An overlay registered with a stream.
+#[derive(Debug, Clone)]
+pub enum Overlay {
+    Text {
+        overlay: TextOverlay,
+        text: OverlayText,
+    },
+    /// An image positioned in a corner (with a 16 pixel margin) or at a fixed position. The image is shared so that the
+    /// same one can be registered with several streams.
+    Image {
+        image: Arc<ImageOverlay>,
+        position: OverlayPosition,
+    },
+}
+
+impl Overlay {
+    pub fn text(overlay: TextOverlay, text: OverlayText) -> Self {
+        Self::Text { overlay, text }
+    }
+
+    pub fn image(image: impl Into<Arc<ImageOverlay>>, position: OverlayPosition) -> Self {
+        Self::Image {
+            image: image.into(),
+            position,
+        }
+    }
+
+    fn draw(&self, yuv: &mut YUVBuffer) {
+        match self {
+            Self::Text { overlay, text } => overlay.draw(yuv, &text.render()),
+            Self::Image { image, position } => {
+                let (x, y) = position.resolve(
+                    (yuv.width() as i64, yuv.height() as i64),
+                    (image.width as i64, image.height as i64),
+                    IMAGE_MARGIN,
+                );
+                image.composite(yuv, x as i32, y as i32);
+            }
+        }
+    }
+}
+
+/// Draws each of the overlays onto the frame.
+pub(crate) fn apply(overlays: &[Overlay], yuv: &mut YUVBuffer) {
+    for overlay in overlays {
+        overlay.draw(yuv);
+    }
+}
+
+/// Converts a pixel to limited range BT.601 using fixed point arithmetic.
+fn rgb_to_yuv(r: u8, g: u8, b: u8) -> (u8, u8, u8) {
+    let (r, g, b) = (r as i32, g as i32, b as i32);
+
+    let y = ((66 * r + 129 * g + 25 * b + 128) >> 8) + 16;
+    let u = ((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128;
+    let v = ((112 * r - 94 * g - 18 * b + 128) >> 8) + 128;
+
+    (y as u8, u as u8, v as u8)
+}
+
+/// Blends `src` over `dst` with an opacity of `alpha / max`, rounding to the nearest value.
+fn blend(src: u8, dst: u8, alpha: u32, max: u32) -> u8 {
+    ((src as u32 * alpha + dst as u32 * (max - alpha) + max / 2) / max) as u8
+}
+
+fn glyph(c: char) -> &'static [u8; GLYPH_HEIGHT] {
+    let c = c.to_ascii_uppercase();
+
+    let index = FONT
+        .binary_search_by_key(&c, |(glyph_char, _)| *glyph_char)
+        .or_else(|_| FONT.binary_search_by_key(&'?', |(glyph_char, _)| *glyph_char))
+        .expect("the font has a ? glyph");
+
+    &FONT[index].1
+}
+
+/// Clips drawing to the frame.
+struct Canvas<'a> {
+    yuv: &'a mut YUVBuffer,
+    width: i64,
+    height: i64,
+}
+
+impl<'a> Canvas<'a> {
+    fn new(yuv: &'a mut YUVBuffer) -> Self {
+        let (width, height) = (yuv.width() as i64, yuv.height() as i64);
+        Self { yuv, width, height }
+    }
+
+    fn fill(&mut self, x: i64, y: i64, width: i64, height: i64, color: OverlayColor) {
+        let (x_start, x_end) = (x.clamp(0, self.width), (x + width).clamp(0, self.width));
+        let (y_start, y_end) = (y.clamp(0, self.height), (y + height).clamp(0, self.height));
+
+        if x_start >= x_end || y_start >= y_end {
+            return;
+        }
+
+        let (x_start, x_end) = (x_start as usize, x_end as usize);
+        let (y_start, y_end) = (y_start as usize, y_end as usize);
+        let frame_width = self.width as usize;
+
+        for row in self.yuv.y_mut()[y_start * frame_width..y_end * frame_width]
+            .chunks_exact_mut(frame_width)
+        {
+            row[x_start..x_end].fill(color.y);
+        }
+
+        if let Some((u, v)) = color.uv {
+            let chroma_width = frame_width / 2;
+            let (x_start, x_end) = (x_start / 2, x_end.div_ceil(2).min(chroma_width));
+            let (y_start, y_end) = (y_start / 2, y_end.div_ceil(2));
+
+            let fill_plane = |plane: &mut [u8], value: u8| {
+                for row in plane
+                    .chunks_exact_mut(chroma_width)
+                    .take(y_end)
+                    .skip(y_start)
+                {
+                    row[x_start..x_end].fill(value);
+                }
+            };
+
+            fill_plane(self.yuv.u_mut(), u);
+            fill_plane(self.yuv.v_mut(), v);
+        }
+    }
+}
@@ -0,0 +1,325 @@
+use crate::mplane::BufferStream;
+use std::collections::VecDeque;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tracing::warn;
+use v4l::buffer::Metadata;
+
+/// The shortest time the capture thread waits for a frame before checking whether it should exit. Waiting for a frame
+/// is only interrupted by restarting the stream so this is kept well above the frame interval.
+const MIN_CAPTURE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Dequeues a stream's frames on a dedicated thread, see [`StreamBuilder::pipelined`](crate::StreamBuilder::pipelined).
+///
+/// Each frame is copied out of the driver's buffer and the buffer is requeued straight away, so the camera always has
+/// buffers to fill while frames are decoded and encoded on the reading thread. The copies are queued in order and when
+/// the queue is full the oldest frame is dropped.
+///
+/// An eventfd is readable while frames are queued (or the capture thread has exited), so that the reader can poll for
+/// frames like it would poll the camera.
+pub(crate) struct Pipeline {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+    // The frame returned by the last call to `next`, whose bytes are recycled by the following call
+    current: Option<RawFrame>,
+    pub(crate) timeout: Option<Duration>,
+}
+
+struct RawFrame {
+    bytes: Vec<u8>,
+    meta: Metadata,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    // Notified whenever a frame is queued or the state below changes
+    changed: Condvar,
+    // Signalled when a frame is queued and drained when the queue is emptied, with the state locked
+    ready: OwnedFd,
+}
+
+impl Shared {
+    fn signal_ready(&self) {
+        let count = 1u64.to_ne_bytes();
+        // SAFETY: The buffer outlives the write. The counter only fails to increase if it is already near overflow.
+        unsafe {
+            libc::write(
+                self.ready.as_raw_fd(),
+                count.as_ptr() as *const _,
+                count.len(),
+            )
+        };
+    }
+
+    fn drain_ready(&self) {
+        let mut count = [0u8; 8];
+        // SAFETY: The buffer outlives the read. The eventfd is non-blocking so this returns EAGAIN if it is not ready.
+        unsafe {
+            libc::read(
+                self.ready.as_raw_fd(),
+                count.as_mut_ptr() as *mut _,
+                count.len(),
+            )
+        };
+    }
+}
+
+struct State {
+    frames: VecDeque<RawFrame>,
+    queue_depth: usize,
+    // The bytes of frames that have been read, reused so that copying a frame does not allocate
+    spare: Vec<Vec<u8>>,
+    dropped_frames: u64,
+    // False while the reader has stopped the stream (eg. while it is paused) until it reads the next frame
+    running: bool,
+    shutdown: bool,
+    finished: bool,
+    // The error that ended the capture thread, returned by the next read
+    error: Option<io::Error>,
+}
+
+impl Pipeline {
+    /// Starts capturing `stream` on a new thread, queueing up to `queue_depth` frames. `frame_duration` is the stream's
+    /// frame interval.
+    pub(crate) fn spawn(
+        stream: BufferStream,
+        queue_depth: usize,
+        frame_duration: Duration,
+    ) -> io::Result<Self> {
+        // SAFETY: eventfd returns a new file descriptor that is owned by the pipeline, or -1 on failure
+        let ready = match unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) } {
+            -1 => return Err(io::Error::last_os_error()),
+            fd => unsafe { OwnedFd::from_raw_fd(fd) },
+        };
+
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                frames: VecDeque::new(),
+                queue_depth: queue_depth.max(1),
+                spare: Vec::new(),
+                dropped_frames: 0,
+                running: true,
+                shutdown: false,
+                finished: false,
+                error: None,
+            }),
+            changed: Condvar::new(),
+            ready,
+        });
+
+        let thread = {
+            let shared = Arc::clone(&shared);
+            let timeout = MIN_CAPTURE_TIMEOUT.max(frame_duration * 4);
+            std::thread::spawn(move || capture(stream, &shared, timeout))
+        };
+
+        Ok(Self {
+            shared,
+            thread: Some(thread),
+            current: None,
+            timeout: None,
+        })
+    }
+
+    /// The eventfd that is readable while `next` can return without waiting.
+    pub(crate) fn readiness_fd(&self) -> RawFd {
+        self.shared.ready.as_raw_fd()
+    }
+
+    /// Waits for the oldest queued frame, restarting the capture if the stream was stopped.
+    pub(crate) fn next(&mut self) -> io::Result<(&[u8], &Metadata)> {
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        let mut state = self.shared.state.lock().unwrap();
+
+        if let Some(frame) = self.current.take() {
+            state.spare.push(frame.bytes);
+        }
+
+        if !state.running {
+            state.running = true;
+            self.shared.changed.notify_all();
+        }
+
+        let frame = loop {
+            if let Some(frame) = state.frames.pop_front() {
+                if state.frames.is_empty() && !state.finished {
+                    self.shared.drain_ready();
+                }
+                break frame;
+            }
+
+            if state.finished {
+                return Err(state.error.take().unwrap_or_else(|| {
+                    io::Error::new(io::ErrorKind::BrokenPipe, "The capture thread has exited")
+                }));
+            }
+
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(io::ErrorKind::TimedOut.into());
+                    }
+
+                    self.shared
+                        .changed
+                        .wait_timeout(state, deadline - now)
+                        .unwrap()
+                        .0
+                }
+                None => self.shared.changed.wait(state).unwrap(),
+            };
+        };
+        drop(state);
+
+        let frame = self.current.insert(frame);
+        Ok((&frame.bytes, &frame.meta))
+    }
+
+    /// Asks the capture thread to stop the stream and discards the queued frames. The next read restarts it.
+    pub(crate) fn stop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.running = false;
+
+        let discarded = std::mem::take(&mut state.frames);
+        state
+            .spare
+            .extend(discarded.into_iter().map(|frame| frame.bytes));
+        if !state.finished {
+            self.shared.drain_ready();
+        }
+        self.shared.changed.notify_all();
+    }
+
+    /// The number of frames dropped because the queue was full.
+    pub(crate) fn dropped_frames(&self) -> u64 {
+        self.shared.state.lock().unwrap().dropped_frames
+    }
+}
+
+impl Drop for Pipeline {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().shutdown = true;
+        self.shared.changed.notify_all();
+
+        // The capture thread releases the buffers before it exits
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                warn!("The capture thread panicked");
+            }
+        }
+    }
+}
+
+fn capture(mut stream: BufferStream, shared: &Shared, timeout: Duration) {
+    stream.set_timeout(timeout);
+    let mut streaming = false;
+
+    let error = 'capture: loop {
+        let mut state = shared.state.lock().unwrap();
+
+        while !state.running && !state.shutdown {
+            if std::mem::take(&mut streaming) {
+                if let Err(err) = stream.stop() {
+                    break 'capture Some(err);
+                }
+            }
+
+            state = shared.changed.wait(state).unwrap();
+        }
+
+        if state.shutdown {
+            break None;
+        }
+
+        let mut bytes = state.spare.pop().unwrap_or_default();
+        drop(state);
+
+        let meta = match stream.next() {
+            Ok((buf, meta)) => {
+                streaming = true;
+                bytes.clear();
+                bytes.extend_from_slice(buf);
+                *meta
+            }
+            // Checks whether the reader has stopped the stream or the pipeline is shutting down
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => continue,
+            Err(err) => break Some(err),
+        };
+
+        let mut state = shared.state.lock().unwrap();
+
+        // Frames captured after the reader stopped the stream are discarded along with the queued ones
+        if !state.running {
+            state.spare.push(bytes);
+            continue;
+        }
+
+        if state.frames.len() >= state.queue_depth {
+            if let Some(oldest) = state.frames.pop_front() {
+                state.spare.push(oldest.bytes);
+                state.dropped_frames += 1;
+            }
+        }
+
+        state.frames.push_back(RawFrame { bytes, meta });
+        shared.signal_ready();
+        shared.changed.notify_all();
+    };
+
+    crate::stop_and_release(stream);
+
+    let mut state = shared.state.lock().unwrap();
+    state.error = error;
+    state.finished = true;
+    // The reader is woken to return the error
+    shared.signal_ready();
+    shared.changed.notify_all();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mplane::MockStream;
+
+    #[test]
+    fn times_out_during_stalls_and_resumes() {
+        let frame = |delay_ms, byte| (Duration::from_millis(delay_ms), vec![byte; 16]);
+        // The camera stalls for 400ms after the third frame
+        let camera = MockStream::new([
+            frame(10, 0),
+            frame(10, 1),
+            frame(10, 2),
+            frame(400, 3),
+            frame(10, 4),
+        ]);
+        let mut pipeline =
+            Pipeline::spawn(BufferStream::Mock(camera), 4, Duration::from_millis(33)).unwrap();
+        pipeline.timeout = Some(Duration::from_millis(150));
+
+        for byte in 0..3 {
+            let (bytes, meta) = pipeline.next().unwrap();
+            assert_eq!((bytes[0], meta.sequence), (byte, byte as u32));
+        }
+
+        let mut timeouts = 0;
+        let (bytes, meta) = loop {
+            match pipeline.next() {
+                Ok(next) => break next,
+                Err(err) if err.kind() == io::ErrorKind::TimedOut => timeouts += 1,
+                Err(err) => panic!("Unexpected error {err:?}"),
+            }
+        };
+        assert!(timeouts >= 1, "The stall times out");
+        assert_eq!((bytes[0], meta.sequence), (3, 3));
+
+        assert_eq!(pipeline.next().unwrap().0[0], 4);
+        // The capture thread exits when the camera fails
+        assert!(pipeline
+            .next()
+            .is_err_and(|err| err.kind() == io::ErrorKind::UnexpectedEof));
+    }
+}
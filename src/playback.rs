@@ -0,0 +1,288 @@
+use crate::nal::{NalType, NalUnits, ParameterSets};
+use crate::recovery::DecoderRecovery;
+use crate::yuv::YUVSource;
+use crate::{decode_jpeg, EncoderOptions, FrameSource, StreamError, YUVBuffer, YUVFrame};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Plays back a recorded H264 or MJPEG file through the same [`FrameSource`] interface as a camera, eg. for testing
+/// without hardware or reprocessing recorded footage.
+///
+/// By default frames are read as fast as possible. `StreamError::EndOfStream` is returned once every frame has been
+/// read.
+pub struct FilePlaybackStream {
+    frames: Vec<Vec<u8>>,
+    next_frame: usize,
+    mode: PlaybackMode,
+    width: u32,
+    height: u32,
+    fps: f64,
+    realtime: bool,
+    started_at: Option<Instant>,
+}
+
+#[allow(clippy::large_enum_variant)]
+enum PlaybackMode {
+    H264 {
+        decoder: openh264::decoder::Decoder,
+        recovery: DecoderRecovery,
+        parameter_sets: ParameterSets,
+    },
+    Mjpeg {
+        encoder: openh264::encoder::Encoder,
+        yuv_buffer: YUVBuffer,
+    },
+}
+
+// SAFETY: See the Send impl for EncoderMode, the encoder and decoder are exclusively owned by the PlaybackMode.
+unsafe impl Send for PlaybackMode {}
+
+impl FilePlaybackStream {
+    /// Opens a raw Annex-B H264 file (eg. recorded with `record_frames`), which is split into access units.
+    ///
+    /// H264 files don't record their frame rate so it has to be given for real time playback.
+    pub fn open_h264<P: AsRef<Path>>(path: P, fps: f64) -> Result<Self, StreamError> {
+        let data = std::fs::read(path).map_err(StreamError::ReadFailure)?;
+        let frames = split_access_units(&data);
+
+        // The video's dimensions are found by decoding up to the first picture
+        let mut decoder = openh264::decoder::Decoder::new()?;
+        let (width, height) = frames
+            .iter()
+            .find_map(|frame| match decoder.decode(frame) {
+                Ok(Some(yuv)) => Some((yuv.width() as u32, yuv.height() as u32)),
+                _ => None,
+            })
+            .ok_or(StreamError::NoYUVFrame(frames.len()))?;
+
+        Ok(Self::new(
+            frames,
+            PlaybackMode::H264 {
+                decoder: openh264::decoder::Decoder::new()?,
+                recovery: DecoderRecovery::default(),
+                parameter_sets: ParameterSets::default(),
+            },
+            width,
+            height,
+            fps,
+        ))
+    }
+
+    /// Opens a file of concatenated JPEGs (eg. the bytes returned by `next_raw` for an MJPEG camera), which are
+    /// transcoded to H264 as they are read.
+    ///
+    /// Frames are split at each JPEG start of image marker so JPEGs containing embedded thumbnails are not supported.
+    pub fn open_mjpeg<P: AsRef<Path>>(path: P, fps: f64) -> Result<Self, StreamError> {
+        let data = std::fs::read(path).map_err(StreamError::ReadFailure)?;
+        let frames = split_jpegs(&data);
+
+        let (width, height) = match frames.first() {
+            Some(jpeg) => jpeg_dimensions(jpeg)?,
+            None => return Err(StreamError::NoYUVFrame(0)),
+        };
+
+        let encoder = EncoderOptions::default()
+            .frame_rate_or(fps as f32)
+            .build(width, height)?;
+        let yuv_buffer = YUVBuffer::new(width as usize, height as usize);
+
+        Ok(Self::new(
+            frames,
+            PlaybackMode::Mjpeg {
+                encoder,
+                yuv_buffer,
+            },
+            width,
+            height,
+            fps,
+        ))
+    }
+
+    fn new(frames: Vec<Vec<u8>>, mode: PlaybackMode, width: u32, height: u32, fps: f64) -> Self {
+        Self {
+            frames,
+            next_frame: 0,
+            mode,
+            width,
+            height,
+            fps,
+            realtime: false,
+            started_at: None,
+        }
+    }
+
+    /// Paces playback to the frame rate, as a camera would deliver frames.
+    pub fn realtime(mut self, realtime: bool) -> Self {
+        self.realtime = realtime;
+        self
+    }
+
+    /// The number of frames in the file.
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Restarts playback from the first frame.
+    pub fn rewind(&mut self) -> Result<(), StreamError> {
+        self.next_frame = 0;
+        self.started_at = None;
+
+        // Reference frames from the end of the file must not be used by the first frames
+        if let PlaybackMode::H264 {
+            decoder,
+            recovery,
+            parameter_sets,
+        } = &mut self.mode
+        {
+            *decoder = openh264::decoder::Decoder::new()?;
+            *recovery = DecoderRecovery::default();
+            *parameter_sets = ParameterSets::default();
+        }
+
+        Ok(())
+    }
+
+    fn wait_for_frame(&mut self, index: usize) {
+        let started_at = *self.started_at.get_or_insert_with(Instant::now);
+        let due = started_at + Duration::from_secs_f64(index as f64 / self.fps);
+
+        if let Some(delay) = due.checked_duration_since(Instant::now()) {
+            std::thread::sleep(delay);
+        }
+    }
+}
+
+impl FrameSource for FilePlaybackStream {
+    fn next(
+        &mut self,
+        get_yuv_frame: bool,
+    ) -> Result<(Vec<u8>, Option<YUVFrame<'_>>), StreamError> {
+        let index = self.next_frame;
+        if index >= self.frames.len() {
+            return Err(StreamError::EndOfStream);
+        }
+        self.next_frame += 1;
+
+        if self.realtime {
+            self.wait_for_frame(index);
+        }
+
+        let frame = &self.frames[index];
+
+        match &mut self.mode {
+            PlaybackMode::H264 {
+                decoder,
+                recovery,
+                parameter_sets,
+            } => {
+                parameter_sets.update(frame);
+
+                // Corrupt frames return `StreamError::DecodeFailed` and playback resyncs at the next key frame
+                let yuv = if get_yuv_frame {
+                    recovery
+                        .decode(decoder, frame, parameter_sets)?
+                        .map(YUVFrame::Decoded)
+                } else {
+                    None
+                };
+
+                Ok((frame.clone(), yuv))
+            }
+            PlaybackMode::Mjpeg {
+                encoder,
+                yuv_buffer,
+            } => {
+                decode_jpeg(frame, yuv_buffer)?;
+
+                let mut h264_bytes = Vec::new();
+                encoder.encode(&*yuv_buffer)?.write_vec(&mut h264_bytes);
+
+                let yuv = get_yuv_frame.then_some(YUVFrame::Borrowed(yuv_buffer));
+
+                Ok((h264_bytes, yuv))
+            }
+        }
+    }
+
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn fps(&self) -> f64 {
+        self.fps
+    }
+}
+
+fn jpeg_dimensions(jpeg: &[u8]) -> Result<(u32, u32), StreamError> {
+    let mut decoder = jpeg_decoder::Decoder::new(jpeg);
+    decoder.read_info()?;
+
+    let info = decoder
+        .info()
+        .expect("JPEG info is available after read_info");
+
+    Ok((info.width as u32, info.height as u32))
+}
+
+/// Splits an Annex-B bitstream into access units, each starting with its first NAL unit's start code.
+///
+/// A new access unit starts at the first delimiter, parameter set or SEI after a slice, or at a slice that starts a
+/// new picture (its first macroblock is 0).
+fn split_access_units(data: &[u8]) -> Vec<Vec<u8>> {
+    let mut starts = Vec::new();
+    let mut has_slice = false;
+
+    for nal in NalUnits::new(data) {
+        let is_slice = matches!(NalType::of(nal), NalType::NonIdr | NalType::Idr);
+
+        let starts_access_unit = match NalType::of(nal) {
+            NalType::Aud | NalType::Sps | NalType::Pps | NalType::Sei => has_slice,
+            // first_mb_in_slice is the first exp-golomb value in the slice header, which is 0 if its first bit is set
+            _ if is_slice => has_slice && nal.get(1).is_some_and(|byte| byte & 0x80 != 0),
+            _ => false,
+        };
+
+        // Include the whole start code, which may be 3 or 4 bytes
+        let mut offset = nal.as_ptr() as usize - data.as_ptr() as usize - 3;
+        if offset > 0 && data[offset - 1] == 0 {
+            offset -= 1;
+        }
+
+        if starts.is_empty() || starts_access_unit {
+            starts.push(offset);
+            has_slice = false;
+        }
+
+        has_slice |= is_slice;
+    }
+
+    let ends = starts.iter().skip(1).copied().chain([data.len()]);
+
+    starts
+        .iter()
+        .zip(ends)
+        .map(|(&start, end)| data[start..end].to_vec())
+        .collect()
+}
+
+/// Splits concatenated JPEGs at each start of image marker.
+fn split_jpegs(data: &[u8]) -> Vec<Vec<u8>> {
+    let starts = data
+        .windows(3)
+        .enumerate()
+        .filter(|(_, window)| window == &[0xff, 0xd8, 0xff])
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+
+    let ends = starts.iter().skip(1).copied().chain([data.len()]);
+
+    starts
+        .iter()
+        .zip(ends)
+        .map(|(&start, end)| data[start..end].to_vec())
+        .collect()
+}
@@ -0,0 +1,117 @@
+use crate::FrameMeta;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Keeps the most recent H264 access units in memory so that an event-triggered recording can include the moments
+/// before the trigger.
+///
+/// Frames are buffered in whole GOPs (a key frame and the frames that depend on it) so that the buffer always starts at
+/// a key frame. At least `max_duration` is retained where possible: the oldest GOP is only dropped once the rest of the
+/// buffer covers the full duration, or when the buffer exceeds its byte limit.
+pub struct GopBuffer {
+    gops: VecDeque<Vec<(FrameMeta, Vec<u8>)>>,
+    max_duration: Duration,
+    max_bytes: Option<usize>,
+    bytes: usize,
+    frames: usize,
+}
+
+impl GopBuffer {
+    /// Creates a buffer that retains roughly the last `max_duration` of the stream.
+    pub fn new(max_duration: Duration) -> Self {
+        Self {
+            gops: VecDeque::new(),
+            max_duration,
+            max_bytes: None,
+            bytes: 0,
+            frames: 0,
+        }
+    }
+
+    /// Limits the memory used by the buffered frames. GOPs are dropped from the front of the buffer until it fits, so
+    /// a single GOP larger than the limit is discarded entirely. Unlimited by default.
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Adds an access unit to the buffer. Frames are discarded until the first key frame.
+    pub fn push(&mut self, meta: FrameMeta, h264_bytes: Vec<u8>) {
+        if meta.is_keyframe {
+            self.gops.push_back(Vec::new());
+        }
+
+        let Some(gop) = self.gops.back_mut() else {
+            return;
+        };
+
+        self.bytes += h264_bytes.len();
+        self.frames += 1;
+        gop.push((meta, h264_bytes));
+
+        self.trim(meta.timestamp);
+    }
+
+    fn trim(&mut self, latest: Duration) {
+        // Drop the oldest GOP if the next one still starts at least max_duration ago
+        while let Some(next_gop) = self.gops.get(1) {
+            let next_start = next_gop[0].0.timestamp;
+
+            if latest.saturating_sub(next_start) < self.max_duration {
+                break;
+            }
+
+            self.pop_front();
+        }
+
+        if let Some(max_bytes) = self.max_bytes {
+            while self.bytes > max_bytes {
+                self.pop_front();
+            }
+        }
+    }
+
+    fn pop_front(&mut self) {
+        if let Some(gop) = self.gops.pop_front() {
+            self.bytes -= gop.iter().map(|(_, bytes)| bytes.len()).sum::<usize>();
+            self.frames -= gop.len();
+        }
+    }
+
+    /// Removes and returns every buffered frame in order, starting with a key frame.
+    ///
+    /// Frames pushed after draining are buffered again once the next key frame arrives, so the stream's next frame
+    /// should be written to the recording directly rather than pushed to the buffer.
+    pub fn drain(&mut self) -> Vec<(FrameMeta, Vec<u8>)> {
+        self.bytes = 0;
+        self.frames = 0;
+
+        self.gops.drain(..).flatten().collect()
+    }
+
+    /// Discards every buffered frame.
+    pub fn clear(&mut self) {
+        self.drain();
+    }
+
+    /// The time between the first and last buffered frames.
+    pub fn buffered_duration(&self) -> Duration {
+        let first = self.gops.front().and_then(|gop| gop.first());
+        let last = self.gops.back().and_then(|gop| gop.last());
+
+        match (first, last) {
+            (Some((first, _)), Some((last, _))) => last.timestamp.saturating_sub(first.timestamp),
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// The total size of the buffered access units.
+    pub fn buffered_bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// The number of buffered access units.
+    pub fn buffered_frames(&self) -> usize {
+        self.frames
+    }
+}
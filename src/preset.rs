@@ -0,0 +1,105 @@
+#[cfg(feature = "openh264")]
+use crate::{EncoderComplexity, EncoderOptions, RateControlMode};
+use crate::{ErrorPolicy, KeyframeAlignment, LatencyProfile};
+
+/// A bundle of stream settings for a common use case, applied with
+/// [`StreamBuilder::preset`](crate::StreamBuilder::preset). See [`to_options`](Self::to_options) for exactly what each
+/// preset sets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamPreset {
+    /// Prioritizes getting each frame out as soon as possible, eg. for remote control: the fewest buffers, the highest
+    /// frame rate and a fast encoder. Corrupt frames are skipped rather than interrupting the stream.
+    LowLatency,
+    /// Sensible settings for most uses, eg. monitoring: the largest resolution and a moderate bitrate.
+    Balanced,
+    /// Prioritizes image quality, eg. for recordings: the largest resolution, a high bitrate and the slowest encoder.
+    /// Recordings start at a key frame and corrupt frames are returned as errors.
+    HighQuality,
+    /// For streams that a timelapse is captured from, see [`TimelapseRecorder`](crate::TimelapseRecorder): the largest
+    /// resolution at a low bitrate with infrequent key frames.
+    Timelapse,
+}
+
+/// Whether a preset chooses the camera's configuration by resolution or by frame rate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormatPriority {
+    /// The largest resolution, then the highest frame rate (see
+    /// [`CaptureConfig::default_score`](crate::CaptureConfig::default_score)).
+    Resolution,
+    /// The highest frame rate, then the largest resolution (see
+    /// [`CaptureConfig::frame_rate_score`](crate::CaptureConfig::frame_rate_score)).
+    FrameRate,
+}
+
+/// The builder settings a [`StreamPreset`] applies.
+#[derive(Debug, Clone, Copy)]
+pub struct PresetOptions {
+    pub latency_profile: LatencyProfile,
+    pub buffer_count: u32,
+    /// The encoder settings for transcoded cameras.
+    #[cfg(feature = "openh264")]
+    pub encoder_options: EncoderOptions,
+    pub format_priority: FormatPriority,
+    pub error_policy: ErrorPolicy,
+    pub keyframe_alignment: KeyframeAlignment,
+}
+
+impl StreamPreset {
+    /// The settings the preset applies to a [`StreamBuilder`](crate::StreamBuilder).
+    pub fn to_options(self) -> PresetOptions {
+        match self {
+            Self::LowLatency => PresetOptions {
+                latency_profile: LatencyProfile::Low,
+                buffer_count: 2,
+                #[cfg(feature = "openh264")]
+                encoder_options: EncoderOptions::new()
+                    .bitrate(2_000_000)
+                    .rate_control_mode(RateControlMode::Bitrate)
+                    .keyframe_interval(30)
+                    .low_latency(true),
+                format_priority: FormatPriority::FrameRate,
+                error_policy: ErrorPolicy::SkipCorruptFrames,
+                keyframe_alignment: KeyframeAlignment::Disabled,
+            },
+            Self::Balanced => PresetOptions {
+                latency_profile: LatencyProfile::Default,
+                buffer_count: 4,
+                #[cfg(feature = "openh264")]
+                encoder_options: EncoderOptions::new()
+                    .bitrate(1_000_000)
+                    .rate_control_mode(RateControlMode::Bitrate)
+                    .keyframe_interval(60)
+                    .complexity(EncoderComplexity::Medium),
+                format_priority: FormatPriority::Resolution,
+                error_policy: ErrorPolicy::SkipCorruptFrames,
+                keyframe_alignment: KeyframeAlignment::SkipToKeyframe,
+            },
+            Self::HighQuality => PresetOptions {
+                latency_profile: LatencyProfile::Default,
+                buffer_count: 4,
+                #[cfg(feature = "openh264")]
+                encoder_options: EncoderOptions::new()
+                    .bitrate(4_000_000)
+                    .rate_control_mode(RateControlMode::Quality)
+                    .keyframe_interval(120)
+                    .complexity(EncoderComplexity::High),
+                format_priority: FormatPriority::Resolution,
+                error_policy: ErrorPolicy::FailFast,
+                keyframe_alignment: KeyframeAlignment::SkipToKeyframeWithParameterSets,
+            },
+            Self::Timelapse => PresetOptions {
+                latency_profile: LatencyProfile::Default,
+                buffer_count: 2,
+                #[cfg(feature = "openh264")]
+                encoder_options: EncoderOptions::new()
+                    .bitrate(500_000)
+                    .rate_control_mode(RateControlMode::Quality)
+                    .keyframe_interval(300)
+                    .complexity(EncoderComplexity::High),
+                format_priority: FormatPriority::Resolution,
+                error_policy: ErrorPolicy::SkipCorruptFrames,
+                keyframe_alignment: KeyframeAlignment::SkipToKeyframe,
+            },
+        }
+    }
+}
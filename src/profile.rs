@@ -0,0 +1,239 @@
+use crate::controls::{self, ControlError, ControlFlags, ControlInfo, ControlType, MenuItem};
+use crate::{mplane, CaptureConfig};
+use v4l::{Device, FourCC, Fraction};
+
+/// A camera's control settings and capture format, eg. to restore focus, exposure and white balance after the camera
+/// re-enumerates (see [`ReconnectingStream::profile`](crate::ReconnectingStream::profile)).
+///
+/// With the `serde` feature profiles can be serialized, eg. with `to_json`, to keep them in a config file.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CameraProfile {
+    pub format: ProfileFormat,
+    /// The writable controls, in the order the camera lists them.
+    pub controls: Vec<ProfileControl>,
+}
+
+/// The capture format recorded in a [`CameraProfile`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ProfileFormat {
+    /// The pixel format's four character code, eg. `MJPG`.
+    pub fourcc: String,
+    pub width: u32,
+    pub height: u32,
+    /// The frame interval (1 / fps) as a numerator and denominator.
+    pub interval: (u32, u32),
+}
+
+/// A control value recorded in a [`CameraProfile`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ProfileControl {
+    pub id: u32,
+    pub name: String,
+    pub value: i64,
+    /// For menu controls, the selected item. Menu items are restored by their name (or integer value) so that the
+    /// setting survives drivers renumbering their menus.
+    pub menu_item: Option<ProfileMenuItem>,
+}
+
+/// A menu control's selected item, see [`MenuItem`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ProfileMenuItem {
+    Name(String),
+    Value(i64),
+}
+
+impl ProfileMenuItem {
+    fn matches(&self, item: &MenuItem) -> bool {
+        match (self, item) {
+            (Self::Name(name), MenuItem::Name(item)) => name.eq_ignore_ascii_case(item),
+            (Self::Value(value), MenuItem::Value(item)) => value == item,
+            _ => false,
+        }
+    }
+}
+
+/// A control in a [`CameraProfile`] that could not be restored, eg. because the camera no longer has it.
+#[derive(Debug)]
+pub struct SkippedControl {
+    pub name: String,
+    pub error: ControlError,
+}
+
+impl CameraProfile {
+    /// Records the camera's current capture format and the values of its writable controls.
+    ///
+    /// Inactive controls (eg. the exposure time while automatic exposure is on) are left out since their values are set
+    /// by the camera.
+    pub fn capture(dev: &Device) -> Result<Self, ControlError> {
+        let multiplanar = mplane::is_multiplanar(dev);
+        let format = mplane::format(dev, multiplanar)?;
+        let interval = mplane::params(dev, multiplanar)?.interval;
+
+        let unwritable = ControlFlags::READ_ONLY
+            | ControlFlags::WRITE_ONLY
+            | ControlFlags::DISABLED
+            | ControlFlags::INACTIVE;
+
+        let controls = controls::list_controls(dev)?
+            .into_iter()
+            .filter(|control| !control.flags.intersects(unwritable))
+            .filter_map(|control| {
+                let value = control.value?;
+                let menu_item = match control.typ {
+                    ControlType::Menu | ControlType::IntegerMenu => control
+                        .menu_items
+                        .iter()
+                        .find(|(index, _)| *index as i64 == value)
+                        .map(|(_, item)| match item {
+                            MenuItem::Name(name) => ProfileMenuItem::Name(name.clone()),
+                            MenuItem::Value(value) => ProfileMenuItem::Value(*value),
+                        }),
+                    _ => None,
+                };
+
+                Some(ProfileControl {
+                    id: control.id,
+                    name: control.name,
+                    value,
+                    menu_item,
+                })
+            })
+            .collect();
+
+        Ok(Self {
+            format: ProfileFormat {
+                fourcc: String::from_utf8_lossy(&format.fourcc.repr).into_owned(),
+                width: format.width,
+                height: format.height,
+                interval: (interval.numerator, interval.denominator),
+            },
+            controls,
+        })
+    }
+
+    /// Restores the profile's control values, returning the controls that could not be restored. The capture format is
+    /// not changed since it is negotiated when a stream is opened, see [`capture_config`](Self::capture_config).
+    ///
+    /// Controls are matched by their ID, or by their name if the camera no longer has a control with that ID. Controls
+    /// that are inactive until another control is set (eg. the focus until auto focus is turned off) are retried once
+    /// the other controls have been set.
+    pub fn apply(&self, dev: &Device) -> Result<Vec<SkippedControl>, ControlError> {
+        let current = controls::list_controls(dev)?;
+        let mut skipped = Vec::new();
+        let mut pending = Vec::new();
+
+        for saved in &self.controls {
+            let control = current
+                .iter()
+                .find(|control| control.id == saved.id)
+                .or_else(|| {
+                    current
+                        .iter()
+                        .find(|control| control.name.eq_ignore_ascii_case(&saved.name))
+                });
+
+            let Some(control) = control else {
+                skipped.push(SkippedControl {
+                    name: saved.name.clone(),
+                    error: ControlError::NotFound(saved.name.clone()),
+                });
+                continue;
+            };
+
+            match menu_value(saved, control) {
+                Some(value) => pending.push((control, value)),
+                None => skipped.push(SkippedControl {
+                    name: control.name.clone(),
+                    error: ControlError::OutOfRange {
+                        name: control.name.clone(),
+                        value: saved.value,
+                        minimum: control.minimum,
+                        maximum: control.maximum,
+                    },
+                }),
+            }
+        }
+
+        // Setting a control can activate others, so inactive controls are retried until none of them can be set
+        loop {
+            let mut inactive = Vec::new();
+            let mut progressed = false;
+
+            for (control, value) in pending {
+                match controls::set_control(dev, control.id, value) {
+                    Ok(()) => progressed = true,
+                    Err(ControlError::Inactive(_)) => inactive.push((control, value)),
+                    Err(error) => skipped.push(SkippedControl {
+                        name: control.name.clone(),
+                        error,
+                    }),
+                }
+            }
+
+            if inactive.is_empty() || !progressed {
+                skipped.extend(inactive.into_iter().map(|(control, _)| SkippedControl {
+                    name: control.name.clone(),
+                    error: ControlError::Inactive(control.name.clone()),
+                }));
+                break;
+            }
+
+            pending = inactive;
+        }
+
+        Ok(skipped)
+    }
+
+    /// The recorded capture format, eg. to pass to [`StreamBuilder::capture_config`](crate::StreamBuilder::capture_config)
+    /// to reopen the camera in the same format. Returns `None` if the four character code is not 4 bytes long.
+    pub fn capture_config(&self) -> Option<CaptureConfig> {
+        let fourcc: [u8; 4] = self.format.fourcc.as_bytes().try_into().ok()?;
+        let (numerator, denominator) = self.format.interval;
+
+        Some(CaptureConfig {
+            fourcc: FourCC::new(&fourcc),
+            width: self.format.width,
+            height: self.format.height,
+            interval: Fraction::new(numerator, denominator),
+        })
+    }
+
+    /// Serializes the profile as pretty printed JSON. Requires the `serde` feature.
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Camera profiles are always serializable")
+    }
+
+    /// Parses a profile serialized with [`to_json`](Self::to_json). Requires the `serde` feature.
+    #[cfg(feature = "serde")]
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+}
+
+/// The value to set a control to: for menu controls the index of the saved item, falling back to the saved index if
+/// the menu has no such item. Returns `None` if the menu has neither.
+fn menu_value(saved: &ProfileControl, control: &ControlInfo) -> Option<i64> {
+    if !matches!(control.typ, ControlType::Menu | ControlType::IntegerMenu) {
+        return Some(saved.value);
+    }
+
+    let by_item = saved.menu_item.as_ref().and_then(|saved_item| {
+        control
+            .menu_items
+            .iter()
+            .find(|(_, item)| saved_item.matches(item))
+    });
+    let by_index = || {
+        control
+            .menu_items
+            .iter()
+            .find(|(index, _)| *index as i64 == saved.value)
+    };
+
+    by_item.or_else(by_index).map(|(index, _)| *index as i64)
+}
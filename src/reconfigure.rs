@@ -0,0 +1,139 @@
+use crate::builder::{Negotiated, StreamConfig};
+use crate::{HealthEventKind, StreamError, WebcamH264Stream};
+use std::time::Instant;
+use tracing::warn;
+use v4l::{FourCC, Fraction};
+
+/// The format to switch a stream to with [`WebcamH264Stream::reconfigure`]. Unset fields are chosen the same way as
+/// when opening a stream, see [`StreamBuilder`](crate::StreamBuilder).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResolutionRequest {
+    /// An exact resolution, see [`StreamBuilder::resolution`](crate::StreamBuilder::resolution).
+    pub resolution: Option<(u32, u32)>,
+    pub max_fps: Option<u32>,
+    /// The preferred pixel format, see [`StreamBuilder::prefer_fourcc`](crate::StreamBuilder::prefer_fourcc).
+    pub fourcc: Option<FourCC>,
+}
+
+impl ResolutionRequest {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            resolution: Some((width, height)),
+            ..Default::default()
+        }
+    }
+}
+
+/// A stream's negotiated format. The width and height are the size of the stream's frames after any crop or scale.
+#[derive(Debug, Clone, Copy)]
+pub struct StreamFormat {
+    pub width: u32,
+    pub height: u32,
+    pub fourcc: FourCC,
+    pub frame_interval: Fraction,
+}
+
+/// The result of [`WebcamH264Stream::reconfigure`].
+#[derive(Debug, Clone, Copy)]
+pub struct Reconfigured {
+    pub old: StreamFormat,
+    pub new: StreamFormat,
+}
+
+impl<'a> WebcamH264Stream<'a> {
+    /// The stream's negotiated format.
+    pub fn format(&self) -> StreamFormat {
+        StreamFormat {
+            width: self.width,
+            height: self.height,
+            fourcc: self.fourcc,
+            frame_interval: self.frame_interval,
+        }
+    }
+
+    /// Switches the stream to a different resolution, frame rate or pixel format without reopening the device, eg.
+    /// between a high quality recording and a low bandwidth preview.
+    ///
+    /// The camera is stopped, the format is renegotiated and the buffers and encoder (or decoder) are recreated. The
+    /// stream's other settings (crop, scale, overlays etc.) are kept. The bitstream restarts with new parameter sets and
+    /// the first frame afterwards is always a key frame: native H264 frames are discarded until one arrives and
+    /// transcoded streams start a new encoder.
+    ///
+    /// If no supported format matches the request the stream is left untouched. If applying the format fails the
+    /// stream's previous format is restored before the error is returned, and if that also fails reading from the
+    /// stream returns `StreamError::NotConfigured`.
+    pub fn reconfigure(&mut self, request: ResolutionRequest) -> Result<Reconfigured, StreamError> {
+        let config = StreamConfig {
+            resolution: request.resolution,
+            max_fps: request.max_fps,
+            preferred_fourcc: request.fourcc,
+            // The request replaces an exact configuration, but a custom selector still chooses between the matches
+            capture_config: None,
+            ..self.config.clone()
+        };
+        let old = self.format();
+
+        // Choosing the format only queries the camera so the stream keeps running if it fails
+        let candidates = config.select(self.device.get())?;
+
+        // The buffers have to be released before the format can be changed
+        if let Some(stream) = self.stream.take() {
+            crate::stop_and_release(stream);
+        }
+
+        let negotiated = match config.apply_first(self.device.get(), candidates) {
+            Ok(negotiated) => negotiated,
+            Err(err) => {
+                let dev = self.device.get();
+                let restored = self
+                    .config
+                    .select(dev)
+                    .and_then(|candidates| self.config.apply_first(dev, candidates));
+
+                match restored {
+                    Ok(negotiated) => self.install(negotiated),
+                    Err(restore_err) => warn!(
+                        "Unable to restore the stream's previous format after reconfiguring failed: {:?}",
+                        restore_err
+                    ),
+                }
+
+                return Err(err);
+            }
+        };
+
+        self.config = config;
+        self.install(negotiated);
+
+        let new = self.format();
+        self.events
+            .emit(HealthEventKind::FormatChanged { old, new });
+
+        Ok(Reconfigured { old, new })
+    }
+
+    fn install(&mut self, negotiated: Negotiated) {
+        self.stream = Some(negotiated.stream);
+        self.encoder_mode = negotiated.encoder_mode;
+        self.width = negotiated.width;
+        self.height = negotiated.height;
+        self.frame_interval = negotiated.frame_interval;
+        self.fourcc = negotiated.fourcc;
+        self.transform = negotiated.transform;
+        self.transform_mode = negotiated.transform_mode;
+        self.yuv_buffer = negotiated.yuv_buffer;
+        self.raw_layout = negotiated.raw_layout;
+
+        self.parameter_sets = Default::default();
+        #[cfg(feature = "openh264")]
+        {
+            self.decoder_recovery = Default::default();
+        }
+        self.keyframe_requested = false;
+        self.awaiting_keyframe = self.is_native_h264();
+        if let Some(decimator) = &mut self.decimator {
+            decimator.restart(self.frame_interval);
+        }
+        self.last_frame_received = Instant::now();
+    }
+}
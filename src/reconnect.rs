@@ -0,0 +1,248 @@
+use crate::events::EventBus;
+use crate::{
+    get_device, get_device_by_bus_info, CameraProfile, DeviceError, Frame, HealthEvent,
+    HealthEventKind, OwnedWebcamH264Stream, StreamBuilder, StreamError, WebcamH264Stream,
+};
+use std::path::PathBuf;
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+use tracing::warn;
+
+/// Identifies a camera in a way that survives it being unplugged and re-enumerated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceSelector {
+    /// The device's bus info, eg. `usb-0000:00:14.0-1` (see [`DeviceInfo::bus_info`](crate::DeviceInfo::bus_info)).
+    BusInfo(String),
+    /// A stable symlink to the device, eg. `/dev/v4l/by-id/usb-...-video-index0`. `/dev/videoN` paths may change when
+    /// the camera re-enumerates so they should be avoided.
+    Path(PathBuf),
+}
+
+impl DeviceSelector {
+    pub(crate) fn open(&self) -> Result<v4l::Device, DeviceError> {
+        match self {
+            Self::BusInfo(bus_info) => get_device_by_bus_info(bus_info),
+            Self::Path(path) => get_device(path),
+        }
+    }
+}
+
+/// The result of reading from a [`ReconnectingStream`].
+pub enum StreamEvent {
+    Frame(Frame),
+    /// The camera was reconnected. The stream was renegotiated so its resolution may have changed and the bitstream
+    /// restarts with new parameter sets.
+    Reconnected {
+        width: u32,
+        height: u32,
+    },
+}
+
+pub(crate) type Configure = Box<dyn FnMut(StreamBuilder<'static>) -> StreamBuilder<'static> + Send>;
+
+/// A stream that reopens the camera if it disconnects, eg. when a USB camera drops off the bus.
+///
+/// Reconnection is attempted with exponential backoff (500ms doubling up to 10s by default). After `max_attempts`
+/// failed attempts (10 by default) the last error is returned; calling `next` again starts a new round of attempts.
+pub struct ReconnectingStream {
+    selector: DeviceSelector,
+    configure: Configure,
+    stream: Option<OwnedWebcamH264Stream>,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    max_attempts: Option<u32>,
+    profile: Option<CameraProfile>,
+    // Shared with each stream that is opened so that subscribers receive both streams' and reconnection events
+    events: EventBus,
+    #[cfg(feature = "metrics")]
+    metrics: Option<(crate::metrics::StreamMetrics, String)>,
+}
+
+impl ReconnectingStream {
+    /// Opens the camera. `configure` is applied to the builder each time the stream is (re)opened.
+    pub fn open(
+        selector: DeviceSelector,
+        configure: impl FnMut(StreamBuilder<'static>) -> StreamBuilder<'static> + Send + 'static,
+    ) -> Result<Self, StreamError> {
+        let mut reconnecting = Self {
+            selector,
+            configure: Box::new(configure),
+            stream: None,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+            max_attempts: Some(10),
+            profile: None,
+            events: EventBus::default(),
+            #[cfg(feature = "metrics")]
+            metrics: None,
+        };
+
+        reconnecting.stream = Some(reconnecting.open_stream()?);
+
+        Ok(reconnecting)
+    }
+
+    /// Sets the delay before the first reconnection attempt and the limit it doubles up to between attempts.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Sets the number of reconnection attempts before giving up, or `None` to retry forever.
+    pub fn max_attempts(mut self, max_attempts: Option<u32>) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// Restores the profile's control settings each time the camera is reconnected, eg. a profile captured after tuning
+    /// the focus and exposure. Controls that can't be restored are logged and skipped.
+    ///
+    /// The profile is applied before the stream is reopened so the builder's settings (eg. hardware flips) take
+    /// precedence. It is not applied to the current stream, use [`CameraProfile::apply`] with
+    /// [`stream().device()`](OwnedWebcamH264Stream::device) for that.
+    pub fn profile(mut self, profile: CameraProfile) -> Self {
+        self.profile = Some(profile);
+        self
+    }
+
+    /// Exports the current and reconnected streams' metrics labelled with `device` (see [`StreamBuilder::metrics`]),
+    /// and counts reconnections in `webcam_reconnects_total`. Requires the `metrics` feature.
+    #[cfg(feature = "metrics")]
+    pub fn metrics(mut self, metrics: &crate::metrics::StreamMetrics, device: &str) -> Self {
+        if let Some(stream) = &mut self.stream {
+            stream.metrics = Some(crate::metrics::MetricsHandle::new(metrics, device));
+        }
+
+        self.metrics = Some((metrics.clone(), device.to_string()));
+        self
+    }
+
+    /// Returns a receiver of health events from the current stream and each reconnected stream along with the
+    /// reconnection attempts, see [`WebcamH264Stream::subscribe_events`].
+    pub fn subscribe_events(&self) -> Receiver<HealthEvent> {
+        self.events.subscribe()
+    }
+
+    /// The number of health events dropped because a receiver from `subscribe_events` was full.
+    pub fn dropped_events(&self) -> u64 {
+        self.events.dropped_events()
+    }
+
+    /// The current stream, or `None` if the camera is disconnected.
+    pub fn stream(&mut self) -> Option<&mut OwnedWebcamH264Stream> {
+        self.stream.as_mut()
+    }
+
+    /// Reads the next frame, reconnecting first if the camera has disconnected.
+    pub fn next(&mut self, get_yuv_frame: bool) -> Result<StreamEvent, StreamError> {
+        let Some(stream) = &mut self.stream else {
+            return self.reconnect();
+        };
+
+        match stream.next_frame(get_yuv_frame) {
+            Ok(frame) => Ok(StreamEvent::Frame(frame)),
+            Err(err) if is_disconnect(&err) => {
+                warn!("Camera {:?} disconnected: {:?}", self.selector, err);
+
+                // The old stream has to be closed before the device can be reopened
+                self.stream = None;
+                self.reconnect()
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn reconnect(&mut self) -> Result<StreamEvent, StreamError> {
+        let mut backoff = self.initial_backoff;
+        let mut attempt = 0;
+
+        loop {
+            std::thread::sleep(backoff);
+            attempt += 1;
+
+            match self.open_stream() {
+                Ok(stream) => {
+                    let (width, height) = (stream.width, stream.height);
+                    self.stream = Some(stream);
+                    self.events
+                        .emit(HealthEventKind::Reconnected { width, height });
+
+                    #[cfg(feature = "metrics")]
+                    if let Some((metrics, device)) = &self.metrics {
+                        metrics.record_reconnect(device);
+                    }
+
+                    return Ok(StreamEvent::Reconnected { width, height });
+                }
+                Err(err) if self.max_attempts.is_some_and(|max| attempt >= max) => {
+                    self.events.emit(HealthEventKind::ReconnectFailed {
+                        attempts: attempt,
+                        error: err.to_string(),
+                    });
+                    return Err(err);
+                }
+                Err(err) => {
+                    warn!(
+                        "Reconnection attempt {} to {:?} failed: {:?}",
+                        attempt, self.selector, err
+                    );
+                    self.events.emit(HealthEventKind::ReconnectAttemptFailed {
+                        attempt,
+                        error: err.to_string(),
+                    });
+                }
+            }
+
+            backoff = (backoff * 2).min(self.max_backoff);
+        }
+    }
+
+    fn open_stream(&mut self) -> Result<OwnedWebcamH264Stream, StreamError> {
+        let device = self.selector.open()?;
+
+        if let Some(profile) = &self.profile {
+            match profile.apply(&device) {
+                Ok(skipped) => {
+                    for skipped in skipped {
+                        warn!(
+                            "Could not restore the {} control of {:?}: {}",
+                            skipped.name, self.selector, skipped.error
+                        );
+                    }
+                }
+                Err(err) => warn!(
+                    "Could not restore the camera profile of {:?}: {:?}",
+                    self.selector, err
+                ),
+            }
+        }
+
+        let builder = (self.configure)(WebcamH264Stream::from_device(device));
+
+        #[cfg(feature = "metrics")]
+        let builder = match &self.metrics {
+            Some((metrics, device)) => builder.metrics(metrics, device),
+            None => builder,
+        };
+
+        let mut stream = builder.open()?;
+        stream.events = self.events.clone();
+
+        Ok(stream)
+    }
+}
+
+pub(crate) fn is_disconnect(err: &StreamError) -> bool {
+    match err {
+        StreamError::DeviceDisconnected => true,
+        // Some UVC drivers report I/O errors for unplugged cameras
+        StreamError::DequeueFailed { source }
+        | StreamError::RequestBuffersFailed { source, .. } => {
+            source.raw_os_error() == Some(libc::EIO)
+        }
+        // The camera may recover after being reopened
+        StreamError::Stalled { .. } => true,
+        _ => false,
+    }
+}
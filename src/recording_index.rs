@@ -0,0 +1,400 @@
+use crate::nal::{NalType, NalUnits};
+use crate::segment::TIMESTAMP_FORMAT;
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
+
+/// Appended to a segment's file name to name its index sidecar.
+pub(crate) const SIDECAR_EXTENSION: &str = ".idx";
+/// The first line of a sidecar, which identifies the format and its version.
+pub(crate) const SIDECAR_HEADER: &str = "h264_webcam_stream index 1\n";
+
+/// The index sidecar of the segment at `path`, eg. `segment-20240131T235959Z-000042.h264.idx`.
+pub(crate) fn sidecar_path(path: &Path) -> PathBuf {
+    let mut sidecar = path.as_os_str().to_owned();
+    sidecar.push(SIDECAR_EXTENSION);
+    PathBuf::from(sidecar)
+}
+
+/// A sidecar line recording a key frame's wall clock time (in microseconds since the Unix epoch) and its byte offset
+/// within the segment.
+pub(crate) fn sidecar_entry(timestamp: DateTime<Utc>, byte_offset: u64) -> String {
+    format!("{} {}\n", timestamp.timestamp_micros(), byte_offset)
+}
+
+/// Where to start reading a recording to play it from a point in time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeekPoint {
+    /// The segment containing the key frame.
+    pub path: PathBuf,
+    /// The offset of the key frame's access unit (including its parameter sets) within the segment.
+    pub byte_offset: u64,
+    /// When the key frame was captured.
+    pub keyframe_ts: DateTime<Utc>,
+}
+
+struct IndexedSegment {
+    path: PathBuf,
+    /// The size of the segment when it was indexed. Anything after it is ignored, eg. for the segment being written.
+    len: u64,
+    ended_at: DateTime<Utc>,
+    /// The key frames' capture times and byte offsets, in order.
+    keyframes: Vec<(DateTime<Utc>, u64)>,
+}
+
+/// Finds the key frames in a directory of recordings written by a [`SegmentedRecorder`](crate::SegmentedRecorder),
+/// eg. to play the video around a point in time.
+///
+/// The recorder writes a small index sidecar (`.idx`) next to each segment listing its key frames' capture times and
+/// byte offsets. Segments whose sidecar is missing or corrupt (and `.h264` files recorded without one) are scanned for
+/// key frames instead, with their times estimated from the segment's start and modification times as if the frame rate
+/// was constant.
+///
+/// The index is a snapshot of the directory when it was opened: open it again to find newer footage.
+pub struct RecordingIndex {
+    /// Ordered by their first key frame.
+    segments: Vec<IndexedSegment>,
+}
+
+impl RecordingIndex {
+    /// Indexes the segments in `dir`.
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let mut segments = Vec::new();
+
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or("");
+
+            // Segments are recognised by their sidecar or, without one, by their extension
+            let is_segment = !name.ends_with(SIDECAR_EXTENSION)
+                && path.is_file()
+                && (sidecar_path(&path).is_file() || name.ends_with(".h264"));
+            if !is_segment {
+                continue;
+            }
+
+            match index_segment(&path) {
+                Ok(Some(segment)) => segments.push(segment),
+                Ok(None) => {}
+                Err(err) => warn!("Unable to index segment {:?}: {:?}", path, err),
+            }
+        }
+
+        segments.sort_by_key(|segment| segment.keyframes[0]);
+
+        Ok(Self { segments })
+    }
+
+    /// The recorded time range, from the first key frame to the end of the newest segment.
+    pub fn range(&self) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        let first = self.segments.first()?.keyframes[0].0;
+        let last = self.segments.iter().map(|segment| segment.ended_at).max()?;
+
+        Some((first, last))
+    }
+
+    /// The last key frame captured at or before `at`. Returns None if `at` is before the recordings, after them or in
+    /// a gap between segments.
+    pub fn locate(&self, at: DateTime<Utc>) -> Option<SeekPoint> {
+        let (segment, keyframe) = self.keyframe_at(at)?;
+        let segment = &self.segments[segment];
+        let (keyframe_ts, byte_offset) = segment.keyframes[keyframe];
+
+        Some(SeekPoint {
+            path: segment.path.clone(),
+            byte_offset,
+            keyframe_ts,
+        })
+    }
+
+    /// Writes the video from `from` to `to` as a single Annex-B stream that plays on its own: it starts at the key frame
+    /// preceding `from` (or the first key frame after it if `from` is not recorded) and ends before the first key frame
+    /// after `to`, spanning as many segments as necessary. Returns the number of bytes written, which is 0 if nothing
+    /// was recorded in the range.
+    pub fn extract_clip(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        writer: &mut impl Write,
+    ) -> io::Result<u64> {
+        let Some((first_segment, keyframe)) =
+            self.keyframe_at(from).or_else(|| self.keyframe_after(from))
+        else {
+            return Ok(0);
+        };
+        let (keyframe_ts, mut offset) = self.segments[first_segment].keyframes[keyframe];
+        if keyframe_ts > to {
+            return Ok(0);
+        }
+
+        let mut written = 0;
+        for segment in &self.segments[first_segment..] {
+            if segment.keyframes[0].0 > to {
+                break;
+            }
+
+            // Ends at the first key frame after `to`
+            let end = segment
+                .keyframes
+                .iter()
+                .find(|&&(timestamp, byte_offset)| timestamp > to && byte_offset > offset)
+                .map(|&(_, byte_offset)| byte_offset);
+
+            let mut file = File::open(&segment.path)?;
+            file.seek(SeekFrom::Start(offset))?;
+            let len = end.unwrap_or(segment.len).saturating_sub(offset);
+            written += io::copy(&mut file.take(len), writer)?;
+
+            if end.is_some() {
+                break;
+            }
+            offset = 0;
+        }
+
+        Ok(written)
+    }
+
+    /// The indices of the segment and key frame captured at or before `at`, if `at` is within the segment.
+    fn keyframe_at(&self, at: DateTime<Utc>) -> Option<(usize, usize)> {
+        let index = self
+            .segments
+            .iter()
+            .rposition(|segment| segment.keyframes[0].0 <= at)?;
+        let segment = &self.segments[index];
+        let keyframe = segment
+            .keyframes
+            .partition_point(|&(timestamp, _)| timestamp <= at)
+            - 1;
+
+        (at <= segment.ended_at).then_some((index, keyframe))
+    }
+
+    /// The indices of the segment and key frame of the first key frame captured after `at`.
+    fn keyframe_after(&self, at: DateTime<Utc>) -> Option<(usize, usize)> {
+        self.segments
+            .iter()
+            .enumerate()
+            .find_map(|(index, segment)| {
+                let keyframe = segment
+                    .keyframes
+                    .iter()
+                    .position(|&(timestamp, _)| timestamp > at)?;
+                Some((index, keyframe))
+            })
+    }
+}
+
+/// Indexes a segment from its sidecar, falling back to scanning it. Returns None for segments without key frames.
+fn index_segment(path: &Path) -> io::Result<Option<IndexedSegment>> {
+    let metadata = std::fs::metadata(path)?;
+    let len = metadata.len();
+    let ended_at = metadata
+        .modified()
+        .map_or_else(|_| Utc::now(), DateTime::from);
+
+    let keyframes = match read_sidecar(&sidecar_path(path), len) {
+        Some(keyframes) => keyframes,
+        None => {
+            debug!("Indexing {:?} by scanning it", path);
+            scan_keyframes(path, ended_at)?
+        }
+    };
+
+    let Some(&(last_keyframe, _)) = keyframes.last() else {
+        return Ok(None);
+    };
+
+    Ok(Some(IndexedSegment {
+        path: path.to_path_buf(),
+        len,
+        // The system clock may have been stepped (eg. by NTP on a device without an RTC) since the frames were captured
+        ended_at: ended_at.max(last_keyframe),
+        keyframes,
+    }))
+}
+
+/// Reads a sidecar, returning None if it is missing, of another version or corrupt. Entries past the end of the segment
+/// (whose frames had not been written to disk yet) are left out.
+fn read_sidecar(path: &Path, segment_len: u64) -> Option<Vec<(DateTime<Utc>, u64)>> {
+    let sidecar = std::fs::read_to_string(path).ok()?;
+    let entries = sidecar.strip_prefix(SIDECAR_HEADER)?;
+
+    let mut keyframes: Vec<(DateTime<Utc>, u64)> = Vec::new();
+    // A line without its newline was being written when the recorder stopped
+    for line in entries
+        .split_inclusive('\n')
+        .filter(|line| line.ends_with('\n'))
+    {
+        let (timestamp, byte_offset) = line.trim_end().split_once(' ')?;
+        let micros: i64 = timestamp.parse().ok()?;
+        let timestamp = Utc
+            .timestamp_opt(
+                micros.div_euclid(1_000_000),
+                micros.rem_euclid(1_000_000) as u32 * 1000,
+            )
+            .single()?;
+        let byte_offset: u64 = byte_offset.parse().ok()?;
+
+        if keyframes
+            .last()
+            .is_some_and(|&(_, previous)| byte_offset <= previous)
+        {
+            return None;
+        }
+        if byte_offset < segment_len {
+            keyframes.push((timestamp, byte_offset));
+        }
+    }
+
+    Some(keyframes)
+}
+
+/// Finds the key frames in a segment, estimating their times by spreading its frames evenly between the start time in
+/// its name (or its modification time if the name has none) and its modification time.
+fn scan_keyframes(path: &Path, ended_at: DateTime<Utc>) -> io::Result<Vec<(DateTime<Utc>, u64)>> {
+    let data = std::fs::read(path)?;
+    let started_at = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(parse_timestamp)
+        .filter(|&started_at| started_at <= ended_at)
+        .unwrap_or(ended_at);
+
+    let mut frames = 0;
+    let mut keyframes = Vec::new();
+    // The start of the non-VCL NAL units (eg. parameter sets) preceding the next frame
+    let mut prefix_start = None;
+
+    for nal in NalUnits::new(&data) {
+        let mut start = nal.as_ptr() as usize - data.as_ptr() as usize - 3;
+        if start > 0 && data[start - 1] == 0 {
+            start -= 1;
+        }
+
+        match NalType::of(nal) {
+            nal_type @ (NalType::Idr | NalType::NonIdr) => {
+                // A first_mb_in_slice of 0 (a single 1 bit) starts a new frame
+                if nal.get(1).is_some_and(|byte| byte & 0x80 != 0) {
+                    if nal_type == NalType::Idr {
+                        keyframes.push((frames, prefix_start.unwrap_or(start) as u64));
+                    }
+                    frames += 1;
+                }
+                prefix_start = None;
+            }
+            _ => {
+                prefix_start.get_or_insert(start);
+            }
+        }
+    }
+
+    let duration = (ended_at - started_at).num_microseconds().unwrap_or(0) as f64;
+    Ok(keyframes
+        .into_iter()
+        .map(|(frame, byte_offset)| {
+            let offset = duration * frame as f64 / frames.max(1) as f64;
+            (
+                started_at + chrono::Duration::microseconds(offset as i64),
+                byte_offset,
+            )
+        })
+        .collect())
+}
+
+/// Finds a `{timestamp}` (eg. `20240131T235959Z`) in a segment's file name.
+fn parse_timestamp(name: &str) -> Option<DateTime<Utc>> {
+    (0..name.len().saturating_sub(15))
+        .filter_map(|start| name.get(start..start + 16))
+        .find_map(|value| NaiveDateTime::parse_from_str(value, TIMESTAMP_FORMAT).ok())
+        .map(|timestamp| Utc.from_utc_datetime(&timestamp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads `contents` as the sidecar of a segment of `segment_len` bytes.
+    fn read(name: &str, contents: &str, segment_len: u64) -> Option<Vec<(DateTime<Utc>, u64)>> {
+        let path =
+            std::env::temp_dir().join(format!("sidecar-{}-{}.idx", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        let keyframes = read_sidecar(&path, segment_len);
+        std::fs::remove_file(&path).unwrap();
+        keyframes
+    }
+
+    fn at(micros: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(micros / 1_000_000, (micros % 1_000_000) as u32 * 1000)
+            .unwrap()
+    }
+
+    #[test]
+    fn reads_sidecar_entries() {
+        let sidecar = format!(
+            "{SIDECAR_HEADER}{}{}",
+            sidecar_entry(at(1_706_745_599_000_000), 0),
+            sidecar_entry(at(1_706_745_600_500_000), 4096)
+        );
+        assert_eq!(
+            read("entries", &sidecar, 8192),
+            Some(vec![
+                (at(1_706_745_599_000_000), 0),
+                (at(1_706_745_600_500_000), 4096)
+            ])
+        );
+    }
+
+    #[test]
+    fn ignores_a_truncated_last_entry() {
+        let sidecar = format!("{SIDECAR_HEADER}1706745599000000 0\n1706745600");
+        assert_eq!(
+            read("truncated", &sidecar, 8192),
+            Some(vec![(at(1_706_745_599_000_000), 0)])
+        );
+        // Truncated within the header
+        assert_eq!(
+            read("truncated-header", "h264_webcam_stream in", 8192),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let sidecar = "h264_webcam_stream index 2\n1706745599000000 0\n";
+        assert_eq!(read("version", sidecar, 8192), None);
+        assert_eq!(read("empty", "", 8192), None);
+    }
+
+    #[test]
+    fn rejects_corrupt_entries() {
+        for (name, entries) in [
+            ("garbage", "1706745599000000 zero\n"),
+            ("missing-offset", "1706745599000000\n"),
+            ("unordered", "1706745599000000 4096\n1706745600500000 0\n"),
+        ] {
+            assert_eq!(
+                read(name, &format!("{SIDECAR_HEADER}{entries}"), 8192),
+                None,
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn leaves_out_entries_past_the_end_of_the_segment() {
+        let sidecar = format!(
+            "{SIDECAR_HEADER}1706745599000000 0\n1706745600500000 4096\n1706745602000000 9000\n"
+        );
+        assert_eq!(
+            read("past-end", &sidecar, 4096),
+            Some(vec![(at(1_706_745_599_000_000), 0)])
+        );
+        // A sidecar whose frames were never written to the segment has no key frames
+        assert_eq!(read("empty-segment", &sidecar, 0), Some(Vec::new()));
+    }
+}
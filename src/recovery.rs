@@ -0,0 +1,160 @@
+use crate::nal::{self, ParameterSets};
+use crate::StreamError;
+use openh264::decoder::{DecodedYUV, Decoder};
+use std::borrow::Cow;
+use std::panic::{self, AssertUnwindSafe};
+
+/// Recovers a decoder of camera-provided (or recorded) H264 from corrupt input.
+///
+/// openh264 can be left in a broken state by a malformed bitstream, eg. from a flaky USB cable, and its wrapper can
+/// panic on some of them. Errors and panics are returned as `StreamError::DecodeFailed`, after which frames are skipped
+/// until the next key frame, which is decoded by a new decoder with the cached SPS and PPS.
+#[derive(Debug, Default)]
+pub(crate) struct DecoderRecovery {
+    resyncing: bool,
+    // The sequence number of the last frame the decoder was in sync after, see `continues_from`
+    last_decoded: Option<u32>,
+}
+
+impl DecoderRecovery {
+    /// Decodes an access unit. `parameter_sets` must already include the access unit's parameter sets.
+    pub(crate) fn decode<'d>(
+        &mut self,
+        decoder: &'d mut Decoder,
+        access_unit: &[u8],
+        parameter_sets: &ParameterSets,
+    ) -> Result<Option<DecodedYUV<'d>>, StreamError> {
+        let mut access_unit = Cow::Borrowed(access_unit);
+
+        if self.resyncing {
+            // P frames can't be decoded without the reference frames that were lost
+            if !nal::is_keyframe(&access_unit) {
+                return Ok(None);
+            }
+
+            *decoder = Decoder::new()?;
+            self.resyncing = false;
+            parameter_sets.prepend_if_missing(access_unit.to_mut(), 0);
+        }
+
+        // The decoder is moved into the closure so that the decoded frame can borrow it
+        let result = panic::catch_unwind(AssertUnwindSafe(move || {
+            let decoder = decoder;
+            decoder.decode(&access_unit)
+        }));
+
+        let reason = match result {
+            Ok(Ok(yuv)) => return Ok(yuv),
+            Ok(Err(err)) => err.to_string(),
+            Err(panic) => match panic.downcast_ref::<&str>() {
+                Some(message) => format!("The decoder panicked: {}", message),
+                None => match panic.downcast_ref::<String>() {
+                    Some(message) => format!("The decoder panicked: {}", message),
+                    None => "The decoder panicked".to_string(),
+                },
+            },
+        };
+
+        self.resyncing = true;
+        Err(StreamError::DecodeFailed { reason })
+    }
+
+    /// Records that the camera frame with this sequence number was passed to `decode`.
+    pub(crate) fn decoded(&mut self, sequence: u32) {
+        self.last_decoded = (!self.resyncing).then_some(sequence);
+    }
+
+    /// True if the frame with this sequence number directly follows the last frame that was decoded in sync, ie. the
+    /// decoder has the reference frames to decode it.
+    pub(crate) fn continues_from(&self, sequence: u32) -> bool {
+        self.last_decoded.map(|last| last.wrapping_add(1)) == Some(sequence)
+    }
+
+    /// Skips frames until the next key frame, which is decoded by a new decoder.
+    pub(crate) fn resync(&mut self) {
+        self.resyncing = true;
+        self.last_decoded = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nal::{NalType, NalUnits};
+    use crate::synthetic::tests::access_units;
+    use crate::{SyntheticSource, YUVFrame};
+
+    /// A slice NAL unit of xorshift noise, which openh264 fails to decode.
+    fn garbage() -> Vec<u8> {
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut nal = vec![0, 0, 0, 1, 0x41];
+        for _ in 0..200 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            nal.push(state as u8);
+        }
+        nal
+    }
+
+    /// Decodes the access units, returning the frame number decoded from each (or None) or the error.
+    fn decode_all(access_units: Vec<Vec<u8>>) -> Vec<Result<Option<u32>, StreamError>> {
+        let mut decoder = Decoder::new().unwrap();
+        let mut recovery = DecoderRecovery::default();
+        let mut parameter_sets = ParameterSets::default();
+
+        access_units
+            .iter()
+            .map(|access_unit| {
+                parameter_sets.update(access_unit);
+                let yuv = recovery.decode(&mut decoder, access_unit, &parameter_sets)?;
+                Ok(yuv.map(|yuv| SyntheticSource::read_frame_counter(&YUVFrame::Decoded(yuv))))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn resumes_at_the_next_key_frame_after_garbage() {
+        let mut access_units = access_units(64, 64, 25, 10);
+        access_units[5].splice(0..0, garbage());
+
+        let results = decode_all(access_units);
+
+        for (frame, result) in results.iter().enumerate() {
+            match frame {
+                5 => assert!(
+                    matches!(result, Err(StreamError::DecodeFailed { .. })),
+                    "Frame 5 fails to decode: {result:?}"
+                ),
+                // The P frames after it are skipped
+                6..=9 => assert!(matches!(result, Ok(None)), "Frame {frame} is skipped"),
+                _ => assert_eq!(
+                    result.as_ref().ok(),
+                    Some(&Some(frame as u32)),
+                    "Frame {frame} decodes"
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn resyncs_with_the_cached_parameter_sets() {
+        let mut access_units = access_units(64, 64, 12, 10);
+        access_units[5].splice(0..0, garbage());
+        // Some cameras send the parameter sets in a buffer of their own, so the key frame is decoded with the cached
+        // ones
+        let idr_start = NalUnits::new(&access_units[10])
+            .find(|nal| NalType::of(nal) == NalType::Idr)
+            .map(|nal| nal.as_ptr() as usize - access_units[10].as_ptr() as usize - 4)
+            .unwrap();
+        let idr = access_units[10].split_off(idr_start);
+        access_units.insert(11, idr);
+
+        let results = decode_all(access_units);
+
+        assert!(matches!(results[5], Err(StreamError::DecodeFailed { .. })));
+        assert!(matches!(results[10], Ok(None)));
+        assert_eq!(results[11].as_ref().ok(), Some(&Some(10)));
+        assert_eq!(results[12].as_ref().ok(), Some(&Some(11)));
+    }
+}
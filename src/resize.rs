@@ -0,0 +1,291 @@
+use crate::orientation::{self, Transform};
+use crate::yuv::YUVSource;
+use crate::{Rect, StreamError, YUVBuffer};
+
+/// How pixels are resampled when a frame is scaled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ScaleFilter {
+    /// Picks the closest source pixel. Fast but blocky, especially when upscaling.
+    Nearest,
+    /// Interpolates between the 4 closest source pixels (the default).
+    #[default]
+    Bilinear,
+}
+
+/// The fractional bits of the fixed point sample positions used for bilinear scaling.
+const FRACTION_BITS: u32 = 8;
+pub(crate) const FRACTION_ONE: u32 = 1 << FRACTION_BITS;
+
+/// Rounds the rectangle's position and size down to even values so that it lines up with the chroma planes, and
+/// checks that it is non-empty and inside the frame.
+pub(crate) fn validate_crop(rect: Rect, frame: (u32, u32)) -> Result<Rect, StreamError> {
+    let even = Rect {
+        x: rect.x & !1,
+        y: rect.y & !1,
+        width: rect.width & !1,
+        height: rect.height & !1,
+    };
+
+    let fits = |start: u32, length: u32, frame_length: u32| {
+        length > 0 && start as u64 + length as u64 <= frame_length as u64
+    };
+
+    if !fits(even.x, even.width, frame.0) || !fits(even.y, even.height, frame.1) {
+        return Err(StreamError::CropOutOfBounds { rect, frame });
+    }
+
+    Ok(even)
+}
+
+/// Checks that a scaled frame's size is non-zero and even (as required by 4:2:0 chroma subsampling).
+pub(crate) fn validate_size(width: u32, height: u32) -> Result<(), StreamError> {
+    if width == 0 || height == 0 || !width.is_multiple_of(2) || !height.is_multiple_of(2) {
+        return Err(StreamError::InvalidFrameSize(width, height));
+    }
+
+    Ok(())
+}
+
+/// Copies a rectangle out of the source, which must already be validated with [`validate_crop`].
+pub(crate) fn crop<T: YUVSource + ?Sized>(source: &T, rect: Rect) -> YUVBuffer {
+    YUVBuffer::from_source(&Cropped { source, rect })
+}
+
+/// Scales the source to the size of `out`.
+pub(crate) fn scale_into<T: YUVSource + ?Sized>(
+    source: &T,
+    out: &mut YUVBuffer,
+    filter: ScaleFilter,
+) {
+    let (src_width, src_height) = (source.width() as usize, source.height() as usize);
+    let (dst_width, dst_height) = (out.width() as usize, out.height() as usize);
+
+    scale_plane(
+        source.y(),
+        source.y_stride() as usize,
+        (src_width, src_height),
+        out.y_mut(),
+        (dst_width, dst_height),
+        filter,
+    );
+    scale_plane(
+        source.u(),
+        source.u_stride() as usize,
+        (src_width / 2, src_height / 2),
+        out.u_mut(),
+        (dst_width / 2, dst_height / 2),
+        filter,
+    );
+    scale_plane(
+        source.v(),
+        source.v_stride() as usize,
+        (src_width / 2, src_height / 2),
+        out.v_mut(),
+        (dst_width / 2, dst_height / 2),
+        filter,
+    );
+}
+
+/// Scales a single tightly packed `dst` plane from a (possibly padded) `src` plane.
+fn scale_plane(
+    src: &[u8],
+    src_stride: usize,
+    (src_width, src_height): (usize, usize),
+    dst: &mut [u8],
+    (dst_width, dst_height): (usize, usize),
+    filter: ScaleFilter,
+) {
+    if dst_width == 0 || dst_height == 0 {
+        return;
+    }
+
+    let columns = sample_positions(src_width, dst_width);
+    let rows = sample_positions(src_height, dst_height);
+
+    for (dst_row, &(y0, y1, fy)) in dst.chunks_exact_mut(dst_width).zip(&rows) {
+        let (top, bottom) = (&src[y0 * src_stride..], &src[y1 * src_stride..]);
+
+        match filter {
+            ScaleFilter::Nearest => {
+                // Round to whichever of the two neighbouring samples is closer
+                let top = if fy >= FRACTION_ONE / 2 { bottom } else { top };
+
+                for (dst, &(x0, x1, fx)) in dst_row.iter_mut().zip(&columns) {
+                    *dst = top[if fx >= FRACTION_ONE / 2 { x1 } else { x0 }];
+                }
+            }
+            ScaleFilter::Bilinear => {
+                for (dst, &(x0, x1, fx)) in dst_row.iter_mut().zip(&columns) {
+                    let lerp =
+                        |row: &[u8]| row[x0] as u32 * (FRACTION_ONE - fx) + row[x1] as u32 * fx;
+                    let value = lerp(top) * (FRACTION_ONE - fy) + lerp(bottom) * fy;
+
+                    *dst = ((value + (1 << (2 * FRACTION_BITS - 1))) >> (2 * FRACTION_BITS)) as u8;
+                }
+            }
+        }
+    }
+}
+
+/// For each destination sample, the two closest source samples and the fixed point weight of the second. The centres
+/// of the source and destination samples are aligned so that the image does not shift when scaled.
+pub(crate) fn sample_positions(src_length: usize, dst_length: usize) -> Vec<(usize, usize, u32)> {
+    let last = src_length.saturating_sub(1);
+
+    (0..dst_length)
+        .map(|i| {
+            let position =
+                ((2 * i + 1) * src_length) as u64 * FRACTION_ONE as u64 / (2 * dst_length) as u64;
+            let position = position.saturating_sub(FRACTION_ONE as u64 / 2);
+
+            let first = ((position >> FRACTION_BITS) as usize).min(last);
+            let weight = (position & (FRACTION_ONE as u64 - 1)) as u32;
+
+            (first, (first + 1).min(last), weight)
+        })
+        .collect()
+}
+
+/// A rectangle within a YUV source that borrows the source's planes and strides rather than copying them.
+struct Cropped<'a, T: ?Sized> {
+    source: &'a T,
+    rect: Rect,
+}
+
+impl<T: YUVSource + ?Sized> YUVSource for Cropped<'_, T> {
+    fn width(&self) -> i32 {
+        self.rect.width as i32
+    }
+
+    fn height(&self) -> i32 {
+        self.rect.height as i32
+    }
+
+    fn y(&self) -> &[u8] {
+        let offset = self.rect.y as usize * self.y_stride() as usize + self.rect.x as usize;
+        &self.source.y()[offset..]
+    }
+
+    fn u(&self) -> &[u8] {
+        let offset =
+            (self.rect.y / 2) as usize * self.u_stride() as usize + (self.rect.x / 2) as usize;
+        &self.source.u()[offset..]
+    }
+
+    fn v(&self) -> &[u8] {
+        let offset =
+            (self.rect.y / 2) as usize * self.v_stride() as usize + (self.rect.x / 2) as usize;
+        &self.source.v()[offset..]
+    }
+
+    fn y_stride(&self) -> i32 {
+        self.source.y_stride()
+    }
+
+    fn u_stride(&self) -> i32 {
+        self.source.u_stride()
+    }
+
+    fn v_stride(&self) -> i32 {
+        self.source.v_stride()
+    }
+}
+
+/// The rotation, flip, crop and scale applied to transcoded frames before they are encoded, in that order.
+pub(crate) struct FrameTransform {
+    orientation: Option<Transform>,
+    // The rotated or flipped frame, if it is then cropped or scaled
+    oriented: YUVBuffer,
+    crop: Option<Rect>,
+    filter: Option<ScaleFilter>,
+    output: YUVBuffer,
+}
+
+impl FrameTransform {
+    /// Validates the crop and scale against the camera's resolution (after rotation). Returns `None` if there is nothing
+    /// to do.
+    pub(crate) fn new(
+        frame: (u32, u32),
+        orientation: Option<Transform>,
+        crop: Option<Rect>,
+        scale: Option<(u32, u32, ScaleFilter)>,
+    ) -> Result<Option<Self>, StreamError> {
+        if orientation.is_none() && crop.is_none() && scale.is_none() {
+            return Ok(None);
+        }
+
+        let frame = match orientation {
+            Some(orientation) => orientation.output_size(frame.0, frame.1),
+            None => frame,
+        };
+
+        let crop = crop.map(|rect| validate_crop(rect, frame)).transpose()?;
+
+        let (width, height) = match (scale, crop) {
+            (Some((width, height, _)), _) => {
+                validate_size(width, height)?;
+                (width, height)
+            }
+            (None, Some(crop)) => (crop.width, crop.height),
+            (None, None) => frame,
+        };
+
+        let oriented = if orientation.is_some() && (crop.is_some() || scale.is_some()) {
+            YUVBuffer::new(frame.0 as usize, frame.1 as usize)
+        } else {
+            YUVBuffer::new(0, 0)
+        };
+
+        Ok(Some(Self {
+            orientation,
+            oriented,
+            crop,
+            filter: scale.map(|(_, _, filter)| filter),
+            output: YUVBuffer::new(width as usize, height as usize),
+        }))
+    }
+
+    /// The size of the transformed frames.
+    pub(crate) fn size(&self) -> (u32, u32) {
+        (self.output.width() as u32, self.output.height() as u32)
+    }
+
+    /// Transforms the captured frame, returning the transformed frame.
+    pub(crate) fn apply(&mut self, source: &YUVBuffer) -> &mut YUVBuffer {
+        let resize = self.crop.is_some() || self.filter.is_some();
+
+        match self.orientation {
+            Some(orientation) if resize => {
+                orientation::transform_into(source, &mut self.oriented, orientation);
+                resize_into(&self.oriented, self.crop, self.filter, &mut self.output);
+            }
+            Some(orientation) => orientation::transform_into(source, &mut self.output, orientation),
+            None => resize_into(source, self.crop, self.filter, &mut self.output),
+        }
+
+        &mut self.output
+    }
+}
+
+/// Crops (if `crop` is set) and then scales (if `filter` is set) the source into `out`.
+fn resize_into(
+    source: &YUVBuffer,
+    crop: Option<Rect>,
+    filter: Option<ScaleFilter>,
+    out: &mut YUVBuffer,
+) {
+    let cropped = Cropped {
+        source,
+        rect: crop.unwrap_or(Rect {
+            x: 0,
+            y: 0,
+            width: source.width() as u32,
+            height: source.height() as u32,
+        }),
+    };
+
+    match filter {
+        Some(filter) => scale_into(&cropped, out, filter),
+        None => out.copy_from(&cropped),
+    }
+}
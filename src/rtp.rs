@@ -0,0 +1,307 @@
+//! Packetization of the H264 stream into RTP packets (RFC 6184), eg. for WebRTC or SIP stacks.
+
+use crate::nal::{NalType, NalUnits};
+use crate::FrameMeta;
+
+const RTP_HEADER_LEN: usize = 12;
+const STAP_A: u8 = 24;
+const FU_A: u8 = 28;
+/// The clock rate of H264 RTP timestamps.
+pub const CLOCK_RATE: u32 = 90_000;
+
+/// An RTP packet carrying H264 payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RtpPacket {
+    pub payload_type: u8,
+    pub sequence_number: u16,
+    pub timestamp: u32,
+    pub ssrc: u32,
+    /// Set on the last packet of an access unit.
+    pub marker: bool,
+    pub payload: Vec<u8>,
+}
+
+impl RtpPacket {
+    /// Serializes the packet with a 12 byte RTP header.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(RTP_HEADER_LEN + self.payload.len());
+
+        // Version 2 with no padding, extension or CSRCs
+        bytes.push(0x80);
+        bytes.push(((self.marker as u8) << 7) | (self.payload_type & 0x7f));
+        bytes.extend_from_slice(&self.sequence_number.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&self.ssrc.to_be_bytes());
+        bytes.extend_from_slice(&self.payload);
+
+        bytes
+    }
+}
+
+/// Splits H264 access units from [`WebcamH264Stream::next`](crate::WebcamH264Stream::next) into RTP packets in
+/// non-interleaved mode (`packetization-mode=1`).
+///
+/// Consecutive NAL units that fit in a single packet (eg. the SPS and PPS) are aggregated into STAP-A packets and NAL
+/// units that are larger than the MTU are fragmented into FU-A packets. Access unit delimiters and filler data are
+/// dropped.
+pub struct H264Packetizer {
+    ssrc: u32,
+    payload_type: u8,
+    mtu: usize,
+    sequence_number: u16,
+}
+
+impl H264Packetizer {
+    /// Creates a packetizer whose packets (including the RTP header) are at most `mtu` bytes.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the MTU is too small to fit the RTP header and a FU-A fragment.
+    pub fn new(ssrc: u32, payload_type: u8, mtu: usize) -> Self {
+        assert!(
+            mtu > RTP_HEADER_LEN + 2,
+            "The MTU must be larger than {} bytes",
+            RTP_HEADER_LEN + 2
+        );
+
+        Self {
+            ssrc,
+            payload_type,
+            mtu,
+            sequence_number: 0,
+        }
+    }
+
+    /// Sets the sequence number of the next packet, which should be random for a new stream (defaults to 0).
+    pub fn sequence_number(mut self, sequence_number: u16) -> Self {
+        self.sequence_number = sequence_number;
+        self
+    }
+
+    /// Packetizes an access unit with a 90kHz timestamp.
+    pub fn packetize(&mut self, h264_bytes: &[u8], timestamp: u32) -> Vec<RtpPacket> {
+        let nals = NalUnits::new(h264_bytes)
+            .filter(|nal| !matches!(NalType::of(nal), NalType::Aud | NalType::Filler))
+            .collect::<Vec<_>>();
+
+        let max_payload = self.mtu - RTP_HEADER_LEN;
+        let mut packets = Vec::new();
+        let mut i = 0;
+
+        while i < nals.len() {
+            // Aggregate as many of the following NAL units as fit, each prefixed with its 16 bit size
+            let mut aggregate_len = 1;
+            let mut end = i;
+            while end < nals.len() && aggregate_len + 2 + nals[end].len() <= max_payload {
+                aggregate_len += 2 + nals[end].len();
+                end += 1;
+            }
+
+            if end - i >= 2 {
+                let nals = &nals[i..end];
+
+                // The forbidden bit is set if it is set for any NAL unit and the NRI is the highest of the NAL units
+                let forbidden = nals.iter().fold(0, |f, nal| f | (nal[0] & 0x80));
+                let nri = nals.iter().map(|nal| nal[0] & 0x60).max().unwrap_or(0);
+
+                let mut payload = Vec::with_capacity(aggregate_len);
+                payload.push(forbidden | nri | STAP_A);
+                for nal in nals {
+                    payload.extend_from_slice(&(nal.len() as u16).to_be_bytes());
+                    payload.extend_from_slice(nal);
+                }
+
+                packets.push(self.packet(payload, timestamp));
+                i = end;
+                continue;
+            }
+
+            let nal = nals[i];
+
+            if nal.len() <= max_payload {
+                packets.push(self.packet(nal.to_vec(), timestamp));
+            } else {
+                let indicator = (nal[0] & 0xe0) | FU_A;
+                let nal_type = nal[0] & 0x1f;
+
+                // The NAL header is replaced by the FU indicator and header
+                let mut fragments = nal[1..].chunks(max_payload - 2).peekable();
+                let mut start = true;
+
+                while let Some(fragment) = fragments.next() {
+                    let end = fragments.peek().is_none();
+                    let header = ((start as u8) << 7) | ((end as u8) << 6) | nal_type;
+
+                    let mut payload = Vec::with_capacity(fragment.len() + 2);
+                    payload.extend_from_slice(&[indicator, header]);
+                    payload.extend_from_slice(fragment);
+
+                    packets.push(self.packet(payload, timestamp));
+                    start = false;
+                }
+            }
+
+            i += 1;
+        }
+
+        if let Some(last) = packets.last_mut() {
+            last.marker = true;
+        }
+
+        packets
+    }
+
+    /// Packetizes an access unit, deriving the RTP timestamp from the frame's capture timestamp.
+    pub fn packetize_with_meta(&mut self, h264_bytes: &[u8], meta: &FrameMeta) -> Vec<RtpPacket> {
+        // RTP timestamps wrap around at 32 bits
+        let timestamp = (meta.timestamp.as_secs_f64() * CLOCK_RATE as f64) as u64 as u32;
+
+        self.packetize(h264_bytes, timestamp)
+    }
+
+    fn packet(&mut self, payload: Vec<u8>, timestamp: u32) -> RtpPacket {
+        let sequence_number = self.sequence_number;
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+
+        RtpPacket {
+            payload_type: self.payload_type,
+            sequence_number,
+            timestamp,
+            ssrc: self.ssrc,
+            marker: false,
+            payload,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MTU: usize = 200;
+
+    /// Reassembles the NAL units of an access unit's packets, checking that they are in sequence and share a
+    /// timestamp, and that only the last is marked.
+    fn depacketize(packets: &[RtpPacket]) -> Vec<Vec<u8>> {
+        let mut nals = Vec::new();
+        let mut fragmented: Option<Vec<u8>> = None;
+
+        for (i, packet) in packets.iter().enumerate() {
+            assert!(packet.to_bytes().len() <= MTU, "Packet {i} fits the MTU");
+            assert_eq!(packet.timestamp, packets[0].timestamp);
+            assert_eq!(
+                packet.sequence_number,
+                packets[0].sequence_number.wrapping_add(i as u16)
+            );
+            assert_eq!(packet.marker, i == packets.len() - 1);
+
+            let payload = &packet.payload;
+            match payload[0] & 0x1f {
+                STAP_A => {
+                    let mut rest = &payload[1..];
+                    while !rest.is_empty() {
+                        let len = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+                        nals.push(rest[2..2 + len].to_vec());
+                        rest = &rest[2 + len..];
+                    }
+                }
+                FU_A => {
+                    let (start, end) = (payload[1] & 0x80 != 0, payload[1] & 0x40 != 0);
+                    if start {
+                        assert!(fragmented.is_none(), "Fragments are not interleaved");
+                        fragmented = Some(vec![(payload[0] & 0xe0) | (payload[1] & 0x1f)]);
+                    }
+                    let nal = fragmented
+                        .as_mut()
+                        .expect("Fragments start with a start bit");
+                    nal.extend_from_slice(&payload[2..]);
+                    if end {
+                        nals.extend(fragmented.take());
+                    }
+                }
+                _ => nals.push(payload.clone()),
+            }
+        }
+
+        assert!(fragmented.is_none(), "The last fragment has the end bit");
+        nals
+    }
+
+    fn annex_b(nals: &[Vec<u8>]) -> Vec<u8> {
+        nals.iter()
+            .flat_map(|nal| [&[0, 0, 0, 1], nal.as_slice()].concat())
+            .collect()
+    }
+
+    /// A NAL unit of `len` bytes whose payload counts up so that misordered fragments are detected.
+    fn nal(header: u8, len: usize) -> Vec<u8> {
+        std::iter::once(header)
+            .chain((1..len).map(|i| (i % 251) as u8 + 1))
+            .collect()
+    }
+
+    #[test]
+    fn fragments_nal_units_larger_than_the_mtu() {
+        let nals = vec![nal(0x67, 12), nal(0x68, 4), nal(0x65, 1000)];
+        let mut packetizer = H264Packetizer::new(1, 96, MTU);
+
+        let packets = packetizer.packetize(&annex_b(&nals), 3000);
+
+        // The parameter sets are aggregated and the IDR slice is split into 6 fragments
+        assert_eq!(packets[0].payload[0] & 0x1f, STAP_A);
+        assert_eq!(
+            packets.len(),
+            1 + 1000usize.div_ceil(MTU - RTP_HEADER_LEN - 2)
+        );
+        assert!(packets[1..]
+            .iter()
+            .all(|packet| packet.payload[0] == (0x60 | FU_A)));
+        assert_eq!(depacketize(&packets), nals);
+    }
+
+    #[test]
+    fn aggregates_small_nal_units() {
+        // More small NAL units than fit in one STAP-A, followed by one that fits a packet of its own
+        let mut nals: Vec<Vec<u8>> = (0..10).map(|_| nal(0x06, 30)).collect();
+        nals.push(nal(0x41, MTU - RTP_HEADER_LEN));
+        let mut packetizer = H264Packetizer::new(1, 96, MTU).sequence_number(u16::MAX - 1);
+
+        let packets = packetizer.packetize(&annex_b(&nals), 0);
+
+        let types: Vec<u8> = packets
+            .iter()
+            .map(|packet| packet.payload[0] & 0x1f)
+            .collect();
+        assert_eq!(types, [STAP_A, STAP_A, 1]);
+        // The sequence numbers wrap around
+        assert_eq!(packets[2].sequence_number, 0);
+        assert_eq!(depacketize(&packets), nals);
+    }
+
+    #[test]
+    fn drops_delimiters_and_filler() {
+        let nals = vec![nal(0x09, 2), nal(0x65, 50), nal(0x0c, 20)];
+        let mut packetizer = H264Packetizer::new(1, 96, MTU);
+
+        let packets = packetizer.packetize(&annex_b(&nals), 0);
+
+        assert_eq!(depacketize(&packets), [nals[1].clone()]);
+    }
+
+    #[test]
+    fn serializes_the_rtp_header() {
+        let packet = RtpPacket {
+            payload_type: 96,
+            sequence_number: 0x1234,
+            timestamp: 0x5678_9abc,
+            ssrc: 0xdead_beef,
+            marker: true,
+            payload: vec![0x65],
+        };
+
+        assert_eq!(
+            packet.to_bytes(),
+            [0x80, 0xe0, 0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xad, 0xbe, 0xef, 0x65]
+        );
+    }
+}
@@ -0,0 +1,475 @@
+//! A minimal RTSP 1.0 server (RFC 2326) for players and NVR software, enabled with the `rtsp` feature.
+//!
+//! The stream is described with an SDP built from the cached parameter sets and sent as RTP (RFC 6184) interleaved
+//! on the RTSP connection, which works through firewalls and NAT. UDP transport is not supported, so clients have to
+//! request TCP (eg. VLC's "RTP over RTSP (TCP)" option, or `--rtsp-tcp` on the command line), which most NVRs fall back
+//! to automatically.
+
+use crate::descriptor::PAYLOAD_TYPE;
+use crate::rtp::H264Packetizer;
+use crate::{Broadcaster, StreamDescriptor};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+/// Interleaved packets can be up to 64KB, but smaller packets keep each write to the socket short.
+const MTU: usize = 1400;
+/// Clients that send no requests (including keep alives) for this long are disconnected.
+const SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long DESCRIBE waits for the first key frame's parameter sets.
+const DESCRIBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often idle threads check whether the server has been dropped.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+/// The number of access units queued for each session before it skips ahead to the next key frame.
+const QUEUE_LENGTH: usize = 30;
+const MAX_HEADER_LINES: usize = 100;
+
+/// Serves a [`Broadcaster`]'s stream over RTSP on background threads, eg. at `rtsp://host:8554/cam`.
+///
+/// Every path serves the same stream. Each session subscribes to the broadcaster, so any number of clients share the
+/// camera and start playing from the cached GOP's key frame. Sessions end on TEARDOWN, when the client disconnects
+/// or when it stops sending keep alives. The server stops when it is dropped.
+pub struct RtspServer {
+    shared: Arc<Shared>,
+    local_addr: SocketAddr,
+    accept_thread: Option<JoinHandle<()>>,
+}
+
+struct Shared {
+    broadcaster: Broadcaster,
+    fps: f64,
+    stop: AtomicBool,
+    // Each connection's socket, so that dropping the server can disconnect them
+    connections: Mutex<HashMap<u64, TcpStream>>,
+    next_connection: AtomicU64,
+    session_count: AtomicUsize,
+}
+
+struct Request {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+struct Session {
+    id: String,
+    // The interleaved channel for RTP. RTCP would use the next channel, but no reports are sent.
+    channel: u8,
+    playing: Option<(Arc<AtomicBool>, JoinHandle<()>)>,
+}
+
+impl Session {
+    fn stop(&mut self) {
+        if let Some((stop, thread)) = self.playing.take() {
+            stop.store(true, Ordering::Relaxed);
+            let _ = thread.join();
+        }
+    }
+}
+
+impl RtspServer {
+    /// Starts listening for RTSP connections on `addr`, eg. `"0.0.0.0:8554"`. `fps` is the stream's frame rate, which
+    /// is advertised in the SDP.
+    pub fn bind(addr: impl ToSocketAddrs, broadcaster: Broadcaster, fps: f64) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+
+        // The listener is polled so that the accept thread notices when the server is dropped
+        listener.set_nonblocking(true)?;
+
+        let shared = Arc::new(Shared {
+            broadcaster,
+            fps,
+            stop: AtomicBool::new(false),
+            connections: Mutex::new(HashMap::new()),
+            next_connection: AtomicU64::new(0),
+            session_count: AtomicUsize::new(0),
+        });
+
+        let accept_thread = {
+            let shared = Arc::clone(&shared);
+            std::thread::spawn(move || accept(listener, shared))
+        };
+
+        Ok(Self {
+            shared,
+            local_addr,
+            accept_thread: Some(accept_thread),
+        })
+    }
+
+    /// The address the server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The number of sessions that are currently playing.
+    pub fn session_count(&self) -> usize {
+        self.shared.session_count.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for RtspServer {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::Relaxed);
+
+        if let Some(thread) = self.accept_thread.take() {
+            let _ = thread.join();
+        }
+
+        for stream in self.shared.connections.lock().unwrap().values() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+fn accept(listener: TcpListener, shared: Arc<Shared>) {
+    while !shared.stop.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, addr)) => {
+                let shared = Arc::clone(&shared);
+                std::thread::spawn(move || {
+                    if let Err(err) = serve_connection(stream, &shared) {
+                        debug!("RTSP client {} error: {:?}", addr, err);
+                    }
+                });
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                std::thread::sleep(POLL_INTERVAL)
+            }
+            Err(err) => warn!("Failed to accept RTSP client: {:?}", err),
+        }
+    }
+}
+
+fn serve_connection(stream: TcpStream, shared: &Arc<Shared>) -> io::Result<()> {
+    // Accepted sockets may inherit the listener's non-blocking mode
+    stream.set_nonblocking(false)?;
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(SESSION_TIMEOUT))?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+
+    let id = shared.next_connection.fetch_add(1, Ordering::Relaxed);
+    shared
+        .connections
+        .lock()
+        .unwrap()
+        .insert(id, stream.try_clone()?);
+
+    // The writer is shared with the session's RTP thread so that responses and packets are not interleaved
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    let mut session = None;
+
+    let result = handle_requests(&stream, &writer, &mut session, shared);
+
+    // The session ends with the connection
+    if let Some(session) = &mut session {
+        session.stop();
+    }
+    shared.connections.lock().unwrap().remove(&id);
+
+    result
+}
+
+fn handle_requests(
+    stream: &TcpStream,
+    writer: &Arc<Mutex<TcpStream>>,
+    session: &mut Option<Session>,
+    shared: &Arc<Shared>,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(stream);
+
+    while let Some(request) = read_request(&mut reader)? {
+        let cseq = request.header("CSeq").unwrap_or("0").to_string();
+        let respond = |status: &str, headers: &[(&str, String)], body: &str| {
+            write_response(&mut writer.lock().unwrap(), &cseq, status, headers, body)
+        };
+
+        // Requests after SETUP must be for the client's session
+        let session_matches = match (request.header("Session"), &*session) {
+            (Some(id), Some(session)) => id.split(';').next().map(str::trim) == Some(&session.id),
+            _ => false,
+        };
+
+        match request.method.as_str() {
+            "OPTIONS" => respond(
+                "200 OK",
+                &[(
+                    "Public",
+                    "OPTIONS, DESCRIBE, SETUP, PLAY, TEARDOWN, GET_PARAMETER".to_string(),
+                )],
+                "",
+            )?,
+            "DESCRIBE" => match describe(stream, shared) {
+                Some(sdp) => {
+                    let content_base = if request.url.ends_with('/') {
+                        request.url.clone()
+                    } else {
+                        format!("{}/", request.url)
+                    };
+
+                    respond(
+                        "200 OK",
+                        &[
+                            ("Content-Base", content_base),
+                            ("Content-Type", "application/sdp".to_string()),
+                        ],
+                        &sdp,
+                    )?
+                }
+                None => respond("503 Service Unavailable", &[], "")?,
+            },
+            "SETUP" => {
+                let transport = request.header("Transport").unwrap_or_default();
+
+                if !transport.to_ascii_uppercase().contains("RTP/AVP/TCP") {
+                    respond("461 Unsupported Transport", &[], "")?;
+                    continue;
+                }
+
+                // Use the channels the client asked for, if any
+                let channel = transport
+                    .split(';')
+                    .find_map(|param| param.trim().strip_prefix("interleaved="))
+                    .and_then(|channels| channels.split('-').next()?.parse().ok())
+                    .unwrap_or(0);
+
+                let session = session.get_or_insert_with(|| Session {
+                    id: format!("{:016X}", random()),
+                    channel,
+                    playing: None,
+                });
+                session.channel = channel;
+
+                respond(
+                    "200 OK",
+                    &[
+                        (
+                            "Transport",
+                            format!(
+                                "RTP/AVP/TCP;unicast;interleaved={}-{}",
+                                channel,
+                                channel.wrapping_add(1)
+                            ),
+                        ),
+                        (
+                            "Session",
+                            format!("{};timeout={}", session.id, SESSION_TIMEOUT.as_secs()),
+                        ),
+                    ],
+                    "",
+                )?
+            }
+            "PLAY" => match session {
+                Some(session) if session_matches => {
+                    let sequence_number = random() as u16;
+                    respond(
+                        "200 OK",
+                        &[
+                            ("Session", session.id.clone()),
+                            ("Range", "npt=0.000-".to_string()),
+                            (
+                                "RTP-Info",
+                                format!("url={};seq={}", request.url, sequence_number),
+                            ),
+                        ],
+                        "",
+                    )?;
+
+                    // PLAY while already playing just keeps the session going
+                    if session.playing.is_none() {
+                        let packetizer = H264Packetizer::new(random() as u32, PAYLOAD_TYPE, MTU)
+                            .sequence_number(sequence_number);
+                        session.playing = Some(play(
+                            packetizer,
+                            session.channel,
+                            Arc::clone(writer),
+                            Arc::clone(shared),
+                        ));
+                    }
+                }
+                _ => respond("454 Session Not Found", &[], "")?,
+            },
+            "TEARDOWN" => {
+                if let Some(session) = session {
+                    session.stop();
+                }
+                respond("200 OK", &[], "")?;
+                return Ok(());
+            }
+            // Clients send these as keep alives
+            "GET_PARAMETER" | "SET_PARAMETER" => respond("200 OK", &[], "")?,
+            _ => respond("501 Not Implemented", &[], "")?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads the next request, skipping any interleaved packets (eg. RTCP receiver reports) sent by the client. Returns
+/// `None` when the client disconnects.
+fn read_request(reader: &mut BufReader<&TcpStream>) -> io::Result<Option<Request>> {
+    loop {
+        let Some(&first) = reader.fill_buf()?.first() else {
+            return Ok(None);
+        };
+
+        if first == b'$' {
+            let mut header = [0u8; 4];
+            reader.read_exact(&mut header)?;
+            let len = u16::from_be_bytes([header[2], header[3]]) as u64;
+            io::copy(&mut reader.take(len), &mut io::sink())?;
+            continue;
+        }
+
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line)? == 0 {
+            return Ok(None);
+        }
+        // Some clients send blank lines between requests
+        if request_line.trim().is_empty() {
+            continue;
+        }
+
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or_default().to_string();
+        let url = parts.next().unwrap_or_default().to_string();
+
+        let mut headers = Vec::new();
+        let mut header = String::new();
+        for _ in 0..MAX_HEADER_LINES {
+            header.clear();
+            if reader.read_line(&mut header)? <= 2 {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                headers.push((name.trim().to_string(), value.trim().to_string()));
+            }
+        }
+
+        let request = Request {
+            method,
+            url,
+            headers,
+        };
+
+        // Request bodies (eg. SET_PARAMETER's) are ignored
+        let content_length = request
+            .header("Content-Length")
+            .and_then(|len| len.parse().ok())
+            .unwrap_or(0);
+        io::copy(&mut reader.take(content_length), &mut io::sink())?;
+
+        return Ok(Some(request));
+    }
+}
+
+fn write_response(
+    writer: &mut TcpStream,
+    cseq: &str,
+    status: &str,
+    headers: &[(&str, String)],
+    body: &str,
+) -> io::Result<()> {
+    let mut response = format!("RTSP/1.0 {status}\r\nCSeq: {cseq}\r\n");
+    for (name, value) in headers {
+        response += &format!("{name}: {value}\r\n");
+    }
+    if !body.is_empty() {
+        response += &format!("Content-Length: {}\r\n", body.len());
+    }
+    response += "\r\n";
+    response += body;
+
+    writer.write_all(response.as_bytes())
+}
+
+/// Builds the SDP, waiting for the broadcaster's first key frame if necessary.
+fn describe(stream: &TcpStream, shared: &Shared) -> Option<String> {
+    let started = Instant::now();
+    let (sps, pps) = loop {
+        if let Some(parameter_sets) = shared.broadcaster.parameter_sets() {
+            break parameter_sets;
+        }
+        if started.elapsed() > DESCRIBE_TIMEOUT || shared.stop.load(Ordering::Relaxed) {
+            return None;
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    };
+
+    let descriptor = StreamDescriptor::new(&sps, &pps, shared.fps)?;
+    Some(descriptor.to_sdp(stream.local_addr().ok()?.ip()))
+}
+
+/// Starts sending the broadcaster's access units to the client as interleaved RTP packets.
+fn play(
+    mut packetizer: H264Packetizer,
+    channel: u8,
+    writer: Arc<Mutex<TcpStream>>,
+    shared: Arc<Shared>,
+) -> (Arc<AtomicBool>, JoinHandle<()>) {
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut subscriber = shared.broadcaster.subscribe(QUEUE_LENGTH);
+
+    let thread = {
+        let stop = Arc::clone(&stop);
+
+        std::thread::spawn(move || {
+            shared.session_count.fetch_add(1, Ordering::Relaxed);
+
+            while !stop.load(Ordering::Relaxed) && !shared.stop.load(Ordering::Relaxed) {
+                let (meta, h264_bytes) = match subscriber.recv_timeout(POLL_INTERVAL) {
+                    Ok(frame) => frame,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                };
+
+                // Each packet is framed with a '$', the channel and its length
+                let mut packets = Vec::new();
+                for packet in packetizer.packetize_with_meta(&h264_bytes, &meta) {
+                    let packet = packet.to_bytes();
+                    packets.push(b'$');
+                    packets.push(channel);
+                    packets.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+                    packets.extend_from_slice(&packet);
+                }
+
+                let mut writer = writer.lock().unwrap();
+                if let Err(err) = writer.write_all(&packets) {
+                    debug!("Failed to send RTP packets: {:?}", err);
+                    // Disconnects the client so that its connection thread ends too
+                    let _ = writer.shutdown(Shutdown::Both);
+                    break;
+                }
+            }
+
+            shared.session_count.fetch_sub(1, Ordering::Relaxed);
+        })
+    };
+
+    (stop, thread)
+}
+
+/// A random number for session IDs, SSRCs and sequence numbers, which only need to be unpredictable enough not to
+/// collide.
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
@@ -0,0 +1,524 @@
+use crate::recording_index::{sidecar_entry, sidecar_path, SIDECAR_HEADER};
+use crate::{nal, FrameMeta, StreamError};
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::warn;
+
+/// When a [`SegmentedRecorder`] starts a new file and how the files are named.
+#[derive(Debug, Clone)]
+pub struct SegmentPolicy {
+    /// Segments are rotated at the first key frame after they reach this duration.
+    pub max_duration: Duration,
+    /// Segments are rotated at the first key frame after they reach this size.
+    pub max_bytes: Option<u64>,
+    /// The segment's file name. `{timestamp}` is replaced with the UTC time the segment started (eg.
+    /// `20240131T235959Z`) and `{sequence}` with the number of the segment within the recording (eg. `000042`).
+    pub filename_template: String,
+}
+
+impl Default for SegmentPolicy {
+    /// 5 minute segments named `segment-{timestamp}-{sequence}.h264`.
+    fn default() -> Self {
+        Self {
+            max_duration: Duration::from_secs(5 * 60),
+            max_bytes: None,
+            filename_template: "segment-{timestamp}-{sequence}.h264".to_string(),
+        }
+    }
+}
+
+/// Statistics for a segment written by a [`SegmentedRecorder`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SegmentStats {
+    pub sequence: u64,
+    pub started_at: DateTime<Utc>,
+    pub frames: u64,
+    pub bytes: u64,
+    /// The time between the segment's first and last frames.
+    pub duration: Duration,
+}
+
+/// The format of the `{timestamp}` in segment file names.
+pub(crate) const TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+/// Limits on the footage a [`SegmentedRecorder`] keeps, see [`SegmentedRecorder::retention`]. Segments are deleted
+/// oldest first when either limit is exceeded.
+#[derive(Debug, Clone, Default)]
+pub struct RetentionPolicy {
+    /// The total size of the segments, including the one being written.
+    pub max_total_bytes: Option<u64>,
+    /// Segments are deleted once they ended longer ago than this.
+    pub max_age: Option<Duration>,
+}
+
+/// Reports the deletion of old segments by a [`SegmentedRecorder`]'s [`RetentionPolicy`].
+#[derive(Debug, Clone)]
+pub enum RetentionEvent {
+    /// The segment at `path` was deleted.
+    Deleted { path: PathBuf, bytes: u64 },
+    /// The segment at `path` could not be deleted (eg. because of its permissions). Recording continues and the
+    /// deletion is retried when the next segment starts.
+    DeleteFailed { path: PathBuf, error: String },
+}
+
+type OnSegmentComplete = Box<dyn FnMut(PathBuf, SegmentStats) + Send>;
+type OnRetentionEvent = Box<dyn FnMut(RetentionEvent) + Send>;
+
+/// Records a stream to a directory of H264 files, starting a new file every few minutes.
+///
+/// Segments only start at key frames so that each file can be played on its own, and frames preceding the first key
+/// frame are discarded. Completed segments are fsynced before the next one is opened so a crash loses at most the
+/// segment being written.
+///
+/// If the disk fills up the partial segment is closed, `StreamError::DiskFull` is returned and the recorder stops;
+/// later frames return `StreamError::RecorderStopped`. The same applies to any other write failure.
+///
+/// Segments already in the directory that match the filename template (eg. from before a restart) are found when the
+/// recorder is created, so that a [`RetentionPolicy`] also deletes them and numbering continues after them.
+pub struct SegmentedRecorder {
+    dir: PathBuf,
+    policy: SegmentPolicy,
+    retention: RetentionPolicy,
+    write_index: bool,
+    on_segment_complete: Option<OnSegmentComplete>,
+    on_retention_event: Option<OnRetentionEvent>,
+    segment: Option<Segment>,
+    /// The completed segments in the directory, oldest first.
+    retained: VecDeque<RetainedSegment>,
+    next_sequence: u64,
+    stopped: bool,
+}
+
+struct RetainedSegment {
+    path: PathBuf,
+    started_at: DateTime<Utc>,
+    ended_at: DateTime<Utc>,
+    bytes: u64,
+}
+
+struct Segment {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    /// The key frame index sidecar, or None if indexing is disabled or writing it failed.
+    index: Option<File>,
+    stats: SegmentStats,
+    first_timestamp: Duration,
+}
+
+impl SegmentedRecorder {
+    /// Creates a recorder that writes segments to `dir`, creating it if necessary.
+    pub fn new(dir: impl Into<PathBuf>, policy: SegmentPolicy) -> Result<Self, StreamError> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir).map_err(StreamError::WriteFailure)?;
+
+        let (retained, next_sequence) =
+            scan_segments(&dir, &policy.filename_template).map_err(StreamError::WriteFailure)?;
+
+        Ok(Self {
+            dir,
+            policy,
+            retention: RetentionPolicy::default(),
+            write_index: true,
+            on_segment_complete: None,
+            on_retention_event: None,
+            segment: None,
+            retained,
+            next_sequence,
+            stopped: false,
+        })
+    }
+
+    /// Deletes the oldest segments, including ones recorded before the recorder was created, when the policy's limits
+    /// are exceeded. The limits are enforced each time a segment starts. The segment being written is never deleted.
+    pub fn retention(mut self, retention: RetentionPolicy) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Whether to write an index sidecar (`.idx`) next to each segment listing its key frames' capture times and byte
+    /// offsets, for seeking with a [`RecordingIndex`](crate::RecordingIndex). Enabled by default.
+    pub fn write_index(mut self, write_index: bool) -> Self {
+        self.write_index = write_index;
+        self
+    }
+
+    /// Calls `callback` each time the retention policy deletes a segment or fails to.
+    pub fn on_retention_event(
+        mut self,
+        callback: impl FnMut(RetentionEvent) + Send + 'static,
+    ) -> Self {
+        self.on_retention_event = Some(Box::new(callback));
+        self
+    }
+
+    /// The start of the oldest segment in the directory and the end of the newest (or now, while a segment is being
+    /// written), eg. to show the recorded history in a UI. Returns None if there are no segments.
+    pub fn retained_range(&self) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        let current = self
+            .segment
+            .as_ref()
+            .map(|segment| segment.stats.started_at);
+        let start = self
+            .retained
+            .front()
+            .map(|segment| segment.started_at)
+            .or(current)?;
+        let end = match current {
+            Some(_) => Utc::now(),
+            None => self.retained.back()?.ended_at,
+        };
+
+        Some((start, end))
+    }
+
+    /// The total size of the segments in the directory, including the one being written.
+    pub fn retained_bytes(&self) -> u64 {
+        let current = self
+            .segment
+            .as_ref()
+            .map_or(0, |segment| segment.stats.bytes);
+        self.retained
+            .iter()
+            .map(|segment| segment.bytes)
+            .sum::<u64>()
+            + current
+    }
+
+    /// Calls `callback` with the path and statistics of each segment once it has been closed, eg. to upload it or to
+    /// prune old footage.
+    pub fn on_segment_complete(
+        mut self,
+        callback: impl FnMut(PathBuf, SegmentStats) + Send + 'static,
+    ) -> Self {
+        self.on_segment_complete = Some(Box::new(callback));
+        self
+    }
+
+    /// The path of the segment currently being written.
+    pub fn current_path(&self) -> Option<&Path> {
+        self.segment.as_ref().map(|segment| segment.path.as_path())
+    }
+
+    /// Writes an access unit from the stream, rotating to a new segment if the current one is full and the access unit
+    /// is a key frame.
+    pub fn push(&mut self, meta: FrameMeta, h264_bytes: &[u8]) -> Result<(), StreamError> {
+        if self.stopped {
+            return Err(StreamError::RecorderStopped);
+        }
+
+        // Transcoded and aligned streams already set is_keyframe but it is cheap to confirm
+        let is_keyframe = meta.is_keyframe || nal::is_keyframe(h264_bytes);
+
+        let result = self.write(meta, is_keyframe, h264_bytes);
+
+        if let Err(err) = &result {
+            self.stopped = true;
+            warn!("Stopping segmented recording: {:?}", err);
+
+            // Close the partial segment so that what was written is kept
+            if let Err(err) = self.close_segment() {
+                warn!("Failed to close the partial segment: {:?}", err);
+            }
+        }
+
+        result
+    }
+
+    fn write(
+        &mut self,
+        meta: FrameMeta,
+        is_keyframe: bool,
+        h264_bytes: &[u8],
+    ) -> Result<(), StreamError> {
+        if is_keyframe && self.segment.as_ref().is_none_or(|s| self.is_full(s)) {
+            self.close_segment()?;
+            self.enforce_retention();
+            self.open_segment(meta.timestamp)?;
+        }
+
+        let Some(segment) = &mut self.segment else {
+            return Ok(());
+        };
+
+        if let (true, Some(index)) = (is_keyframe, &mut segment.index) {
+            let entry = sidecar_entry(meta.wall_clock(), segment.stats.bytes);
+            if let Err(err) = index.write_all(entry.as_bytes()) {
+                // Seeking falls back to scanning the segment
+                warn!("Unable to write the index of {:?}: {:?}", segment.path, err);
+                segment.index = None;
+            }
+        }
+
+        segment
+            .writer
+            .write_all(h264_bytes)
+            .map_err(|err| write_error(err, &segment.path))?;
+
+        segment.stats.frames += 1;
+        segment.stats.bytes += h264_bytes.len() as u64;
+        segment.stats.duration = meta.timestamp.saturating_sub(segment.first_timestamp);
+
+        Ok(())
+    }
+
+    fn is_full(&self, segment: &Segment) -> bool {
+        segment.stats.duration >= self.policy.max_duration
+            || self
+                .policy
+                .max_bytes
+                .is_some_and(|max_bytes| segment.stats.bytes >= max_bytes)
+    }
+
+    fn open_segment(&mut self, first_timestamp: Duration) -> Result<(), StreamError> {
+        let started_at = Utc::now();
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+
+        let filename = self
+            .policy
+            .filename_template
+            .replace(
+                "{timestamp}",
+                &started_at.format(TIMESTAMP_FORMAT).to_string(),
+            )
+            .replace("{sequence}", &format!("{:06}", sequence));
+        let path = self.dir.join(filename);
+
+        // Existing footage is never overwritten
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(|err| write_error(err, &path))?;
+
+        let index = self
+            .write_index
+            .then(|| create_sidecar(&path))
+            .and_then(|result| {
+                result
+                    .map_err(|err| warn!("Unable to create the index of {:?}: {:?}", path, err))
+                    .ok()
+            });
+
+        self.segment = Some(Segment {
+            path,
+            writer: BufWriter::new(file),
+            index,
+            stats: SegmentStats {
+                sequence,
+                started_at,
+                frames: 0,
+                bytes: 0,
+                duration: Duration::ZERO,
+            },
+            first_timestamp,
+        });
+
+        Ok(())
+    }
+
+    fn close_segment(&mut self) -> Result<(), StreamError> {
+        let Some(segment) = self.segment.take() else {
+            return Ok(());
+        };
+
+        let file = segment
+            .writer
+            .into_inner()
+            .map_err(|err| write_error(err.into_error(), &segment.path))?;
+        file.sync_all()
+            .map_err(|err| write_error(err, &segment.path))?;
+        if let Some(Err(err)) = segment.index.map(|index| index.sync_all()) {
+            warn!("Unable to sync the index of {:?}: {:?}", segment.path, err);
+        }
+
+        self.retained.push_back(RetainedSegment {
+            path: segment.path.clone(),
+            started_at: segment.stats.started_at,
+            ended_at: Utc::now(),
+            bytes: segment.stats.bytes,
+        });
+
+        if let Some(callback) = &mut self.on_segment_complete {
+            callback(segment.path, segment.stats);
+        }
+
+        Ok(())
+    }
+
+    /// Deletes the oldest completed segments until the retention policy's limits are met. Segments that can't be
+    /// deleted are kept (and still counted) so that deleting them is retried later.
+    fn enforce_retention(&mut self) {
+        let cutoff = self
+            .retention
+            .max_age
+            .and_then(|max_age| chrono::Duration::from_std(max_age).ok())
+            .and_then(|max_age| Utc::now().checked_sub_signed(max_age));
+        let mut total_bytes = self.retained_bytes();
+
+        let mut index = 0;
+        while let Some(segment) = self.retained.get(index) {
+            let too_old = cutoff.is_some_and(|cutoff| segment.ended_at < cutoff);
+            let too_big = self
+                .retention
+                .max_total_bytes
+                .is_some_and(|max_total_bytes| total_bytes > max_total_bytes);
+            if !too_old && !too_big {
+                break;
+            }
+
+            let event = match std::fs::remove_file(&segment.path) {
+                Ok(()) => RetentionEvent::Deleted {
+                    path: segment.path.clone(),
+                    bytes: segment.bytes,
+                },
+                // Deleted by something else
+                Err(err) if err.kind() == io::ErrorKind::NotFound => RetentionEvent::Deleted {
+                    path: segment.path.clone(),
+                    bytes: segment.bytes,
+                },
+                Err(err) => {
+                    warn!("Unable to delete old segment {:?}: {:?}", segment.path, err);
+                    index += 1;
+                    RetentionEvent::DeleteFailed {
+                        path: segment.path.clone(),
+                        error: err.to_string(),
+                    }
+                }
+            };
+
+            if matches!(event, RetentionEvent::Deleted { .. }) {
+                if let Err(err) = std::fs::remove_file(sidecar_path(&segment.path)) {
+                    if err.kind() != io::ErrorKind::NotFound {
+                        warn!(
+                            "Unable to delete the index of {:?}: {:?}",
+                            segment.path, err
+                        );
+                    }
+                }
+                total_bytes -= segment.bytes;
+                self.retained.remove(index);
+            }
+
+            if let Some(callback) = &mut self.on_retention_event {
+                callback(event);
+            }
+        }
+    }
+
+    /// Closes the current segment (calling `on_segment_complete` as usual), returning its path and statistics if one
+    /// was open.
+    pub fn finish(mut self) -> Result<Option<(PathBuf, SegmentStats)>, StreamError> {
+        let completed = self
+            .segment
+            .as_ref()
+            .map(|segment| (segment.path.clone(), segment.stats));
+
+        self.close_segment()?;
+
+        Ok(completed)
+    }
+}
+
+/// Finds the files in `dir` named by `filename_template`, oldest first, and the sequence number to continue from.
+fn scan_segments(
+    dir: &Path,
+    filename_template: &str,
+) -> io::Result<(VecDeque<RetainedSegment>, u64)> {
+    let mut segments = Vec::new();
+    let mut next_sequence = 0;
+
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some((timestamp, sequence)) = name
+            .to_str()
+            .and_then(|name| parse_filename(filename_template, name))
+        else {
+            continue;
+        };
+        let metadata = match entry.metadata() {
+            Ok(metadata) if metadata.is_file() => metadata,
+            Ok(_) => continue,
+            Err(err) => {
+                warn!("Unable to read segment {:?}: {:?}", entry.path(), err);
+                continue;
+            }
+        };
+
+        let ended_at = metadata
+            .modified()
+            .map_or_else(|_| Utc::now(), DateTime::from);
+        if let Some(sequence) = sequence {
+            next_sequence = next_sequence.max(sequence + 1);
+        }
+
+        segments.push((
+            sequence,
+            RetainedSegment {
+                path: entry.path(),
+                started_at: timestamp.unwrap_or(ended_at),
+                ended_at,
+                bytes: metadata.len(),
+            },
+        ));
+    }
+
+    segments.sort_by_key(|(sequence, segment)| (segment.started_at, *sequence));
+
+    Ok((
+        segments.into_iter().map(|(_, segment)| segment).collect(),
+        next_sequence,
+    ))
+}
+
+/// Matches a file name against a filename template, returning the `{timestamp}` and `{sequence}` it contains.
+fn parse_filename(template: &str, name: &str) -> Option<(Option<DateTime<Utc>>, Option<u64>)> {
+    let (mut timestamp, mut sequence) = (None, None);
+    let (mut template, mut name) = (template, name);
+
+    loop {
+        let Some(start) = template.find('{') else {
+            return (template == name).then_some((timestamp, sequence));
+        };
+        name = name.strip_prefix(&template[..start])?;
+        template = &template[start..];
+
+        if let Some(rest) = template.strip_prefix("{timestamp}") {
+            // Eg. 20240131T235959Z
+            let value = name.get(..16)?;
+            let parsed = NaiveDateTime::parse_from_str(value, TIMESTAMP_FORMAT).ok()?;
+            timestamp = Some(Utc.from_utc_datetime(&parsed));
+            (template, name) = (rest, &name[16..]);
+        } else if let Some(rest) = template.strip_prefix("{sequence}") {
+            let digits = name.bytes().take_while(u8::is_ascii_digit).count();
+            sequence = Some(name[..digits].parse().ok()?);
+            (template, name) = (rest, &name[digits..]);
+        } else {
+            name = name.strip_prefix('{')?;
+            template = &template[1..];
+        }
+    }
+}
+
+fn create_sidecar(path: &Path) -> io::Result<File> {
+    let mut sidecar = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(sidecar_path(path))?;
+    sidecar.write_all(SIDECAR_HEADER.as_bytes())?;
+    Ok(sidecar)
+}
+
+fn write_error(err: io::Error, path: &Path) -> StreamError {
+    if err.raw_os_error() == Some(libc::ENOSPC) {
+        StreamError::DiskFull(path.to_path_buf())
+    } else {
+        StreamError::WriteFailure(err)
+    }
+}
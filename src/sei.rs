@@ -0,0 +1,269 @@
+//! Capture timestamps embedded in the H264 bitstream as SEI (supplemental enhancement information) NAL units, so that
+//! they survive remuxing, see [`StreamBuilder::sei_timestamps`](crate::StreamBuilder::sei_timestamps).
+//!
+//! Each timestamp is an unregistered user data SEI message identified by [`TIMESTAMP_UUID`], with a 20 byte big endian
+//! payload: the UTC capture time in nanoseconds since the Unix epoch (i64), the kernel's capture timestamp in
+//! nanoseconds (u64) and the driver's sequence number (u32). Decoders ignore user data they don't recognise. A
+//! `pic_timing` SEI would need the SPS's VUI to signal it, which camera encoders rarely do, so it is not used.
+
+use crate::nal::{NalType, NalUnits};
+use crate::FrameMeta;
+use chrono::{DateTime, TimeZone, Utc};
+use std::time::Duration;
+
+/// The UUID that identifies timestamp SEI messages written by this crate.
+pub const TIMESTAMP_UUID: [u8; 16] = [
+    0x8a, 0x3c, 0x5e, 0x21, 0x7f, 0x0b, 0x4d, 0x96, 0xb1, 0x42, 0x6e, 0xd3, 0x19, 0xc5, 0xa7, 0x04,
+];
+
+// user_data_unregistered
+const USER_DATA_UNREGISTERED: u32 = 5;
+const PAYLOAD_LEN: usize = TIMESTAMP_UUID.len() + 20;
+
+/// A capture timestamp read back from a bitstream by [`extract_sei_timestamps`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeiTimestamp {
+    /// The frame's capture time in UTC, see [`FrameMeta::wall_clock`].
+    pub wall_clock: DateTime<Utc>,
+    /// The kernel's capture timestamp, see [`FrameMeta::timestamp`].
+    pub timestamp: Duration,
+    pub sequence: u32,
+}
+
+/// Builds a timestamp SEI NAL unit for a frame, including its 4 byte start code.
+pub fn timestamp_sei(meta: &FrameMeta) -> Vec<u8> {
+    let wall_clock = meta.wall_clock();
+    let wall_clock_nanos = wall_clock.timestamp_nanos();
+
+    let mut rbsp = Vec::with_capacity(PAYLOAD_LEN + 4);
+    rbsp.push(USER_DATA_UNREGISTERED as u8);
+    rbsp.push(PAYLOAD_LEN as u8);
+    rbsp.extend_from_slice(&TIMESTAMP_UUID);
+    rbsp.extend_from_slice(&wall_clock_nanos.to_be_bytes());
+    rbsp.extend_from_slice(&(meta.timestamp.as_nanos() as u64).to_be_bytes());
+    rbsp.extend_from_slice(&meta.sequence.to_be_bytes());
+    // rbsp_trailing_bits
+    rbsp.push(0x80);
+
+    let mut nal = vec![0, 0, 0, 1, 0x06];
+    add_emulation_prevention(&rbsp, &mut nal);
+    nal
+}
+
+/// Inserts a timestamp SEI for `meta` before the first slice of the last access unit after `start`, after any access
+/// unit delimiter and parameter sets. The access units before it are duplicates encoded to fill dropped frames, which
+/// were not captured. Nothing is inserted if there is no slice.
+pub(crate) fn insert_timestamp(h264_bytes: &mut Vec<u8>, start: usize, meta: &FrameMeta) {
+    let access_unit = &h264_bytes[start..];
+
+    let mut slices = NalUnits::new(access_unit).filter(|nal| {
+        matches!(
+            NalType::of(nal),
+            NalType::Idr
+                | NalType::NonIdr
+                | NalType::PartitionA
+                | NalType::PartitionB
+                | NalType::PartitionC
+        )
+    });
+    let Some(first_slice) = slices.next() else {
+        return;
+    };
+    // A first_mb_in_slice of 0 (a single 1 bit) starts a new picture
+    let starts_picture = |slice: &&[u8]| {
+        matches!(NalType::of(slice), NalType::Idr | NalType::NonIdr)
+            && slice.get(1).is_some_and(|byte| byte & 0x80 != 0)
+    };
+    let slice = slices.filter(starts_picture).last().unwrap_or(first_slice);
+
+    // The slice's start code, including the leading zero of a 4 byte start code
+    let mut position = slice.as_ptr() as usize - access_unit.as_ptr() as usize - 3;
+    if position > 0 && access_unit[position - 1] == 0 {
+        position -= 1;
+    }
+
+    let position = start + position;
+    h264_bytes.splice(position..position, timestamp_sei(meta));
+}
+
+/// Returns the timestamps of every timestamp SEI in an Annex-B bitstream, in order. Other SEI messages are ignored.
+pub fn extract_sei_timestamps(bitstream: &[u8]) -> Vec<SeiTimestamp> {
+    NalUnits::new(bitstream)
+        .filter(|nal| NalType::of(nal) == NalType::Sei)
+        .flat_map(|nal| {
+            let rbsp = crate::sps::remove_emulation_prevention(&nal[1..]);
+            sei_messages(&rbsp)
+                .into_iter()
+                .filter_map(parse_timestamp)
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Splits an SEI RBSP into its messages' payload types and payloads.
+fn sei_messages(mut rbsp: &[u8]) -> Vec<(u32, &[u8])> {
+    let mut messages = Vec::new();
+
+    // Each message is at least 2 bytes, anything shorter is the trailing bits
+    while rbsp.len() >= 2 && rbsp[0] != 0x80 {
+        let Some((payload_type, rest)) = read_sei_value(rbsp) else {
+            break;
+        };
+        let Some((payload_size, rest)) = read_sei_value(rest) else {
+            break;
+        };
+        let Some(payload) = rest.get(..payload_size as usize) else {
+            break;
+        };
+
+        messages.push((payload_type, payload));
+        rbsp = &rest[payload_size as usize..];
+    }
+
+    messages
+}
+
+/// Reads an SEI payload type or size, which is coded as a run of 0xFF bytes each adding 255 followed by a final byte.
+fn read_sei_value(data: &[u8]) -> Option<(u32, &[u8])> {
+    let mut value = 0u32;
+
+    for (i, &byte) in data.iter().enumerate() {
+        value = value.checked_add(byte as u32)?;
+        if byte != 0xff {
+            return Some((value, &data[i + 1..]));
+        }
+    }
+
+    None
+}
+
+fn parse_timestamp((payload_type, payload): (u32, &[u8])) -> Option<SeiTimestamp> {
+    if payload_type != USER_DATA_UNREGISTERED || payload.len() != PAYLOAD_LEN {
+        return None;
+    }
+
+    let (uuid, fields) = payload.split_at(TIMESTAMP_UUID.len());
+    if uuid != TIMESTAMP_UUID {
+        return None;
+    }
+
+    let wall_clock_nanos = i64::from_be_bytes(fields[0..8].try_into().ok()?);
+    let timestamp_nanos = u64::from_be_bytes(fields[8..16].try_into().ok()?);
+    let sequence = u32::from_be_bytes(fields[16..20].try_into().ok()?);
+
+    Some(SeiTimestamp {
+        wall_clock: Utc.timestamp_nanos(wall_clock_nanos),
+        timestamp: Duration::from_nanos(timestamp_nanos),
+        sequence,
+    })
+}
+
+// Inserts a 0x03 byte wherever two zero bytes are followed by a byte that could be mistaken for a start code
+fn add_emulation_prevention(rbsp: &[u8], out: &mut Vec<u8>) {
+    let mut zeros = 0;
+
+    for &byte in rbsp {
+        if zeros >= 2 && byte <= 3 {
+            out.push(3);
+            zeros = 0;
+        }
+
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        out.push(byte);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ClockMapping;
+
+    fn meta(sequence: u32) -> FrameMeta {
+        FrameMeta {
+            timestamp: Duration::from_millis(1000 + sequence as u64 * 33),
+            // Fixed so that every frame's wall clock time is the same on every call
+            clock: ClockMapping::default(),
+            sequence,
+            dropped_since_last: 0,
+            bytesused: 0,
+            is_keyframe: false,
+        }
+    }
+
+    #[test]
+    fn round_trips_payloads_that_need_emulation_prevention() {
+        // The small sequence number and timestamp are mostly zero bytes
+        let meta = FrameMeta {
+            timestamp: Duration::from_nanos(1),
+            ..meta(2)
+        };
+
+        let sei = timestamp_sei(&meta);
+        assert!(sei[4..].windows(3).any(|window| window == [0, 0, 3]));
+        assert!(!sei[4..].windows(3).any(|window| window == [0, 0, 1]));
+
+        assert_eq!(
+            extract_sei_timestamps(&sei),
+            [SeiTimestamp {
+                wall_clock: meta.wall_clock(),
+                timestamp: meta.timestamp,
+                sequence: 2,
+            }]
+        );
+    }
+
+    #[cfg(feature = "openh264")]
+    #[test]
+    fn inserted_timestamps_decode_and_extract() {
+        use crate::synthetic::tests::access_units;
+        use openh264::decoder::Decoder;
+
+        let mut decoder = Decoder::new().unwrap();
+        let mut bitstream = Vec::new();
+        for (sequence, mut access_unit) in access_units(64, 64, 30, 10).into_iter().enumerate() {
+            insert_timestamp(&mut access_unit, 0, &meta(sequence as u32));
+
+            let decoded = decoder.decode(&access_unit).unwrap();
+            assert!(decoded.is_some(), "Frame {sequence} decodes");
+            bitstream.extend_from_slice(&access_unit);
+        }
+
+        let timestamps = extract_sei_timestamps(&bitstream);
+        assert_eq!(timestamps.len(), 30);
+        for (sequence, timestamp) in timestamps.iter().enumerate() {
+            let meta = meta(sequence as u32);
+            assert_eq!(timestamp.sequence, sequence as u32);
+            assert_eq!(timestamp.timestamp, meta.timestamp);
+            assert_eq!(timestamp.wall_clock, meta.wall_clock());
+        }
+    }
+
+    #[cfg(feature = "openh264")]
+    #[test]
+    fn inserts_timestamps_into_the_last_access_unit() {
+        use crate::synthetic::tests::access_units;
+
+        let access_units = access_units(64, 64, 3, 10);
+        // The previous frame, then a duplicate filling a dropped frame followed by the captured frame
+        let mut h264_bytes = access_units[0].clone();
+        let start = h264_bytes.len();
+        h264_bytes.extend_from_slice(&access_units[1]);
+        h264_bytes.extend_from_slice(&access_units[2]);
+
+        insert_timestamp(&mut h264_bytes, start, &meta(7));
+
+        let types: Vec<NalType> = NalUnits::new(&h264_bytes).map(NalType::of).collect();
+        assert_eq!(
+            types,
+            [
+                NalType::Sps,
+                NalType::Pps,
+                NalType::Idr,
+                NalType::NonIdr,
+                NalType::Sei,
+                NalType::NonIdr
+            ]
+        );
+        assert_eq!(extract_sei_timestamps(&h264_bytes)[0].sequence, 7);
+    }
+}
@@ -0,0 +1,194 @@
+use std::cmp::Reverse;
+use std::sync::Arc;
+use tracing::warn;
+use v4l::frameinterval::{FrameIntervalEnum, Stepwise};
+use v4l::framesize::{Discrete, FrameSizeEnum};
+use v4l::video::Capture;
+use v4l::{Device, FourCC, Fraction};
+
+/// The pixel formats a stream can be opened with. Uncompressed and raw Bayer formats are transcoded to H264.
+pub(crate) const FOURCCS: [&[u8; 4]; 9] = [
+    b"H264", b"MJPG", b"YUYV", b"UYVY", b"NV12", b"RGGB", b"BA81", b"GRBG", b"GBRG",
+];
+
+/// Ranks compressed formats over uncompressed YUV formats, and those over raw Bayer formats which are the slowest to
+/// convert.
+fn format_rank(fourcc: FourCC) -> u8 {
+    match &fourcc.repr {
+        b"H264" | b"MJPG" => 2,
+        b"YUYV" | b"UYVY" | b"NV12" => 1,
+        _ => 0,
+    }
+}
+
+/// A combination of pixel format, resolution and frame interval that a camera supports, see
+/// [`enumerate_configurations`].
+#[derive(Debug, Clone, Copy)]
+pub struct CaptureConfig {
+    pub fourcc: FourCC,
+    pub width: u32,
+    pub height: u32,
+    /// The frame interval (1 / fps). For cameras that report a range of intervals this is the shortest one.
+    pub interval: Fraction,
+}
+
+impl CaptureConfig {
+    pub fn fps(&self) -> f64 {
+        self.interval.denominator as f64 / self.interval.numerator as f64
+    }
+
+    /// The score the stream builder uses to choose a configuration by default: compressed formats over uncompressed ones (YUYV,
+    /// UYVY or NV12) and those over raw Bayer formats, then the
+    /// largest resolution, then the highest frame rate and finally H264 over MJPEG.
+    pub fn default_score(&self) -> impl Ord {
+        (
+            // Only fall back to uncompressed formats if no compressed formats are available
+            format_rank(self.fourcc),
+            // Prefer larger resolutions
+            self.width,
+            self.height,
+            // Prefer higher framerates: Scale the fraction so that it doesn't round to zero for sorting purposes
+            self.interval.denominator as u64 * 1_000_000 / self.interval.numerator.max(1) as u64,
+            // Prefer H264 over MJPEG
+            self.fourcc == FourCC::new(b"H264"),
+        )
+    }
+
+    /// Like [`default_score`](Self::default_score) but preferring the highest frame rate over the largest resolution,
+    /// eg. for smooth or low latency video.
+    pub fn frame_rate_score(&self) -> impl Ord {
+        (
+            format_rank(self.fourcc),
+            self.interval.denominator as u64 * 1_000_000 / self.interval.numerator.max(1) as u64,
+            self.width,
+            self.height,
+            self.fourcc == FourCC::new(b"H264"),
+        )
+    }
+}
+
+/// Sorts the configurations a camera supports from best to worst, see
+/// [`StreamBuilder::selector`](crate::StreamBuilder::selector).
+pub(crate) type Selector = Arc<dyn Fn(&mut Vec<CaptureConfig>) + Send + Sync>;
+
+pub(crate) fn selector<S: Ord>(
+    score: impl Fn(&CaptureConfig) -> S + Send + Sync + 'static,
+) -> Selector {
+    Arc::new(move |configs| configs.sort_by_cached_key(|config| Reverse(score(config))))
+}
+
+/// Lists the configurations the camera supports in the formats a stream can be opened with (H264, MJPG, YUYV,
+/// UYVY, NV12 and 8-bit Bayer).
+///
+/// Cameras that report a range of frame sizes are listed at the range's minimum and maximum size and the common sizes
+/// within it.
+pub fn enumerate_configurations(dev: &Device) -> Vec<CaptureConfig> {
+    enumerate(dev, None)
+}
+
+/// Enumerates the camera's configurations, including `requested` if it is within a range of frame sizes.
+pub(crate) fn enumerate(dev: &Device, requested: Option<(u32, u32)>) -> Vec<CaptureConfig> {
+    FOURCCS
+        .into_iter()
+        .map(FourCC::new)
+        // Get an iterator of stepwise or discrete frame sizes
+        .filter_map(|fourcc| dev.enum_framesizes(fourcc).ok())
+        .flatten()
+        // Normalize the frame sizes as discrete
+        .flat_map(|framesize| {
+            candidate_sizes(framesize.size, requested)
+                .into_iter()
+                .map(move |discrete| (framesize.fourcc, discrete))
+        })
+        // Get the frame interval (1 / fps) for each frame size
+        .filter_map(|(fourcc, discrete)| {
+            dev.enum_frameintervals(fourcc, discrete.width, discrete.height)
+                .map_err(|err| {
+                    warn!(
+                        "Unable to get camera frame internals for {}x{}, skipping: {:?}",
+                        discrete.width, discrete.height, err
+                    )
+                })
+                .map(move |intervals| intervals.into_iter().map(move |f| (fourcc, f)))
+                .ok()
+        })
+        .flatten()
+        .map(|(fourcc, f)| CaptureConfig {
+            fourcc,
+            width: f.width,
+            height: f.height,
+            interval: match f.interval {
+                FrameIntervalEnum::Discrete(seconds) => seconds,
+                FrameIntervalEnum::Stepwise(Stepwise { min, .. }) => min,
+            },
+        })
+        .collect()
+}
+
+/// Common resolutions tried for cameras that report a range of frame sizes.
+const COMMON_SIZES: [(u32, u32); 12] = [
+    (320, 240),
+    (640, 360),
+    (640, 480),
+    (800, 600),
+    (960, 540),
+    (1024, 768),
+    (1280, 720),
+    (1280, 960),
+    (1600, 1200),
+    (1920, 1080),
+    (2560, 1440),
+    (3840, 2160),
+];
+
+/// Returns the frame sizes to consider for a camera's frame size enumeration.
+///
+/// Stepwise (and continuous) ranges are not expanded into every size they allow since querying the frame intervals of
+/// each would be very slow. Instead the minimum and maximum sizes, the common sizes that fit the range and the
+/// requested resolution (if it fits) are used.
+fn candidate_sizes(size: FrameSizeEnum, requested: Option<(u32, u32)>) -> Vec<Discrete> {
+    let stepwise = match size {
+        FrameSizeEnum::Discrete(discrete) => return vec![discrete],
+        FrameSizeEnum::Stepwise(stepwise) => stepwise,
+    };
+
+    // Continuous ranges are reported with a step of 1, but guard against drivers that report 0
+    let step_width = stepwise.step_width.max(1);
+    let step_height = stepwise.step_height.max(1);
+
+    let fits = |(width, height): (u32, u32)| {
+        (stepwise.min_width..=stepwise.max_width).contains(&width)
+            && (stepwise.min_height..=stepwise.max_height).contains(&height)
+            && (width - stepwise.min_width) % step_width == 0
+            && (height - stepwise.min_height) % step_height == 0
+    };
+
+    // Rounds a size down onto the step grid
+    let align = |(width, height): (u32, u32)| {
+        (
+            width - (width.saturating_sub(stepwise.min_width)) % step_width,
+            height - (height.saturating_sub(stepwise.min_height)) % step_height,
+        )
+    };
+
+    let mut sizes = vec![
+        (stepwise.min_width, stepwise.min_height),
+        align((stepwise.max_width, stepwise.max_height)),
+    ];
+
+    sizes.extend(
+        COMMON_SIZES
+            .into_iter()
+            .map(align)
+            .filter(|&size| fits(size)),
+    );
+    sizes.extend(requested.filter(|&size| fits(size)));
+
+    sizes.sort();
+    sizes.dedup();
+
+    sizes
+        .into_iter()
+        .map(|(width, height)| Discrete { width, height })
+        .collect()
+}
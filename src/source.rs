@@ -0,0 +1,40 @@
+use crate::{StreamError, WebcamH264Stream, YUVFrame};
+
+/// A source of H264 frames, implemented by [`WebcamH264Stream`] and [`FilePlaybackStream`](crate::FilePlaybackStream)
+/// so that code can be written once for both live and recorded video.
+pub trait FrameSource {
+    /// Gets the next H264 access unit and, if `get_yuv_frame` is true, a YUV image of the frame. See
+    /// [`WebcamH264Stream::next`].
+    fn next(&mut self, get_yuv_frame: bool)
+        -> Result<(Vec<u8>, Option<YUVFrame<'_>>), StreamError>;
+
+    /// The width of the video in pixels.
+    fn width(&self) -> u32;
+
+    /// The height of the video in pixels.
+    fn height(&self) -> u32;
+
+    /// The nominal frame rate of the video.
+    fn fps(&self) -> f64;
+}
+
+impl<'a> FrameSource for WebcamH264Stream<'a> {
+    fn next(
+        &mut self,
+        get_yuv_frame: bool,
+    ) -> Result<(Vec<u8>, Option<YUVFrame<'_>>), StreamError> {
+        WebcamH264Stream::next(self, get_yuv_frame)
+    }
+
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn fps(&self) -> f64 {
+        WebcamH264Stream::fps(self)
+    }
+}
@@ -0,0 +1,291 @@
+//! Parsing of the fields of an H264 sequence parameter set that describe the coded video, eg. to check the
+//! resolution a camera actually encodes or to fill in codec strings.
+
+use crate::nal::NalType;
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpsError {
+    #[error("The NAL unit is not an SPS")]
+    NotSps,
+    #[error("The SPS ended unexpectedly")]
+    Truncated,
+    #[error("The SPS contains an out of range value")]
+    OutOfRange,
+}
+
+/// The coded format described by an SPS.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpsInfo {
+    /// The width of the decoded frames, after the SPS's cropping.
+    pub width: u32,
+    /// The height of the decoded frames, after the SPS's cropping.
+    pub height: u32,
+    pub profile_idc: u8,
+    /// The constraint_set flags and reserved bits following the profile.
+    pub constraint_flags: u8,
+    pub level_idc: u8,
+    /// The frame rate from the VUI timing info, if the camera includes it.
+    pub fps_from_vui: Option<f64>,
+}
+
+impl SpsInfo {
+    /// The RFC 6381 codec string, eg. `avc1.64001f` for the MP4 codecs parameter or Media Source Extensions.
+    pub fn codec_string(&self) -> String {
+        format!(
+            "avc1.{:02x}{:02x}{:02x}",
+            self.profile_idc, self.constraint_flags, self.level_idc
+        )
+    }
+}
+
+/// Parses an SPS NAL unit (without its start code).
+pub fn parse(sps: &[u8]) -> Result<SpsInfo, SpsError> {
+    match sps.first() {
+        Some(&header) if NalType::from_header(header) == NalType::Sps => {}
+        Some(_) => return Err(SpsError::NotSps),
+        None => return Err(SpsError::Truncated),
+    }
+
+    let rbsp = remove_emulation_prevention(&sps[1..]);
+    let mut bits = BitReader::new(&rbsp);
+
+    let profile_idc = bits.read_bits(8)? as u8;
+    let constraint_flags = bits.read_bits(8)? as u8;
+    let level_idc = bits.read_bits(8)? as u8;
+    // seq_parameter_set_id
+    bits.read_ue()?;
+
+    let mut chroma_format_idc = 1;
+    let mut separate_colour_plane = false;
+
+    if matches!(
+        profile_idc,
+        100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135
+    ) {
+        chroma_format_idc = bits.read_ue()?;
+        if chroma_format_idc == 3 {
+            separate_colour_plane = bits.read_flag()?;
+        }
+        // bit_depth_luma_minus8, bit_depth_chroma_minus8 and qpprime_y_zero_transform_bypass_flag
+        bits.read_ue()?;
+        bits.read_ue()?;
+        bits.read_flag()?;
+
+        if bits.read_flag()? {
+            let scaling_lists = if chroma_format_idc == 3 { 12 } else { 8 };
+            for i in 0..scaling_lists {
+                if bits.read_flag()? {
+                    skip_scaling_list(&mut bits, if i < 6 { 16 } else { 64 })?;
+                }
+            }
+        }
+    }
+
+    // log2_max_frame_num_minus4
+    bits.read_ue()?;
+
+    match bits.read_ue()? {
+        // log2_max_pic_order_cnt_lsb_minus4
+        0 => {
+            bits.read_ue()?;
+        }
+        1 => {
+            // delta_pic_order_always_zero_flag, offset_for_non_ref_pic and offset_for_top_to_bottom_field
+            bits.read_flag()?;
+            bits.read_se()?;
+            bits.read_se()?;
+            for _ in 0..bits.read_ue()? {
+                bits.read_se()?;
+            }
+        }
+        _ => {}
+    }
+
+    // max_num_ref_frames and gaps_in_frame_num_value_allowed_flag
+    bits.read_ue()?;
+    bits.read_flag()?;
+
+    let width_in_mbs = u64::from(bits.read_ue()?) + 1;
+    let height_in_map_units = u64::from(bits.read_ue()?) + 1;
+    let frame_mbs_only = bits.read_flag()?;
+    if !frame_mbs_only {
+        // mb_adaptive_frame_field_flag
+        bits.read_flag()?;
+    }
+    // direct_8x8_inference_flag
+    bits.read_flag()?;
+
+    let mut crop = [0; 4];
+    if bits.read_flag()? {
+        for offset in &mut crop {
+            *offset = u64::from(bits.read_ue()?);
+        }
+    }
+    let [crop_left, crop_right, crop_top, crop_bottom] = crop;
+
+    // Crop offsets are in chroma samples, and in field pairs for interlaced streams
+    let field_factor = if frame_mbs_only { 1 } else { 2 };
+    let (crop_unit_x, crop_unit_y) = match (separate_colour_plane, chroma_format_idc) {
+        (true, _) | (false, 0) => (1, field_factor),
+        (false, 1) => (2, 2 * field_factor),
+        (false, 2) => (2, field_factor),
+        (false, 3) => (1, field_factor),
+        _ => return Err(SpsError::OutOfRange),
+    };
+
+    let width = (width_in_mbs * 16)
+        .checked_sub(crop_unit_x * (crop_left + crop_right))
+        .ok_or(SpsError::OutOfRange)?;
+    let height = (height_in_map_units * 16 * field_factor)
+        .checked_sub(crop_unit_y * (crop_top + crop_bottom))
+        .ok_or(SpsError::OutOfRange)?;
+
+    let fps_from_vui = if bits.read_flag()? {
+        read_vui_fps(&mut bits)?
+    } else {
+        None
+    };
+
+    Ok(SpsInfo {
+        width: u32::try_from(width).map_err(|_| SpsError::OutOfRange)?,
+        height: u32::try_from(height).map_err(|_| SpsError::OutOfRange)?,
+        profile_idc,
+        constraint_flags,
+        level_idc,
+        fps_from_vui,
+    })
+}
+
+// Reads the VUI parameters up to the timing info
+fn read_vui_fps(bits: &mut BitReader) -> Result<Option<f64>, SpsError> {
+    // aspect_ratio_info_present_flag
+    if bits.read_flag()? {
+        const EXTENDED_SAR: u32 = 255;
+        if bits.read_bits(8)? == EXTENDED_SAR {
+            // sar_width and sar_height
+            bits.read_bits(32)?;
+        }
+    }
+
+    // overscan_info_present_flag and overscan_appropriate_flag
+    if bits.read_flag()? {
+        bits.read_flag()?;
+    }
+
+    // video_signal_type_present_flag
+    if bits.read_flag()? {
+        // video_format and video_full_range_flag
+        bits.read_bits(4)?;
+        // colour_description_present_flag followed by the primaries, transfer characteristics and matrix
+        if bits.read_flag()? {
+            bits.read_bits(24)?;
+        }
+    }
+
+    // chroma_loc_info_present_flag
+    if bits.read_flag()? {
+        bits.read_ue()?;
+        bits.read_ue()?;
+    }
+
+    if !bits.read_flag()? {
+        return Ok(None);
+    }
+
+    let num_units_in_tick = bits.read_bits(32)?;
+    let time_scale = bits.read_bits(32)?;
+
+    // A frame lasts two ticks (one per field)
+    Ok((num_units_in_tick > 0 && time_scale > 0)
+        .then(|| f64::from(time_scale) / (2.0 * f64::from(num_units_in_tick))))
+}
+
+fn skip_scaling_list(bits: &mut BitReader, size: usize) -> Result<(), SpsError> {
+    let mut last_scale = 8;
+    let mut next_scale = 8;
+
+    for _ in 0..size {
+        if next_scale != 0 {
+            let delta_scale = i64::from(bits.read_se()?);
+            next_scale = (last_scale + delta_scale).rem_euclid(256);
+        }
+        if next_scale != 0 {
+            last_scale = next_scale;
+        }
+    }
+
+    Ok(())
+}
+
+// Replaces each `00 00 03` sequence with `00 00`
+pub(crate) fn remove_emulation_prevention(data: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(data.len());
+    let mut zeros = 0;
+
+    for &byte in data {
+        if zeros >= 2 && byte == 3 {
+            zeros = 0;
+            continue;
+        }
+
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        rbsp.push(byte);
+    }
+
+    rbsp
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    fn read_flag(&mut self) -> Result<bool, SpsError> {
+        let byte = self
+            .data
+            .get(self.position / 8)
+            .ok_or(SpsError::Truncated)?;
+        let bit = (byte >> (7 - self.position % 8)) & 1;
+        self.position += 1;
+        Ok(bit == 1)
+    }
+
+    fn read_bits(&mut self, count: u32) -> Result<u32, SpsError> {
+        let mut value = 0;
+        for _ in 0..count {
+            value = (value << 1) | u32::from(self.read_flag()?);
+        }
+        Ok(value)
+    }
+
+    // An unsigned Exp-Golomb code
+    fn read_ue(&mut self) -> Result<u32, SpsError> {
+        let mut leading_zeros = 0;
+        while !self.read_flag()? {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                return Err(SpsError::OutOfRange);
+            }
+        }
+
+        let value = (1u64 << leading_zeros) - 1 + u64::from(self.read_bits(leading_zeros)?);
+        u32::try_from(value).map_err(|_| SpsError::OutOfRange)
+    }
+
+    // A signed Exp-Golomb code
+    fn read_se(&mut self) -> Result<i32, SpsError> {
+        let code = i64::from(self.read_ue()?);
+        let value = if code % 2 == 1 {
+            (code + 1) / 2
+        } else {
+            -code / 2
+        };
+        Ok(value as i32)
+    }
+}
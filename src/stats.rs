@@ -0,0 +1,275 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// The longest window the frame rate and bitrate are averaged over.
+const WINDOW: Duration = Duration::from_secs(10);
+
+/// A snapshot of a stream's capture statistics since it was opened (or since [`reset_stats`]), eg. for diagnosing a
+/// choppy stream.
+///
+/// [`reset_stats`]: crate::WebcamH264Stream::reset_stats
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StreamStats {
+    /// The number of frames returned by the stream.
+    pub frames: u64,
+    /// The number of frames the driver dropped, detected by gaps in the frames' sequence numbers. This usually means
+    /// frames were not read quickly enough.
+    pub dropped_frames: u64,
+    /// The number of corrupt frames skipped (see [`ErrorPolicy::SkipCorruptFrames`](crate::ErrorPolicy)).
+    pub corrupt_frames: u64,
+    /// The number of frames with bitstream issues (see
+    /// [`StreamBuilder::validate_bitstream`](crate::StreamBuilder::validate_bitstream)). Always 0 if the bitstream is not
+    /// validated.
+    pub invalid_frames: u64,
+    /// The number of frames skipped by [`DequeuePolicy::LatestOnly`](crate::DequeuePolicy) because a newer frame was
+    /// already waiting.
+    pub stale_frames: u64,
+    /// The number of duplicate frames encoded to fill gaps left by dropped frames, see
+    /// [`StreamBuilder::fill_dropped_frames`](crate::StreamBuilder::fill_dropped_frames).
+    pub duplicated_frames: u64,
+    /// The number of H264 (or passed through JPEG) bytes returned by the stream.
+    pub bytes: u64,
+    /// The frame rate over the last second.
+    pub fps_1s: f64,
+    /// The frame rate over the last 10 seconds.
+    pub fps_10s: f64,
+    /// The bitrate in bits per second over the last 10 seconds.
+    pub bitrate_10s: f64,
+    /// The mean and maximum time spent processing each frame after it was captured, ie. decoding, transcoding and
+    /// encoding it.
+    pub mean_processing_time: Duration,
+    pub max_processing_time: Duration,
+    /// The mean and maximum time from the kernel capturing each frame to the stream returning it, including the time
+    /// it was queued in the driver's buffers. None if the driver's timestamps are not CLOCK_MONOTONIC.
+    pub mean_latency: Option<Duration>,
+    pub max_latency: Option<Duration>,
+    /// The time since the statistics were started or reset.
+    pub elapsed: Duration,
+}
+
+/// Periodically called with a snapshot of the stream's statistics, see
+/// [`StreamBuilder::on_stats`](crate::StreamBuilder::on_stats).
+pub(crate) struct StatsCallback {
+    pub(crate) interval: Duration,
+    pub(crate) callback: Box<dyn FnMut(&StreamStats) + Send>,
+    pub(crate) last_called: Instant,
+}
+
+/// Maintains a stream's statistics. Recording a frame is O(1) (amortized) so that it can always be on.
+pub(crate) struct StatsTracker {
+    started: Instant,
+    frames: u64,
+    dropped_frames: u64,
+    invalid_frames: u64,
+    stale_frames: u64,
+    duplicated_frames: u64,
+    // Unlike dropped_frames this is not cleared by reset, so that it can be exported as a counter
+    lifetime_dropped_frames: u64,
+    bytes: u64,
+    total_processing_time: Duration,
+    max_processing_time: Duration,
+    total_latency: Duration,
+    latency_frames: u32,
+    max_latency: Option<Duration>,
+    last_latency: Option<Duration>,
+    last_sequence: Option<u32>,
+    // The time and size of each frame in the last 10 seconds
+    window: VecDeque<(Instant, usize)>,
+    corrupt_frames_at_reset: u64,
+    callback: Option<StatsCallback>,
+}
+
+impl StatsTracker {
+    pub(crate) fn new(mut callback: Option<StatsCallback>) -> Self {
+        if let Some(callback) = &mut callback {
+            callback.last_called = Instant::now();
+        }
+
+        Self {
+            started: Instant::now(),
+            frames: 0,
+            dropped_frames: 0,
+            invalid_frames: 0,
+            stale_frames: 0,
+            duplicated_frames: 0,
+            lifetime_dropped_frames: 0,
+            bytes: 0,
+            total_processing_time: Duration::ZERO,
+            max_processing_time: Duration::ZERO,
+            total_latency: Duration::ZERO,
+            latency_frames: 0,
+            max_latency: None,
+            last_latency: None,
+            last_sequence: None,
+            window: VecDeque::new(),
+            corrupt_frames_at_reset: 0,
+            callback,
+        }
+    }
+
+    /// Clears the statistics. `corrupt_frames` is the stream's total so that the snapshot's count restarts from zero.
+    pub(crate) fn reset(&mut self, corrupt_frames: u64) {
+        let callback = self.callback.take();
+        let lifetime_dropped_frames = self.lifetime_dropped_frames;
+
+        *self = Self::new(callback);
+        self.lifetime_dropped_frames = lifetime_dropped_frames;
+        self.corrupt_frames_at_reset = corrupt_frames;
+    }
+
+    /// Records a buffer dequeued from the driver, including ones that are skipped, returning the number of frames the
+    /// driver dropped since the previous buffer.
+    pub(crate) fn record_sequence(&mut self, sequence: u32) -> u32 {
+        let mut dropped = 0;
+
+        if let Some(last_sequence) = self.last_sequence {
+            // The sequence wraps around, and restarts if the stream is restarted
+            let gap = sequence.wrapping_sub(last_sequence).wrapping_sub(1);
+            if gap < u32::MAX / 2 {
+                self.dropped_frames += gap as u64;
+                self.lifetime_dropped_frames += gap as u64;
+                dropped = gap;
+            }
+        }
+
+        self.last_sequence = Some(sequence);
+        dropped
+    }
+
+    /// Records a frame that failed validation.
+    pub(crate) fn record_invalid_frame(&mut self) {
+        self.invalid_frames += 1;
+    }
+
+    /// Records duplicate frames encoded to fill a gap.
+    #[cfg(feature = "openh264")]
+    pub(crate) fn record_duplicated_frames(&mut self, count: u32) {
+        self.duplicated_frames += count as u64;
+    }
+
+    /// Records a frame skipped in favour of a newer one.
+    pub(crate) fn record_stale_frame(&mut self) {
+        self.stale_frames += 1;
+    }
+
+    /// Records a frame returned by the stream, calling the stats callback if it is due.
+    pub(crate) fn record_frame(
+        &mut self,
+        bytes: usize,
+        processing_time: Duration,
+        latency: Option<Duration>,
+        corrupt_frames: u64,
+    ) {
+        let now = Instant::now();
+
+        self.frames += 1;
+        self.bytes += bytes as u64;
+        self.total_processing_time += processing_time;
+        self.max_processing_time = self.max_processing_time.max(processing_time);
+
+        self.last_latency = latency;
+        if let Some(latency) = latency {
+            self.total_latency += latency;
+            self.latency_frames += 1;
+            self.max_latency = Some(self.max_latency.map_or(latency, |max| max.max(latency)));
+        }
+
+        self.window.push_back((now, bytes));
+        while self
+            .window
+            .front()
+            .is_some_and(|(time, _)| now.duration_since(*time) > WINDOW)
+        {
+            self.window.pop_front();
+        }
+
+        let due = self
+            .callback
+            .as_ref()
+            .is_some_and(|callback| now.duration_since(callback.last_called) >= callback.interval);
+
+        if due {
+            let stats = self.snapshot(corrupt_frames);
+
+            if let Some(callback) = &mut self.callback {
+                callback.last_called = now;
+                (callback.callback)(&stats);
+            }
+        }
+    }
+
+    /// The number of frames dropped since the stream was opened, ignoring resets.
+    #[cfg(feature = "metrics")]
+    pub(crate) fn lifetime_dropped_frames(&self) -> u64 {
+        self.lifetime_dropped_frames
+    }
+
+    /// The latency of the most recent frame, see [`StreamStats::mean_latency`].
+    pub(crate) fn last_latency(&self) -> Option<Duration> {
+        self.last_latency
+    }
+
+    pub(crate) fn snapshot(&self, corrupt_frames: u64) -> StreamStats {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.started);
+
+        // Rates are averaged over the time since the stats started if it is shorter than the window
+        let rate = |window: Duration| {
+            let frames = self
+                .window
+                .iter()
+                .rev()
+                .take_while(|(time, _)| now.duration_since(*time) <= window);
+
+            let (count, bytes) = frames.fold((0, 0), |(count, bytes), (_, size)| {
+                (count + 1, bytes + size)
+            });
+            let secs = window.min(elapsed).as_secs_f64();
+
+            match secs {
+                secs if secs > 0.0 => (count as f64 / secs, bytes as f64 * 8.0 / secs),
+                _ => (0.0, 0.0),
+            }
+        };
+
+        let (fps_1s, _) = rate(Duration::from_secs(1));
+        let (fps_10s, bitrate_10s) = rate(WINDOW);
+
+        StreamStats {
+            frames: self.frames,
+            dropped_frames: self.dropped_frames,
+            corrupt_frames: corrupt_frames - self.corrupt_frames_at_reset,
+            invalid_frames: self.invalid_frames,
+            stale_frames: self.stale_frames,
+            duplicated_frames: self.duplicated_frames,
+            bytes: self.bytes,
+            fps_1s,
+            fps_10s,
+            bitrate_10s,
+            mean_processing_time: match self.frames {
+                0 => Duration::ZERO,
+                frames => Duration::from_nanos(
+                    (self.total_processing_time.as_nanos() / frames as u128) as u64,
+                ),
+            },
+            max_processing_time: self.max_processing_time,
+            mean_latency: (self.latency_frames > 0)
+                .then(|| self.total_latency / self.latency_frames),
+            max_latency: self.max_latency,
+            elapsed,
+        }
+    }
+}
+
+/// The current CLOCK_MONOTONIC time, which V4L2 timestamps are usually relative to.
+pub(crate) fn monotonic_now() -> Duration {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `now` is a valid timespec and CLOCK_MONOTONIC is always supported on Linux
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now);
+    }
+    Duration::new(now.tv_sec as u64, now.tv_nsec as u32)
+}
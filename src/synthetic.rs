@@ -0,0 +1,269 @@
+use crate::{EncoderOptions, FrameSource, StreamError, YUVBuffer, YUVFrame};
+use std::time::{Duration, Instant};
+
+/// The image generated by a [`SyntheticSource`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pattern {
+    /// Static 75% color bars.
+    ColorBars,
+    /// A white box bouncing around a grey background, eg. for testing motion detection.
+    MovingBox,
+    /// The frame number encoded as 32 vertical black (0) and white (1) stripes, most significant bit first. Read it
+    /// back with [`SyntheticSource::read_frame_counter`].
+    FrameCounter,
+}
+
+/// (Y, U, V) values of the 75% color bars in BT.601 limited range.
+const COLOR_BARS: [(u8, u8, u8); 8] = [
+    (180, 128, 128),
+    (162, 44, 142),
+    (131, 156, 44),
+    (112, 72, 58),
+    (84, 184, 198),
+    (65, 100, 212),
+    (35, 212, 114),
+    (16, 128, 128),
+];
+
+/// Generates deterministic frames and encodes them with openh264 in the same way as a transcoded camera, eg. for
+/// testing without a camera. Frames are generated as fast as possible unless `realtime` is enabled.
+pub struct SyntheticSource {
+    pattern: Pattern,
+    encoder: openh264::encoder::Encoder,
+    yuv_buffer: YUVBuffer,
+    width: usize,
+    height: usize,
+    fps: f64,
+    frame_number: u32,
+    realtime: bool,
+    started_at: Option<Instant>,
+}
+
+// SAFETY: See the Send impl for EncoderMode, the encoder is exclusively owned by the source.
+unsafe impl Send for SyntheticSource {}
+
+impl SyntheticSource {
+    /// Creates a source of `width` x `height` frames.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the width or height is odd.
+    pub fn new(width: u32, height: u32, fps: f64, pattern: Pattern) -> Result<Self, StreamError> {
+        assert!(
+            width.is_multiple_of(2) && height.is_multiple_of(2),
+            "The width and height must be even"
+        );
+
+        let encoder = EncoderOptions::default()
+            .frame_rate_or(fps as f32)
+            .build(width, height)?;
+
+        Ok(Self {
+            pattern,
+            encoder,
+            yuv_buffer: YUVBuffer::new(width as usize, height as usize),
+            width: width as usize,
+            height: height as usize,
+            fps,
+            frame_number: 0,
+            realtime: false,
+            started_at: None,
+        })
+    }
+
+    /// Paces the frames to the frame rate, as a camera would deliver them.
+    pub fn realtime(mut self, realtime: bool) -> Self {
+        self.realtime = realtime;
+        self
+    }
+
+    /// The number of the next frame to be generated, starting at 0.
+    pub fn frame_number(&self) -> u32 {
+        self.frame_number
+    }
+
+    /// Reads the frame number from a (possibly decoded) frame generated with [`Pattern::FrameCounter`].
+    ///
+    /// Each stripe must survive compression so frames should be at least 64 pixels wide.
+    pub fn read_frame_counter(frame: &YUVFrame) -> u32 {
+        let (y_stride, _, _) = frame.strides();
+        let row = &frame.y()[frame.height() / 2 * y_stride..];
+        let stripe_width = frame.width() / 32;
+
+        (0..32).fold(0, |counter, bit| {
+            let luma = row[bit * stripe_width + stripe_width / 2];
+            (counter << 1) | (luma >= 128) as u32
+        })
+    }
+
+    fn draw(&mut self) {
+        let (width, height) = (self.width, self.height);
+        let frame_number = self.frame_number;
+
+        // Fills the luma and chroma planes from a function of the (luma) pixel coordinates
+        let mut fill = |pixel: &dyn Fn(usize, usize) -> (u8, u8, u8)| {
+            let buffer = &mut self.yuv_buffer;
+
+            for (i, y) in buffer.y_mut().iter_mut().enumerate() {
+                *y = pixel(i % width, i / width).0;
+            }
+            for (i, u) in buffer.u_mut().iter_mut().enumerate() {
+                *u = pixel(i % (width / 2) * 2, i / (width / 2) * 2).1;
+            }
+            for (i, v) in buffer.v_mut().iter_mut().enumerate() {
+                *v = pixel(i % (width / 2) * 2, i / (width / 2) * 2).2;
+            }
+        };
+
+        match self.pattern {
+            Pattern::ColorBars => fill(&|x, _| COLOR_BARS[x * COLOR_BARS.len() / width]),
+            Pattern::MovingBox => {
+                let size = (width.min(height) / 4).max(2);
+                let box_x = bounce(frame_number as usize * 4, width - size);
+                let box_y = bounce(frame_number as usize * 3, height - size);
+
+                fill(&|x, y| {
+                    let in_box =
+                        (box_x..box_x + size).contains(&x) && (box_y..box_y + size).contains(&y);
+
+                    if in_box {
+                        (235, 128, 128)
+                    } else {
+                        (96, 128, 128)
+                    }
+                })
+            }
+            Pattern::FrameCounter => {
+                let stripe_width = (width / 32).max(1);
+
+                fill(&|x, _| {
+                    let bit = (x / stripe_width).min(31);
+
+                    if frame_number >> (31 - bit) & 1 == 1 {
+                        (235, 128, 128)
+                    } else {
+                        (16, 128, 128)
+                    }
+                })
+            }
+        }
+    }
+}
+
+/// Moves back and forth between 0 and `max`.
+fn bounce(position: usize, max: usize) -> usize {
+    if max == 0 {
+        return 0;
+    }
+
+    let position = position % (2 * max);
+    if position <= max {
+        position
+    } else {
+        2 * max - position
+    }
+}
+
+impl FrameSource for SyntheticSource {
+    fn next(
+        &mut self,
+        get_yuv_frame: bool,
+    ) -> Result<(Vec<u8>, Option<YUVFrame<'_>>), StreamError> {
+        if self.realtime {
+            let started_at = *self.started_at.get_or_insert_with(Instant::now);
+            let due = started_at + Duration::from_secs_f64(self.frame_number as f64 / self.fps);
+
+            if let Some(delay) = due.checked_duration_since(Instant::now()) {
+                std::thread::sleep(delay);
+            }
+        }
+
+        self.draw();
+        self.frame_number = self.frame_number.wrapping_add(1);
+
+        let mut h264_bytes = Vec::new();
+        self.encoder
+            .encode(&self.yuv_buffer)?
+            .write_vec(&mut h264_bytes);
+
+        let yuv = get_yuv_frame.then_some(YUVFrame::Borrowed(&self.yuv_buffer));
+
+        Ok((h264_bytes, yuv))
+    }
+
+    fn width(&self) -> u32 {
+        self.width as u32
+    }
+
+    fn height(&self) -> u32 {
+        self.height as u32
+    }
+
+    fn fps(&self) -> f64 {
+        self.fps
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::nal::{self, NalType, NalUnits};
+    use openh264::decoder::Decoder;
+
+    /// Encodes `count` frames of the frame counter pattern as access units, with a key frame every `keyframe_interval`
+    /// frames (including the first).
+    pub(crate) fn access_units(
+        width: u32,
+        height: u32,
+        count: usize,
+        keyframe_interval: u32,
+    ) -> Vec<Vec<u8>> {
+        let mut source = SyntheticSource::new(width, height, 30.0, Pattern::FrameCounter).unwrap();
+        source.encoder = EncoderOptions::default()
+            .frame_rate(30.0)
+            .keyframe_interval(keyframe_interval)
+            .build(width, height)
+            .unwrap();
+
+        (0..count).map(|_| source.next(false).unwrap().0).collect()
+    }
+
+    #[test]
+    fn encodes_frames_that_decode_back() {
+        let access_units = access_units(320, 240, 100, 30);
+        let mut decoder = Decoder::new().unwrap();
+
+        for (frame_number, access_unit) in access_units.iter().enumerate() {
+            let is_keyframe = frame_number % 30 == 0;
+            assert_eq!(
+                nal::is_keyframe(access_unit),
+                is_keyframe,
+                "Frame {frame_number} is a key frame"
+            );
+            if is_keyframe {
+                let types: Vec<_> = NalUnits::new(access_unit).map(NalType::of).collect();
+                assert!(types.contains(&NalType::Sps) && types.contains(&NalType::Pps));
+            }
+
+            let yuv = decoder
+                .decode(access_unit)
+                .unwrap()
+                .map(YUVFrame::Decoded)
+                .expect("Every access unit is a frame");
+            assert_eq!((yuv.width(), yuv.height()), (320, 240));
+            assert_eq!(
+                SyntheticSource::read_frame_counter(&yuv),
+                frame_number as u32
+            );
+        }
+    }
+
+    #[test]
+    fn lends_its_yuv_buffer() {
+        let mut source = SyntheticSource::new(64, 64, 30.0, Pattern::FrameCounter).unwrap();
+        let (_, yuv) = source.next(true).unwrap();
+        let yuv = yuv.unwrap();
+        assert!(matches!(yuv, YUVFrame::Borrowed(_)));
+        assert_eq!(SyntheticSource::read_frame_counter(&yuv), 0);
+    }
+}
@@ -0,0 +1,332 @@
+use crate::{FrameMeta, OwnedWebcamH264Stream, StreamError, YUVBuffer};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// An H264 access unit or JPEG delivered to a [`StreamTee`] sink. The bytes are shared between sinks.
+pub type EncodedFrame = (FrameMeta, Arc<[u8]>);
+
+/// Identifies a sink registered with a [`StreamTee`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SinkId(u64);
+
+/// Captures a stream on a background thread and delivers its frames to any number of sinks.
+///
+/// Each sink has a bounded queue. When a sink's queue is full its frames are dropped (and counted) rather than stalling
+/// the capture. After dropping a frame H264 sinks skip ahead to the next key frame so that their bitstream remains
+/// decodable, and new H264 sinks start at a key frame.
+///
+/// YUV and JPEG frames are only decoded / encoded while a sink is due for one.
+pub struct StreamTee {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<Result<OwnedWebcamH264Stream, StreamError>>>,
+}
+
+#[derive(Default)]
+struct Shared {
+    sinks: Mutex<Vec<Sink>>,
+    next_id: AtomicU64,
+    stop: AtomicBool,
+}
+
+struct Sink {
+    id: SinkId,
+    kind: SinkKind,
+    dropped_frames: u64,
+}
+
+enum SinkKind {
+    H264 {
+        sender: SyncSender<EncodedFrame>,
+        awaiting_keyframe: bool,
+    },
+    Yuv {
+        sender: SyncSender<(FrameMeta, Arc<YUVBuffer>)>,
+        schedule: Schedule,
+    },
+    Jpeg {
+        sender: SyncSender<EncodedFrame>,
+        schedule: Schedule,
+        quality: u8,
+    },
+}
+
+struct Schedule {
+    interval: Duration,
+    next_frame: Instant,
+}
+
+impl Schedule {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            next_frame: Instant::now(),
+        }
+    }
+
+    fn is_due(&self, now: Instant) -> bool {
+        self.next_frame <= now
+    }
+
+    fn advance(&mut self, now: Instant) {
+        self.next_frame = now + self.interval;
+    }
+}
+
+impl StreamTee {
+    /// Starts capturing `stream` on a new thread.
+    pub fn spawn(stream: OwnedWebcamH264Stream) -> Self {
+        let shared = Arc::new(Shared::default());
+
+        let thread = {
+            let shared = Arc::clone(&shared);
+            std::thread::spawn(move || capture(stream, &shared))
+        };
+
+        Self {
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    /// Adds a sink that receives every H264 access unit, queueing up to `capacity` frames.
+    pub fn add_h264_sink(&self, capacity: usize) -> (SinkId, Receiver<EncodedFrame>) {
+        let (sender, receiver) = sync_channel(capacity);
+        let id = self.add_sink(SinkKind::H264 {
+            sender,
+            awaiting_keyframe: true,
+        });
+
+        (id, receiver)
+    }
+
+    /// Adds a sink that receives a YUV frame at most once every `interval`, queueing up to `capacity` frames.
+    pub fn add_yuv_sink(
+        &self,
+        capacity: usize,
+        interval: Duration,
+    ) -> (SinkId, Receiver<(FrameMeta, Arc<YUVBuffer>)>) {
+        let (sender, receiver) = sync_channel(capacity);
+        let id = self.add_sink(SinkKind::Yuv {
+            sender,
+            schedule: Schedule::new(interval),
+        });
+
+        (id, receiver)
+    }
+
+    /// Adds a sink that receives a JPEG at most once every `interval`, queueing up to `capacity` frames. `quality`
+    /// ranges from 1 to 100.
+    pub fn add_jpeg_sink(
+        &self,
+        capacity: usize,
+        interval: Duration,
+        quality: u8,
+    ) -> (SinkId, Receiver<EncodedFrame>) {
+        let (sender, receiver) = sync_channel(capacity);
+        let id = self.add_sink(SinkKind::Jpeg {
+            sender,
+            schedule: Schedule::new(interval),
+            quality,
+        });
+
+        (id, receiver)
+    }
+
+    fn add_sink(&self, kind: SinkKind) -> SinkId {
+        let id = SinkId(self.shared.next_id.fetch_add(1, Ordering::Relaxed));
+
+        self.shared.sinks.lock().unwrap().push(Sink {
+            id,
+            kind,
+            dropped_frames: 0,
+        });
+
+        id
+    }
+
+    /// Removes a sink, returning false if it was not found. Sinks are also removed when their receiver is dropped.
+    pub fn remove_sink(&self, id: SinkId) -> bool {
+        let mut sinks = self.shared.sinks.lock().unwrap();
+        let len = sinks.len();
+        sinks.retain(|sink| sink.id != id);
+
+        sinks.len() != len
+    }
+
+    /// Returns the number of frames dropped because the sink's queue was full, or `None` if the sink was removed.
+    pub fn dropped_frames(&self, id: SinkId) -> Option<u64> {
+        let sinks = self.shared.sinks.lock().unwrap();
+        sinks
+            .iter()
+            .find(|sink| sink.id == id)
+            .map(|sink| sink.dropped_frames)
+    }
+
+    /// Stops capturing and returns the stream, or the error that stopped the capture thread.
+    ///
+    /// The capture thread stops after its current frame so this blocks until the camera delivers it.
+    pub fn stop(mut self) -> Result<OwnedWebcamH264Stream, StreamError> {
+        self.shared.stop.store(true, Ordering::Relaxed);
+
+        let thread = self.thread.take().expect("capture thread already joined");
+        thread.join().expect("capture thread panicked")
+    }
+}
+
+impl Drop for StreamTee {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+fn capture(
+    mut stream: OwnedWebcamH264Stream,
+    shared: &Shared,
+) -> Result<OwnedWebcamH264Stream, StreamError> {
+    let result = capture_frames(&mut stream, shared);
+
+    // Disconnect the receivers so that sinks see the end of the stream
+    shared.sinks.lock().unwrap().clear();
+
+    result.map(|_| stream)
+}
+
+fn capture_frames(stream: &mut OwnedWebcamH264Stream, shared: &Shared) -> Result<(), StreamError> {
+    let (width, height) = (stream.width as usize, stream.height as usize);
+    let mut keyframe_requested = false;
+
+    while !shared.stop.load(Ordering::Relaxed) {
+        let now = Instant::now();
+
+        let (get_yuv_frame, awaiting_keyframe) = {
+            let sinks = shared.sinks.lock().unwrap();
+
+            let get_yuv_frame = sinks.iter().any(|sink| match &sink.kind {
+                SinkKind::Yuv { schedule, .. } | SinkKind::Jpeg { schedule, .. } => {
+                    schedule.is_due(now)
+                }
+                SinkKind::H264 { .. } => false,
+            });
+
+            let awaiting_keyframe = sinks.iter().any(|sink| {
+                matches!(
+                    sink.kind,
+                    SinkKind::H264 {
+                        awaiting_keyframe: true,
+                        ..
+                    }
+                )
+            });
+
+            (get_yuv_frame, awaiting_keyframe)
+        };
+
+        // Key frames are requested once for any number of waiting sinks
+        if awaiting_keyframe && !keyframe_requested {
+            keyframe_requested = true;
+
+            if let Err(err) = stream.request_keyframe() {
+                warn!(
+                    "Unable to request a key frame for a new H264 sink: {:?}",
+                    err
+                );
+            }
+        }
+
+        let (meta, h264_bytes, yuv_frame) = stream.next_with_meta(get_yuv_frame)?;
+        let h264_bytes: Arc<[u8]> = h264_bytes.into();
+
+        if meta.is_keyframe {
+            keyframe_requested = false;
+        }
+
+        let mut yuv_buffer = None;
+        let mut jpegs: Vec<(u8, Arc<[u8]>)> = Vec::new();
+
+        let mut sinks = shared.sinks.lock().unwrap();
+        let mut disconnected = Vec::new();
+
+        for sink in sinks.iter_mut() {
+            let delivered = match &mut sink.kind {
+                SinkKind::H264 {
+                    sender,
+                    awaiting_keyframe,
+                } => {
+                    if *awaiting_keyframe && !meta.is_keyframe {
+                        continue;
+                    }
+
+                    let delivered = deliver(sender, (meta, Arc::clone(&h264_bytes)));
+                    *awaiting_keyframe = delivered == Delivery::Dropped;
+                    delivered
+                }
+                SinkKind::Yuv { sender, schedule } => {
+                    let Some(yuv_frame) = yuv_frame.as_ref().filter(|_| schedule.is_due(now))
+                    else {
+                        continue;
+                    };
+                    schedule.advance(now);
+
+                    let yuv = yuv_buffer.get_or_insert_with(|| {
+                        Arc::new(YUVBuffer::from_source(yuv_frame.source()))
+                    });
+
+                    deliver(sender, (meta, Arc::clone(yuv)))
+                }
+                SinkKind::Jpeg {
+                    sender,
+                    schedule,
+                    quality,
+                } => {
+                    let Some(yuv_frame) = yuv_frame.as_ref().filter(|_| schedule.is_due(now))
+                    else {
+                        continue;
+                    };
+                    schedule.advance(now);
+
+                    // Each quality is only encoded once per frame
+                    let jpeg = match jpegs.iter().find(|(q, _)| q == quality) {
+                        Some((_, jpeg)) => Arc::clone(jpeg),
+                        None => {
+                            let jpeg: Arc<[u8]> =
+                                yuv_frame.to_jpeg_cropped(width, height, *quality)?.into();
+                            jpegs.push((*quality, Arc::clone(&jpeg)));
+                            jpeg
+                        }
+                    };
+
+                    deliver(sender, (meta, jpeg))
+                }
+            };
+
+            match delivered {
+                Delivery::Sent => {}
+                Delivery::Dropped => sink.dropped_frames += 1,
+                Delivery::Disconnected => disconnected.push(sink.id),
+            }
+        }
+
+        sinks.retain(|sink| !disconnected.contains(&sink.id));
+    }
+
+    Ok(())
+}
+
+#[derive(PartialEq, Eq)]
+enum Delivery {
+    Sent,
+    Dropped,
+    Disconnected,
+}
+
+fn deliver<T>(sender: &SyncSender<T>, frame: T) -> Delivery {
+    match sender.try_send(frame) {
+        Ok(()) => Delivery::Sent,
+        Err(TrySendError::Full(_)) => Delivery::Dropped,
+        Err(TrySendError::Disconnected(_)) => Delivery::Disconnected,
+    }
+}
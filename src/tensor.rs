@@ -0,0 +1,189 @@
+//! Conversion of frames to normalized `f32` tensors for machine learning models.
+
+use crate::resize::{sample_positions, FRACTION_ONE};
+use crate::yuv::YUVSource;
+use crate::ScaleFilter;
+
+/// The order of a tensor's dimensions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TensorLayout {
+    /// Channels, height, width (planar), as used by most PyTorch and ONNX models (the default).
+    #[default]
+    Chw,
+    /// Height, width, channels (interleaved), as used by most TensorFlow models.
+    Hwc,
+}
+
+/// The channels of a tensor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TensorColor {
+    /// Red, green and blue channels (the default).
+    #[default]
+    Rgb,
+    /// A single luma channel.
+    Gray,
+}
+
+/// How a tensor's 0 to 255 pixel values are scaled.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Normalize {
+    /// Leave the values from 0 to 255.
+    None,
+    /// Scale the values to 0 to 1 (the default).
+    #[default]
+    UnitRange,
+    /// Scale the values to 0 to 1 and then compute `(value - mean) / std` for each channel, eg. with the ImageNet
+    /// statistics. Gray tensors use the first channel's statistics.
+    MeanStd { mean: [f32; 3], std: [f32; 3] },
+}
+
+/// The size and format of a tensor produced by [`YUVFrame::write_tensor`](crate::YUVFrame::write_tensor).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TensorSpec {
+    pub width: usize,
+    pub height: usize,
+    pub layout: TensorLayout,
+    pub color: TensorColor,
+    pub normalize: Normalize,
+    /// How the frame is resampled to the tensor's size.
+    pub filter: ScaleFilter,
+}
+
+impl TensorSpec {
+    /// A CHW RGB tensor normalized to 0 to 1 and bilinearly scaled to `width` x `height`.
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            layout: TensorLayout::default(),
+            color: TensorColor::default(),
+            normalize: Normalize::default(),
+            filter: ScaleFilter::default(),
+        }
+    }
+
+    pub fn channels(&self) -> usize {
+        match self.color {
+            TensorColor::Rgb => 3,
+            TensorColor::Gray => 1,
+        }
+    }
+
+    /// The number of `f32` values in the tensor.
+    pub fn element_count(&self) -> usize {
+        self.channels() * self.width * self.height
+    }
+
+    /// The tensor's shape in the order of its layout.
+    pub fn shape(&self) -> (usize, usize, usize) {
+        match self.layout {
+            TensorLayout::Chw => (self.channels(), self.height, self.width),
+            TensorLayout::Hwc => (self.height, self.width, self.channels()),
+        }
+    }
+}
+
+/// Scales, converts and normalizes the source into `out` in a single pass over the tensor.
+pub(crate) fn write_tensor<T: YUVSource + ?Sized>(source: &T, spec: &TensorSpec, out: &mut [f32]) {
+    assert_eq!(
+        out.len(),
+        spec.element_count(),
+        "Tensor buffer does not match the spec's dimensions"
+    );
+
+    let (width, height) = (source.width() as usize, source.height() as usize);
+    let luma_columns = sample_positions(width, spec.width);
+    let luma_rows = sample_positions(height, spec.height);
+    let chroma_columns = sample_positions(width / 2, spec.width);
+    let chroma_rows = sample_positions(height / 2, spec.height);
+
+    // Normalization is folded into a multiply and add for each channel
+    let (scale, mean, std) = match spec.normalize {
+        Normalize::None => (1.0, [0.0; 3], [1.0; 3]),
+        Normalize::UnitRange => (1.0 / 255.0, [0.0; 3], [1.0; 3]),
+        Normalize::MeanStd { mean, std } => (1.0 / 255.0, mean, std),
+    };
+    let gain: [f32; 3] = std::array::from_fn(|channel| scale / std[channel]);
+    let offset: [f32; 3] = std::array::from_fn(|channel| -mean[channel] / std[channel]);
+
+    // The distance between a pixel's channels in the output
+    let channel_step = match spec.layout {
+        TensorLayout::Chw => spec.width * spec.height,
+        TensorLayout::Hwc => 1,
+    };
+
+    for y in 0..spec.height {
+        let luma = Rows::new(source.y(), source.y_stride() as usize, luma_rows[y]);
+        let u = Rows::new(source.u(), source.u_stride() as usize, chroma_rows[y]);
+        let v = Rows::new(source.v(), source.v_stride() as usize, chroma_rows[y]);
+
+        for x in 0..spec.width {
+            let index = match spec.layout {
+                TensorLayout::Chw => y * spec.width + x,
+                TensorLayout::Hwc => (y * spec.width + x) * spec.channels(),
+            };
+
+            // The same BT.601 limited range coefficients as `YUVFrame::to_rgb`
+            let luma = (luma.sample(luma_columns[x], spec.filter) - 16.0) * (255.0 / 219.0);
+
+            let pixel = match spec.color {
+                TensorColor::Gray => [luma, 0.0, 0.0],
+                TensorColor::Rgb => {
+                    let u = u.sample(chroma_columns[x], spec.filter) - 128.0;
+                    let v = v.sample(chroma_columns[x], spec.filter) - 128.0;
+
+                    [
+                        luma + 1.596 * v,
+                        luma - 0.392 * u - 0.813 * v,
+                        luma + 2.017 * u,
+                    ]
+                }
+            };
+
+            for (channel, value) in pixel.into_iter().take(spec.channels()).enumerate() {
+                out[index + channel * channel_step] =
+                    value.clamp(0.0, 255.0) * gain[channel] + offset[channel];
+            }
+        }
+    }
+}
+
+/// The two rows of a plane that a row of the tensor is interpolated from.
+struct Rows<'a> {
+    top: &'a [u8],
+    bottom: &'a [u8],
+    /// The fixed point weight of the bottom row.
+    weight: u32,
+}
+
+impl<'a> Rows<'a> {
+    fn new(plane: &'a [u8], stride: usize, (y0, y1, fy): (usize, usize, u32)) -> Self {
+        Self {
+            top: &plane[y0 * stride..],
+            bottom: &plane[y1 * stride..],
+            weight: fy,
+        }
+    }
+
+    /// Samples the rows at a position from [`sample_positions`].
+    fn sample(&self, (x0, x1, fx): (usize, usize, u32), filter: ScaleFilter) -> f32 {
+        let fy = self.weight;
+
+        match filter {
+            ScaleFilter::Nearest => {
+                let row = if fy >= FRACTION_ONE / 2 {
+                    self.bottom
+                } else {
+                    self.top
+                };
+                row[if fx >= FRACTION_ONE / 2 { x1 } else { x0 }] as f32
+            }
+            ScaleFilter::Bilinear => {
+                let lerp = |row: &[u8]| row[x0] as u32 * (FRACTION_ONE - fx) + row[x1] as u32 * fx;
+                let value = lerp(self.top) * (FRACTION_ONE - fy) + lerp(self.bottom) * fy;
+
+                value as f32 * (1.0 / (FRACTION_ONE * FRACTION_ONE) as f32)
+            }
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use crate::{EncoderOptions, FrameAccumulator, StreamError, YUVFrame};
+use chrono::{DateTime, Duration, Utc};
+use openh264::encoder::Encoder;
+
+/// Re-encodes a subset of a stream's YUV frames as a separate timelapse H264 stream, capturing one frame every
+/// `frame_period`.
+///
+/// The timelapse is encoded for playback at 30fps by default regardless of how often frames are captured.
+pub struct TimelapseRecorder {
+    frame_period: Duration,
+    width: u32,
+    height: u32,
+    fps: f32,
+    encoder_options: EncoderOptions,
+    encoder: Option<Encoder>,
+    next_frame: Option<DateTime<Utc>>,
+    accumulator: Option<FrameAccumulator>,
+}
+
+impl TimelapseRecorder {
+    /// Creates a recorder for frames of the given dimensions (eg. the stream's `width` and `height`).
+    pub fn new(frame_period: Duration, width: u32, height: u32) -> Self {
+        Self {
+            frame_period,
+            width,
+            height,
+            fps: 30.0,
+            encoder_options: EncoderOptions::default(),
+            encoder: None,
+            next_frame: None,
+            accumulator: None,
+        }
+    }
+
+    /// Sets the frame rate the timelapse will be played back at (defaults to 30fps).
+    pub fn playback_fps(mut self, fps: f32) -> Self {
+        self.fps = fps;
+        self
+    }
+
+    /// Configures the timelapse's H264 encoder. The frame rate hint defaults to the playback frame rate.
+    pub fn encoder_options(mut self, encoder_options: EncoderOptions) -> Self {
+        self.encoder_options = encoder_options;
+        self
+    }
+
+    /// Averages frames before they are recorded to reduce noise, eg. `FrameAccumulator::new(width, height, 10)` records
+    /// the average of the 10 frames pushed from when each timelapse frame is due. An
+    /// [exponential](FrameAccumulator::exponential) accumulator is fed every frame pushed and its running average is
+    /// recorded instead.
+    pub fn accumulator(mut self, accumulator: FrameAccumulator) -> Self {
+        self.accumulator = Some(accumulator);
+        self
+    }
+
+    /// The frame rate the timelapse is encoded for, eg. to pass to [`Mp4Writer::create`](crate::mp4::Mp4Writer::create).
+    pub fn fps(&self) -> f32 {
+        self.fps
+    }
+
+    /// Adds a frame captured at `now` to the timelapse if a timelapse frame is due, returning its H264 bytes.
+    ///
+    /// Returns `None` if the frame was skipped, or if it was added to the accumulator and the averaged frame isn't ready
+    /// yet.
+    pub fn push(
+        &mut self,
+        yuv: &YUVFrame,
+        now: DateTime<Utc>,
+    ) -> Result<Option<Vec<u8>>, StreamError> {
+        let due = self.next_frame.is_none_or(|next_frame| now >= next_frame);
+
+        let averaged;
+        let yuv = match &mut self.accumulator {
+            // Frames are only averaged once a timelapse frame is due, except for running averages
+            Some(accumulator) if due || accumulator.is_exponential() => {
+                match accumulator.push(yuv)? {
+                    Some(average) if due => {
+                        averaged = YUVFrame::Buffer(average);
+                        &averaged
+                    }
+                    _ => return Ok(None),
+                }
+            }
+            _ if !due => return Ok(None),
+            _ => yuv,
+        };
+
+        if (yuv.width(), yuv.height()) != (self.width as usize, self.height as usize) {
+            return Err(StreamError::FrameSizeMismatch {
+                expected: (self.width, self.height),
+                actual: (yuv.width() as u32, yuv.height() as u32),
+            });
+        }
+
+        let encoder = match &mut self.encoder {
+            Some(encoder) => encoder,
+            encoder => encoder.insert(
+                self.encoder_options
+                    .frame_rate_or(self.fps)
+                    .build(self.width, self.height)?,
+            ),
+        };
+
+        let mut h264_bytes = Vec::new();
+        yuv.encode_using(encoder)?.write_vec(&mut h264_bytes);
+
+        // Frames are scheduled relative to the previous one so the capture interval doesn't drift, unless the
+        // recorder has fallen more than a whole period behind
+        let next_frame = self.next_frame.unwrap_or(now) + self.frame_period;
+        self.next_frame = Some(if next_frame <= now {
+            now + self.frame_period
+        } else {
+            next_frame
+        });
+
+        Ok(Some(h264_bytes))
+    }
+
+    /// Finishes the timelapse, returning any H264 bytes still buffered by the encoder.
+    ///
+    /// openh264 encodes each frame as it is pushed so this is currently always empty, but callers should still write
+    /// the result to the end of the timelapse.
+    pub fn finish(self) -> Result<Vec<u8>, StreamError> {
+        Ok(Vec::new())
+    }
+}
@@ -0,0 +1,81 @@
+//! Async support for tokio applications, enabled with the `tokio` feature.
+
+use crate::{Frame, OwnedWebcamH264Stream, StreamError};
+use ::tokio::task::{self, JoinHandle};
+use futures_core::Stream;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// An async [`Stream`] of frames from a webcam.
+///
+/// Frames are read on tokio's blocking thread pool. Each frame is only read once the previous one has been taken so
+/// the camera's buffers are never queued up behind a slow consumer.
+///
+/// The stream is cancellation safe: if it is dropped while a frame is being read the read finishes in the
+/// background, after which the camera is stopped and its buffers are released.
+pub struct AsyncWebcamH264Stream {
+    state: State,
+    get_yuv_frames: bool,
+}
+
+enum State {
+    Idle(Box<OwnedWebcamH264Stream>),
+    Reading(ReadTask),
+    Done,
+}
+
+type ReadTask = JoinHandle<(Box<OwnedWebcamH264Stream>, Result<Frame, StreamError>)>;
+
+impl AsyncWebcamH264Stream {
+    pub fn new(stream: OwnedWebcamH264Stream) -> Self {
+        Self {
+            state: State::Idle(Box::new(stream)),
+            get_yuv_frames: false,
+        }
+    }
+
+    /// Sets whether a YUV frame is requested with each frame, as with `next(true)`.
+    pub fn get_yuv_frames(mut self, get_yuv_frames: bool) -> Self {
+        self.get_yuv_frames = get_yuv_frames;
+        self
+    }
+}
+
+impl Stream for AsyncWebcamH264Stream {
+    type Item = Result<Frame, StreamError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            match std::mem::replace(&mut this.state, State::Done) {
+                State::Idle(mut stream) => {
+                    let get_yuv_frame = this.get_yuv_frames;
+
+                    this.state = State::Reading(task::spawn_blocking(move || {
+                        let result = stream.next_frame(get_yuv_frame);
+                        (stream, result)
+                    }));
+                }
+                State::Reading(mut read_task) => {
+                    let Poll::Ready(joined) = Pin::new(&mut read_task).poll(cx) else {
+                        this.state = State::Reading(read_task);
+                        return Poll::Pending;
+                    };
+
+                    match joined {
+                        Ok((stream, result)) => {
+                            this.state = State::Idle(stream);
+                            return Poll::Ready(Some(result));
+                        }
+                        Err(err) if err.is_panic() => std::panic::resume_unwind(err.into_panic()),
+                        // The runtime is shutting down
+                        Err(_) => return Poll::Ready(None),
+                    }
+                }
+                State::Done => return Poll::Ready(None),
+            }
+        }
+    }
+}
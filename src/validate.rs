@@ -0,0 +1,243 @@
+use crate::nal::{NalType, NalUnits, ParameterSets};
+#[cfg(feature = "openh264")]
+use crate::recovery::DecoderRecovery;
+use crate::FrameMeta;
+#[cfg(feature = "openh264")]
+use crate::StreamError;
+
+/// A problem found in an access unit by a [`BitstreamValidator`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationIssue {
+    /// The access unit contains no NAL units, eg. because it has no start codes.
+    NoNalUnits,
+    /// The access unit starts with `bytes` bytes that are not preceded by a start code.
+    LeadingGarbage { bytes: usize },
+    /// A NAL unit's forbidden_zero_bit is set, which means it is corrupt.
+    ForbiddenBitSet { nal_type: NalType },
+    /// A NAL unit of a reserved or unspecified type, which cameras and encoders never produce.
+    UnknownNalType(u8),
+    /// An IDR slice arrived before an SPS or PPS was seen, so it cannot be decoded.
+    MissingParameterSets { sps: bool, pps: bool },
+    /// The key frame failed to decode, see [`BitstreamValidator::decode_keyframes`].
+    UndecodableKeyframe(String),
+}
+
+impl std::fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoNalUnits => write!(f, "no NAL units"),
+            Self::LeadingGarbage { bytes } => {
+                write!(f, "{} bytes before the first start code", bytes)
+            }
+            Self::ForbiddenBitSet { nal_type } => {
+                write!(f, "forbidden_zero_bit set in a {:?} NAL unit", nal_type)
+            }
+            Self::UnknownNalType(nal_type) => write!(f, "unknown NAL unit type {}", nal_type),
+            Self::MissingParameterSets { sps, pps } => {
+                let missing = match (sps, pps) {
+                    (true, true) => "SPS and PPS",
+                    (true, false) => "SPS",
+                    _ => "PPS",
+                };
+                write!(f, "IDR slice before the first {}", missing)
+            }
+            Self::UndecodableKeyframe(err) => write!(f, "key frame failed to decode: {}", err),
+        }
+    }
+}
+
+/// The result of validating an access unit.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ValidationReport {
+    /// True if the access unit contains an IDR slice.
+    pub is_keyframe: bool,
+    pub issues: Vec<ValidationIssue>,
+}
+
+impl ValidationReport {
+    /// True if no issues were found.
+    pub fn is_valid(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// Checks an H264 bitstream's access units for signs of corruption, eg. to alert on a camera emitting garbage instead
+/// of finding out when a recording turns out to be undecodable.
+///
+/// Each access unit is checked for start codes, forbidden_zero_bit violations, unknown NAL unit types and key frames
+/// that precede the stream's parameter sets. Key frames can also be decoded with a separate decoder to confirm that
+/// they are decodable. Use it standalone, or enable it on a stream with
+/// [`StreamBuilder::validate_bitstream`](crate::StreamBuilder::validate_bitstream).
+#[derive(Default)]
+pub struct BitstreamValidator {
+    parameter_sets: ParameterSets,
+    #[cfg(feature = "openh264")]
+    decode_keyframes: bool,
+    #[cfg(feature = "openh264")]
+    decoder: Option<openh264::decoder::Decoder>,
+    #[cfg(feature = "openh264")]
+    recovery: DecoderRecovery,
+    access_units: u64,
+    invalid_access_units: u64,
+}
+
+// SAFETY: See the Send impl for EncoderMode, the decoder is exclusively owned by the validator.
+#[cfg(feature = "openh264")]
+unsafe impl Send for BitstreamValidator {}
+
+impl BitstreamValidator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decodes each key frame (with the most recent parameter sets) to check that it is decodable. This costs a decode
+    /// per GOP, so it is off by default. Requires the `openh264` feature.
+    #[cfg(feature = "openh264")]
+    pub fn decode_keyframes(mut self, decode_keyframes: bool) -> Self {
+        self.decode_keyframes = decode_keyframes;
+        self
+    }
+
+    /// Checks an Annex-B access unit. Access units must be validated in stream order since parameter sets are tracked
+    /// between them.
+    pub fn validate(&mut self, access_unit: &[u8]) -> ValidationReport {
+        let mut report = ValidationReport::default();
+
+        let leading = leading_bytes(access_unit);
+        if leading > 0 {
+            report
+                .issues
+                .push(ValidationIssue::LeadingGarbage { bytes: leading });
+        }
+
+        let mut nal_units = 0;
+        for nal in NalUnits::new(access_unit) {
+            nal_units += 1;
+            let nal_type = NalType::of(nal);
+
+            if nal[0] & 0x80 != 0 {
+                report
+                    .issues
+                    .push(ValidationIssue::ForbiddenBitSet { nal_type });
+            }
+
+            match nal_type {
+                NalType::Other(other) if !is_known_nal_type(other) => {
+                    report.issues.push(ValidationIssue::UnknownNalType(other));
+                }
+                NalType::Idr if !report.is_keyframe => {
+                    report.is_keyframe = true;
+
+                    // Parameter sets earlier in this access unit count, so they are cached up to the IDR slice
+                    let (sps, pps) = (
+                        self.parameter_sets.sps.is_none(),
+                        self.parameter_sets.pps.is_none(),
+                    );
+                    if sps || pps {
+                        report
+                            .issues
+                            .push(ValidationIssue::MissingParameterSets { sps, pps });
+                    }
+                }
+                NalType::Sps if nal.len() >= 4 => self.parameter_sets.sps = Some(nal.to_vec()),
+                NalType::Pps => self.parameter_sets.pps = Some(nal.to_vec()),
+                _ => {}
+            }
+        }
+
+        if nal_units == 0 {
+            report.issues.push(ValidationIssue::NoNalUnits);
+        }
+
+        #[cfg(feature = "openh264")]
+        if self.decode_keyframes && report.is_keyframe && report.is_valid() {
+            if let Err(err) = self.decode(access_unit) {
+                let reason = match err {
+                    StreamError::DecodeFailed { reason } => reason,
+                    err => err.to_string(),
+                };
+                report
+                    .issues
+                    .push(ValidationIssue::UndecodableKeyframe(reason));
+            }
+        }
+
+        self.access_units += 1;
+        if !report.is_valid() {
+            self.invalid_access_units += 1;
+        }
+
+        report
+    }
+
+    /// The number of access units validated.
+    pub fn access_units(&self) -> u64 {
+        self.access_units
+    }
+
+    /// The number of access units that had at least one issue.
+    pub fn invalid_access_units(&self) -> u64 {
+        self.invalid_access_units
+    }
+
+    /// Decodes a key frame on its own, with the cached parameter sets prepended if it does not contain them.
+    #[cfg(feature = "openh264")]
+    fn decode(&mut self, access_unit: &[u8]) -> Result<(), StreamError> {
+        let decoder = match &mut self.decoder {
+            Some(decoder) => decoder,
+            None => self.decoder.insert(openh264::decoder::Decoder::new()?),
+        };
+
+        let mut h264_bytes = access_unit.to_vec();
+        self.parameter_sets.prepend_if_missing(&mut h264_bytes, 0);
+        self.recovery
+            .decode(decoder, &h264_bytes, &self.parameter_sets)?;
+
+        Ok(())
+    }
+}
+
+/// The number of bytes before the first start code, ignoring the zeros of a 4 byte start code.
+fn leading_bytes(access_unit: &[u8]) -> usize {
+    let first_start_code = access_unit
+        .windows(3)
+        .position(|window| window == [0, 0, 1])
+        .unwrap_or(access_unit.len());
+
+    access_unit[..first_start_code]
+        .iter()
+        .rposition(|&byte| byte != 0)
+        .map_or(0, |last| last + 1)
+}
+
+/// True for NAL unit types outside of [`NalType`]'s named types that H264 encoders can produce: SPS extensions, SVC
+/// and MVC prefixes and slices, and auxiliary slices.
+fn is_known_nal_type(nal_type: u8) -> bool {
+    matches!(nal_type, 13..=15 | 19..=21)
+}
+
+/// Called with each frame that has validation issues, see
+/// [`StreamBuilder::on_invalid_frame`](crate::StreamBuilder::on_invalid_frame).
+pub(crate) type ValidationCallback = Box<dyn FnMut(&FrameMeta, &ValidationReport) + Send>;
+
+/// A stream's validator, see [`StreamBuilder::validate_bitstream`](crate::StreamBuilder::validate_bitstream).
+pub(crate) struct StreamValidation {
+    pub(crate) validator: BitstreamValidator,
+    pub(crate) callback: Option<ValidationCallback>,
+    pub(crate) last_report: Option<ValidationReport>,
+}
+
+impl StreamValidation {
+    /// Validates a frame returned by the stream, calling the callback if it has issues.
+    pub(crate) fn validate(&mut self, meta: &FrameMeta, h264_bytes: &[u8]) -> &ValidationReport {
+        let report = self.validator.validate(h264_bytes);
+
+        if !report.is_valid() {
+            if let Some(callback) = &mut self.callback {
+                callback(meta, &report);
+            }
+        }
+
+        self.last_report.insert(report)
+    }
+}
@@ -0,0 +1,707 @@
+//! Live streaming to browsers over WebSockets, enabled with the `ws_stream` feature.
+//!
+//! The stream is muxed into fragmented MP4 with one fragment per frame, which browsers play with Media Source
+//! Extensions. `GET /` serves a small page that does this, so the stream can be checked end to end by opening the
+//! server's address in a browser.
+
+use crate::mp4::{FragmentedMp4Muxer, Segment};
+use crate::nal::{NalType, NalUnits};
+use crate::FrameMeta;
+use ::tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use ::tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use ::tokio::net::{TcpListener, TcpStream};
+use ::tokio::runtime::Handle;
+use ::tokio::sync::Notify;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// The GUID appended to the client's key in the WebSocket handshake (RFC 6455).
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_HEADER_LINES: usize = 100;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+const INDEX_HTML: &str = r#"<!DOCTYPE html>
+<html>
+<head><title>h264_webcam_stream</title></head>
+<body style="margin: 0; background: black">
+<video id="video" autoplay muted playsinline style="width: 100vw; height: 100vh"></video>
+<script>
+  const video = document.getElementById("video");
+  const source = new MediaSource();
+  video.src = URL.createObjectURL(source);
+
+  source.addEventListener("sourceopen", () => {
+    const socket = new WebSocket(`ws://${location.host}/stream`);
+    socket.binaryType = "arraybuffer";
+
+    let buffer = null;
+    const queue = [];
+    const append = () => {
+      if (buffer && !buffer.updating && queue.length > 0) {
+        buffer.appendBuffer(queue.shift());
+      }
+    };
+
+    socket.onmessage = (event) => {
+      if (typeof event.data === "string") {
+        // The first message is the MIME type, including the stream's codec
+        buffer = source.addSourceBuffer(event.data);
+        buffer.mode = "sequence";
+        buffer.addEventListener("updateend", () => {
+          // Stay close to the live edge and keep the buffer from growing without bound
+          const end = buffer.buffered.length ? buffer.buffered.end(buffer.buffered.length - 1) : 0;
+          if (end - video.currentTime > 1) video.currentTime = end - 0.1;
+          if (!buffer.updating && buffer.buffered.length && video.currentTime - buffer.buffered.start(0) > 30) {
+            buffer.remove(0, video.currentTime - 10);
+          }
+          append();
+        });
+      } else {
+        queue.push(event.data);
+        append();
+      }
+    };
+  });
+</script>
+</body>
+</html>
+"#;
+
+/// Statistics for a client connected to a [`WsStreamer`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientStats {
+    pub addr: SocketAddr,
+    /// The number of fragments (frames) sent to the client.
+    pub sent_fragments: u64,
+    /// The number of fragments dropped because the client couldn't keep up.
+    pub dropped_fragments: u64,
+    /// How many times the client fell behind and skipped ahead to a newer key frame.
+    pub skips: u64,
+    /// The number of fragments waiting to be sent.
+    pub queued: usize,
+}
+
+/// Streams H264 access units to browsers over WebSockets as fragmented MP4, see the [module docs](self).
+///
+/// New clients are sent the cached initialization segment and then fragments from the next key frame, so they can
+/// start decoding straight away. Each client has its own send queue: when a client falls more than
+/// [`max_queued`](Self::max_queued) fragments behind, its queue is discarded and it skips ahead to the next key frame
+/// rather than slowing down the camera or other clients. Requesting a key frame when
+/// [`waiting_for_keyframe`](Self::waiting_for_keyframe) is true gets new and lagging clients going sooner.
+///
+/// The server runs on the tokio runtime it was bound on, with a task for accepting connections and two for each client,
+/// and stops when it is dropped.
+pub struct WsStreamer {
+    shared: Arc<Shared>,
+    local_addr: SocketAddr,
+    muxer: Option<FragmentedMp4Muxer>,
+    // Each fragment is emitted when the next frame is pushed, so this is whether the next fragment is a key frame
+    pending_keyframe: Option<bool>,
+}
+
+struct Shared {
+    // The MIME type and initialization segment, sent to every new client
+    init: Mutex<Option<(String, Arc<[u8]>)>>,
+    clients: Mutex<Vec<Arc<Client>>>,
+    max_queued: AtomicUsize,
+    stop: AtomicBool,
+    // Wakes the accept task when the server is dropped
+    stopped: Notify,
+}
+
+struct Client {
+    addr: SocketAddr,
+    queue: Mutex<ClientQueue>,
+    ready: Notify,
+    closed: AtomicBool,
+    sent_fragments: AtomicU64,
+    dropped_fragments: AtomicU64,
+    skips: AtomicU64,
+}
+
+struct ClientQueue {
+    fragments: VecDeque<Arc<[u8]>>,
+    // Fragments are only queued from a key frame onwards, for new clients and clients that fell behind
+    awaiting_keyframe: bool,
+}
+
+impl WsStreamer {
+    /// Starts listening for connections on `addr`, eg. `"0.0.0.0:8080"`. Must be called from within a tokio runtime.
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let runtime = Handle::try_current().map_err(io::Error::other)?;
+
+        let listener = std::net::TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        listener.set_nonblocking(true)?;
+        let listener = {
+            let _runtime = runtime.enter();
+            TcpListener::from_std(listener)?
+        };
+
+        let shared = Arc::new(Shared {
+            init: Mutex::new(None),
+            clients: Mutex::new(Vec::new()),
+            max_queued: AtomicUsize::new(60),
+            stop: AtomicBool::new(false),
+            stopped: Notify::new(),
+        });
+        runtime.spawn(accept(listener, Arc::clone(&shared)));
+
+        Ok(Self {
+            shared,
+            local_addr,
+            muxer: None,
+            pending_keyframe: None,
+        })
+    }
+
+    /// Sets how many fragments can be queued for a client before it skips ahead to the next key frame (defaults to
+    /// 60, ie. 2 seconds at 30fps).
+    pub fn max_queued(self, max_queued: usize) -> Self {
+        self.shared
+            .max_queued
+            .store(max_queued.max(1), Ordering::Relaxed);
+        self
+    }
+
+    /// The address the server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The number of clients currently connected to the stream.
+    pub fn client_count(&self) -> usize {
+        self.shared.clients.lock().unwrap().len()
+    }
+
+    /// Statistics for each connected client.
+    pub fn client_stats(&self) -> Vec<ClientStats> {
+        self.shared
+            .clients
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|client| ClientStats {
+                addr: client.addr,
+                sent_fragments: client.sent_fragments.load(Ordering::Relaxed),
+                dropped_fragments: client.dropped_fragments.load(Ordering::Relaxed),
+                skips: client.skips.load(Ordering::Relaxed),
+                queued: client.queue.lock().unwrap().fragments.len(),
+            })
+            .collect()
+    }
+
+    /// True if any client is waiting for a key frame, eg. to request one with
+    /// [`WebcamH264Stream::request_keyframe`](crate::WebcamH264Stream::request_keyframe) so that it doesn't have to
+    /// wait for the camera's next GOP.
+    pub fn waiting_for_keyframe(&self) -> bool {
+        self.shared
+            .clients
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|client| client.queue.lock().unwrap().awaiting_keyframe)
+    }
+
+    /// Sends an access unit to every client, timing it using the frame's capture timestamp. Frames before the first
+    /// key frame with parameter sets are skipped.
+    pub fn push_with_meta(&mut self, h264_bytes: &[u8], meta: &FrameMeta) {
+        let is_keyframe = crate::nal::is_keyframe(h264_bytes);
+
+        if self.muxer.is_none() {
+            let Some(muxer) = is_keyframe.then(|| create_muxer(h264_bytes)).flatten() else {
+                return;
+            };
+            self.muxer = Some(muxer);
+        }
+        let Some(muxer) = &mut self.muxer else {
+            return;
+        };
+
+        // The muxer skips access units without any frame data (eg. from an encoder that skipped a frame), so they must
+        // not be counted as the pending fragment
+        let has_frame = NalUnits::new(h264_bytes)
+            .any(|nal| !matches!(NalType::of(nal), NalType::Sps | NalType::Pps | NalType::Aud));
+        if !has_frame {
+            return;
+        }
+
+        for segment in muxer.push_with_meta(h264_bytes, meta) {
+            match segment {
+                Segment::Init(init) => {
+                    let mime_type = mime_type(h264_bytes);
+                    *self.shared.init.lock().unwrap() = Some((mime_type, init.into()));
+                }
+                Segment::Media(fragment) => {
+                    let is_keyframe = self.pending_keyframe.unwrap_or(false);
+                    self.shared.broadcast(fragment.into(), is_keyframe);
+                }
+            }
+        }
+
+        self.pending_keyframe = Some(is_keyframe);
+    }
+}
+
+impl Drop for WsStreamer {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::Relaxed);
+        self.shared.stopped.notify_one();
+
+        for client in self.shared.clients.lock().unwrap().iter() {
+            client.close();
+        }
+    }
+}
+
+impl Shared {
+    fn broadcast(&self, fragment: Arc<[u8]>, is_keyframe: bool) {
+        let max_queued = self.max_queued.load(Ordering::Relaxed);
+
+        for client in self.clients.lock().unwrap().iter() {
+            let mut queue = client.queue.lock().unwrap();
+
+            if queue.awaiting_keyframe {
+                if !is_keyframe {
+                    continue;
+                }
+                queue.awaiting_keyframe = false;
+            }
+
+            if queue.fragments.len() >= max_queued {
+                // The queued fragments are stale by now, so the client skips ahead to the next key frame
+                let dropped = queue.fragments.len() as u64 + 1;
+                queue.fragments.clear();
+                queue.awaiting_keyframe = true;
+
+                client
+                    .dropped_fragments
+                    .fetch_add(dropped, Ordering::Relaxed);
+                client.skips.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+
+            queue.fragments.push_back(Arc::clone(&fragment));
+            drop(queue);
+            client.ready.notify_one();
+        }
+    }
+}
+
+impl Client {
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.ready.notify_one();
+    }
+
+    /// Waits for the next fragment to send, returning `None` once the client or server is closed.
+    async fn next_fragment(&self, stop: &AtomicBool) -> Option<Arc<[u8]>> {
+        loop {
+            if self.closed.load(Ordering::Relaxed) || stop.load(Ordering::Relaxed) {
+                return None;
+            }
+            if let Some(fragment) = self.queue.lock().unwrap().fragments.pop_front() {
+                return Some(fragment);
+            }
+            // Notifications sent since the queue was checked are kept until this waits for them
+            self.ready.notified().await;
+        }
+    }
+}
+
+fn create_muxer(h264_bytes: &[u8]) -> Option<FragmentedMp4Muxer> {
+    let sps = NalUnits::new(h264_bytes).find(|nal| NalType::of(nal) == NalType::Sps)?;
+    let info = crate::sps::parse(sps).ok()?;
+    // Only used for the duration of frames pushed without metadata, which isn't possible here
+    let fps = info.fps_from_vui.unwrap_or(30.0);
+
+    Some(FragmentedMp4Muxer::new(info.width, info.height, fps).fragment_per_frame(true))
+}
+
+fn mime_type(h264_bytes: &[u8]) -> String {
+    let codec = NalUnits::new(h264_bytes)
+        .find(|nal| NalType::of(nal) == NalType::Sps)
+        .and_then(|sps| crate::sps::parse(sps).ok())
+        .map_or_else(|| "avc1.42e01f".to_string(), |info| info.codec_string());
+
+    format!("video/mp4; codecs=\"{codec}\"")
+}
+
+async fn accept(listener: TcpListener, shared: Arc<Shared>) {
+    loop {
+        let result = ::tokio::select! {
+            _ = shared.stopped.notified() => return,
+            result = listener.accept() => result,
+        };
+
+        match result {
+            Ok((stream, addr)) => {
+                let shared = Arc::clone(&shared);
+                ::tokio::spawn(async move {
+                    if let Err(err) = serve_client(stream, addr, &shared).await {
+                        debug!("WebSocket stream client {} error: {:?}", addr, err);
+                    }
+                });
+            }
+            Err(err) => warn!("Failed to accept WebSocket stream client: {:?}", err),
+        }
+    }
+}
+
+/// Fails with a `TimedOut` error if the client takes longer than [`CLIENT_TIMEOUT`].
+async fn timeout<T>(future: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+    ::tokio::time::timeout(CLIENT_TIMEOUT, future)
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+}
+
+/// Reads the request line and the `Sec-WebSocket-Key` header, if any.
+async fn read_request(
+    reader: &mut BufReader<OwnedReadHalf>,
+) -> io::Result<(String, Option<String>)> {
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+
+    let mut websocket_key = None;
+    let mut header = String::new();
+    for _ in 0..MAX_HEADER_LINES {
+        header.clear();
+        if reader.read_line(&mut header).await? <= 2 {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("sec-websocket-key") {
+                websocket_key = Some(value.trim().to_string());
+            }
+        }
+    }
+
+    Ok((request_line, websocket_key))
+}
+
+async fn serve_client(stream: TcpStream, addr: SocketAddr, shared: &Shared) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let (request_line, websocket_key) = timeout(read_request(&mut reader)).await?;
+    let mut parts = request_line.split_whitespace();
+
+    match (parts.next(), parts.next(), websocket_key) {
+        (Some("GET"), Some("/stream"), Some(key)) => {
+            let accept = crate::base64::encode(&sha1(format!("{key}{WEBSOCKET_GUID}").as_bytes()));
+            let response = format!(
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                 Sec-WebSocket-Accept: {accept}\r\n\r\n"
+            );
+            timeout(writer.write_all(response.as_bytes())).await?;
+
+            stream_to_client(reader, writer, addr, shared).await
+        }
+        (Some("GET"), Some("/"), _) => {
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n\r\n\
+                 {INDEX_HTML}",
+                INDEX_HTML.len()
+            );
+            timeout(writer.write_all(response.as_bytes())).await
+        }
+        (Some("GET"), _, _) => write_status(&mut writer, "404 Not Found").await,
+        _ => write_status(&mut writer, "405 Method Not Allowed").await,
+    }
+}
+
+async fn stream_to_client(
+    reader: BufReader<OwnedReadHalf>,
+    mut writer: OwnedWriteHalf,
+    addr: SocketAddr,
+    shared: &Shared,
+) -> io::Result<()> {
+    let client = Arc::new(Client {
+        addr,
+        queue: Mutex::new(ClientQueue {
+            fragments: VecDeque::new(),
+            awaiting_keyframe: true,
+        }),
+        ready: Notify::new(),
+        closed: AtomicBool::new(false),
+        sent_fragments: AtomicU64::new(0),
+        dropped_fragments: AtomicU64::new(0),
+        skips: AtomicU64::new(0),
+    });
+    shared.clients.lock().unwrap().push(Arc::clone(&client));
+
+    // The client's frames are only read to notice when it disconnects
+    let reader = {
+        let client = Arc::clone(&client);
+        ::tokio::spawn(async move {
+            let _ = read_until_closed(reader).await;
+            client.close();
+        })
+    };
+
+    let result = async {
+        let mut sent_init = false;
+
+        while let Some(fragment) = client.next_fragment(&shared.stop).await {
+            // Clients that connect before the first key frame are sent the init segment once it exists
+            if !sent_init {
+                let init = shared.init.lock().unwrap().clone();
+                if let Some((mime_type, init)) = init {
+                    write_frame(&mut writer, OPCODE_TEXT, mime_type.as_bytes()).await?;
+                    write_frame(&mut writer, OPCODE_BINARY, &init).await?;
+                    sent_init = true;
+                }
+            }
+
+            write_frame(&mut writer, OPCODE_BINARY, &fragment).await?;
+            client.sent_fragments.fetch_add(1, Ordering::Relaxed);
+        }
+
+        write_frame(&mut writer, OPCODE_CLOSE, &[]).await
+    }
+    .await;
+
+    reader.abort();
+    let _ = timeout(writer.shutdown()).await;
+    shared
+        .clients
+        .lock()
+        .unwrap()
+        .retain(|other| !Arc::ptr_eq(other, &client));
+
+    result
+}
+
+/// Reads and discards the client's WebSocket frames until it sends a close frame or disconnects.
+async fn read_until_closed(mut reader: BufReader<OwnedReadHalf>) -> io::Result<()> {
+    loop {
+        let mut header = [0u8; 2];
+        reader.read_exact(&mut header).await?;
+
+        let opcode = header[0] & 0x0f;
+        let len = match header[1] & 0x7f {
+            126 => reader.read_u16().await? as u64,
+            127 => reader.read_u64().await?,
+            len => len as u64,
+        };
+        let mask_len = if header[1] & 0x80 != 0 { 4 } else { 0 };
+
+        // Skip the masking key and payload
+        ::tokio::io::copy(
+            &mut (&mut reader).take(len + mask_len),
+            &mut ::tokio::io::sink(),
+        )
+        .await?;
+
+        if opcode == OPCODE_CLOSE {
+            return Ok(());
+        }
+    }
+}
+
+/// Writes an unfragmented, unmasked WebSocket frame.
+async fn write_frame(writer: &mut OwnedWriteHalf, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut header = vec![0x80 | opcode];
+
+    match payload.len() {
+        len if len < 126 => header.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            header.push(126);
+            header.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            header.push(127);
+            header.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+
+    timeout(async {
+        writer.write_all(&header).await?;
+        writer.write_all(payload).await
+    })
+    .await
+}
+
+async fn write_status(writer: &mut OwnedWriteHalf, status: &str) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status
+    );
+    timeout(writer.write_all(response.as_bytes())).await
+}
+
+/// SHA-1, which the WebSocket handshake requires.
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [
+        0x6745_2301,
+        0xEFCD_AB89,
+        0x98BA_DCFE,
+        0x1032_5476,
+        0xC3D2_E1F0,
+    ];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ED9_EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1B_BCDC),
+                _ => (b ^ c ^ d, 0xCA62_C1D6),
+            };
+
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        for (h, value) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(value);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (chunk, word) in digest.chunks_mut(4).zip(h) {
+        chunk.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(all(test, feature = "openh264"))]
+mod tests {
+    use super::*;
+    use crate::synthetic::tests::access_units;
+    use crate::{ClockMapping, TimestampClock};
+
+    fn meta(frame: usize) -> FrameMeta {
+        FrameMeta {
+            timestamp: Duration::from_secs_f64(frame as f64 / 30.0),
+            clock: ClockMapping::measure(TimestampClock::Monotonic),
+            sequence: frame as u32,
+            dropped_since_last: 0,
+            bytesused: 0,
+            is_keyframe: false,
+        }
+    }
+
+    /// Reads a WebSocket frame's opcode and payload.
+    async fn read_message(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let read = async {
+            let mut header = [0u8; 2];
+            stream.read_exact(&mut header).await?;
+            let len = match header[1] {
+                126 => stream.read_u16().await? as usize,
+                127 => stream.read_u64().await? as usize,
+                len => len as usize,
+            };
+            let mut payload = vec![0; len];
+            stream.read_exact(&mut payload).await?;
+            Ok((header[0] & 0x0f, payload))
+        };
+        timeout(read).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn streams_from_the_next_key_frame() {
+        let mut ws = WsStreamer::bind("127.0.0.1:0").unwrap();
+        let access_units = access_units(64, 64, 25, 10);
+        for (i, access_unit) in access_units[..5].iter().enumerate() {
+            ws.push_with_meta(access_unit, &meta(i));
+        }
+
+        // The handshake from RFC 6455
+        let mut stream = TcpStream::connect(ws.local_addr()).await.unwrap();
+        stream
+            .write_all(
+                b"GET /stream HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                  Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            response.push(stream.read_u8().await.unwrap());
+        }
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101 "), "{response}");
+        assert!(
+            response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"),
+            "{response}"
+        );
+
+        while ws.client_count() == 0 {
+            ::tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(ws.waiting_for_keyframe());
+        for (i, access_unit) in access_units.iter().enumerate().skip(5) {
+            ws.push_with_meta(access_unit, &meta(i));
+        }
+        assert!(!ws.waiting_for_keyframe());
+
+        let (opcode, mime_type) = read_message(&mut stream).await;
+        assert_eq!(opcode, OPCODE_TEXT);
+        let mime_type = String::from_utf8(mime_type).unwrap();
+        assert!(
+            mime_type.starts_with("video/mp4; codecs=\"avc1."),
+            "{mime_type}"
+        );
+
+        let (opcode, init) = read_message(&mut stream).await;
+        assert_eq!((opcode, &init[4..8]), (OPCODE_BINARY, &b"ftyp"[..]));
+
+        // Frames 10 to 23 are sent, as each fragment is emitted when the next frame is pushed
+        for _ in 10..24 {
+            let (opcode, fragment) = read_message(&mut stream).await;
+            assert_eq!((opcode, &fragment[4..8]), (OPCODE_BINARY, &b"moof"[..]));
+        }
+
+        drop(ws);
+        assert_eq!(read_message(&mut stream).await.0, OPCODE_CLOSE);
+    }
+
+    #[tokio::test]
+    async fn serves_the_player_page() {
+        let ws = WsStreamer::bind("127.0.0.1:0").unwrap();
+
+        let mut stream = TcpStream::connect(ws.local_addr()).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        timeout(stream.read_to_string(&mut response)).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.ends_with(INDEX_HTML), "{response}");
+    }
+}
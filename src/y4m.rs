@@ -0,0 +1,108 @@
+use crate::{StreamError, YUVFrame};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Writes YUV frames to a YUV4MPEG2 (`.y4m`) file as uncompressed I420, eg. for inspecting decoded frames in mpv or
+/// ffmpeg.
+///
+/// Frames are cropped to the size given when the writer was created, dropping any decoder padding (eg. a 1920x1080
+/// stream decoded as 1920x1088).
+pub struct Y4mWriter<W: Write> {
+    writer: W,
+    width: usize,
+    height: usize,
+}
+
+impl Y4mWriter<BufWriter<File>> {
+    /// Creates a Y4M file at `path`.
+    pub fn create<P: AsRef<Path>>(path: P, width: u32, height: u32, fps: f64) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?), width, height, fps)
+    }
+}
+
+impl<W: Write> Y4mWriter<W> {
+    /// Writes the Y4M header to `writer`.
+    pub fn new(mut writer: W, width: u32, height: u32, fps: f64) -> io::Result<Self> {
+        // Fractional frame rates (eg. 29.97) are written in thousandths
+        let (fps_numerator, fps_denominator) = if fps.fract() == 0.0 {
+            (fps as u64, 1)
+        } else {
+            ((fps * 1000.0).round() as u64, 1000)
+        };
+
+        writeln!(
+            writer,
+            "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C420mpeg2 XCOLORRANGE=LIMITED",
+            width, height, fps_numerator, fps_denominator
+        )?;
+
+        Ok(Self {
+            writer,
+            width: width as usize,
+            height: height as usize,
+        })
+    }
+
+    /// Writes a frame, cropping it to the header's dimensions.
+    ///
+    /// Returns `StreamError::FrameSizeMismatch` if the frame is smaller than the header's dimensions or larger than
+    /// decoder padding (up to the next multiple of 16) can account for.
+    pub fn write_frame(&mut self, frame: &YUVFrame) -> Result<(), StreamError> {
+        let padded = |size: usize| size.div_ceil(16) * 16;
+
+        if frame.width() < self.width
+            || frame.height() < self.height
+            || frame.width() > padded(self.width)
+            || frame.height() > padded(self.height)
+        {
+            return Err(StreamError::FrameSizeMismatch {
+                expected: (self.width as u32, self.height as u32),
+                actual: (frame.width() as u32, frame.height() as u32),
+            });
+        }
+
+        let (y_stride, u_stride, v_stride) = frame.strides();
+        let (chroma_width, chroma_height) = (self.width.div_ceil(2), self.height.div_ceil(2));
+
+        self.writer
+            .write_all(b"FRAME\n")
+            .map_err(StreamError::WriteFailure)?;
+
+        for (plane, stride, width, height) in [
+            (frame.y(), y_stride, self.width, self.height),
+            (frame.u(), u_stride, chroma_width, chroma_height),
+            (frame.v(), v_stride, chroma_width, chroma_height),
+        ] {
+            write_plane(&mut self.writer, plane, stride, width, height)
+                .map_err(StreamError::WriteFailure)?;
+        }
+
+        Ok(())
+    }
+
+    /// Flushes and returns the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+fn write_plane(
+    writer: &mut impl Write,
+    plane: &[u8],
+    stride: usize,
+    width: usize,
+    height: usize,
+) -> io::Result<()> {
+    // Tightly packed planes (eg. YUVBuffer) are written in one go
+    if stride == width {
+        return writer.write_all(&plane[..width * height]);
+    }
+
+    for row in plane.chunks(stride).take(height) {
+        writer.write_all(&row[..width])?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,775 @@
+use crate::convert::{self, Packed422, RowPair};
+use crate::{BayerPattern, StreamError};
+use v4l::{Format, FourCC};
+
+#[cfg(feature = "openh264")]
+pub use openh264::formats::YUVSource;
+
+/// A planar YUV 4:2:0 image. This is openh264's `YUVSource` trait when the `openh264` feature is enabled.
+#[cfg(not(feature = "openh264"))]
+pub trait YUVSource {
+    fn width(&self) -> i32;
+    fn height(&self) -> i32;
+
+    fn y(&self) -> &[u8];
+    fn u(&self) -> &[u8];
+    fn v(&self) -> &[u8];
+
+    fn y_stride(&self) -> i32;
+    fn u_stride(&self) -> i32;
+    fn v_stride(&self) -> i32;
+}
+
+/// How a raw frame is laid out in the driver's buffers, from the format the driver applied.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct RawLayout {
+    /// The length of each row in bytes including any padding (`bytesperline`), or 0 if the driver didn't report it.
+    pub(crate) stride: usize,
+    /// The number of rows in the Y plane of NV12 frames, or 0 if it is the frame's height. Some SoC drivers pad the
+    /// plane to a multiple of 16 or more rows and start the chroma plane after the padding.
+    pub(crate) luma_rows: usize,
+}
+
+impl RawLayout {
+    pub(crate) fn new(format: &Format) -> Self {
+        let stride = format.stride as usize;
+        let height = format.height as usize;
+
+        // A padded Y plane shows in the image size, which is 1.5 planes of `luma_rows` rows. Larger images are taken to
+        // be padded at the end instead.
+        let luma_rows = match &format.fourcc.repr {
+            b"NV12" if stride > 0 => {
+                let rows = format.size as usize / stride * 2 / 3;
+                if rows > height && rows <= height.next_multiple_of(64) {
+                    rows
+                } else {
+                    0
+                }
+            }
+            _ => 0,
+        };
+
+        Self { stride, luma_rows }
+    }
+
+    /// A layout without row padding.
+    pub(crate) fn packed() -> Self {
+        Self::default()
+    }
+
+    /// The row stride, which is at least `row_bytes` (the length of a row without padding).
+    pub(crate) fn stride(&self, row_bytes: usize) -> usize {
+        self.stride.max(row_bytes)
+    }
+}
+
+/// An I420 (YUV 4:2:0 planar) image buffer.
+///
+/// Unlike openh264's YUVBuffer the individual planes can be written to directly which allows camera formats other than
+/// RGB to be converted without an intermediate copy.
+#[derive(Clone)]
+pub struct YUVBuffer {
+    yuv: Vec<u8>,
+    width: usize,
+    height: usize,
+}
+
+impl YUVBuffer {
+    /// Allocates a new YUV buffer with the given width and height.
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            yuv: vec![0u8; (3 * (width * height)) / 2],
+            width,
+            height,
+        }
+    }
+
+    /// Allocates a new YUV buffer with the given width and height and converts the packed `[rgb rgb rgb ...]` data into
+    /// it.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if `rgb` does not match the dimensions given.
+    pub fn with_rgb(width: usize, height: usize, rgb: &[u8]) -> Self {
+        let mut yuv = Self::new(width, height);
+        yuv.read_rgb(rgb);
+        yuv
+    }
+
+    /// Copies the visible area of any YUV source (eg. a decoded frame with padded strides) into a new tightly packed
+    /// buffer.
+    pub fn from_source<T: YUVSource + ?Sized>(source: &T) -> Self {
+        let mut yuv = Self::new(source.width() as usize, source.height() as usize);
+        yuv.copy_from(source);
+        yuv
+    }
+
+    /// Copies the visible area of any YUV source into this buffer, reusing its allocation. The buffer is resized if the
+    /// source's dimensions differ.
+    pub fn copy_from<T: YUVSource + ?Sized>(&mut self, source: &T) {
+        let width = source.width() as usize;
+        let height = source.height() as usize;
+
+        if (width, height) != (self.width, self.height) {
+            self.yuv.resize((3 * (width * height)) / 2, 0);
+            self.width = width;
+            self.height = height;
+        }
+
+        let copy_plane = |dst: &mut [u8], src: &[u8], stride: usize, width: usize| {
+            for (dst_row, src_row) in dst.chunks_exact_mut(width).zip(src.chunks(stride)) {
+                dst_row.copy_from_slice(&src_row[..width]);
+            }
+        };
+
+        copy_plane(self.y_mut(), source.y(), source.y_stride() as usize, width);
+        copy_plane(
+            self.u_mut(),
+            source.u(),
+            source.u_stride() as usize,
+            width / 2,
+        );
+        copy_plane(
+            self.v_mut(),
+            source.v(),
+            source.v_stride() as usize,
+            width / 2,
+        );
+    }
+
+    /// Reads a packed `[rgb rgb rgb ...]` buffer, converts it to YUV and stores it.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if `rgb` does not match the dimensions of the buffer.
+    pub fn read_rgb(&mut self, rgb: &[u8]) {
+        self.convert_rgb(rgb, true);
+    }
+
+    /// The portable implementation of [`read_rgb`](Self::read_rgb), for benchmarking and verifying the SIMD one.
+    #[doc(hidden)]
+    pub fn read_rgb_scalar(&mut self, rgb: &[u8]) {
+        self.convert_rgb(rgb, false);
+    }
+
+    fn convert_rgb(&mut self, rgb: &[u8], simd: bool) {
+        let width = self.width;
+
+        assert_eq!(rgb.len(), width * self.height * 3);
+        assert_eq!(self.height % 2, 0, "height needs to be a multiple of 2");
+
+        for (rows, out) in rgb.chunks_exact((width * 6).max(1)).zip(self.row_pairs()) {
+            let (top, bottom) = rows.split_at(width * 3);
+            convert::rgb_row_pair(top, bottom, out, simd);
+        }
+    }
+
+    /// Splits the planes into pairs of rows and the row of chroma samples they share.
+    fn row_pairs(&mut self) -> impl Iterator<Item = RowPair<'_>> {
+        let width = self.width;
+        // Empty buffers have no rows but the chunk sizes can't be 0
+        let half_width = (width / 2).max(1);
+
+        let (y_plane, uv_planes) = self.yuv.split_at_mut(width * self.height);
+        let (u_plane, v_plane) = uv_planes.split_at_mut(y_plane.len() / 4);
+
+        y_plane
+            .chunks_exact_mut((width * 2).max(1))
+            .zip(u_plane.chunks_exact_mut(half_width))
+            .zip(v_plane.chunks_exact_mut(half_width))
+            .map(move |((y, u), v)| {
+                let (y_top, y_bottom) = y.split_at_mut(width);
+                RowPair {
+                    y_top,
+                    y_bottom,
+                    u,
+                    v,
+                }
+            })
+    }
+
+    /// Converts an RGB image given by the colour of each pixel to YUV and stores it.
+    pub(crate) fn read_rgb_with(&mut self, pixel: impl Fn(usize, usize) -> (f32, f32, f32)) {
+        let width = self.width;
+        let height = self.height;
+        let half_width = width / 2;
+
+        assert_eq!(width % 2, 0, "width needs to be multiple of 2");
+        assert_eq!(height % 2, 0, "height needs to be a multiple of 2");
+
+        let (y_plane, uv_planes) = self.yuv.split_at_mut(width * height);
+        let (u_plane, v_plane) = uv_planes.split_at_mut(width * height / 4);
+
+        for j in 0..height / 2 {
+            for i in 0..half_width {
+                let (px, py) = (i * 2, j * 2);
+                let mut sum = (0.0, 0.0, 0.0);
+
+                for (x, y) in [(px, py), (px + 1, py), (px, py + 1), (px + 1, py + 1)] {
+                    let (r, g, b) = pixel(x, y);
+                    y_plane[x + y * width] =
+                        (0.2578125 * r + 0.50390625 * g + 0.09765625 * b + 16.0) as u8;
+                    sum = (sum.0 + r, sum.1 + g, sum.2 + b);
+                }
+
+                let (r, g, b) = (sum.0 / 4.0, sum.1 / 4.0, sum.2 / 4.0);
+                u_plane[i + j * half_width] =
+                    (-0.1484375 * r + -0.2890625 * g + 0.4375 * b + 128.0) as u8;
+                v_plane[i + j * half_width] =
+                    (0.4375 * r + -0.3671875 * g + -0.0703125 * b + 128.0) as u8;
+            }
+        }
+    }
+
+    /// Reads a packed full range `[ycbcr ycbcr ycbcr ...]` (YCbCr 4:4:4 as stored in JPEGs) buffer, subsamples the
+    /// chroma and rescales it to the limited range expected by H264 encoders.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if `ycbcr` does not match the dimensions of the buffer.
+    pub fn read_ycbcr(&mut self, ycbcr: &[u8]) {
+        let width = self.width;
+        let height = self.height;
+
+        assert_eq!(ycbcr.len(), width * height * 3);
+        assert_eq!(width % 2, 0, "width needs to be multiple of 2");
+        assert_eq!(height % 2, 0, "height needs to be a multiple of 2");
+
+        // Rescaled in 16 bit fixed point: luma by 219/255 from 16 and chroma by 224/255 around 128, with the chroma
+        // averaged over the 4 pixels it covers
+        let luma = |sample: u8| ((sample as u32 * 56284 + (16 << 16) + (1 << 15)) >> 16) as u8;
+        let chroma = |sum: i32| (((sum - 512) * 14392 + (128 << 16) + (1 << 15)) >> 16) as u8;
+
+        for (rows, out) in ycbcr.chunks_exact((width * 6).max(1)).zip(self.row_pairs()) {
+            let (top, bottom) = rows.split_at(width * 3);
+
+            let y_pairs = out
+                .y_top
+                .chunks_exact_mut(2)
+                .zip(out.y_bottom.chunks_exact_mut(2));
+            let uv = out.u.iter_mut().zip(out.v.iter_mut());
+            let pixel_pairs = top.chunks_exact(6).zip(bottom.chunks_exact(6));
+
+            for (((y_top, y_bottom), (u, v)), (top, bottom)) in y_pairs.zip(uv).zip(pixel_pairs) {
+                y_top[0] = luma(top[0]);
+                y_top[1] = luma(top[3]);
+                y_bottom[0] = luma(bottom[0]);
+                y_bottom[1] = luma(bottom[3]);
+
+                let sum = |offset: usize| {
+                    top[offset] as i32
+                        + top[offset + 3] as i32
+                        + bottom[offset] as i32
+                        + bottom[offset + 3] as i32
+                };
+                *u = chroma(sum(1));
+                *v = chroma(sum(2));
+            }
+        }
+    }
+
+    /// Reads a packed YUYV (YUV 4:2:2) buffer and stores it, averaging each pair of rows to subsample the chroma
+    /// vertically.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if `yuyv` does not match the dimensions of the buffer.
+    pub fn read_yuyv(&mut self, yuyv: &[u8]) {
+        assert_eq!(yuyv.len(), self.width * self.height * 2);
+        self.read_packed_422(yuyv, RawLayout::packed(), convert::YUYV, true);
+    }
+
+    /// The portable implementation of [`read_yuyv`](Self::read_yuyv), for benchmarking and verifying the SIMD one.
+    #[doc(hidden)]
+    pub fn read_yuyv_scalar(&mut self, yuyv: &[u8]) {
+        assert_eq!(yuyv.len(), self.width * self.height * 2);
+        self.read_packed_422(yuyv, RawLayout::packed(), convert::YUYV, false);
+    }
+
+    /// Reads a packed UYVY (YUV 4:2:2) buffer, which is YUYV with the luma and chroma bytes swapped, and stores it
+    /// like [`read_yuyv`](Self::read_yuyv).
+    ///
+    /// # Panics
+    ///
+    /// Will panic if `uyvy` does not match the dimensions of the buffer.
+    pub fn read_uyvy(&mut self, uyvy: &[u8]) {
+        assert_eq!(uyvy.len(), self.width * self.height * 2);
+        self.read_packed_422(uyvy, RawLayout::packed(), convert::UYVY, true);
+    }
+
+    /// Reads a raw frame from a driver's buffer, checking that it is long enough for the format's rows (including any
+    /// row padding) first so that short or partial frames are returned as [`StreamError::TruncatedFrame`] rather than
+    /// panicking.
+    #[cfg_attr(not(feature = "openh264"), allow(dead_code))]
+    pub(crate) fn read_raw(
+        &mut self,
+        fourcc: FourCC,
+        raw: &[u8],
+        layout: RawLayout,
+    ) -> Result<(), StreamError> {
+        let (width, height) = (self.width, self.height);
+        let expected = |row_bytes: usize, rows: usize| {
+            rows.saturating_sub(1) * layout.stride(row_bytes) + row_bytes
+        };
+        let check = |expected: usize| match raw.len() >= expected {
+            true => Ok(()),
+            false => Err(StreamError::TruncatedFrame {
+                fourcc,
+                expected,
+                actual: raw.len(),
+            }),
+        };
+
+        match &fourcc.repr {
+            b"YUYV" | b"UYVY" => {
+                check(expected(width * 2, height))?;
+                let format = match &fourcc.repr {
+                    b"YUYV" => convert::YUYV,
+                    _ => convert::UYVY,
+                };
+                self.read_packed_422(raw, layout, format, true);
+            }
+            b"NV12" => {
+                let chroma_start = layout.stride(width) * layout.luma_rows.max(height);
+                check(chroma_start + expected(width, height / 2))?;
+                self.read_nv12_with_layout(raw, layout);
+            }
+            _ => {
+                let Some(pattern) = BayerPattern::from_fourcc(fourcc) else {
+                    unreachable!("{} is not a raw format", fourcc)
+                };
+                check(expected(width, height))?;
+                self.read_bayer_with_layout(raw, pattern, layout);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads a packed 4:2:2 frame whose rows start every `layout.stride` bytes. `packed` must be long enough for the
+    /// rows.
+    fn read_packed_422(&mut self, packed: &[u8], layout: RawLayout, format: Packed422, simd: bool) {
+        let width = self.width;
+        let stride = layout.stride(width * 2);
+
+        assert_eq!(self.height % 2, 0, "height needs to be a multiple of 2");
+
+        for (y, out) in self.row_pairs().enumerate() {
+            let top = &packed[y * 2 * stride..][..width * 2];
+            let bottom = &packed[(y * 2 + 1) * stride..][..width * 2];
+            convert::packed_422_row_pair(top, bottom, format, out, simd);
+        }
+    }
+
+    /// Reads a semi-planar NV12 (YUV 4:2:0) buffer, ie. a Y plane followed by a plane of interleaved U and V samples,
+    /// and stores it.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if `nv12` does not match the dimensions of the buffer.
+    pub fn read_nv12(&mut self, nv12: &[u8]) {
+        assert_eq!(nv12.len(), self.width * self.height * 3 / 2);
+        self.read_nv12_with_layout(nv12, RawLayout::packed());
+    }
+
+    /// Reads an NV12 frame whose rows start every `layout.stride` bytes and whose chroma plane starts after
+    /// `layout.luma_rows` rows. `nv12` must be long enough for the planes.
+    fn read_nv12_with_layout(&mut self, nv12: &[u8], layout: RawLayout) {
+        let width = self.width;
+        let height = self.height;
+        let stride = layout.stride(width);
+
+        assert_eq!(width % 2, 0, "width needs to be multiple of 2");
+        assert_eq!(height % 2, 0, "height needs to be a multiple of 2");
+
+        let (y_plane, uv_planes) = self.yuv.split_at_mut(width * height);
+        let (u_plane, v_plane) = uv_planes.split_at_mut(width * height / 4);
+        let (nv12_y, nv12_uv) = nv12.split_at(stride * layout.luma_rows.max(height));
+
+        for (y, row) in y_plane.chunks_exact_mut(width.max(1)).enumerate() {
+            row.copy_from_slice(&nv12_y[y * stride..][..width]);
+        }
+
+        let half_width = (width / 2).max(1);
+        for (y, (u_row, v_row)) in u_plane
+            .chunks_exact_mut(half_width)
+            .zip(v_plane.chunks_exact_mut(half_width))
+            .enumerate()
+        {
+            let uv_row = &nv12_uv[y * stride..][..width];
+            for ((u, v), uv) in u_row
+                .iter_mut()
+                .zip(v_row.iter_mut())
+                .zip(uv_row.chunks_exact(2))
+            {
+                *u = uv[0];
+                *v = uv[1];
+            }
+        }
+    }
+
+    /// Mutable access to the Y (luma) plane.
+    pub fn y_mut(&mut self) -> &mut [u8] {
+        &mut self.yuv[0..self.width * self.height]
+    }
+
+    /// Mutable access to the U (blue projection) plane.
+    pub fn u_mut(&mut self) -> &mut [u8] {
+        let base_u = self.width * self.height;
+        &mut self.yuv[base_u..base_u + base_u / 4]
+    }
+
+    /// Mutable access to the V (red projection) plane.
+    pub fn v_mut(&mut self) -> &mut [u8] {
+        let base_u = self.width * self.height;
+        let base_v = base_u + base_u / 4;
+        &mut self.yuv[base_v..]
+    }
+}
+
+impl YUVSource for YUVBuffer {
+    fn width(&self) -> i32 {
+        self.width as i32
+    }
+
+    fn height(&self) -> i32 {
+        self.height as i32
+    }
+
+    fn y(&self) -> &[u8] {
+        &self.yuv[0..self.width * self.height]
+    }
+
+    fn u(&self) -> &[u8] {
+        let base_u = self.width * self.height;
+        &self.yuv[base_u..base_u + base_u / 4]
+    }
+
+    fn v(&self) -> &[u8] {
+        let base_u = self.width * self.height;
+        let base_v = base_u + base_u / 4;
+        &self.yuv[base_v..]
+    }
+
+    fn y_stride(&self) -> i32 {
+        self.width as i32
+    }
+
+    fn u_stride(&self) -> i32 {
+        (self.width / 2) as i32
+    }
+
+    fn v_stride(&self) -> i32 {
+        (self.width / 2) as i32
+    }
+}
+
+/// The range of the Y, U and V values in a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorRange {
+    /// BT.601 limited (studio) range where Y is 16-235 and U / V are 16-240. This is what camera H264 streams and the
+    /// openh264 encoder use.
+    #[default]
+    Limited,
+    /// Full range where Y, U and V use all of 0-255 (as in JPEGs).
+    Full,
+}
+
+/// Converts the visible area of an I420 source to packed RGB (3 channels) or RGBA (4 channels, opaque), respecting the
+/// source's strides.
+///
+/// # Panics
+///
+/// Will panic if `out` is not `width * height * channels` bytes long.
+pub(crate) fn write_rgb<T: YUVSource>(
+    source: &T,
+    out: &mut [u8],
+    channels: usize,
+    range: ColorRange,
+) {
+    let width = source.width() as usize;
+    let height = source.height() as usize;
+
+    assert_eq!(
+        out.len(),
+        width * height * channels,
+        "RGB buffer does not match the frame dimensions"
+    );
+
+    // Scale factors for Y and the coefficients for converting U / V to R, G and B
+    let (y_offset, y_scale, rv, gu, gv, bu) = match range {
+        ColorRange::Limited => (16.0, 255.0 / 219.0, 1.596, 0.392, 0.813, 2.017),
+        ColorRange::Full => (0.0, 1.0, 1.402, 0.344136, 0.714136, 1.772),
+    };
+
+    for (row, out_row) in out.chunks_exact_mut(width * channels).enumerate() {
+        let y_row = &source.y()[row * source.y_stride() as usize..];
+        let u_row = &source.u()[row / 2 * source.u_stride() as usize..];
+        let v_row = &source.v()[row / 2 * source.v_stride() as usize..];
+
+        for (x, pixel) in out_row.chunks_exact_mut(channels).enumerate() {
+            let y = (y_row[x] as f32 - y_offset) * y_scale;
+            let u = u_row[x / 2] as f32 - 128.0;
+            let v = v_row[x / 2] as f32 - 128.0;
+
+            pixel[0] = (y + rv * v).round().clamp(0.0, 255.0) as u8;
+            pixel[1] = (y - gu * u - gv * v).round().clamp(0.0, 255.0) as u8;
+            pixel[2] = (y + bu * u).round().clamp(0.0, 255.0) as u8;
+
+            if channels == 4 {
+                pixel[3] = 255;
+            }
+        }
+    }
+}
+
+/// Counts the luma values of every `step`th pixel (horizontally and vertically) in the visible area of the source.
+pub(crate) fn luma_histogram<T: YUVSource + ?Sized>(source: &T, step: usize) -> [u32; 256] {
+    let width = source.width() as usize;
+    let height = source.height() as usize;
+    let step = step.max(1);
+
+    let mut histogram = [0; 256];
+
+    for row in source
+        .y()
+        .chunks(source.y_stride() as usize)
+        .take(height)
+        .step_by(step)
+    {
+        for luma in row[..width].iter().step_by(step) {
+            histogram[*luma as usize] += 1;
+        }
+    }
+
+    histogram
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A YUYV frame where every sample has a value distinct from its neighbours', with `padding` bytes of 0xff after
+    /// each row.
+    fn yuyv_frame(width: usize, height: usize, padding: usize) -> Vec<u8> {
+        let mut frame = Vec::new();
+        for y in 0..height {
+            for x in (0..width).step_by(2) {
+                let (y0, y1) = ((x + y * 3) as u8, (x + 1 + y * 3) as u8);
+                let (u, v) = (100 + (x / 2 + y * 2) as u8, 200 + (x / 2 + y * 2) as u8);
+                frame.extend([y0, u, y1, v]);
+            }
+            frame.extend(std::iter::repeat_n(0xff, padding));
+        }
+        frame
+    }
+
+    /// The planes expected from [`yuyv_frame`]: the luma unchanged and each pair of rows' chroma averaged (rounding
+    /// up).
+    fn expected_planes(width: usize, height: usize) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        let mut planes = (Vec::new(), Vec::new(), Vec::new());
+        for y in 0..height {
+            for x in 0..width {
+                planes.0.push((x + y * 3) as u8);
+            }
+        }
+        for y in (0..height).step_by(2) {
+            for x in 0..width / 2 {
+                let average = |base: usize| ((base + x + y * 2) * 2 + 2).div_ceil(2) as u8;
+                planes.1.push(average(100));
+                planes.2.push(average(200));
+            }
+        }
+        planes
+    }
+
+    #[test]
+    fn reads_yuyv_planes() {
+        // Wide enough for the SIMD kernels with a scalar tail
+        for width in [4, 36] {
+            let mut buffer = YUVBuffer::new(width, 4);
+            buffer.read_yuyv(&yuyv_frame(width, 4, 0));
+
+            let (y, u, v) = expected_planes(width, 4);
+            assert_eq!(buffer.y(), y, "Y plane of a {width} pixel wide frame");
+            assert_eq!(buffer.u(), u, "U plane of a {width} pixel wide frame");
+            assert_eq!(buffer.v(), v, "V plane of a {width} pixel wide frame");
+        }
+    }
+
+    #[test]
+    fn reads_yuyv_with_padded_rows() {
+        let yuyv = FourCC::new(b"YUYV");
+        let layout = RawLayout {
+            stride: 36 * 2 + 8,
+            ..RawLayout::default()
+        };
+        let mut buffer = YUVBuffer::new(36, 4);
+
+        buffer
+            .read_raw(yuyv, &yuyv_frame(36, 4, 8), layout)
+            .unwrap();
+
+        let (y, u, v) = expected_planes(36, 4);
+        assert_eq!(
+            (buffer.y(), buffer.u(), buffer.v()),
+            (&y[..], &u[..], &v[..])
+        );
+    }
+
+    #[test]
+    fn reads_ycbcr_planes() {
+        // Every luma value appears
+        let (width, height) = (256, 4);
+        let ycbcr: Vec<u8> = (0..width * height * 3)
+            .map(|i| (i * 97 % 256) as u8)
+            .collect();
+        let mut buffer = YUVBuffer::new(width, height);
+        buffer.read_ycbcr(&ycbcr);
+
+        // The full range samples rescaled to the limited range in floating point
+        let sample = |x: usize, y: usize, offset: usize| ycbcr[(x + y * width) * 3 + offset] as f32;
+        let luma = (0..width * height).map(|i| ycbcr[i * 3] as f32 * (219.0 / 255.0) + 16.0);
+        let chroma = |offset: usize| {
+            (0..height / 2).flat_map(move |y| {
+                (0..width / 2).map(move |x| {
+                    let sum = sample(x * 2, y * 2, offset)
+                        + sample(x * 2 + 1, y * 2, offset)
+                        + sample(x * 2, y * 2 + 1, offset)
+                        + sample(x * 2 + 1, y * 2 + 1, offset);
+                    (sum / 4.0 - 128.0) * (224.0 / 255.0) + 128.0
+                })
+            })
+        };
+
+        for (plane, (actual, expected)) in [
+            ("Y", (buffer.y(), luma.collect::<Vec<_>>())),
+            ("U", (buffer.u(), chroma(1).collect())),
+            ("V", (buffer.v(), chroma(2).collect())),
+        ] {
+            assert_eq!(actual.len(), expected.len());
+            for (actual, expected) in actual.iter().zip(expected) {
+                assert!(
+                    (*actual as f32 - expected).abs() <= 0.5 + 1e-3,
+                    "The {plane} sample {actual} should be {expected}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn reads_uyvy_planes() {
+        let uyvy: Vec<u8> = yuyv_frame(36, 4, 0)
+            .chunks_exact(4)
+            .flat_map(|yuyv| [yuyv[1], yuyv[0], yuyv[3], yuyv[2]])
+            .collect();
+        let mut buffer = YUVBuffer::new(36, 4);
+
+        buffer
+            .read_raw(FourCC::new(b"UYVY"), &uyvy, RawLayout::default())
+            .unwrap();
+
+        let (y, u, v) = expected_planes(36, 4);
+        assert_eq!(
+            (buffer.y(), buffer.u(), buffer.v()),
+            (&y[..], &u[..], &v[..])
+        );
+    }
+
+    /// An NV12 frame with the same luma as [`yuyv_frame`] and distinct U (from 50) and V (from 200) samples, with rows
+    /// `stride` bytes apart and a Y plane of `luma_rows` rows.
+    fn nv12_frame(width: usize, height: usize, stride: usize, luma_rows: usize) -> Vec<u8> {
+        let mut frame = Vec::new();
+        for y in 0..luma_rows {
+            let row = (0..width).map(|x| if y < height { (x + y * 3) as u8 } else { 0xff });
+            frame.extend(row.chain(std::iter::repeat_n(0xff, stride - width)));
+        }
+        for y in 0..height / 2 {
+            for x in 0..width / 2 {
+                frame.extend([50 + (x + y) as u8, 200 + (x + y) as u8]);
+            }
+            frame.extend(std::iter::repeat_n(0xff, stride - width));
+        }
+        frame
+    }
+
+    fn nv12_chroma(width: usize, height: usize) -> (Vec<u8>, Vec<u8>) {
+        (0..height / 2)
+            .flat_map(|y| (0..width / 2).map(move |x| (50 + (x + y) as u8, 200 + (x + y) as u8)))
+            .unzip()
+    }
+
+    #[test]
+    fn reads_nv12_planes() {
+        let mut buffer = YUVBuffer::new(8, 4);
+        buffer.read_nv12(&nv12_frame(8, 4, 8, 4));
+
+        let (y, _, _) = expected_planes(8, 4);
+        let (u, v) = nv12_chroma(8, 4);
+        assert_eq!(buffer.y(), y);
+        assert_eq!(buffer.u(), u, "U plane");
+        assert_eq!(buffer.v(), v, "V plane");
+    }
+
+    #[test]
+    fn reads_nv12_with_padded_rows_and_planes() {
+        let nv12 = FourCC::new(b"NV12");
+        // The driver pads both planes to 16 rows
+        let mut frame = nv12_frame(8, 4, 16, 16);
+        frame.resize(16 * 16 * 3 / 2, 0xff);
+
+        let mut format = Format::new(8, 4, nv12);
+        format.stride = 16;
+        format.size = frame.len() as u32;
+        let layout = RawLayout::new(&format);
+        assert_eq!(layout.luma_rows, 16);
+
+        let mut buffer = YUVBuffer::new(8, 4);
+        buffer.read_raw(nv12, &frame, layout).unwrap();
+
+        let (y, _, _) = expected_planes(8, 4);
+        let (u, v) = nv12_chroma(8, 4);
+        assert_eq!(buffer.y(), y);
+        assert_eq!(buffer.u(), u, "U plane");
+        assert_eq!(buffer.v(), v, "V plane");
+    }
+
+    #[test]
+    fn rejects_short_nv12_frames() {
+        let nv12 = FourCC::new(b"NV12");
+        let layout = RawLayout {
+            stride: 16,
+            luma_rows: 16,
+        };
+        let frame = nv12_frame(8, 4, 16, 16);
+        let mut buffer = YUVBuffer::new(8, 4);
+
+        // The last chroma row needs no padding
+        let result = buffer.read_raw(nv12, &frame[..frame.len() - 9], layout);
+        assert!(matches!(
+            result,
+            Err(StreamError::TruncatedFrame {
+                expected: 280,
+                actual: 279,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_short_yuyv_frames() {
+        let yuyv = FourCC::new(b"YUYV");
+        let mut buffer = YUVBuffer::new(36, 4);
+        let frame = yuyv_frame(36, 4, 0);
+
+        let result = buffer.read_raw(yuyv, &frame[..frame.len() - 1], RawLayout::default());
+        assert!(matches!(
+            result,
+            Err(StreamError::TruncatedFrame {
+                expected: 288,
+                actual: 287,
+                ..
+            })
+        ));
+    }
+}